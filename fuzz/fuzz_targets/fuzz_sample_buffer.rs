@@ -4,7 +4,7 @@ use libfuzzer_sys::{
 	arbitrary::{Arbitrary, Unstructured},
 	fuzz_target,
 };
-use mu_rust::{Asdu, Sample, sample_buffer::SampleBufferQueue};
+use mu_rust::{Asdu, Quality, Sample, UtcTime, sample_buffer::SampleBufferQueue};
 
 #[derive(Debug)]
 struct AsduWrapper(Asdu);
@@ -16,7 +16,7 @@ impl Arbitrary<'_> for AsduWrapper {
 			datset: u.arbitrary()?,
 			smp_cnt: u.arbitrary()?,
 			conf_rev: u.arbitrary()?,
-			refr_tm: u.arbitrary()?,
+			refr_tm: u.arbitrary::<Option<[u8; 8]>>()?.map(UtcTime::from_octets),
 			smp_synch: u.arbitrary()?,
 			smp_rate: u.arbitrary()?,
 			sample: Sample {
@@ -28,8 +28,10 @@ impl Arbitrary<'_> for AsduWrapper {
 				voltage_b: u.arbitrary()?,
 				voltage_c: u.arbitrary()?,
 				voltage_n: u.arbitrary()?,
+				quality: u.arbitrary::<[u32; 8]>()?.map(Quality),
 			},
 			smp_mod: u.arbitrary()?,
+			gm_identity: u.arbitrary()?,
 		}))
 	}
 }
@@ -43,7 +45,7 @@ fuzz_target!(|data: Vec<AsduWrapper>| {
 	let mut ns = 156255;
 
 	for AsduWrapper(asdu) in data {
-		sample_buffer_queue.insert_sample(1_000_000_000, ns, sample_rate, buffer_length, asdu);
+		sample_buffer_queue.insert_sample(0, 1_000_000_000, ns, sample_rate, buffer_length, asdu);
 		ns += 1000;
 	}
 });
@@ -1,3 +1,11 @@
+//! Decoding and encoding of the subset of ASN.1 Basic Encoding Rules used by IEC 61850 protocols.
+//!
+//! The following are considered stable, and can be used to decode other IEC 61850 PDUs (such as GOOSE) which share
+//! the same TLV structure: `Tag`, `Encoding`, `Identifier`, `DecodeError`, and the `read_*` and `write_*` functions.
+//! New `DecodeError` variants may be added, so matches on it should include a wildcard arm.
+
+use std::fmt;
+
 use thiserror::Error;
 
 use crate::bytes::{BytesReader, BytesReaderError};
@@ -24,6 +32,7 @@ pub struct Identifier {
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
+#[non_exhaustive]
 pub enum DecodeError {
 	#[error("Encountered an unexpected tag")]
 	UnexpectedTag,
@@ -35,16 +44,105 @@ pub enum DecodeError {
 	ReservedLength,
 	#[error("Length exceeds the maximum supported value")]
 	LengthOutOfRange,
+	#[error("Length is not encoded in the fewest possible bytes")]
+	NonMinimalLength,
 	#[error("Invalid integer encoding")]
 	InvalidIntegerEncoding,
+	#[error("Expected {expected} bytes, but found {actual}")]
+	UnexpectedLength { expected: usize, actual: usize },
 	#[error("Integer is out of range")]
 	IntegerOutOfRange,
 	#[error("Constructed strings are not supported")]
 	ConstructedString,
 	#[error("Invalid VisibleString")]
 	InvalidVisibleString,
+	#[error("Encountered an unknown field in an ASDU")]
+	UnknownAsduField,
+	#[error("noASDU declares {declared} ASDUs, but {found} were found")]
+	AsduCountMismatch { declared: u16, found: usize },
+	#[error("APPID {0:#06X} is outside the range reserved for sampled values")]
+	AppidOutOfRange(u16),
+	#[error("Sample value is not a finite number")]
+	NonFiniteValue,
 	#[error(transparent)]
 	ReadError(#[from] BytesReaderError),
+	#[error("{source} at byte {offset}")]
+	At { offset: usize, source: Box<DecodeError> },
+}
+
+impl DecodeError {
+	/// Attaches the offset (from the start of the input) at which the error occurred. If an offset is already attached,
+	/// it is kept, since it will be from closer to the source of the error.
+	pub fn at(self, offset: usize) -> Self {
+		match self {
+			Self::At { .. } => self,
+			_ => Self::At {
+				offset,
+				source: Box::new(self),
+			},
+		}
+	}
+
+	/// Returns the offset at which the error occurred, if known.
+	pub fn offset(&self) -> Option<usize> {
+		match self {
+			Self::At { offset, .. } => Some(*offset),
+			_ => None,
+		}
+	}
+
+	/// Returns the error without any offset attached.
+	pub fn kind(&self) -> &Self {
+		match self {
+			Self::At { source, .. } => source,
+			_ => self,
+		}
+	}
+
+	/// Returns a wrapper which displays the error followed by the bytes around its offset in hex, with the byte at the
+	/// offset in brackets. `bytes` should be the input in which the error occurred.
+	pub fn context<'a>(&'a self, bytes: &'a [u8]) -> DecodeErrorContext<'a> {
+		DecodeErrorContext { error: self, bytes }
+	}
+}
+
+/// The number of bytes shown on each side of the offset by `DecodeErrorContext`.
+const CONTEXT_BYTES: usize = 8;
+
+/// Displays a `DecodeError` with the bytes around its offset, as returned by `DecodeError::context`.
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeErrorContext<'a> {
+	error: &'a DecodeError,
+	bytes: &'a [u8],
+}
+
+impl fmt::Display for DecodeErrorContext<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.error)?;
+		let Some(offset) = self.error.offset() else {
+			return Ok(());
+		};
+
+		let start = offset.saturating_sub(CONTEXT_BYTES).min(self.bytes.len());
+		let end = offset.saturating_add(CONTEXT_BYTES + 1).min(self.bytes.len());
+		write!(f, ":")?;
+		if start > 0 {
+			write!(f, " ...")?;
+		}
+		for (position, byte) in (start..end).zip(&self.bytes[start..end]) {
+			if position == offset {
+				write!(f, " [{byte:02X}]")?;
+			} else {
+				write!(f, " {byte:02X}")?;
+			}
+		}
+		if offset >= self.bytes.len() {
+			write!(f, " [end]")?;
+		} else if end < self.bytes.len() {
+			write!(f, " ...")?;
+		}
+		Ok(())
+	}
 }
 
 pub fn read_identifier(reader: &mut BytesReader<'_>) -> Result<Identifier, DecodeError> {
@@ -114,6 +212,16 @@ pub fn read_optional_identifier(reader: &mut BytesReader<'_>, tag: Tag) -> Resul
 }
 
 pub fn read_length(reader: &mut BytesReader<'_>) -> Result<usize, DecodeError> {
+	read_length_with(reader, false)
+}
+
+/// Reads a length like `read_length`, but rejects long-form lengths which could have been encoded in fewer bytes, as
+/// DER requires, with `NonMinimalLength`.
+pub fn read_minimal_length(reader: &mut BytesReader<'_>) -> Result<usize, DecodeError> {
+	read_length_with(reader, true)
+}
+
+fn read_length_with(reader: &mut BytesReader<'_>, minimal: bool) -> Result<usize, DecodeError> {
 	match reader.read_u8()? {
 		// Definite form, short
 		value @ 0..0b1000_0000 => Ok(value as usize),
@@ -127,13 +235,22 @@ pub fn read_length(reader: &mut BytesReader<'_>) -> Result<usize, DecodeError> {
 		// Definite form, long
 		value => {
 			let mut length: usize = 0;
-			for _ in 0..(value & 0b0111_1111) {
+			for i in 0..(value & 0b0111_1111) {
 				if length.leading_zeros() < 8 {
 					return Err(DecodeError::LengthOutOfRange);
 				}
 
+				let byte = reader.read_u8()?;
+				if minimal && i == 0 && byte == 0 {
+					return Err(DecodeError::NonMinimalLength);
+				}
 				length <<= 8;
-				length |= reader.read_u8()? as usize;
+				length |= byte as usize;
+			}
+
+			// Lengths below 0x80 must use the short form.
+			if minimal && length < 0x80 {
+				return Err(DecodeError::NonMinimalLength);
 			}
 			Ok(length)
 		}
@@ -141,35 +258,46 @@ pub fn read_length(reader: &mut BytesReader<'_>) -> Result<usize, DecodeError> {
 }
 
 pub fn read_integer_as_u16(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<u16, DecodeError> {
+	read_unsigned_integer(reader, encoding)
+}
+
+pub fn read_integer_as_u32(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<u32, DecodeError> {
+	read_unsigned_integer(reader, encoding)
+}
+
+/// Reads an INTEGER which must be non-negative and fit in `T`. Values which are negative or too large for `T` give
+/// `IntegerOutOfRange`.
+pub fn read_unsigned_integer<T: TryFrom<u64>>(
+	reader: &mut BytesReader<'_>,
+	encoding: Encoding,
+) -> Result<T, DecodeError> {
 	if encoding != Encoding::Primitive {
 		return Err(DecodeError::InvalidIntegerEncoding);
 	}
 
 	let length = read_length(reader)?;
 
-	match *reader.read_bytes(length)? {
+	let magnitude = match *reader.read_bytes(length)? {
 		// Integers must contain at least one byte.
-		[] => Err(DecodeError::InvalidIntegerEncoding),
+		[] => return Err(DecodeError::InvalidIntegerEncoding),
 
 		// Overlong encodings (those where the first nine bits are the same) are invalid.
-		[0, ..0x80, ..] => Err(DecodeError::InvalidIntegerEncoding),
-		[0xFF, (0x80..), ..] => Err(DecodeError::InvalidIntegerEncoding),
-
-		// Negative values are out of range for a u16.
-		[(0x80..), ..] => Err(DecodeError::IntegerOutOfRange),
+		[0, ..0x80, ..] => return Err(DecodeError::InvalidIntegerEncoding),
+		[0xFF, (0x80..), ..] => return Err(DecodeError::InvalidIntegerEncoding),
 
-		// 1 byte encoding (0..=127)
-		[b_0] => Ok(b_0 as u16),
+		// Negative values are out of range for an unsigned type.
+		[(0x80..), ..] => return Err(DecodeError::IntegerOutOfRange),
 
-		// 2 byte encoding (128..=32767)
-		[b_0, b_1] => Ok(u16::from_be_bytes([b_0, b_1])),
-
-		// 3 byte encoding (32768..65535)
-		[0, b_0, b_1] => Ok(u16::from_be_bytes([b_0, b_1])),
+		// A leading zero byte is needed to encode values whose most significant bit is set.
+		[0, ref magnitude @ ..] => magnitude,
+		ref magnitude => magnitude,
+	};
 
-		// Any other valid encoding would be out of range for a u16.
-		_ => Err(DecodeError::IntegerOutOfRange),
+	if magnitude.len() > size_of::<u64>() {
+		return Err(DecodeError::IntegerOutOfRange);
 	}
+	let value = magnitude.iter().fold(0, |value, &byte| (value << 8) | u64::from(byte));
+	T::try_from(value).map_err(|_| DecodeError::IntegerOutOfRange)
 }
 
 pub fn read_octet_string<'b>(reader: &mut BytesReader<'b>, encoding: Encoding) -> Result<&'b [u8], DecodeError> {
@@ -181,6 +309,25 @@ pub fn read_octet_string<'b>(reader: &mut BytesReader<'b>, encoding: Encoding) -
 	reader.read_bytes(length).map_err(Into::into)
 }
 
+/// Reads an OCTET STRING in either the primitive or the constructed form, copying it into a new buffer. In the
+/// constructed form, the string is split into segments, each of which must be a primitive OCTET STRING.
+///
+/// `read_octet_string` should be preferred where the constructed form need not be supported, since it does not copy.
+pub fn read_octet_string_owned(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<Vec<u8>, DecodeError> {
+	if encoding == Encoding::Primitive {
+		return read_octet_string(reader, encoding).map(Vec::from);
+	}
+
+	let length = read_length(reader)?;
+	let mut segments = reader.take_sub_reader(length)?;
+	let mut bytes = Vec::new();
+	while !segments.is_empty() {
+		let encoding = read_required_identifier(&mut segments, Tag::Universal(4))?;
+		bytes.extend_from_slice(read_octet_string(&mut segments, encoding)?);
+	}
+	Ok(bytes)
+}
+
 pub fn read_visiblestring<'b>(reader: &mut BytesReader<'b>, encoding: Encoding) -> Result<&'b str, DecodeError> {
 	if encoding == Encoding::Constructed {
 		return Err(DecodeError::ConstructedString);
@@ -200,6 +347,78 @@ pub fn read_visiblestring<'b>(reader: &mut BytesReader<'b>, encoding: Encoding)
 	}
 }
 
+pub fn write_identifier(buf: &mut Vec<u8>, identifier: Identifier) {
+	let (class, num) = match identifier.tag {
+		Tag::Universal(num) => (0, num),
+		Tag::Application(num) => (1, num),
+		Tag::ContextSpecific(num) => (2, num),
+		Tag::Private(num) => (3, num),
+	};
+	let constructed = match identifier.encoding {
+		Encoding::Primitive => 0,
+		Encoding::Constructed => 1 << 5,
+	};
+
+	if num < 31 {
+		buf.push(class << 6 | constructed | num as u8);
+		return;
+	}
+
+	// High tag number form: the number follows in base 128, most significant digit first, with the top bit of every
+	// byte but the last set.
+	buf.push(class << 6 | constructed | 31);
+	let digits = (32 - num.leading_zeros()).div_ceil(7);
+	for i in (0..digits).rev() {
+		let continuation = if i == 0 { 0 } else { 0x80 };
+		buf.push(continuation | ((num >> (7 * i)) & 0x7F) as u8);
+	}
+}
+
+/// Writes a length in the definite form, using as few bytes as possible.
+pub fn write_length(buf: &mut Vec<u8>, length: usize) {
+	if length < 0x80 {
+		buf.push(length as u8);
+		return;
+	}
+
+	let bytes = length.to_be_bytes();
+	let skip = length.leading_zeros() as usize / 8;
+	buf.push(0x80 | (bytes.len() - skip) as u8);
+	buf.extend_from_slice(&bytes[skip..]);
+}
+
+pub fn write_octet_string(buf: &mut Vec<u8>, tag: Tag, bytes: &[u8]) {
+	let identifier = Identifier {
+		tag,
+		encoding: Encoding::Primitive,
+	};
+	write_identifier(buf, identifier);
+	write_length(buf, bytes.len());
+	buf.extend_from_slice(bytes);
+}
+
+/// Writes an integer in two's complement, using as few bytes as possible.
+pub fn write_integer(buf: &mut Vec<u8>, tag: Tag, value: i64) {
+	let bytes = value.to_be_bytes();
+	// Leading bytes can be dropped while the first nine bits of the remainder would all be the same.
+	let skip = bytes
+		.windows(2)
+		.take_while(|pair| matches!(*pair, [0, ..0x80] | [0xFF, (0x80..)]))
+		.count();
+	write_octet_string(buf, tag, &bytes[skip..]);
+}
+
+/// Writes a constructed value whose contents have already been encoded.
+pub fn write_constructed(buf: &mut Vec<u8>, tag: Tag, contents: &[u8]) {
+	let identifier = Identifier {
+		tag,
+		encoding: Encoding::Constructed,
+	};
+	write_identifier(buf, identifier);
+	write_length(buf, contents.len());
+	buf.extend_from_slice(contents);
+}
+
 #[cfg(test)]
 mod tests {
 	#![allow(clippy::unusual_byte_groupings)]
@@ -372,6 +591,28 @@ mod tests {
 		read_length(&mut reader).expect_err("should fail when reader runs out of bytes");
 	}
 
+	#[test]
+	fn read_minimal_length_non_minimal() {
+		for bytes in [&[0x81, 0x00][..], &[0x81, 0x7F], &[0x82, 0x00, 0x80]] {
+			let result = read_minimal_length(&mut BytesReader::new(bytes));
+			assert_eq!(result, Err(DecodeError::NonMinimalLength), "{bytes:02X?}");
+			// The lenient form accepts them.
+			read_length(&mut BytesReader::new(bytes)).unwrap();
+		}
+
+		#[rustfmt::skip]
+		let bytes = [
+			0x7F,
+			0x81, 0x80,
+			0x82, 0x12, 0x34,
+		];
+		let mut reader = BytesReader::new(&bytes);
+		assert_eq!(read_minimal_length(&mut reader), Ok(0x7F));
+		assert_eq!(read_minimal_length(&mut reader), Ok(0x80));
+		assert_eq!(read_minimal_length(&mut reader), Ok(0x1234));
+		assert!(reader.is_empty());
+	}
+
 	#[test]
 	fn read_integer_as_u16_valid() {
 		#[rustfmt::skip]
@@ -425,6 +666,62 @@ mod tests {
 			.expect_err("should fail with value which is out of range");
 	}
 
+	#[test]
+	fn read_integer_as_u32_valid() {
+		#[rustfmt::skip]
+		let bytes = [
+			0x01, 0x12,
+			0x03, 0x34, 0x56, 0x78,
+			0x04, 0x7F, 0xFF, 0xFF, 0xFF,
+			0x05, 0x00, 0x89, 0xAB, 0xCD, 0xEF,
+		];
+		let mut reader = BytesReader::new(&bytes);
+
+		let result = read_integer_as_u32(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Ok(0x12));
+
+		let result = read_integer_as_u32(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Ok(0x345678));
+
+		let result = read_integer_as_u32(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Ok(0x7FFFFFFF));
+
+		let result = read_integer_as_u32(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Ok(0x89ABCDEF));
+
+		assert!(reader.is_empty());
+	}
+
+	#[test]
+	fn read_integer_as_u32_overlong() {
+		let mut reader = BytesReader::new(&[0x03, 0x00, 0x12, 0x34]);
+		read_integer_as_u32(&mut reader, Encoding::Primitive).expect_err("should fail with overlong encoding");
+
+		let mut reader = BytesReader::new(&[0x05, 0xFF, 0x89, 0xAB, 0xCD, 0xEF]);
+		read_integer_as_u32(&mut reader, Encoding::Primitive).expect_err("should fail with overlong encoding");
+	}
+
+	#[test]
+	fn read_integer_as_u32_out_of_range() {
+		let mut reader = BytesReader::new(&[0x04, 0x89, 0xAB, 0xCD, 0xEF]);
+		let result = read_integer_as_u32(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Err(DecodeError::IntegerOutOfRange), "should fail with negative value");
+
+		let mut reader = BytesReader::new(&[0x05, 0x01, 0x23, 0x45, 0x67, 0x89]);
+		let result = read_integer_as_u32(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Err(DecodeError::IntegerOutOfRange), "should fail with value which is out of range");
+
+		let mut reader = BytesReader::new(&[0x0A, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		let result = read_unsigned_integer::<u64>(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Err(DecodeError::IntegerOutOfRange), "should fail with value wider than 64 bits");
+	}
+
+	#[test]
+	fn read_unsigned_integer_u64() {
+		let mut reader = BytesReader::new(&[0x09, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+		assert_eq!(read_unsigned_integer(&mut reader, Encoding::Primitive), Ok(u64::MAX));
+	}
+
 	#[test]
 	fn read_octet_string_valid() {
 		let mut reader = BytesReader::new(b"\x06abc\x00\x01\x02");
@@ -445,6 +742,40 @@ mod tests {
 		read_octet_string(&mut reader, Encoding::Constructed).expect_err("should fail with constructed tag");
 	}
 
+	#[test]
+	fn read_octet_string_owned_primitive() {
+		let mut reader = BytesReader::new(b"\x03abc");
+		let result = read_octet_string_owned(&mut reader, Encoding::Primitive);
+		assert_eq!(result, Ok(b"abc".to_vec()));
+		assert!(reader.is_empty());
+	}
+
+	#[test]
+	fn read_octet_string_owned_constructed() {
+		let mut reader = BytesReader::new(b"\x0B\x04\x03abc\x04\x00\x04\x02de\xFF");
+		let result = read_octet_string_owned(&mut reader, Encoding::Constructed);
+		assert_eq!(result, Ok(b"abcde".to_vec()));
+		assert_eq!(reader.read_u8(), Ok(0xFF));
+		assert!(reader.is_empty());
+	}
+
+	#[test]
+	fn read_octet_string_owned_invalid_segments() {
+		// Segments must be universal OCTET STRINGs.
+		let mut reader = BytesReader::new(b"\x05\x80\x03abc");
+		let result = read_octet_string_owned(&mut reader, Encoding::Constructed);
+		assert_eq!(result, Err(DecodeError::UnexpectedTag));
+
+		// Segments may not themselves be constructed.
+		let mut reader = BytesReader::new(b"\x07\x24\x05\x04\x03abc");
+		let result = read_octet_string_owned(&mut reader, Encoding::Constructed);
+		assert_eq!(result, Err(DecodeError::ConstructedString));
+
+		// Segments may not extend beyond the end of the string.
+		let mut reader = BytesReader::new(b"\x04\x04\x03abc");
+		read_octet_string_owned(&mut reader, Encoding::Constructed).expect_err("should fail with truncated segment");
+	}
+
 	#[test]
 	fn read_visiblestring_valid() {
 		let mut reader = BytesReader::new(b"\x04test");
@@ -487,4 +818,119 @@ mod tests {
 		let mut reader = BytesReader::new(b"\x05caf\xC3\xA9"); // 'café' in UTF-8
 		read_visiblestring(&mut reader, Encoding::Primitive).expect_err("should fail with non-ASCII characters");
 	}
+
+	#[test]
+	fn write_identifier_round_trip() {
+		#[rustfmt::skip]
+		let identifiers = [
+			Identifier { tag: Tag::Universal(10), encoding: Encoding::Primitive },
+			Identifier { tag: Tag::Application(21), encoding: Encoding::Constructed },
+			Identifier { tag: Tag::ContextSpecific(30), encoding: Encoding::Primitive },
+			Identifier { tag: Tag::Private(31), encoding: Encoding::Primitive },
+			Identifier { tag: Tag::Universal(0x555), encoding: Encoding::Primitive },
+			Identifier { tag: Tag::ContextSpecific(0x19ABCDEF), encoding: Encoding::Constructed },
+		];
+		let mut buf = vec![];
+		for identifier in identifiers {
+			write_identifier(&mut buf, identifier);
+		}
+		assert_eq!(buf[..7], [0b00_0_01010, 0b01_1_10101, 0b10_0_11110, 0b11_0_11111, 0x1F, 0b00_0_11111, 0x8A]);
+
+		let mut reader = BytesReader::new(&buf);
+		for identifier in identifiers {
+			assert_eq!(read_identifier(&mut reader), Ok(identifier));
+		}
+		assert!(reader.is_empty());
+	}
+
+	#[test]
+	fn write_length_minimal() {
+		let mut buf = vec![];
+		for length in [0x12, 0x7F, 0x80, 0x1234, 0x12345678] {
+			write_length(&mut buf, length);
+		}
+		#[rustfmt::skip]
+		assert_eq!(buf, [
+			0x12,
+			0x7F,
+			0x81, 0x80,
+			0x82, 0x12, 0x34,
+			0x84, 0x12, 0x34, 0x56, 0x78,
+		]);
+	}
+
+	#[test]
+	fn write_integer_minimal() {
+		let cases: [(i64, &[u8]); 7] = [
+			(0, &[0x00]),
+			(0x12, &[0x12]),
+			(0x80, &[0x00, 0x80]),
+			(0x89AB, &[0x00, 0x89, 0xAB]),
+			(-1, &[0xFF]),
+			(-128, &[0x80]),
+			(-129, &[0xFF, 0x7F]),
+		];
+		for (value, expected) in cases {
+			let mut buf = vec![];
+			write_integer(&mut buf, Tag::Universal(2), value);
+			assert_eq!(buf[2..], *expected, "{value}");
+			assert_eq!(buf[..2], [0x02, expected.len() as u8]);
+		}
+
+		for value in [0, 0x7F, 0x80, 0x3456, 0xFFFF] {
+			let mut buf = vec![];
+			write_integer(&mut buf, Tag::Universal(2), value);
+			let mut reader = BytesReader::new(&buf[1..]);
+			assert_eq!(read_integer_as_u16(&mut reader, Encoding::Primitive), Ok(value as u16));
+		}
+	}
+
+	#[test]
+	fn write_octet_string_round_trip() {
+		let mut buf = vec![];
+		write_octet_string(&mut buf, Tag::ContextSpecific(7), b"abc\x00\x01\x02");
+		assert_eq!(buf, b"\x87\x06abc\x00\x01\x02");
+
+		let mut reader = BytesReader::new(&buf);
+		let encoding = read_required_identifier(&mut reader, Tag::ContextSpecific(7)).unwrap();
+		assert_eq!(read_octet_string(&mut reader, encoding), Ok(&b"abc\x00\x01\x02"[..]));
+	}
+
+	#[test]
+	fn decode_error_offset() {
+		let error = DecodeError::UnexpectedTag;
+		assert_eq!(error.offset(), None);
+		assert_eq!(error.kind(), &DecodeError::UnexpectedTag);
+
+		let error = error.at(37).at(12);
+		assert_eq!(error.offset(), Some(37));
+		assert_eq!(error.kind(), &DecodeError::UnexpectedTag);
+		assert_eq!(error.to_string(), "Encountered an unexpected tag at byte 37");
+	}
+
+	#[test]
+	fn decode_error_context() {
+		let bytes: Vec<u8> = (0..40).collect();
+		let error = DecodeError::UnexpectedTag.at(20);
+		assert_eq!(
+			error.context(&bytes).to_string(),
+			"Encountered an unexpected tag at byte 20: ... 0C 0D 0E 0F 10 11 12 13 [14] 15 16 17 18 19 1A 1B 1C ..."
+		);
+
+		let error = DecodeError::UnexpectedTag.at(2);
+		assert_eq!(
+			error.context(&bytes[..5]).to_string(),
+			"Encountered an unexpected tag at byte 2: 00 01 [02] 03 04"
+		);
+
+		let error = DecodeError::IndefiniteLength.at(3);
+		assert_eq!(
+			error.context(&bytes[..3]).to_string(),
+			"Indefinite length is not supported at byte 3: 00 01 02 [end]"
+		);
+
+		// Without an offset, there is no context to show.
+		let error = DecodeError::UnexpectedTag;
+		assert_eq!(error.context(&bytes).to_string(), "Encountered an unexpected tag");
+	}
 }
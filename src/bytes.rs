@@ -1,8 +1,12 @@
+//! A cursor over a byte slice, used by the decoders in `ber`. All of its methods are considered stable.
+
 use thiserror::Error;
 
 #[derive(Debug, Clone)]
 pub struct BytesReader<'b> {
 	bytes: &'b [u8],
+	/// The offset of `bytes` within the slice passed to `new`.
+	position: usize,
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
@@ -13,7 +17,13 @@ pub enum BytesReaderError {
 
 impl<'b> BytesReader<'b> {
 	pub fn new(bytes: &'b [u8]) -> Self {
-		Self { bytes }
+		Self { bytes, position: 0 }
+	}
+
+	/// Returns the number of bytes consumed since the reader was created. For a sub-reader, this includes the bytes
+	/// consumed by its parent before it was taken, so it is always an offset into the original slice.
+	pub fn position(&self) -> usize {
+		self.position
 	}
 
 	pub fn read_bytes(&mut self, length: usize) -> Result<&'b [u8], BytesReaderError> {
@@ -22,6 +32,7 @@ impl<'b> BytesReader<'b> {
 			.split_at_checked(length)
 			.ok_or(BytesReaderError::EndOfBuffer)?;
 		self.bytes = remaining;
+		self.position += length;
 		Ok(read)
 	}
 
@@ -30,30 +41,42 @@ impl<'b> BytesReader<'b> {
 		self.read_bytes(N).map(|slice| slice.try_into().unwrap())
 	}
 
+	/// Returns a new reader over the next `length` bytes, and advances past them.
 	pub fn take_sub_reader(&mut self, length: usize) -> Result<Self, BytesReaderError> {
-		self.read_bytes(length).map(Self::new)
+		let position = self.position;
+		self.read_bytes(length).map(|bytes| Self { bytes, position })
 	}
 
+	/// Restricts the reader to its next `length` bytes, discarding any after them.
 	pub fn limit(&mut self, length: usize) -> Result<(), BytesReaderError> {
 		self.bytes = self.bytes.get(..length).ok_or(BytesReaderError::EndOfBuffer)?;
 		Ok(())
 	}
 
 	pub fn skip(&mut self, length: usize) -> Result<(), BytesReaderError> {
-		self.bytes = self.bytes.get(length..).ok_or(BytesReaderError::EndOfBuffer)?;
-		Ok(())
+		self.read_bytes(length).map(|_| ())
 	}
 
 	pub fn read_u8(&mut self) -> Result<u8, BytesReaderError> {
-		let &value = self.bytes.first().ok_or(BytesReaderError::EndOfBuffer)?;
-		self.bytes = &self.bytes[1..];
-		Ok(value)
+		self.read_u8_array().map(|[value]| value)
 	}
 
 	pub fn read_u16_be(&mut self) -> Result<u16, BytesReaderError> {
 		self.read_u8_array().map(u16::from_be_bytes)
 	}
 
+	pub fn read_u32_be(&mut self) -> Result<u32, BytesReaderError> {
+		self.read_u8_array().map(u32::from_be_bytes)
+	}
+
+	pub fn read_u64_be(&mut self) -> Result<u64, BytesReaderError> {
+		self.read_u8_array().map(u64::from_be_bytes)
+	}
+
+	pub fn read_i32_be(&mut self) -> Result<i32, BytesReaderError> {
+		self.read_u8_array().map(i32::from_be_bytes)
+	}
+
 	pub fn is_empty(&self) -> bool {
 		self.bytes.is_empty()
 	}
@@ -165,4 +188,49 @@ mod tests {
 		assert_eq!(reader.read_u16_be(), Ok(0x1234));
 		assert_eq!(reader.read_u16_be(), Err(BytesReaderError::EndOfBuffer));
 	}
+
+	#[test]
+	fn read_u32_be() {
+		let mut reader = BytesReader::new(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE]);
+		assert_eq!(reader.read_u32_be(), Ok(0x12345678));
+		assert_eq!(reader.read_u32_be(), Err(BytesReaderError::EndOfBuffer));
+	}
+
+	#[test]
+	fn read_u64_be() {
+		let mut reader = BytesReader::new(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x12]);
+		assert_eq!(reader.read_u64_be(), Ok(0x123456789ABCDEF0));
+		assert_eq!(reader.read_u64_be(), Err(BytesReaderError::EndOfBuffer));
+	}
+
+	#[test]
+	fn read_i32_be() {
+		let mut reader = BytesReader::new(&[0x12, 0x34, 0x56, 0x78, 0xFF, 0xFF, 0xFF, 0xFE, 0x80]);
+		assert_eq!(reader.read_i32_be(), Ok(0x12345678));
+		assert_eq!(reader.read_i32_be(), Ok(-2));
+		assert_eq!(reader.read_i32_be(), Err(BytesReaderError::EndOfBuffer));
+	}
+
+	#[test]
+	fn position() {
+		let mut reader = BytesReader::new(b"position test");
+		assert_eq!(reader.position(), 0);
+		assert_eq!(reader.read_u8(), Ok(b'p'));
+		assert_eq!(reader.read_bytes(3), Ok(b"osi".as_slice()));
+		assert_eq!(reader.position(), 4);
+		assert_eq!(reader.skip(1), Ok(()));
+		assert_eq!(reader.limit(7), Ok(()));
+		assert_eq!(reader.position(), 5);
+
+		let mut sub_reader = reader.take_sub_reader(4).unwrap();
+		assert_eq!(sub_reader.position(), 5);
+		assert_eq!(sub_reader.read_bytes(2), Ok(b"io".as_slice()));
+		assert_eq!(sub_reader.position(), 7);
+		assert_eq!(reader.position(), 9);
+
+		// Failed reads do not consume anything.
+		assert_eq!(reader.read_bytes(4), Err(BytesReaderError::EndOfBuffer));
+		assert_eq!(reader.skip(4), Err(BytesReaderError::EndOfBuffer));
+		assert_eq!(reader.position(), 9);
+	}
 }
@@ -0,0 +1,264 @@
+use std::{
+	collections::VecDeque,
+	fs::{File, OpenOptions},
+	io::{BufWriter, ErrorKind, Write},
+	path::PathBuf,
+};
+
+use crate::{config::CaptureConfig, ethernet::MacAddress};
+
+/// The magic number identifying a pcap file with nanosecond resolution timestamps.
+const PCAP_MAGIC_NS: u32 = 0xA1B2_3C4D;
+
+/// The pcap link type for Ethernet frames.
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// The maximum length of a captured frame.
+const SNAPLEN: u32 = 65535;
+
+/// Records received frames into a set of rotating pcap files, so that the most recent frames are available for
+/// analysis after a problem occurs.
+///
+/// Each file covers a fixed period of time, and only the most recent files are kept. The recorder can be frozen, after
+/// which no further frames are recorded and no files are deleted, preserving the frames leading up to the problem.
+/// Existing files are never overwritten, so files from a previous run (for example, before a restart within the same
+/// period) are preserved, and a sequence number is added to the name of the new file.
+///
+/// Since the socket only provides the frame's payload, an Ethernet header is reconstructed for each frame using the
+/// multicast destination address and the SV EtherType. The source address is not known, so it is left as zero.
+#[derive(Debug)]
+pub struct FrameRecorder {
+	directory: PathBuf,
+	prefix: String,
+	file_duration_secs: u64,
+	file_count: usize,
+	freeze_after_parse_errors: Option<u32>,
+	/// Paths of the files which have been written, oldest first.
+	files: VecDeque<PathBuf>,
+	/// The file currently being written to, along with the start of the time period that it covers.
+	current: Option<(BufWriter<File>, u64)>,
+	/// The number of parse errors which have occurred during the current file's time period.
+	parse_errors: u32,
+	ethernet_header: [u8; 14],
+	frozen: bool,
+}
+
+impl FrameRecorder {
+	/// Creates a new recorder which writes files to the directory given in `config`. File names start with `prefix`,
+	/// which allows several recorders to share a directory.
+	pub fn new(config: &CaptureConfig, prefix: &str, destination: MacAddress) -> std::io::Result<Self> {
+		std::fs::create_dir_all(&config.directory)?;
+
+		let mut ethernet_header = [0; 14];
+		ethernet_header[0..6].copy_from_slice(&destination.to_bytes());
+		ethernet_header[12..14].copy_from_slice(&crate::ethernet::ETHERTYPE_SV.to_be_bytes());
+
+		Ok(Self {
+			directory: config.directory.clone(),
+			prefix: prefix.to_owned(),
+			file_duration_secs: config.file_duration_secs.max(1),
+			file_count: config.file_count.max(1),
+			freeze_after_parse_errors: config.freeze_after_parse_errors,
+			files: VecDeque::new(),
+			current: None,
+			parse_errors: 0,
+			ethernet_header,
+			frozen: false,
+		})
+	}
+
+	/// Records a frame received at the specified time. Does nothing if the recorder is frozen.
+	pub fn record(&mut self, timestamp_s: u64, timestamp_ns: u32, payload: &[u8]) -> std::io::Result<()> {
+		if self.frozen {
+			return Ok(());
+		}
+
+		let period_start = timestamp_s - timestamp_s % self.file_duration_secs;
+		if self.current.as_ref().is_none_or(|&(_, start)| start != period_start) {
+			self.rotate(period_start)?;
+		}
+
+		let (writer, _) = self.current.as_mut().unwrap();
+		let length = (self.ethernet_header.len() + payload.len()) as u32;
+		writer.write_all(&(timestamp_s as u32).to_le_bytes())?;
+		writer.write_all(&timestamp_ns.to_le_bytes())?;
+		writer.write_all(&length.min(SNAPLEN).to_le_bytes())?;
+		writer.write_all(&length.to_le_bytes())?;
+		writer.write_all(&self.ethernet_header)?;
+		writer.write_all(&payload[..payload.len().min((SNAPLEN as usize) - self.ethernet_header.len())])?;
+		Ok(())
+	}
+
+	/// Notes that a frame could not be parsed, freezing the recorder if the configured number of parse errors has
+	/// been reached within the current file's time period.
+	pub fn note_parse_error(&mut self) -> std::io::Result<()> {
+		self.parse_errors += 1;
+		if self.freeze_after_parse_errors.is_some_and(|limit| self.parse_errors >= limit) {
+			self.freeze()?;
+		}
+		Ok(())
+	}
+
+	/// Stops recording, preserving the files which have already been written.
+	pub fn freeze(&mut self) -> std::io::Result<()> {
+		if !self.frozen {
+			self.frozen = true;
+			if let Some((writer, _)) = self.current.as_mut() {
+				writer.flush()?;
+			}
+			log::warn!(
+				"Frame capture for '{}' has been frozen; files in '{}' will be preserved.",
+				self.prefix,
+				self.directory.display()
+			);
+		}
+		Ok(())
+	}
+
+	pub fn is_frozen(&self) -> bool {
+		self.frozen
+	}
+
+	/// Starts a new file for the period starting at `period_start`, deleting the oldest file if necessary.
+	fn rotate(&mut self, period_start: u64) -> std::io::Result<()> {
+		if let Some((mut writer, _)) = self.current.take() {
+			writer.flush()?;
+		}
+
+		while self.files.len() >= self.file_count {
+			let path = self.files.pop_front().unwrap();
+			if let Err(err) = std::fs::remove_file(&path) {
+				log::warn!("Unable to remove old capture file '{}': {err}", path.display());
+			}
+		}
+
+		let (path, file) = self.create_file(period_start)?;
+		let mut writer = BufWriter::new(file);
+		writer.write_all(&PCAP_MAGIC_NS.to_le_bytes())?;
+		writer.write_all(&2_u16.to_le_bytes())?; // Major version
+		writer.write_all(&4_u16.to_le_bytes())?; // Minor version
+		writer.write_all(&0_i32.to_le_bytes())?; // Time zone offset (always zero)
+		writer.write_all(&0_u32.to_le_bytes())?; // Timestamp accuracy (always zero)
+		writer.write_all(&SNAPLEN.to_le_bytes())?;
+		writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+
+		self.files.push_back(path);
+		self.current = Some((writer, period_start));
+		self.parse_errors = 0;
+		Ok(())
+	}
+
+	/// Creates the file for the period starting at `period_start`, numbering it if a file for the period exists.
+	fn create_file(&self, period_start: u64) -> std::io::Result<(PathBuf, File)> {
+		let mut sequence = 0;
+		loop {
+			let name = match sequence {
+				0 => format!("{}-{period_start}.pcap", self.prefix),
+				_ => format!("{}-{period_start}-{sequence}.pcap", self.prefix),
+			};
+			let path = self.directory.join(name);
+			match OpenOptions::new().write(true).create_new(true).open(&path) {
+				Ok(file) => return Ok((path, file)),
+				Err(err) if err.kind() == ErrorKind::AlreadyExists => sequence += 1,
+				Err(err) => return Err(err),
+			}
+		}
+	}
+}
+
+impl Drop for FrameRecorder {
+	fn drop(&mut self) {
+		if let Some((writer, _)) = self.current.as_mut() {
+			let _ = writer.flush();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_config(name: &str) -> CaptureConfig {
+		let directory = std::env::temp_dir().join(format!("mu_rust_capture_{name}_{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		CaptureConfig {
+			directory,
+			file_duration_secs: 10,
+			file_count: 2,
+			freeze_after_parse_errors: Some(2),
+		}
+	}
+
+	fn file_names(config: &CaptureConfig) -> Vec<String> {
+		let mut names = std::fs::read_dir(&config.directory)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect::<Vec<_>>();
+		names.sort();
+		names
+	}
+
+	#[test]
+	fn rotation() {
+		let config = test_config("rotation");
+		let destination = MacAddress::try_from("01-0C-CD-04-00-01".to_owned()).unwrap();
+		let mut recorder = FrameRecorder::new(&config, "eth0", destination).unwrap();
+
+		for timestamp_s in [1000, 1005, 1010, 1025] {
+			recorder.record(timestamp_s, 0, &[0xAA; 32]).unwrap();
+		}
+		drop(recorder);
+
+		assert_eq!(file_names(&config), ["eth0-1010.pcap", "eth0-1020.pcap"]);
+
+		let bytes = std::fs::read(config.directory.join("eth0-1020.pcap")).unwrap();
+		assert_eq!(bytes.len(), 24 + 16 + 14 + 32);
+		assert_eq!(bytes[0..4], PCAP_MAGIC_NS.to_le_bytes());
+		assert_eq!(bytes[24..28], 1025_u32.to_le_bytes());
+		assert_eq!(bytes[52..54], [0x88, 0xBA]);
+
+		std::fs::remove_dir_all(&config.directory).unwrap();
+	}
+
+	#[test]
+	fn existing_files_are_kept() {
+		let config = test_config("existing");
+		let destination = MacAddress::try_from("01-0C-CD-04-00-01".to_owned()).unwrap();
+		let mut recorder = FrameRecorder::new(&config, "eth0", destination).unwrap();
+		recorder.record(1000, 0, &[0xAA; 32]).unwrap();
+		drop(recorder);
+
+		// A restart within the same period starts a new file rather than truncating the existing one.
+		let mut recorder = FrameRecorder::new(&config, "eth0", destination).unwrap();
+		recorder.record(1005, 0, &[0xBB; 16]).unwrap();
+		drop(recorder);
+
+		assert_eq!(file_names(&config), ["eth0-1000-1.pcap", "eth0-1000.pcap"]);
+		let bytes = std::fs::read(config.directory.join("eth0-1000.pcap")).unwrap();
+		assert_eq!(bytes.len(), 24 + 16 + 14 + 32);
+
+		std::fs::remove_dir_all(&config.directory).unwrap();
+	}
+
+	#[test]
+	fn freeze_on_parse_errors() {
+		let config = test_config("freeze");
+		let destination = MacAddress::try_from("01-0C-CD-04-00-01".to_owned()).unwrap();
+		let mut recorder = FrameRecorder::new(&config, "eth0", destination).unwrap();
+
+		recorder.record(1000, 0, &[0xAA; 32]).unwrap();
+		recorder.note_parse_error().unwrap();
+		assert!(!recorder.is_frozen());
+		recorder.note_parse_error().unwrap();
+		assert!(recorder.is_frozen());
+
+		for timestamp_s in [1010, 1020, 1030] {
+			recorder.record(timestamp_s, 0, &[0xAA; 32]).unwrap();
+		}
+		drop(recorder);
+
+		assert_eq!(file_names(&config), ["eth0-1000.pcap"]);
+
+		std::fs::remove_dir_all(&config.directory).unwrap();
+	}
+}
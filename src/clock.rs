@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+/// The minimum time between queries of the clock synchronisation status.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads the kernel's clock discipline status, returning the clock state along with the status itself.
+fn read_timex() -> std::io::Result<(libc::c_int, libc::timex)> {
+	// SAFETY: `timex` is plain old data, for which all zeroes is a valid value. With `modes` zero, `adjtimex` (or
+	// `ntp_adjtime` on macOS and FreeBSD) only reads the clock status into `timex`.
+	let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+	#[cfg(target_os = "linux")]
+	let state = unsafe { libc::adjtimex(&mut timex) };
+	#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+	let state = unsafe { libc::ntp_adjtime(&mut timex) };
+	if state == -1 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok((state, timex))
+}
+
+/// Queries the kernel's clock discipline status to determine whether the system clock is synchronised.
+pub fn clock_synchronised() -> std::io::Result<bool> {
+	let (state, timex) = read_timex()?;
+	Ok(state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0)
+}
+
+/// Queries the offset of TAI from UTC kept by the kernel, in seconds. The offset is zero unless it has been set by the
+/// NTP or PTP daemon disciplining the clock.
+#[cfg(target_os = "linux")]
+pub fn tai_offset() -> std::io::Result<i64> {
+	let (_, timex) = read_timex()?;
+	Ok(timex.tai.into())
+}
+
+/// macOS and FreeBSD do not report the TAI offset, but hardware timestamps are not supported there either.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn tai_offset() -> std::io::Result<i64> {
+	Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Tracks whether the system clock is synchronised, querying the kernel at most once per `CHECK_INTERVAL` and logging
+/// each change in status.
+#[derive(Debug, Default)]
+pub struct ClockSyncMonitor {
+	last_check: Option<Instant>,
+	synchronised: Option<bool>,
+}
+
+impl ClockSyncMonitor {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns whether the system clock is synchronised, querying the kernel if the status was last checked more than
+	/// `CHECK_INTERVAL` ago. The clock is assumed to be unsynchronised if its status cannot be determined.
+	pub fn is_synchronised(&mut self) -> bool {
+		let now = Instant::now();
+		if let (Some(last_check), Some(synchronised)) = (self.last_check, self.synchronised) {
+			if now.duration_since(last_check) < CHECK_INTERVAL {
+				return synchronised;
+			}
+		}
+		self.last_check = Some(now);
+
+		let synchronised = clock_synchronised().unwrap_or_else(|err| {
+			log::warn!("Unable to query clock synchronisation status: {err}");
+			false
+		});
+		self.update(synchronised);
+		synchronised
+	}
+
+	/// Records the current status, logging it if it has changed. Returns whether it has changed.
+	fn update(&mut self, synchronised: bool) -> bool {
+		let previous = self.synchronised.replace(synchronised);
+		match (previous, synchronised) {
+			(None, true) => log::info!("System clock is synchronised."),
+			(None, false) => log::warn!("System clock is not synchronised."),
+			(Some(false), true) => log::info!("System clock is now synchronised."),
+			(Some(true), false) => log::warn!("System clock has lost synchronisation."),
+			(Some(_), _) => return false,
+		}
+		true
+	}
+}
+
+/// Tracks the offset of TAI from UTC, querying the kernel at most once per `CHECK_INTERVAL` so that the offset follows
+/// leap seconds, and logging each change. This is used to convert hardware timestamps, which are taken by a PTP
+/// hardware clock running on TAI, to UTC.
+#[derive(Debug, Default)]
+pub struct TaiOffsetMonitor {
+	last_check: Option<Instant>,
+	offset: Option<i64>,
+	/// Whether the latest query failed, so that repeated failures are only logged once.
+	failed: bool,
+}
+
+impl TaiOffsetMonitor {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Returns the offset of TAI from UTC in seconds, querying the kernel if the offset was last checked more than
+	/// `CHECK_INTERVAL` ago. The previous offset, or zero if there is none, is used if it cannot be determined.
+	pub fn offset(&mut self) -> i64 {
+		let now = Instant::now();
+		if self.last_check.is_some_and(|last_check| now.duration_since(last_check) < CHECK_INTERVAL) {
+			return self.offset.unwrap_or(0);
+		}
+		self.last_check = Some(now);
+
+		match tai_offset() {
+			Ok(offset) => {
+				self.update(offset);
+				self.failed = false;
+			}
+			Err(err) => {
+				if !self.failed {
+					log::warn!("Unable to query the TAI offset: {err}");
+				}
+				self.failed = true;
+			}
+		}
+		self.offset.unwrap_or(0)
+	}
+
+	/// Records the current offset, logging it if it has changed. Returns whether it has changed.
+	fn update(&mut self, offset: i64) -> bool {
+		let previous = self.offset.replace(offset);
+		match (previous, offset) {
+			(None, 0) => log::warn!("The kernel's TAI offset is not set, so hardware timestamps are treated as UTC."),
+			(None, _) => log::info!("Converting hardware timestamps to UTC using a TAI offset of {offset} s."),
+			(Some(previous), _) if previous != offset => {
+				log::info!("TAI offset changed from {previous} s to {offset} s.");
+			}
+			(Some(_), _) => return false,
+		}
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn query_status() {
+		// The status depends on the host, but the query itself should succeed without privileges.
+		clock_synchronised().unwrap();
+	}
+
+	#[test]
+	fn transitions() {
+		let mut monitor = ClockSyncMonitor::new();
+		assert!(monitor.update(false));
+		assert!(!monitor.update(false));
+		assert!(monitor.update(true));
+		assert!(!monitor.update(true));
+		assert!(monitor.update(false));
+		assert_eq!(monitor.synchronised, Some(false));
+	}
+
+	#[test]
+	fn tai_offset_transitions() {
+		#[cfg(target_os = "linux")]
+		tai_offset().unwrap();
+
+		let mut monitor = TaiOffsetMonitor::new();
+		assert!(monitor.update(37));
+		assert!(!monitor.update(37));
+		assert!(monitor.update(38));
+		assert_eq!(monitor.offset, Some(38));
+	}
+}
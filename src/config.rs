@@ -1,7 +1,9 @@
-use std::net::SocketAddr;
-use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
 
-use crate::ethernet::MacAddress;
+use crate::{ethernet::MacAddress, output::parse_destination, Asdu, UtcTime};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -10,22 +12,1582 @@ pub enum OutputChannelType {
 	Current,
 }
 
+/// The format used for the timestamp of each OpenPMU datagram.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+	/// Separate `<Date>` and `<Time>` elements, as expected by OpenPMU.
+	#[default]
+	Split,
+	/// A single `<Timestamp>` element containing an ISO 8601 UTC timestamp (e.g. `2024-01-01T00:00:00.000000Z`).
+	Iso8601,
+}
+
+/// A pattern which the svID of received ASDUs is expected to match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SvidPattern {
+	Exact(String),
+	/// Any of a list of svIDs, for networks with several known merging units.
+	OneOf(Vec<String>),
+	Regex(#[serde(deserialize_with = "deserialize_regex")] Regex),
+}
+
+impl SvidPattern {
+	pub fn matches(&self, svid: &str) -> bool {
+		match self {
+			Self::Exact(expected) => svid == expected,
+			Self::OneOf(expected) => expected.iter().any(|expected| svid == expected),
+			Self::Regex(regex) => regex.is_match(svid),
+		}
+	}
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+	let pattern = String::deserialize(deserializer)?;
+	Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// Checks that received ASDUs have the expected svID, to catch subscriptions to the wrong stream.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SvidValidation {
+	#[serde(flatten)]
+	pub pattern: SvidPattern,
+	/// Whether to drop frames containing an ASDU whose svID does not match. Otherwise, a warning is logged but the
+	/// frame is still used.
+	#[serde(default)]
+	pub strict: bool,
+}
+
+/// When the sample counter (smpCnt) of the input streams returns to zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmpCntReset {
+	/// The counter resets to zero at the top of each second, as specified by IEC 61869-9, so it gives the position of
+	/// each sample within the second.
+	#[default]
+	Second,
+	/// The counter runs freely and wraps from 65535 to zero. Since it is not aligned to UTC, the timestamps of each
+	/// stream's samples are anchored to the time at which its first sample was received.
+	Wrap,
+}
+
+impl SmpCntReset {
+	/// Returns the number of samples between resets, for an input stream with the specified sample rate.
+	pub fn period(self, sample_rate: u32) -> u32 {
+		match self {
+			Self::Second => sample_rate,
+			Self::Wrap => 1 << 16,
+		}
+	}
+
+	/// Determines whether a change in smpCnt from `previous` to `smp_cnt` is consistent with this reset convention.
+	/// Up to a tenth of a second of samples is tolerated both for steps backwards (from reordered frames) and for
+	/// resets occurring early (from frames lost just before the reset).
+	pub fn is_consistent(self, sample_rate: u32, previous: u16, smp_cnt: u16) -> bool {
+		let period = self.period(sample_rate);
+		let tolerance = sample_rate / 10;
+		let (previous, smp_cnt) = (u32::from(previous), u32::from(smp_cnt));
+		if smp_cnt >= period {
+			return false;
+		}
+		let reset = smp_cnt < previous && previous - smp_cnt > tolerance;
+		!reset || period - (previous + 1) <= tolerance
+	}
+}
+
+/// Restricts the devices which frames are accepted from, based on their source MAC address.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SourceFilter {
+	/// If present, only frames from these addresses are accepted.
+	pub allow: Option<Vec<MacAddress>>,
+	/// Frames from these addresses are always rejected.
+	#[serde(default)]
+	pub deny: Vec<MacAddress>,
+}
+
+impl SourceFilter {
+	/// Determines whether a frame from the specified source address should be accepted. If the source address is
+	/// unknown, the frame is only accepted when there is no allowlist.
+	pub fn accepts(&self, source: Option<MacAddress>) -> bool {
+		let denied = source.is_some_and(|source| self.deny.contains(&source));
+		let allowed = match (&self.allow, source) {
+			(None, _) => true,
+			(Some(allow), Some(source)) => allow.contains(&source),
+			(Some(_), None) => false,
+		};
+		allowed && !denied
+	}
+}
+
+/// The protocol used to send OpenPMU datagrams to the destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputProtocol {
+	/// Each datagram is sent as a UDP datagram.
+	#[default]
+	Udp,
+	/// Datagrams are sent over a TCP connection, each preceded by its length as a 4 byte big-endian integer.
+	Tcp,
+}
+
+/// How each value in the sample field of an ASDU is encoded. Every value occupies the first 4 bytes of an 8 byte chunk,
+/// followed by its quality word, and is multiplied by the 9-2LE scale factor for its channel type once decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+	/// A big-endian two's complement integer, as specified by 9-2LE.
+	#[default]
+	Int32,
+	/// A big-endian IEEE 754 single precision number.
+	Float32,
+	/// A big-endian two's complement integer in the last 3 bytes. The first byte is ignored.
+	Int24,
+}
+
+impl SampleFormat {
+	/// Decodes a value from its 4 bytes.
+	pub fn decode(self, bytes: [u8; 4]) -> f64 {
+		match self {
+			Self::Int32 => f64::from(i32::from_be_bytes(bytes)),
+			Self::Float32 => f64::from(f32::from_be_bytes(bytes)),
+			// Shifting the value into the top 24 bits and back extends its sign.
+			Self::Int24 => f64::from(i32::from_be_bytes([bytes[1], bytes[2], bytes[3], 0]) >> 8),
+		}
+	}
+}
+
+/// What to do with the first buffer after startup if it is incomplete, because the first sample received was partway
+/// through the buffer's timespan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialBufferPolicy {
+	/// Send the buffer as normal, with zeros for the missing samples.
+	#[default]
+	Send,
+	/// Send the buffer with a `<Partial>` element indicating that it is incomplete.
+	Flag,
+	/// Do not send the buffer.
+	Discard,
+}
+
+/// What to do with samples whose smpSynch field indicates that the merging unit is synchronised to a local clock,
+/// rather than a global one. Such samples are consistent with each other, but are not synchrophasor-grade.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalSyncPolicy {
+	/// Use the samples as normal.
+	#[default]
+	Accept,
+	/// Use the samples, and add a `<LocalSync>` element to each buffer containing them.
+	Flag,
+	/// Do not use the samples.
+	Drop,
+}
+
+impl LocalSyncPolicy {
+	/// The value of smpSynch indicating synchronisation to a local clock.
+	pub const LOCAL: u8 = 1;
+
+	/// Determines whether a sample with the specified smpSynch value should be used.
+	pub fn accepts(self, smp_synch: u8) -> bool {
+		self != Self::Drop || smp_synch != Self::LOCAL
+	}
+
+	/// Determines whether a buffer should be flagged, given whether it contains locally synchronised samples.
+	pub fn flags(self, local_sync: bool) -> bool {
+		self == Self::Flag && local_sync
+	}
+}
+
+/// Where the time at which each sample was taken is obtained from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeSource {
+	/// The time at which the frame was received, which includes the network latency. smpCnt gives the position of the
+	/// sample within the second before it was received.
+	#[default]
+	ReceiveTime,
+	/// The refrTm field of the ASDU, set by the merging unit. Samples without one use their receive time.
+	RefrTm,
+	/// The refrTm field of the ASDU if the merging unit is synchronised (smpSynch is non-zero), and the receive time
+	/// otherwise.
+	Auto,
+}
+
+impl TimeSource {
+	/// Returns the refrTm to use as the time of the sample in an ASDU, or `None` if its receive time should be used.
+	pub fn refr_tm<S>(self, asdu: &Asdu<S>) -> Option<UtcTime> {
+		match self {
+			Self::ReceiveTime => None,
+			Self::RefrTm => asdu.refr_tm,
+			Self::Auto => asdu.refr_tm.filter(|_| asdu.smp_synch != 0),
+		}
+	}
+}
+
+/// Which clock timestamps each frame as it is received, giving the receive time used by `TimeSource::ReceiveTime`.
+///
+/// Hardware timestamps are only available on Linux, and require a network interface and driver which support hardware
+/// receive timestamping of all frames (`ethtool -T <interface>` lists `hardware-receive`, `hardware-raw-clock` and the
+/// `all` receive filter). The interface is configured when the bridge starts, which requires the `CAP_NET_ADMIN`
+/// capability unless it has already been configured by another program, such as `ptp4l`. Hardware timestamps come
+/// from the interface's PTP hardware clock, which must be synchronised to TAI (for example by `ptp4l`, with `phc2sys`
+/// keeping the system clock in step and setting the kernel's TAI offset).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampSource {
+	/// The kernel's clock, when it begins processing the frame.
+	#[default]
+	Software,
+	/// The network interface's hardware clock, when the frame arrives. Frames which the interface does not timestamp
+	/// fall back to a software timestamp.
+	///
+	/// The hardware clock is assumed to run on TAI, as it does when disciplined by PTP, and its timestamps are
+	/// converted to UTC using the TAI offset kept by the kernel (as reported by `adjtimex`). The offset must be set
+	/// by the PTP daemon, such as `phc2sys` or `ptp4l`; if it is not, the timestamps will be 37 s ahead. Enabling
+	/// hardware timestamping reconfigures the interface, and its previous configuration is restored on exit.
+	Hardware,
+}
+
+/// What to do with buffers while the system clock is not synchronised, according to the clock discipline status kept
+/// by the kernel (as reported by `adjtimex`). The status is maintained by the NTP or PTP daemon disciplining the clock,
+/// which must be configured to do so.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockSyncPolicy {
+	/// Do not check the system clock.
+	#[default]
+	Ignore,
+	/// Send buffers as normal, adding a `<ClockUnsynchronised>` element to each buffer sent while the clock is not
+	/// synchronised.
+	Flag,
+	/// Do not send buffers while the clock is not synchronised.
+	Hold,
+}
+
+/// The range of values considered plausible for a channel. Values outside of this range are assumed to be the result of
+/// a corrupt frame.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ValueBounds {
+	pub min: f32,
+	pub max: f32,
+}
+
+impl ValueBounds {
+	pub fn contains(&self, value: f32) -> bool {
+		(self.min..=self.max).contains(&value)
+	}
+
+	/// Determines whether the bounds can be used to clamp values, which requires both to be finite and in order.
+	pub fn is_valid(&self) -> bool {
+		self.min.is_finite() && self.max.is_finite() && self.min <= self.max
+	}
+}
+
+/// What to do with a sample which contains implausible values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfBoundsAction {
+	/// Clamp the implausible values to the nearest bound, keeping the rest of the sample.
+	#[default]
+	Clamp,
+	/// Drop the entire sample.
+	Drop,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct PlausibilityConfig {
+	/// The plausible range of current values, in amperes. If absent, all current values are accepted.
+	pub current: Option<ValueBounds>,
+	/// The plausible range of voltage values, in volts. If absent, all voltage values are accepted.
+	pub voltage: Option<ValueBounds>,
+	#[serde(default)]
+	pub action: OutOfBoundsAction,
+}
+
+/// The magnitudes at which decoded values are considered saturated, such as the largest value that the merging unit's
+/// analogue front end can represent. Values are compared as decoded from the sampled value message (in amperes or
+/// volts), before calibration or any transform.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct SaturationThresholds {
+	/// The saturation magnitude of current values, in amperes. If absent, saturation of currents is not detected.
+	pub current: Option<f32>,
+	/// The saturation magnitude of voltage values, in volts. If absent, saturation of voltages is not detected.
+	pub voltage: Option<f32>,
+}
+
+impl SaturationThresholds {
+	/// Returns the saturation magnitude for channels of the specified type, if one is configured.
+	pub fn for_type(self, type_: &OutputChannelType) -> Option<f32> {
+		match type_ {
+			OutputChannelType::Current => self.current,
+			OutputChannelType::Voltage => self.voltage,
+		}
+	}
+}
+
+fn default_capture_file_duration_secs() -> u64 {
+	10
+}
+
+fn default_capture_file_count() -> usize {
+	6
+}
+
+/// Configuration for recording received frames to rotating pcap files.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureConfig {
+	/// The directory to write capture files to.
+	pub directory: PathBuf,
+	/// The period of time covered by each capture file.
+	#[serde(default = "default_capture_file_duration_secs")]
+	pub file_duration_secs: u64,
+	/// The number of capture files to keep. Older files are deleted.
+	#[serde(default = "default_capture_file_count")]
+	pub file_count: usize,
+	/// If set, recording stops once this many frames fail to parse within one file's period, so that the frames
+	/// leading up to the errors are preserved.
+	pub freeze_after_parse_errors: Option<u32>,
+}
+
+fn default_transform_scale() -> f32 {
+	1.0
+}
+
+fn default_gain() -> f32 {
+	1.0
+}
+
+/// A conversion applied to a channel's values before they are output, allowing them to be expressed in the units that
+/// the consumer expects (e.g. primary rather than secondary values, or per-unit).
+///
+/// Values are decoded from the sampled value message (in amperes or volts), corrected by the channel's calibration
+/// gain and offset, and then transformed as `(value * scale + offset) / base`, where dividing by `base` is skipped if
+/// it is absent.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Transform {
+	#[serde(default = "default_transform_scale")]
+	pub scale: f32,
+	#[serde(default)]
+	pub offset: f32,
+	/// The base value for a per-unit conversion, e.g. the nominal voltage.
+	pub base: Option<f32>,
+}
+
+impl Transform {
+	pub fn apply(&self, value: f32) -> f32 {
+		let value = value * self.scale + self.offset;
+		match self.base {
+			Some(base) => value / base,
+			None => value,
+		}
+	}
+
+	/// Checks that the transform is usable, returning a description of the problem if not.
+	fn check(&self) -> Result<(), &'static str> {
+		if !self.scale.is_finite() || self.scale == 0.0 {
+			Err("scale must be finite and non-zero")
+		} else if !self.offset.is_finite() {
+			Err("offset must be finite")
+		} else if self.base.is_some_and(|base| !base.is_finite() || base <= 0.0) {
+			Err("base must be finite and positive")
+		} else {
+			Ok(())
+		}
+	}
+}
+
 #[derive(Deserialize)]
 pub struct OutputChannel {
 	pub name: String,
+	/// The label written to the `<Phase>` element of this channel. It is passed through unchanged (apart from XML
+	/// escaping) and is independent of `input_channel`. Conventionally, this is `a`, `b`, `c` or `n` for phase and
+	/// neutral quantities, but any label the consumer expects may be used, e.g. `+`, `-` and `0` for sequence
+	/// components, or `L1` and `L2` for split-phase systems.
 	pub phase: String,
 	#[serde(rename = "type")]
 	pub type_: OutputChannelType,
 	pub input_channel: usize,
+	/// The svID of the stream that this channel's samples are taken from. Channels without a stream take their samples
+	/// from `default_svid`.
+	pub stream: Option<String>,
+	/// A calibration correction for gain errors in the instrument transformer chain. Values decoded from the sampled
+	/// value message (already multiplied by the 9-2LE scale factor, so in amperes or volts) are corrected as
+	/// `value * gain + offset`, before any transform is applied.
+	#[serde(default = "default_gain")]
+	pub gain: f32,
+	/// A calibration correction for offset errors, added after multiplying by `gain`.
+	#[serde(default)]
+	pub offset: f32,
+	pub transform: Option<Transform>,
+	/// The nominal magnitude of this channel, after any transform. If set, samples are quantized relative to this
+	/// value instead of the largest magnitude in each buffer, so that the scale is the same for every datagram.
+	/// Samples beyond the nominal magnitude are clipped.
+	pub nominal: Option<f32>,
+}
+
+impl OutputChannel {
+	/// Creates a channel which outputs the specified input channel unchanged, from the default stream.
+	pub fn new(
+		name: impl Into<String>,
+		type_: OutputChannelType,
+		phase: impl Into<String>,
+		input_channel: usize,
+	) -> Self {
+		Self {
+			name: name.into(),
+			phase: phase.into(),
+			type_,
+			input_channel,
+			stream: None,
+			gain: default_gain(),
+			offset: 0.0,
+			transform: None,
+			nominal: None,
+		}
+	}
+
+	/// Returns the conversion applied to this channel's values before they are output, which combines the calibration
+	/// correction with the transform. Returns `None` if values are output unchanged.
+	pub fn effective_transform(&self) -> Option<Transform> {
+		if self.gain == 1.0 && self.offset == 0.0 {
+			return self.transform;
+		}
+		let transform = self.transform.unwrap_or(Transform {
+			scale: 1.0,
+			offset: 0.0,
+			base: None,
+		});
+		// `(value * gain + offset) * scale + transform.offset`, expanded into a single multiplication and addition.
+		Some(Transform {
+			scale: self.gain * transform.scale,
+			offset: self.offset * transform.scale + transform.offset,
+			base: transform.base,
+		})
+	}
+}
+
+/// Deserializes either a single value or a list of values into a `Vec`, so that options which originally accepted a
+/// single value remain backwards compatible.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum OneOrMany<T> {
+		One(T),
+		Many(Vec<T>),
+	}
+
+	Ok(match OneOrMany::deserialize(deserializer)? {
+		OneOrMany::One(value) => vec![value],
+		OneOrMany::Many(values) => values,
+	})
+}
+
+fn deserialize_destinations<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error> {
+	one_or_many::<D, String>(deserializer)?
+		.iter()
+		.map(|destination| parse_destination(destination).map_err(serde::de::Error::custom))
+		.collect()
+}
+
+fn default_frame_decimation() -> u32 {
+	1
+}
+
+fn default_receive_queue_capacity() -> usize {
+	1024
+}
+
+fn default_bits() -> u32 {
+	16
+}
+
+fn default_send_delay_secs() -> f64 {
+	0.05
+}
+
+fn default_max_queued_buffers() -> usize {
+	1000
+}
+
+fn default_stats_interval_secs() -> u64 {
+	10
 }
 
 #[derive(Deserialize)]
 pub struct Configuration {
 	pub nominal_frequency: u32,
 	pub sample_rate: u32,
-	pub interface: String,
+	/// The network interfaces to receive sampled value messages on. Either a single name or a list may be given. These
+	/// do not need an IP address, since sampled values are received at layer 2. Datagrams are sent through whichever
+	/// interface has a route to each destination.
+	#[serde(rename = "interface", deserialize_with = "one_or_many")]
+	pub interfaces: Vec<String>,
 	#[serde(rename = "output_channel")]
 	pub channels: Vec<OutputChannel>,
-	pub destination: SocketAddr,
+	/// The addresses which OpenPMU datagrams are sent to. Either a single address or a list may be given; each datagram
+	/// is sent to every destination. IPv4 and IPv6 addresses can be mixed. A link-local IPv6 address must include the
+	/// interface to send from as its zone, either by name or by index, such as `[fe80::1%eth0]:48001`.
+	#[serde(rename = "destination", deserialize_with = "deserialize_destinations")]
+	pub destinations: Vec<SocketAddr>,
 	pub mac_address: MacAddress,
+	/// Whether to check that any bytes following the SV message in a frame are zero padding.
+	#[serde(default)]
+	pub validate_padding: bool,
+	#[serde(default)]
+	pub timestamp_format: TimestampFormat,
+	/// Whether to reject frames whose ASDUs contain unknown fields, rather than skipping those fields.
+	#[serde(default)]
+	pub strict_asdu_fields: bool,
+	/// Whether to accept ASDU fields encoded as constructed OCTET STRINGs, as sent by some IEDs for long values.
+	#[serde(default)]
+	pub constructed_strings: bool,
+	/// Whether to reject frames containing BER lengths which are not encoded in the fewest possible bytes.
+	#[serde(default)]
+	pub minimal_lengths: bool,
+	/// Whether to reject frames whose APPID is outside the range reserved for sampled values (0x4000 to 0x7FFF), which
+	/// usually indicates a cabling or configuration mistake.
+	#[serde(default)]
+	pub validate_appid: bool,
+	#[serde(default)]
+	pub sample_format: SampleFormat,
+	#[serde(default)]
+	pub plausibility: PlausibilityConfig,
+	/// If configured, the number of samples saturated in each channel is included in the output as a `<Saturated>`
+	/// element, and summarised in the log.
+	#[serde(default)]
+	pub saturation: SaturationThresholds,
+	pub capture: Option<CaptureConfig>,
+	/// Only samples whose smpCnt is a multiple of N are used, reducing the output sample rate by a factor of N. Frames
+	/// without such a sample are skipped before they are parsed, which reduces the work of parsing, buffering and
+	/// sending on hosts which cannot keep up with the full rate. The sample rate must be divisible by this value.
+	#[serde(default = "default_frame_decimation")]
+	pub frame_decimation: u32,
+	/// The number of buffers sent per second. By default, two are sent per cycle at the nominal frequency. The output
+	/// sample rate must be divisible by this value.
+	pub frames_per_second: Option<u32>,
+	/// Whether to include the number of samples clipped during quantization in each channel of the output.
+	#[serde(default)]
+	pub emit_clip_counts: bool,
+	/// Whether to include the smpCnt, confRev and smpSynch of the ASDU which started each buffer, as `<SmpCnt>`,
+	/// `<ConfRev>` and `<SmpSynch>` elements, so that the receiver can cross-check the merging unit's state.
+	#[serde(default)]
+	pub emit_asdu_header: bool,
+	#[serde(default)]
+	pub initial_buffer: InitialBufferPolicy,
+	#[serde(default)]
+	pub source_filter: SourceFilter,
+	/// If present, only frames whose APPID is in this list are decoded. Other frames are rejected before their ASDUs
+	/// are decoded, which is cheaper when many streams share the network.
+	pub appids: Option<Vec<u16>>,
+	/// Whether to drop frames with the simulation bit set, which are sent by test equipment.
+	#[serde(default)]
+	pub drop_simulated_frames: bool,
+	/// If present, the svID of every received ASDU is checked against this pattern. By default, any svID is accepted.
+	pub expected_svid: Option<SvidValidation>,
+	/// The svID of the stream used by channels which do not name one. If this is not set, those channels take samples
+	/// from any svID which is not named by another channel, so only one merging unit may send such svIDs; a warning
+	/// is logged if several do.
+	pub default_svid: Option<String>,
+	#[serde(default)]
+	pub protocol: OutputProtocol,
+	/// When using TCP, the number of datagrams to hold while disconnected, which are sent once the connection is
+	/// re-established. If zero, datagrams are dropped while disconnected.
+	#[serde(default)]
+	pub tcp_max_pending: usize,
+	/// When using UDP with a multicast destination, the time-to-live (IPv4) or hop limit (IPv6) of datagrams, from 0 to
+	/// 255. By default this is 1, so datagrams do not leave the local subnet. It has no effect on unicast destinations.
+	pub multicast_ttl: Option<u32>,
+	/// When using UDP with a multicast destination, the name of the interface to send datagrams from. By default, the
+	/// interface is chosen by the routing table. It has no effect on unicast destinations.
+	pub multicast_interface: Option<String>,
+	/// The number of received frames which can wait to be processed on each interface. Frames received while the
+	/// queue is full are dropped.
+	#[serde(default = "default_receive_queue_capacity")]
+	pub receive_queue_capacity: usize,
+	#[serde(default)]
+	pub smp_cnt_reset: SmpCntReset,
+	/// If set, each datagram is padded with trailing whitespace to this number of bytes, which XML parsers ignore.
+	/// This should not exceed the path MTU (less the IP and UDP headers). A warning is logged for any datagram whose
+	/// content alone is larger, and it is sent unpadded. By default, datagrams are not padded.
+	pub datagram_size: Option<usize>,
+	/// The name of an output channel whose signal is used to estimate the frequency of each buffer, which is written
+	/// in a `<Frequency>` element. By default, no estimate is made.
+	pub frequency_reference: Option<String>,
+	/// Whether to estimate the frequency of each buffer from all of the voltage output channels together, writing it
+	/// in a `<Frequency>` element. This cannot be combined with `frequency_reference`.
+	#[serde(default)]
+	pub frequency_from_voltages: bool,
+	/// Whether to check that the smpCnt of consecutive ASDUs in each frame are evenly spaced, counting the frames
+	/// where they are not. This characterises the timing of merging units which send several ASDUs per frame.
+	#[serde(default)]
+	pub asdu_spacing_diagnostics: bool,
+	/// Whether to report the VLAN identifier and priority of each received frame, which are shown by `--discover`. On
+	/// Linux, the kernel otherwise removes VLAN tags before frames are received, and enabling this has the kernel
+	/// filter every frame on the interface.
+	#[serde(default)]
+	pub vlan_tags: bool,
+	#[serde(default)]
+	pub timestamp_source: TimestampSource,
+	#[serde(default)]
+	pub local_sync: LocalSyncPolicy,
+	#[serde(default)]
+	pub clock_sync: ClockSyncPolicy,
+	/// Whether to omit the indentation and line breaks from the XML output, which makes datagrams smaller but harder
+	/// to read.
+	#[serde(default)]
+	pub compact_xml: bool,
+	/// The number of bits used for each sample in the channel payloads, either 16 or 32. 32-bit samples preserve more
+	/// of the input's resolution, but not all OpenPMU consumers accept them.
+	#[serde(default = "default_bits")]
+	pub bits: u32,
+	/// How long after the end of each buffer's timespan to wait before sending it, in seconds, measured from when its
+	/// first sample was received. Samples received after the buffer is sent are lost, so a longer delay tolerates more
+	/// network jitter at the cost of end-to-end latency. The default of 0.05 s is the delay used before this option was
+	/// added, so that existing deployments keep their behaviour; low-latency networks can use a smaller value, such as
+	/// 0.005 s.
+	#[serde(default = "default_send_delay_secs")]
+	pub send_delay_secs: f64,
+	#[serde(default)]
+	pub time_source: TimeSource,
+	/// The maximum number of buffers waiting to be sent. If sending stalls, the oldest buffer is dropped to make room
+	/// for each new one, so that memory use is bounded.
+	#[serde(default = "default_max_queued_buffers")]
+	pub max_queued_buffers: usize,
+	/// The interval between the periodic log messages, in seconds. These report the throughput of the bridge and the
+	/// number of buffers waiting to be sent, and summarise the samples lost from gaps in smpCnt, the samples with
+	/// implausible values and the samples saturated (but only for an interval with any). The older name
+	/// `loss_summary_secs` is also accepted.
+	#[serde(default = "default_stats_interval_secs", alias = "loss_summary_secs")]
+	pub stats_interval_secs: u64,
+	/// If set, the bridge's counters are served over HTTP at `/metrics` on this address, in the Prometheus text format.
+	/// This requires the `metrics` feature.
+	pub metrics_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, PartialEq, Eq, Error)]
+pub enum ConfigError {
+	#[error("sample_rate must be greater than zero")]
+	ZeroSampleRate,
+	#[error("nominal_frequency must be greater than zero")]
+	ZeroNominalFrequency,
+	#[error("at least one output_channel must be given")]
+	NoOutputChannels,
+	#[error("input_channel of channel '{channel}' ({input_channel}) must be less than 8")]
+	InvalidInputChannel { channel: String, input_channel: usize },
+	#[error("frame_decimation must be greater than zero")]
+	ZeroFrameDecimation,
+	#[error("sample_rate ({sample_rate}) must be divisible by frame_decimation ({frame_decimation})")]
+	IndivisibleDecimation { sample_rate: u32, frame_decimation: u32 },
+	#[error(
+		"the output sample rate ({output_sample_rate}) must be a non-zero multiple of twice the nominal frequency \
+		 ({nominal_frequency})"
+	)]
+	IndivisibleWindow {
+		output_sample_rate: u32,
+		nominal_frequency: u32,
+	},
+	#[error("frames_per_second must be greater than zero")]
+	ZeroFramesPerSecond,
+	#[error(
+		"the output sample rate ({output_sample_rate}) must be a non-zero multiple of frames_per_second \
+		 ({frames_per_second})"
+	)]
+	IndivisibleFrameRate { output_sample_rate: u32, frames_per_second: u32 },
+	#[error("invalid transform for channel '{channel}': {reason}")]
+	InvalidTransform { channel: String, reason: &'static str },
+	#[error("the calibration of channel '{channel}' must have a finite, non-zero gain and a finite offset")]
+	InvalidCalibration { channel: String },
+	#[error("saturation thresholds must be positive and finite")]
+	InvalidSaturationThreshold,
+	#[error("plausibility bounds must be finite, with min no greater than max")]
+	InvalidPlausibilityBounds,
+	#[error("the nominal magnitude of channel '{channel}' must be positive and finite")]
+	InvalidNominal { channel: String },
+	#[error("receive_queue_capacity must be greater than zero")]
+	ZeroReceiveQueueCapacity,
+	#[error("frame_decimation ({frame_decimation}) must be a power of two when smpCnt wraps at 65536")]
+	IndivisibleWrap { frame_decimation: u32 },
+	#[error("frequency_reference ('{0}') is not the name of an output channel")]
+	UnknownFrequencyReference(String),
+	#[error("frequency_from_voltages cannot be combined with frequency_reference")]
+	ConflictingFrequencySources,
+	#[error("frequency_from_voltages requires at least one voltage output channel")]
+	NoVoltageChannels,
+	#[error("bits ({0}) must be 16 or 32")]
+	UnsupportedBits(u32),
+	#[error("send_delay_secs must be non-negative and finite")]
+	InvalidSendDelay,
+	#[error("max_queued_buffers must be greater than zero")]
+	ZeroMaxQueuedBuffers,
+	#[error("stats_interval_secs must be greater than zero")]
+	ZeroStatsInterval,
+	#[error("at least one destination must be given")]
+	NoDestinations,
+	#[error("the link-local destination {0} must include a zone, such as [fe80::1%eth0]:48001")]
+	MissingZone(SocketAddr),
+	#[error("multicast_ttl ({0}) must be at most 255")]
+	InvalidMulticastTtl(u32),
+}
+
+impl Configuration {
+	/// Checks the configuration for values which are syntactically valid but cannot be used.
+	pub fn validate(&self) -> Result<(), ConfigError> {
+		if self.sample_rate == 0 {
+			return Err(ConfigError::ZeroSampleRate);
+		}
+
+		if self.nominal_frequency == 0 {
+			return Err(ConfigError::ZeroNominalFrequency);
+		}
+
+		self.buffer_length()?;
+
+		if self.channels.is_empty() {
+			return Err(ConfigError::NoOutputChannels);
+		}
+
+		if self.receive_queue_capacity == 0 {
+			return Err(ConfigError::ZeroReceiveQueueCapacity);
+		}
+
+		if self.destinations.is_empty() {
+			return Err(ConfigError::NoDestinations);
+		}
+
+		if let Some(ttl) = self.multicast_ttl.filter(|&ttl| ttl > 255) {
+			return Err(ConfigError::InvalidMulticastTtl(ttl));
+		}
+
+		for destination in &self.destinations {
+			if let SocketAddr::V6(address) = destination {
+				if address.ip().is_unicast_link_local() && address.scope_id() == 0 {
+					return Err(ConfigError::MissingZone(*destination));
+				}
+			}
+		}
+
+		if !matches!(self.bits, 16 | 32) {
+			return Err(ConfigError::UnsupportedBits(self.bits));
+		}
+
+		if !(self.send_delay_secs.is_finite() && self.send_delay_secs >= 0.0) {
+			return Err(ConfigError::InvalidSendDelay);
+		}
+
+		if self.max_queued_buffers == 0 {
+			return Err(ConfigError::ZeroMaxQueuedBuffers);
+		}
+
+		if self.stats_interval_secs == 0 {
+			return Err(ConfigError::ZeroStatsInterval);
+		}
+
+		let thresholds = [self.saturation.current, self.saturation.voltage];
+		if thresholds
+			.into_iter()
+			.flatten()
+			.any(|threshold| !(threshold.is_finite() && threshold > 0.0))
+		{
+			return Err(ConfigError::InvalidSaturationThreshold);
+		}
+
+		let bounds = [self.plausibility.current, self.plausibility.voltage];
+		if bounds.iter().flatten().any(|bounds| !bounds.is_valid()) {
+			return Err(ConfigError::InvalidPlausibilityBounds);
+		}
+
+		if let Some(reference) = &self.frequency_reference {
+			if !self.channels.iter().any(|channel| &channel.name == reference) {
+				return Err(ConfigError::UnknownFrequencyReference(reference.clone()));
+			}
+		}
+
+		if self.frequency_from_voltages {
+			if self.frequency_reference.is_some() {
+				return Err(ConfigError::ConflictingFrequencySources);
+			}
+			if !self
+				.channels
+				.iter()
+				.any(|channel| matches!(channel.type_, OutputChannelType::Voltage))
+			{
+				return Err(ConfigError::NoVoltageChannels);
+			}
+		}
+
+		if self.smp_cnt_reset == SmpCntReset::Wrap && !self.frame_decimation.is_power_of_two() {
+			return Err(ConfigError::IndivisibleWrap {
+				frame_decimation: self.frame_decimation,
+			});
+		}
+
+		for channel in &self.channels {
+			if channel.input_channel >= 8 {
+				return Err(ConfigError::InvalidInputChannel {
+					channel: channel.name.clone(),
+					input_channel: channel.input_channel,
+				});
+			}
+			if !(channel.gain.is_finite() && channel.gain != 0.0 && channel.offset.is_finite()) {
+				return Err(ConfigError::InvalidCalibration {
+					channel: channel.name.clone(),
+				});
+			}
+			if let Some(Err(reason)) = channel.transform.map(|transform| transform.check()) {
+				return Err(ConfigError::InvalidTransform {
+					channel: channel.name.clone(),
+					reason,
+				});
+			}
+			if channel.nominal.is_some_and(|nominal| !(nominal.is_finite() && nominal > 0.0)) {
+				return Err(ConfigError::InvalidNominal {
+					channel: channel.name.clone(),
+				});
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Determines whether a frame with the specified APPID should be decoded.
+	pub fn accepts_appid(&self, appid: u16) -> bool {
+		self.appids.as_ref().is_none_or(|appids| appids.contains(&appid))
+	}
+
+	/// Returns the svID of the stream that a channel takes its samples from, or `None` if it takes samples from any
+	/// svID not named by another channel.
+	pub fn channel_stream<'a>(&'a self, channel: &'a OutputChannel) -> Option<&'a str> {
+		channel.stream.as_deref().or(self.default_svid.as_deref())
+	}
+
+	/// Returns the distinct streams referenced by the output channels, in order of first appearance. `None` represents
+	/// the stream used by channels which take samples from any svID not named by another channel.
+	pub fn streams(&self) -> Vec<Option<&str>> {
+		let mut streams = Vec::new();
+		for channel in &self.channels {
+			let stream = self.channel_stream(channel);
+			if !streams.contains(&stream) {
+				streams.push(stream);
+			}
+		}
+		streams
+	}
+
+	/// Determines the index (within `streams`) of the stream that an ASDU with the given svID belongs to, or `None` if
+	/// the ASDU is not used by any channel.
+	pub fn stream_index(streams: &[Option<&str>], svid: &str) -> Option<usize> {
+		streams
+			.iter()
+			.position(|&stream| stream == Some(svid))
+			.or_else(|| streams.iter().position(Option::is_none))
+	}
+
+	/// The sample rate of the output, after frame decimation has been applied. This is the value of `<Fs>`.
+	pub fn output_sample_rate(&self) -> u32 {
+		self.sample_rate / self.frame_decimation.max(1)
+	}
+
+	/// Applies frame decimation to a sample with the specified smpCnt. Returns the smpCnt of the sample at the output
+	/// sample rate, or `None` if the sample is skipped.
+	///
+	/// Samples are chosen by their smpCnt rather than by counting frames, so the samples kept are evenly spaced and
+	/// distinct even when each frame carries several ASDUs, and a lost frame does not shift the samples kept after it.
+	pub fn decimate(&self, smp_cnt: u16) -> Option<u16> {
+		let (smp_cnt, frame_decimation) = (u32::from(smp_cnt), self.frame_decimation.max(1));
+		smp_cnt
+			.is_multiple_of(frame_decimation)
+			.then(|| (smp_cnt / frame_decimation) as u16)
+	}
+
+	/// Determines whether a frame whose `no_asdu` ASDUs hold consecutive samples, starting from `smp_cnt`, contains a
+	/// sample kept by frame decimation. Frames without one can be skipped without being parsed.
+	pub fn decimation_keeps_frame(&self, smp_cnt: u16, no_asdu: u16) -> bool {
+		let frame_decimation = self.frame_decimation.max(1);
+		// The sample rate is divisible by the decimation factor, so this also holds across a reset of smpCnt.
+		let until_kept = (frame_decimation - u32::from(smp_cnt) % frame_decimation) % frame_decimation;
+		until_kept < u32::from(no_asdu)
+	}
+
+	/// Calculates the number of samples in each output buffer (the value of `<n>`), which covers one frame at
+	/// `frames_per_second`, or half of a cycle at the nominal frequency if that is not set.
+	///
+	/// Both `<Fs>` and `<n>` must be integers, and every buffer must contain exactly `<n>` samples, so the following
+	/// must hold:
+	/// - `sample_rate` is divisible by `frame_decimation`, and
+	/// - the resulting output sample rate is a non-zero multiple of the frame rate.
+	///
+	/// For example, 4000 Hz at 50 Hz nominal gives 40 samples per buffer, and 4800 Hz or 14400 Hz work at both 50 Hz
+	/// and 60 Hz. However, 4000 Hz at 60 Hz nominal would require 33.3 samples per buffer, so it is rejected.
+	///
+	/// Since the length divides the output sample rate, buffers are aligned to the start of each second, and `<Frame>`
+	/// counts from zero to one less than the frame rate within each second.
+	pub fn buffer_length(&self) -> Result<u32, ConfigError> {
+		if self.frame_decimation == 0 {
+			return Err(ConfigError::ZeroFrameDecimation);
+		}
+
+		if !self.sample_rate.is_multiple_of(self.frame_decimation) {
+			return Err(ConfigError::IndivisibleDecimation {
+				sample_rate: self.sample_rate,
+				frame_decimation: self.frame_decimation,
+			});
+		}
+
+		let output_sample_rate = self.output_sample_rate();
+		if let Some(frames_per_second) = self.frames_per_second {
+			if frames_per_second == 0 {
+				return Err(ConfigError::ZeroFramesPerSecond);
+			}
+			if output_sample_rate < frames_per_second || !output_sample_rate.is_multiple_of(frames_per_second) {
+				return Err(ConfigError::IndivisibleFrameRate {
+					output_sample_rate,
+					frames_per_second,
+				});
+			}
+			return Ok(output_sample_rate / frames_per_second);
+		}
+
+		let window_error = || ConfigError::IndivisibleWindow {
+			output_sample_rate,
+			nominal_frequency: self.nominal_frequency,
+		};
+
+		let frames_per_second = self
+			.nominal_frequency
+			.checked_mul(2)
+			.filter(|&frames_per_second| frames_per_second > 0)
+			.ok_or_else(window_error)?;
+
+		if output_sample_rate < frames_per_second || !output_sample_rate.is_multiple_of(frames_per_second) {
+			return Err(window_error());
+		}
+
+		Ok(output_sample_rate / frames_per_second)
+	}
+
+	/// Returns a builder for configuring the bridge programmatically rather than from a file.
+	pub fn builder() -> ConfigurationBuilder {
+		ConfigurationBuilder(Self::default())
+	}
+}
+
+/// The same values as a configuration file which only gives the required options, with a sample rate of 4000 Hz at a
+/// nominal frequency of 50 Hz. There are no interfaces, output channels or destinations, so these must be added for
+/// the configuration to be valid.
+impl Default for Configuration {
+	fn default() -> Self {
+		Self {
+			nominal_frequency: 50,
+			sample_rate: 4000,
+			interfaces: Vec::new(),
+			channels: Vec::new(),
+			destinations: Vec::new(),
+			// The first of the multicast addresses reserved for sampled values.
+			mac_address: MacAddress::from_bytes([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x01]),
+			validate_padding: false,
+			timestamp_format: TimestampFormat::default(),
+			strict_asdu_fields: false,
+			constructed_strings: false,
+			minimal_lengths: false,
+			validate_appid: false,
+			sample_format: SampleFormat::default(),
+			plausibility: PlausibilityConfig::default(),
+			saturation: SaturationThresholds::default(),
+			capture: None,
+			frame_decimation: default_frame_decimation(),
+			frames_per_second: None,
+			emit_clip_counts: false,
+			emit_asdu_header: false,
+			initial_buffer: InitialBufferPolicy::default(),
+			source_filter: SourceFilter::default(),
+			appids: None,
+			drop_simulated_frames: false,
+			expected_svid: None,
+			default_svid: None,
+			protocol: OutputProtocol::default(),
+			tcp_max_pending: 0,
+			multicast_ttl: None,
+			multicast_interface: None,
+			receive_queue_capacity: default_receive_queue_capacity(),
+			smp_cnt_reset: SmpCntReset::default(),
+			datagram_size: None,
+			frequency_reference: None,
+			frequency_from_voltages: false,
+			asdu_spacing_diagnostics: false,
+			vlan_tags: false,
+			timestamp_source: TimestampSource::default(),
+			local_sync: LocalSyncPolicy::default(),
+			clock_sync: ClockSyncPolicy::default(),
+			compact_xml: false,
+			bits: default_bits(),
+			send_delay_secs: default_send_delay_secs(),
+			time_source: TimeSource::default(),
+			max_queued_buffers: default_max_queued_buffers(),
+			stats_interval_secs: default_stats_interval_secs(),
+			metrics_addr: None,
+		}
+	}
+}
+
+/// Builds a `Configuration`, starting from its default values. Options without a method here can be set with `with`.
+pub struct ConfigurationBuilder(Configuration);
+
+impl ConfigurationBuilder {
+	pub fn nominal_frequency(mut self, nominal_frequency: u32) -> Self {
+		self.0.nominal_frequency = nominal_frequency;
+		self
+	}
+
+	pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+		self.0.sample_rate = sample_rate;
+		self
+	}
+
+	/// Adds an interface to receive sampled value messages on.
+	pub fn interface(mut self, interface: impl Into<String>) -> Self {
+		self.0.interfaces.push(interface.into());
+		self
+	}
+
+	/// Adds an output channel, after any previously added.
+	pub fn channel(mut self, channel: OutputChannel) -> Self {
+		self.0.channels.push(channel);
+		self
+	}
+
+	/// Adds a destination for OpenPMU datagrams.
+	pub fn destination(mut self, destination: SocketAddr) -> Self {
+		self.0.destinations.push(destination);
+		self
+	}
+
+	pub fn mac_address(mut self, mac_address: MacAddress) -> Self {
+		self.0.mac_address = mac_address;
+		self
+	}
+
+	pub fn frame_decimation(mut self, frame_decimation: u32) -> Self {
+		self.0.frame_decimation = frame_decimation;
+		self
+	}
+
+	pub fn frames_per_second(mut self, frames_per_second: u32) -> Self {
+		self.0.frames_per_second = Some(frames_per_second);
+		self
+	}
+
+	/// Sets any other options of the configuration.
+	pub fn with(mut self, f: impl FnOnce(&mut Configuration)) -> Self {
+		f(&mut self.0);
+		self
+	}
+
+	/// Validates and returns the configuration.
+	pub fn build(self) -> Result<Configuration, ConfigError> {
+		self.0.validate()?;
+		Ok(self.0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A configuration with a single voltage channel, which is not validated so that tests can make it invalid.
+	fn test_config(sample_rate: u32, nominal_frequency: u32, frame_decimation: u32) -> Configuration {
+		let builder = Configuration::builder()
+			.sample_rate(sample_rate)
+			.nominal_frequency(nominal_frequency)
+			.frame_decimation(frame_decimation)
+			.interface("eth0")
+			.channel(OutputChannel::new("VA", OutputChannelType::Voltage, "a", 4))
+			.destination("127.0.0.1:48001".parse().unwrap());
+		builder.0
+	}
+
+	#[test]
+	fn builder() {
+		let config = Configuration::builder()
+			.interface("eth0")
+			.channel(OutputChannel::new("VA", OutputChannelType::Voltage, "a", 4))
+			.destination("127.0.0.1:48001".parse().unwrap())
+			.with(|config| config.compact_xml = true)
+			.build()
+			.unwrap();
+		assert_eq!(config.buffer_length(), Ok(40));
+		assert!(config.compact_xml);
+
+		// The defaults are the same as those of a configuration file.
+		let parsed: Configuration = toml::from_str(
+			r#"
+			nominal_frequency = 50
+			sample_rate = 4000
+			interface = "eth0"
+			mac_address = "01-0C-CD-04-00-01"
+			destination = "127.0.0.1:48001"
+
+			[[output_channel]]
+			name = "VA"
+			phase = "a"
+			type = "voltage"
+			input_channel = 4
+			"#,
+		)
+		.unwrap();
+		assert_eq!(config.mac_address, parsed.mac_address);
+		assert_eq!(config.channels[0].effective_transform(), parsed.channels[0].effective_transform());
+		assert_eq!(config.bits, parsed.bits);
+		assert_eq!(config.send_delay_secs, parsed.send_delay_secs);
+		assert_eq!(config.max_queued_buffers, parsed.max_queued_buffers);
+		assert_eq!(config.receive_queue_capacity, parsed.receive_queue_capacity);
+		assert_eq!(config.stats_interval_secs, parsed.stats_interval_secs);
+
+		let result = Configuration::builder().interface("eth0").destination(parsed.destinations[0]).build();
+		assert_eq!(result.err(), Some(ConfigError::NoOutputChannels));
+		let result = Configuration::builder().sample_rate(4000).nominal_frequency(60).build();
+		let error = ConfigError::IndivisibleWindow { output_sample_rate: 4000, nominal_frequency: 60 };
+		assert_eq!(result.err(), Some(error));
+	}
+
+	#[test]
+	fn transform_per_unit() {
+		// A 230 V (line-to-neutral) nominal voltage, measured through a VT with a ratio of 100.
+		let transform = Transform {
+			scale: 100.0,
+			offset: 0.0,
+			base: Some(23_000.0),
+		};
+		assert_eq!(transform.check(), Ok(()));
+		assert_eq!(transform.apply(230.0), 1.0);
+		assert_eq!(transform.apply(-115.0), -0.5);
+
+		let transform = Transform {
+			scale: 2.0,
+			offset: 1.0,
+			base: None,
+		};
+		assert_eq!(transform.apply(3.0), 7.0);
+	}
+
+	#[test]
+	fn transform_invalid() {
+		let transform = Transform {
+			scale: 1.0,
+			offset: 0.0,
+			base: Some(0.0),
+		};
+		assert!(transform.check().is_err());
+
+		let transform = Transform {
+			scale: 0.0,
+			offset: 0.0,
+			base: None,
+		};
+		assert!(transform.check().is_err());
+
+		let transform = Transform {
+			scale: 1.0,
+			offset: f32::NAN,
+			base: None,
+		};
+		assert!(transform.check().is_err());
+	}
+
+	#[test]
+	fn calibration() {
+		let mut config = test_config(4000, 50, 1);
+		let channel = &mut config.channels[0];
+		assert_eq!((channel.gain, channel.offset), (1.0, 0.0));
+		assert_eq!(channel.effective_transform(), None);
+
+		// The calibration is applied before the transform.
+		channel.gain = 1.5;
+		channel.offset = -2.0;
+		assert_eq!(channel.effective_transform().unwrap().apply(4.0), 4.0);
+		channel.transform = Some(Transform {
+			scale: 100.0,
+			offset: 10.0,
+			base: Some(200.0),
+		});
+		assert_eq!(channel.effective_transform().unwrap().apply(4.0), 2.05);
+		assert_eq!(config.validate(), Ok(()));
+
+		config.channels[0].gain = 0.0;
+		let error = ConfigError::InvalidCalibration { channel: "VA".into() };
+		assert_eq!(config.validate(), Err(error));
+	}
+
+	#[test]
+	fn saturation_thresholds() {
+		let mut config = test_config(4000, 50, 1);
+		config.saturation.voltage = Some(400.0);
+		assert_eq!(config.saturation.for_type(&OutputChannelType::Voltage), Some(400.0));
+		assert_eq!(config.saturation.for_type(&OutputChannelType::Current), None);
+		assert_eq!(config.validate(), Ok(()));
+
+		config.saturation.current = Some(-1.0);
+		assert_eq!(config.validate(), Err(ConfigError::InvalidSaturationThreshold));
+	}
+
+	#[test]
+	fn plausibility_bounds() {
+		let mut config = test_config(4000, 50, 1);
+		config.plausibility.current = Some(ValueBounds { min: -100.0, max: 100.0 });
+		assert_eq!(config.validate(), Ok(()));
+
+		config.plausibility.current = Some(ValueBounds { min: 100.0, max: -100.0 });
+		assert_eq!(config.validate(), Err(ConfigError::InvalidPlausibilityBounds));
+
+		config.plausibility.current = None;
+		config.plausibility.voltage = Some(ValueBounds { min: f32::NAN, max: 1000.0 });
+		assert_eq!(config.validate(), Err(ConfigError::InvalidPlausibilityBounds));
+		config.plausibility.voltage = Some(ValueBounds { min: -1000.0, max: f32::INFINITY });
+		assert_eq!(config.validate(), Err(ConfigError::InvalidPlausibilityBounds));
+	}
+
+	#[test]
+	fn buffer_length_valid() {
+		assert_eq!(test_config(4000, 50, 1).buffer_length(), Ok(40));
+		assert_eq!(test_config(4800, 50, 1).buffer_length(), Ok(48));
+		assert_eq!(test_config(4800, 60, 1).buffer_length(), Ok(40));
+		assert_eq!(test_config(14400, 60, 1).buffer_length(), Ok(120));
+		assert_eq!(test_config(4000, 50, 2).buffer_length(), Ok(20));
+		assert_eq!(test_config(4800, 60, 4).buffer_length(), Ok(10));
+	}
+
+	#[test]
+	fn decimate_by_smp_cnt() {
+		let config = test_config(4000, 50, 4);
+		// Two ASDUs per frame, with the frame holding samples 4 and 5 lost.
+		let frames: [&[u16]; 5] = [&[0, 1], &[2, 3], &[6, 7], &[8, 9], &[10, 11]];
+		let kept = frames
+			.iter()
+			.flat_map(|asdus| asdus.iter())
+			.filter_map(|&smp_cnt| config.decimate(smp_cnt));
+		assert_eq!(kept.collect::<Vec<_>>(), [0, 2]);
+
+		// Eight ASDUs per frame, with every frame kept in part.
+		let kept = (8..24)
+			.filter_map(|smp_cnt| config.decimate(smp_cnt))
+			.collect::<Vec<_>>();
+		assert_eq!(kept, [2, 3, 4, 5]);
+
+		assert_eq!(test_config(4000, 50, 1).decimate(3999), Some(3999));
+
+		// Only frames holding one of the samples kept are parsed.
+		let kept = frames
+			.iter()
+			.filter(|asdus| config.decimation_keeps_frame(asdus[0], asdus.len() as u16))
+			.count();
+		assert_eq!(kept, 2);
+		assert!(config.decimation_keeps_frame(3997, 4));
+		assert!(!config.decimation_keeps_frame(3997, 3));
+		assert!(test_config(4000, 50, 1).decimation_keeps_frame(1, 1));
+	}
+
+	#[test]
+	fn buffer_length_frames_per_second() {
+		let mut config = test_config(4000, 50, 1);
+		config.frames_per_second = Some(50);
+		assert_eq!(config.buffer_length(), Ok(80));
+		config.frames_per_second = Some(60);
+		let error = ConfigError::IndivisibleFrameRate { output_sample_rate: 4000, frames_per_second: 60 };
+		assert_eq!(config.buffer_length(), Err(error));
+		config.frames_per_second = Some(0);
+		assert_eq!(config.validate(), Err(ConfigError::ZeroFramesPerSecond));
+
+		// A fixed frame rate does not depend on the nominal frequency.
+		let mut config = test_config(4800, 60, 2);
+		config.frames_per_second = Some(50);
+		assert_eq!(config.buffer_length(), Ok(48));
+	}
+
+	#[test]
+	fn buffer_length_invalid() {
+		assert_eq!(
+			test_config(4000, 60, 1).buffer_length(),
+			Err(ConfigError::IndivisibleWindow {
+				output_sample_rate: 4000,
+				nominal_frequency: 60
+			})
+		);
+		assert_eq!(
+			test_config(4000, 50, 3).buffer_length(),
+			Err(ConfigError::IndivisibleDecimation {
+				sample_rate: 4000,
+				frame_decimation: 3
+			})
+		);
+		assert_eq!(
+			test_config(4000, 50, 16).buffer_length(),
+			Err(ConfigError::IndivisibleWindow {
+				output_sample_rate: 250,
+				nominal_frequency: 50
+			})
+		);
+		assert_eq!(
+			test_config(4000, 50, 0).buffer_length(),
+			Err(ConfigError::ZeroFrameDecimation)
+		);
+		assert!(test_config(4000, 0, 1).buffer_length().is_err());
+		assert!(test_config(50, 50, 1).buffer_length().is_err());
+	}
+
+	#[test]
+	fn bits() {
+		let mut config = test_config(4000, 50, 1);
+		assert_eq!(config.bits, 16);
+		assert_eq!(config.validate(), Ok(()));
+		config.bits = 32;
+		assert_eq!(config.validate(), Ok(()));
+		config.bits = 24;
+		assert_eq!(config.validate(), Err(ConfigError::UnsupportedBits(24)));
+	}
+
+	#[test]
+	fn frequency_from_voltages() {
+		let mut config = test_config(4000, 50, 1);
+		config.frequency_from_voltages = true;
+		assert_eq!(config.validate(), Ok(()));
+		config.frequency_reference = Some("VA".to_owned());
+		assert_eq!(config.validate(), Err(ConfigError::ConflictingFrequencySources));
+		config.frequency_reference = None;
+		config.channels[0].type_ = OutputChannelType::Current;
+		assert_eq!(config.validate(), Err(ConfigError::NoVoltageChannels));
+	}
+
+	#[test]
+	fn sample_format_decode() {
+		assert_eq!(SampleFormat::Int32.decode([0xFF, 0xFF, 0xFF, 0xFE]), -2.0);
+		assert_eq!(SampleFormat::Int32.decode([0x00, 0x01, 0x00, 0x00]), 65536.0);
+		assert_eq!(SampleFormat::Float32.decode(1.5f32.to_be_bytes()), 1.5);
+		assert_eq!(SampleFormat::Float32.decode((-230.25f32).to_be_bytes()), -230.25);
+		assert_eq!(SampleFormat::Int24.decode([0x00, 0x7F, 0xFF, 0xFF]), 8_388_607.0);
+		assert_eq!(SampleFormat::Int24.decode([0x00, 0xFF, 0xFF, 0xFE]), -2.0);
+		// The unused byte does not affect the value.
+		assert_eq!(SampleFormat::Int24.decode([0xAB, 0x80, 0x00, 0x00]), -8_388_608.0);
+	}
+
+	#[test]
+	fn validate_basic_settings() {
+		assert_eq!(test_config(4000, 50, 1).validate(), Ok(()));
+		assert_eq!(test_config(0, 50, 1).validate(), Err(ConfigError::ZeroSampleRate));
+		assert_eq!(test_config(4000, 0, 1).validate(), Err(ConfigError::ZeroNominalFrequency));
+
+		let mut config = test_config(4000, 50, 1);
+		config.channels[0].input_channel = 8;
+		assert_eq!(
+			config.validate(),
+			Err(ConfigError::InvalidInputChannel {
+				channel: "VA".to_owned(),
+				input_channel: 8
+			})
+		);
+
+		config.channels.clear();
+		assert_eq!(config.validate(), Err(ConfigError::NoOutputChannels));
+	}
+
+	#[test]
+	fn destinations() {
+		let mut config = test_config(4000, 50, 1);
+		assert_eq!(config.destinations, ["127.0.0.1:48001".parse().unwrap()]);
+
+		let config_str = r#"
+			nominal_frequency = 50
+			sample_rate = 4000
+			interface = "eth0"
+			mac_address = "01-0C-CD-04-00-01"
+			destination = ["127.0.0.1:48001", "[::1]:48002"]
+
+			[[output_channel]]
+			name = "VA"
+			phase = "a"
+			type = "voltage"
+			input_channel = 4
+		"#;
+		let many: Configuration = toml::from_str(config_str).unwrap();
+		assert_eq!(many.destinations.len(), 2);
+		assert_eq!(many.validate(), Ok(()));
+
+		let link_local = "[fe80::1]:48001".parse().unwrap();
+		config.destinations = vec![link_local];
+		assert_eq!(config.validate(), Err(ConfigError::MissingZone(link_local)));
+		config.destinations = vec!["[fe80::1%2]:48001".parse().unwrap()];
+		assert_eq!(config.validate(), Ok(()));
+
+		config.multicast_ttl = Some(256);
+		assert_eq!(config.validate(), Err(ConfigError::InvalidMulticastTtl(256)));
+		config.multicast_ttl = None;
+
+		config.destinations.clear();
+		assert_eq!(config.validate(), Err(ConfigError::NoDestinations));
+	}
+
+	#[test]
+	fn send_delay() {
+		let mut config = test_config(4000, 50, 1);
+		assert_eq!(config.send_delay_secs, 0.05);
+		config.send_delay_secs = 0.0;
+		assert_eq!(config.validate(), Ok(()));
+		config.send_delay_secs = -0.01;
+		assert_eq!(config.validate(), Err(ConfigError::InvalidSendDelay));
+		config.send_delay_secs = f64::NAN;
+		assert_eq!(config.validate(), Err(ConfigError::InvalidSendDelay));
+	}
+
+	#[test]
+	fn stats_interval() {
+		let mut config = test_config(4000, 50, 1);
+		assert_eq!(config.stats_interval_secs, 10);
+		config.stats_interval_secs = 0;
+		assert_eq!(config.validate(), Err(ConfigError::ZeroStatsInterval));
+
+		// The older name of the option is still accepted.
+		let config_str = r#"
+			nominal_frequency = 50
+			sample_rate = 4000
+			interface = "eth0"
+			mac_address = "01-0C-CD-04-00-01"
+			destination = "127.0.0.1:48001"
+			loss_summary_secs = 30
+			output_channel = []
+		"#;
+		let config: Configuration = toml::from_str(config_str).unwrap();
+		assert_eq!(config.stats_interval_secs, 30);
+	}
+
+	#[test]
+	fn appids() {
+		let mut config = test_config(4000, 50, 1);
+		assert!(config.accepts_appid(0x4000));
+		config.appids = Some(vec![0x4000, 0x4001]);
+		assert!(config.accepts_appid(0x4001));
+		assert!(!config.accepts_appid(0x4002));
+	}
+
+	#[test]
+	fn smp_cnt_reset_second() {
+		let reset = SmpCntReset::Second;
+		assert_eq!(reset.period(4000), 4000);
+		assert!(reset.is_consistent(4000, 100, 101));
+		assert!(reset.is_consistent(4000, 3999, 0));
+		// Frames were lost just before the reset, or were reordered.
+		assert!(reset.is_consistent(4000, 3990, 1));
+		assert!(reset.is_consistent(4000, 101, 100));
+		// The counter went past the end of the second.
+		assert!(!reset.is_consistent(4000, 3999, 4000));
+		// The counter reset long before the end of the second.
+		assert!(!reset.is_consistent(4000, 2500, 0));
+	}
+
+	#[test]
+	fn smp_cnt_reset_wrap() {
+		let reset = SmpCntReset::Wrap;
+		assert_eq!(reset.period(4000), 65536);
+		assert!(reset.is_consistent(4000, 3999, 4000));
+		assert!(reset.is_consistent(4000, 65535, 0));
+		// The counter actually resets every second.
+		assert!(!reset.is_consistent(4000, 3999, 0));
+	}
+
+	#[test]
+	fn expected_svid() {
+		let validation: SvidValidation = toml::from_str(r#"exact = "MU01""#).unwrap();
+		assert!(!validation.strict);
+		assert!(validation.pattern.matches("MU01"));
+		assert!(!validation.pattern.matches("MU011"));
+		assert!(!validation.pattern.matches("mu01"));
+
+		let validation: SvidValidation = toml::from_str(
+			r#"
+			regex = "^MU0[1-4]$"
+			strict = true
+			"#,
+		)
+		.unwrap();
+		assert!(validation.strict);
+		assert!(validation.pattern.matches("MU01"));
+		assert!(validation.pattern.matches("MU04"));
+		assert!(!validation.pattern.matches("MU05"));
+		assert!(!validation.pattern.matches("XMU01"));
+
+		let validation: SvidValidation = toml::from_str(r#"one_of = ["MU01", "MU02"]"#).unwrap();
+		assert!(validation.pattern.matches("MU01"));
+		assert!(validation.pattern.matches("MU02"));
+		assert!(!validation.pattern.matches("MU03"));
+
+		assert!(toml::from_str::<SvidValidation>(r#"regex = "MU(""#).is_err());
+	}
+
+	#[test]
+	fn default_svid() {
+		let mut config = test_config(4000, 50, 1);
+		let mut channel = OutputChannel::new("VB", OutputChannelType::Voltage, "b", 5);
+		channel.stream = Some("MU02".to_owned());
+		config.channels.push(channel);
+
+		// Without a default svID, the channels without a stream take samples from any svID not named by another.
+		let streams = config.streams();
+		assert_eq!(streams, [None, Some("MU02")]);
+		assert_eq!(Configuration::stream_index(&streams, "MU02"), Some(1));
+		assert_eq!(Configuration::stream_index(&streams, "MU03"), Some(0));
+
+		config.default_svid = Some("MU01".to_owned());
+		let streams = config.streams();
+		assert_eq!(streams, [Some("MU01"), Some("MU02")]);
+		assert_eq!(config.channel_stream(&config.channels[0]), Some("MU01"));
+		assert_eq!(Configuration::stream_index(&streams, "MU01"), Some(0));
+		assert_eq!(Configuration::stream_index(&streams, "MU03"), None);
+	}
+
+	#[test]
+	fn time_source() {
+		let refr_tm = UtcTime::from_octets([0x65, 0x92, 0x00, 0x80, 0x80, 0x00, 0x00, 0x0A]);
+		let mut asdu = Asdu {
+			svid: "MU01",
+			datset: None,
+			smp_cnt: 0,
+			conf_rev: 1,
+			refr_tm: Some(refr_tm),
+			smp_synch: 0,
+			smp_rate: None,
+			sample: Default::default(),
+			smp_mod: None,
+			gm_identity: None,
+		};
+		assert_eq!(TimeSource::ReceiveTime.refr_tm(&asdu), None);
+		assert_eq!(TimeSource::RefrTm.refr_tm(&asdu), Some(refr_tm));
+		assert_eq!(TimeSource::Auto.refr_tm(&asdu), None);
+
+		asdu.smp_synch = 2;
+		assert_eq!(TimeSource::Auto.refr_tm(&asdu), Some(refr_tm));
+
+		asdu.refr_tm = None;
+		assert_eq!(TimeSource::RefrTm.refr_tm(&asdu), None);
+		assert_eq!(TimeSource::Auto.refr_tm(&asdu), None);
+	}
+
+	#[test]
+	fn local_sync_policy() {
+		let policy = LocalSyncPolicy::Accept;
+		assert!(policy.accepts(0) && policy.accepts(1) && policy.accepts(2));
+		assert!(!policy.flags(true));
+
+		let policy = LocalSyncPolicy::Flag;
+		assert!(policy.accepts(0) && policy.accepts(1) && policy.accepts(2));
+		assert!(policy.flags(true));
+		assert!(!policy.flags(false));
+
+		let policy = LocalSyncPolicy::Drop;
+		assert!(policy.accepts(0) && policy.accepts(2));
+		assert!(!policy.accepts(1));
+		assert!(!policy.flags(true));
+	}
 }
@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use crate::{ethernet::MacAddress, SvMessage};
+
+/// Identifies a stream of sampled values observed on the network.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StreamKey {
+	svid: String,
+	conf_rev: u32,
+	appid: u16,
+	source_mac: Option<MacAddress>,
+	vlan_id: Option<u16>,
+}
+
+#[derive(Debug, Default)]
+struct StreamStats {
+	frames: u64,
+	asdus: u64,
+}
+
+/// Keeps track of the distinct streams observed on the network, to help with writing the configuration.
+#[derive(Debug, Default)]
+pub struct Discovery {
+	streams: BTreeMap<StreamKey, StreamStats>,
+}
+
+impl Discovery {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Records a received message, which was sent from `source_mac` on VLAN `vlan_id`.
+	pub fn record(&mut self, message: &SvMessage, source_mac: Option<MacAddress>, vlan_id: Option<u16>) {
+		let mut keys = Vec::new();
+		for asdu in &message.asdus {
+			let key = StreamKey {
+				svid: asdu.svid.clone(),
+				conf_rev: asdu.conf_rev,
+				appid: message.appid,
+				source_mac,
+				vlan_id,
+			};
+
+			// A frame is only counted once for each stream, even if it contains several ASDUs from that stream.
+			let stats = self.streams.entry(key.clone()).or_default();
+			stats.asdus += 1;
+			if !keys.contains(&key) {
+				stats.frames += 1;
+				keys.push(key);
+			}
+		}
+	}
+
+	/// Formats the observed streams as a table. The sample rate of each stream is inferred from the number of ASDUs
+	/// received over the observation period, `elapsed`.
+	pub fn to_table(&self, elapsed: Duration) -> String {
+		let mut table = String::new();
+		let _ = writeln!(
+			&mut table,
+			"{:<32} {:>8} {:>6} {:<17} {:>4} {:>10} {:>12}",
+			"svID", "confRev", "APPID", "Source", "VLAN", "Frames", "Rate (Hz)"
+		);
+
+		for (key, stats) in &self.streams {
+			let source = match key.source_mac {
+				Some(source_mac) => source_mac.to_string(),
+				None => "unknown".to_owned(),
+			};
+			let vlan = match key.vlan_id {
+				Some(vlan_id) => vlan_id.to_string(),
+				None => "-".to_owned(),
+			};
+			let rate = stats.asdus as f64 / elapsed.as_secs_f64();
+			let _ = writeln!(
+				&mut table,
+				"{:<32} {:>8} {:>#06X} {:<17} {:>4} {:>10} {:>12.0}",
+				key.svid, key.conf_rev, key.appid, source, vlan, stats.frames, rate
+			);
+		}
+
+		table
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Asdu, Sample};
+
+	fn message(appid: u16, svids: &[&str]) -> SvMessage {
+		let asdus = svids
+			.iter()
+			.map(|&svid| Asdu {
+				svid: svid.to_owned(),
+				datset: None,
+				smp_cnt: 0,
+				conf_rev: 1,
+				refr_tm: None,
+				smp_synch: 2,
+				smp_rate: None,
+				sample: Sample::default(),
+				smp_mod: None,
+				gm_identity: None,
+			})
+			.collect();
+		SvMessage {
+			appid,
+			length: 0,
+			simulated: false,
+			reserved: [0; 2],
+			asdus,
+		}
+	}
+
+	#[test]
+	fn table() {
+		let source = MacAddress::try_from("00-1A-2B-3C-4D-5E".to_owned()).unwrap();
+
+		let mut discovery = Discovery::new();
+		for _ in 0..1000 {
+			discovery.record(&message(0x4000, &["MU01", "MU01"]), Some(source), Some(100));
+		}
+		discovery.record(&message(0x4001, &["MU02"]), None, None);
+
+		let table = discovery.to_table(Duration::from_millis(500));
+		let lines = table.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 3);
+		assert!(lines[1].starts_with("MU01 "));
+		assert!(lines[1].contains("0x4000"));
+		assert!(lines[1].contains("00-1A-2B-3C-4D-5E  100 "));
+		assert!(lines[1].contains(" 1000 "));
+		assert!(lines[1].ends_with(" 4000"));
+		assert!(lines[2].starts_with("MU02 "));
+		assert!(lines[2].contains("unknown"));
+		assert!(lines[2].contains("    - "));
+	}
+}
@@ -1,19 +1,37 @@
 use std::{
-	ffi::{c_int, c_longlong, c_uint, c_ushort, c_void, CString, OsStr},
-	os::{
-		fd::{AsRawFd, FromRawFd, OwnedFd},
-		unix::ffi::OsStrExt,
-	},
+	os::fd::{AsRawFd, BorrowedFd},
+	time::Duration,
 };
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 use thiserror::Error;
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+// Frames are sent and received with a packet socket on Linux, and with the Berkeley Packet Filter on macOS and
+// FreeBSD. Each implementation provides an `EthernetSocket` with the same interface.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+mod bpf;
+#[cfg(target_os = "linux")]
+mod linux;
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub use bpf::EthernetSocket;
+#[cfg(target_os = "linux")]
+pub use linux::EthernetSocket;
+
+#[cfg(feature = "tokio")]
+mod async_socket;
+#[cfg(feature = "tokio")]
+pub use async_socket::AsyncEthernetSocket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
 #[serde(try_from = "String")]
 pub struct MacAddress([u8; 6]);
 
 impl MacAddress {
+	pub const fn from_bytes(bytes: [u8; 6]) -> Self {
+		Self(bytes)
+	}
+
 	pub fn to_bytes(self) -> [u8; 6] {
 		self.0
 	}
@@ -23,18 +41,29 @@ impl MacAddress {
 #[error("invalid MAC address syntax")]
 pub struct MacAddressParseError;
 
+/// Parses a MAC address from six pairs of hexadecimal digits in either case, which may be separated by `-` or `:`.
 impl TryFrom<String> for MacAddress {
 	type Error = MacAddressParseError;
 	fn try_from(s: String) -> Result<Self, Self::Error> {
+		let parse_octet = |octet_str: &str| {
+			if octet_str.len() == 2 && octet_str.bytes().all(|b| b.is_ascii_hexdigit()) {
+				Ok(u8::from_str_radix(octet_str, 16).unwrap())
+			} else {
+				Err(MacAddressParseError)
+			}
+		};
 		let mut addr_bytes = [0; 6];
-		let mut octet_str_iter = s.split(['-', ':']);
 
-		for i in 0..6 {
-			let octet_str = octet_str_iter.next().ok_or(MacAddressParseError)?;
-			if octet_str.len() != 2 {
-				return Err(MacAddressParseError);
+		if s.len() == 12 && s.is_ascii() {
+			for (i, addr_byte) in addr_bytes.iter_mut().enumerate() {
+				*addr_byte = parse_octet(&s[2 * i..2 * i + 2])?;
 			}
-			addr_bytes[i] = u8::from_str_radix(octet_str, 16).map_err(|_| MacAddressParseError)?;
+			return Ok(MacAddress(addr_bytes));
+		}
+
+		let mut octet_str_iter = s.split(['-', ':']);
+		for addr_byte in &mut addr_bytes {
+			*addr_byte = parse_octet(octet_str_iter.next().ok_or(MacAddressParseError)?)?;
 		}
 
 		if octet_str_iter.next().is_some() {
@@ -45,6 +74,13 @@ impl TryFrom<String> for MacAddress {
 	}
 }
 
+/// Serializes a MAC address in the same form as its `Display` implementation, which can be deserialized again.
+impl Serialize for MacAddress {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
 impl std::fmt::Display for MacAddress {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		for i in 0..6 {
@@ -58,209 +94,159 @@ impl std::fmt::Display for MacAddress {
 }
 
 /// The value of the EtherType field used IEC 61850-9-2 sampled value messages.
-const ETHERTYPE_SV: u16 = 0x88BA;
-
-/// Obtains the index of the network interface with the given name.
-fn interface_name_to_index(name: &OsStr) -> std::io::Result<c_uint> {
-	// `if_nametoindex` expects a null terminated string.
-	let c_name = CString::new(name.as_bytes()).map_err(|_| std::io::ErrorKind::InvalidInput)?;
-
-	let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
-	// `if_nametoindex` returns 0 on error, with the error code in `errno`.
-	if index == 0 {
-		Err(std::io::Error::last_os_error())
-	} else {
-		Ok(index)
+pub(crate) const ETHERTYPE_SV: u16 = 0x88BA;
+
+/// EtherTypes of the 802.1Q VLAN tag and the 802.1ad service tag, which may precede the SV EtherType.
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+pub(crate) const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+/// The parts of a complete Ethernet frame containing a sampled value message, for sources which receive the Ethernet
+/// header along with the payload.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SvFrame<'a> {
+	pub source_mac: MacAddress,
+	/// The tag control information of the frame's outermost VLAN tag, if it has one.
+	pub vlan_tci: Option<u16>,
+	pub payload: &'a [u8],
+}
+
+impl<'a> SvFrame<'a> {
+	/// Parses an Ethernet frame, skipping any VLAN tags. Returns `None` if the frame does not contain a sampled value
+	/// message.
+	pub fn parse(frame: &'a [u8]) -> Option<Self> {
+		let source_mac = MacAddress(frame.get(6..12)?.try_into().unwrap());
+		let mut vlan_tci = None;
+
+		let mut offset = 12;
+		loop {
+			let ethertype = u16::from_be_bytes(frame.get(offset..offset + 2)?.try_into().unwrap());
+			match ethertype {
+				ETHERTYPE_VLAN | ETHERTYPE_QINQ => {
+					let tci = u16::from_be_bytes(frame.get(offset + 2..offset + 4)?.try_into().unwrap());
+					vlan_tci.get_or_insert(tci);
+					offset += 4;
+				}
+				ETHERTYPE_SV => {
+					return Some(Self {
+						source_mac,
+						vlan_tci,
+						payload: &frame[offset + 2..],
+					});
+				}
+				_ => return None,
+			}
+		}
 	}
 }
 
+/// The minimum length of an Ethernet frame's payload. Shorter payloads must be padded.
+const MIN_PAYLOAD_LENGTH: usize = 46;
+
+/// Builds the payload of an Ethernet frame containing an SV message with the given APPID, whose APDU is `apdu`. The
+/// message is padded with zeros to the minimum length of a payload.
+pub(crate) fn sv_payload(appid: u16, apdu: &[u8]) -> std::io::Result<Vec<u8>> {
+	let header = crate::sv_header(appid, apdu.len()).ok_or(std::io::ErrorKind::InvalidInput)?;
+	let mut payload = Vec::with_capacity(MIN_PAYLOAD_LENGTH.max(header.len() + apdu.len()));
+	payload.extend(header);
+	payload.extend(apdu);
+	payload.resize(payload.len().max(MIN_PAYLOAD_LENGTH), 0);
+	Ok(payload)
+}
+
 /// A struct providing information about a received Ethernet frame.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RecvInfo {
 	/// The length of the frame's payload in bytes.
 	pub length: usize,
 	pub timestamp_s: i64,
 	pub timestamp_ns: u32,
+	/// Whether the timestamp was taken by the network interface's hardware clock, rather than by the kernel. Hardware
+	/// timestamps use the timescale of the hardware clock, which is TAI when it is disciplined by PTP, rather than UTC.
+	pub hardware_timestamp: bool,
+	/// The address of the device which sent the frame, if known.
+	pub source_mac: Option<MacAddress>,
+	/// The VLAN identifier from the frame's 802.1Q tag, if it was tagged and the tag is known.
+	pub vlan_id: Option<u16>,
+	/// The priority code point from the frame's 802.1Q tag, if it was tagged and the tag is known.
+	pub priority: Option<u8>,
 }
 
-#[derive(Debug)]
-pub struct EthernetSocket {
-	fd: OwnedFd,
+/// Splits the tag control information of an 802.1Q tag into its VLAN identifier and priority code point.
+pub(crate) fn split_vlan_tci(tci: u16) -> (u16, u8) {
+	(tci & 0x0FFF, (tci >> 13) as u8)
 }
 
-impl EthernetSocket {
-	/// Creates an Ethernet socket which receives Ethernet frames containing sampled value messages.
-	///
-	/// If `interface` is `None`, Ethernet frames will be received from all network interfaces. Otherwise, frames will
-	/// only be received on the specified interface.
-	pub fn new(interface: &OsStr, source_addr: MacAddress) -> std::io::Result<Self> {
-
-		// Create the socket.
-		// - `AF_PACKET` specifies that the socket is for receiving layer 2 frames (see the `packet(7)` man page).
-		// - For packet sockets, `SOCK_DGRAM` indicates that only the payload should be included. We use this type so
-		//   that we don't need to worry about handling VLAN tagging.
-		// - When a packet socket is created, it will receive frames on all network interfaces until it is bound to a
-		//   particular interface using `bind`. In the brief period of time between creating the socket and calling
-		//   `bind`, the socket can still receive frames from other interfaces. To prevent this, we specify 0 as the
-		//   protocol, meaning no frames will be received. The EtherType to filter for is instead passed to `bind`,
-		//   along with the network interface.
-		let socket = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, 0) };
-		// `socket` returns -1 on error, with the error code in `errno`.
-		if socket == -1 {
-			return Err(std::io::Error::last_os_error());
-		}
-
-		// Get the numerical index of the network interface from its name.
-		let interface_index = interface_name_to_index(interface)?;
-
-		// Bind the socket such that we only receive frames on the specified interface.
-		let address = libc::sockaddr_ll {
-			sll_family: libc::AF_PACKET as c_ushort, // Always `AF_PACKET`.
-			sll_protocol: ETHERTYPE_SV.to_be(), // Only receive frames with the IEC 61850-9-2 SV EtherType.
-			sll_ifindex: interface_index as c_int, // The numerical index of the interface to receive from.
-			// Remaining fields are not used for `bind`.
-			sll_hatype: 0,
-			sll_pkttype: 0,
-			sll_halen: 0,
-			sll_addr: [0; 8],
-		};
-
-		let result = unsafe {
-			libc::bind(
-				socket,
-				&address as *const libc::sockaddr_ll as *const libc::sockaddr,
-				size_of::<libc::sockaddr_ll>() as libc::socklen_t,
-			)
+/// Waits up to `timeout` for `fd` to become readable, returning whether it did. A signal received while waiting ends
+/// the wait early, as though it had timed out, so that the caller can check whether it should stop.
+fn wait_readable(fd: BorrowedFd, timeout: Duration) -> std::io::Result<bool> {
+	let mut pollfd = libc::pollfd {
+		fd: fd.as_raw_fd(),
+		events: libc::POLLIN,
+		revents: 0,
+	};
+	// Round up, so that a short non-zero timeout does not become a non-blocking poll.
+	let timeout_ms = timeout.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int;
+
+	let result = unsafe { libc::poll(&raw mut pollfd, 1, timeout_ms) };
+	// `poll` returns -1 on error, with the error code in `errno`.
+	if result == -1 {
+		let err = std::io::Error::last_os_error();
+		return if err.kind() == std::io::ErrorKind::Interrupted {
+			Ok(false)
+		} else {
+			Err(err)
 		};
-		// `bind` returns -1 on error, with the error code in `errno`.
-		if result == -1 {
-			return Err(std::io::Error::last_os_error());
-		}
+	}
+	Ok(result > 0)
+}
 
-		// Enable the `SO_TIMESTAMPNS_NEW` socket option so that we get a timestamp with each frame received.
-		// This timestamp will be more accurate than simply checking the time after receiving a frame, since it does
-		// not include the time taken by the kernel to process the frame.
-		let optval = 1;
-		let result = unsafe {
-			libc::setsockopt(
-				socket,
-				libc::SOL_SOCKET,
-				libc::SO_TIMESTAMPNS_NEW,
-				&raw const optval as *const c_void,
-				size_of::<c_int>() as libc::socklen_t,
-			)
-		};
-		// `setsockopt` returns -1 on error, with the error code in `errno`.
-		if result == -1 {
-			return Err(std::io::Error::last_os_error());
-		}
+#[cfg(test)]
+mod tests {
+	use std::os::fd::AsFd;
 
-		// Configure the network interface to receive frames with the specified multicast destination address.
-		let mreq = libc::packet_mreq {
-			mr_ifindex: interface_index as c_int,
-			mr_type: libc::PACKET_MR_MULTICAST as c_ushort,
-			mr_alen: 6,
-			mr_address: std::array::from_fn(|i| source_addr.to_bytes().get(i).cloned().unwrap_or(0)),
-		};
+	use super::*;
 
-		let result = unsafe {
-			libc::setsockopt(
-				socket,
-				libc::SOL_PACKET,
-				libc::PACKET_ADD_MEMBERSHIP,
-				&raw const mreq as *const c_void,
-				size_of::<libc::packet_mreq>() as libc::socklen_t,
-			)
-		};
-		if result == -1 {
-			return Err(std::io::Error::last_os_error());
+	#[test]
+	fn parse_mac_address() {
+		let expected = MacAddress::from_bytes([0x01, 0x0C, 0xCD, 0x04, 0x00, 0xAB]);
+		for s in ["01-0C-CD-04-00-AB", "01:0c:cd:04:00:ab", "010CCD0400AB", "010ccd0400Ab"] {
+			assert_eq!(MacAddress::try_from(s.to_owned()).unwrap(), expected, "{s}");
 		}
+		assert_eq!(expected.to_string(), "01-0C-CD-04-00-AB");
 
-		Ok(Self {
-			fd: unsafe { OwnedFd::from_raw_fd(socket) },
-		})
-	}
-
-	/// Receives a single Ethernet frame on the socket. The frame's payload will be written to `buf`, while its length
-	/// and timestamp are returned in the `RecvInfo` structure.
-	///
-	/// This function will block until a frame is received.
-	pub fn recv(&self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
-		// This matches Linux's `__kernel_timespec` type, which uses 64 bit fields even on 32 bit systems.
-		#[repr(C)]
-		struct KernelTimespec {
-			tv_sec: c_longlong,
-			tv_nsec: c_longlong,
+		for s in ["01-0C-CD-04-00", "01-0C-CD-04-00-AB-CD", "010CCD0400A", "010CCD0400ABC", "1-0C-CD-04-00-AB"] {
+			MacAddress::try_from(s.to_owned()).expect_err(s);
 		}
-
-		// Timestamps are received as control messages (also known as ancillary data), which requires a separate buffer.
-		// This buffer must have enough space for both the timestamp and some additional metadata; the total size is
-		// calculated using `CMSG_SPACE`.
-		const CMSG_BUFFER_LENGTH: usize = unsafe { libc::CMSG_SPACE(size_of::<KernelTimespec>() as u32) } as usize;
-
-		// The control message buffer must have the same alignment as the `cmsghdr` type. A struct is used to control
-		// its alignment.
-		#[repr(C)]
-		struct CMsgBuffer {
-			// Since the struct uses the C representation, the first member is guaranteed to be at offset 0, meaning it
-			// has the same alignment as the struct.
-			buffer: [u8; CMSG_BUFFER_LENGTH],
-			// A zero-sized array does not affect the size of the containing struct, but does affect its alignment.
-			// Since a struct has the same alignment as its most aligned member, this guarantees that it will have an
-			// alignment at least as large as `cmsghdr`.
-			_align: [libc::cmsghdr; 0],
+		for s in ["+1-0C-CD-04-00-AB", "010CCD0400+B", "01-0C-CD-04-00-AG", "01-0C-CD-04-00-éA"] {
+			MacAddress::try_from(s.to_owned()).expect_err(s);
 		}
+	}
 
-		// Create an instance of the struct to hold the buffer.
-		let mut cmsg_buffer = CMsgBuffer {
-			buffer: [0; CMSG_BUFFER_LENGTH],
-			_align: [],
-		};
-
-		// The `recvmsg` function is able to write data into several non-contiguous buffers. Since we don't need this
-		// feature, we can just specifiy a single buffer.
-		let mut msg_iov = libc::iovec {
-			iov_base: buf.as_mut_ptr() as *mut c_void,
-			iov_len: buf.len(),
-		};
-
-		let mut msg = libc::msghdr {
-			msg_name: std::ptr::null_mut(), // Can be used if we want to know who sent the frame (for now we don't).
-			msg_namelen: 0,
-			msg_iov: &raw mut msg_iov,
-			msg_iovlen: 1,
-			msg_control: cmsg_buffer.buffer.as_mut_ptr() as *mut c_void,
-			msg_controllen: cmsg_buffer.buffer.len(),
-			msg_flags: 0,
-		};
+	#[test]
+	fn serialize_mac_address() {
+		let address = MacAddress::from_bytes([0x01, 0x0C, 0xCD, 0x04, 0x00, 0xAB]);
+		let json = serde_json::to_string(&address).unwrap();
+		assert_eq!(json, r#""01-0C-CD-04-00-AB""#);
+		assert_eq!(serde_json::from_str::<MacAddress>(&json).unwrap(), address);
+	}
 
-		let length = unsafe { libc::recvmsg(self.fd.as_raw_fd(), &raw mut msg, 0) };
-		// `recvmsg` returns -1 on error, with the error code in `errno`.
-		if length == -1 {
-			return Err(std::io::Error::last_os_error());
-		}
+	#[test]
+	fn short_sv_payload_is_padded() {
+		let payload = sv_payload(0x4000, &[0x60, 0x02, 0x80, 0x00]).unwrap();
+		assert_eq!(payload.len(), MIN_PAYLOAD_LENGTH);
+		assert_eq!(payload[..12], [0x40, 0x00, 0x00, 0x0C, 0, 0, 0, 0, 0x60, 0x02, 0x80, 0x00]);
+		assert!(payload[12..].iter().all(|&byte| byte == 0));
 
-		// Iterate through all received control messages to get the one containing the timestamp.
-		// This is probably a bit overkill, since the timestamp control message should be the only one present.
-		let mut cmsg: *const libc::cmsghdr = unsafe { libc::CMSG_FIRSTHDR(&raw const msg) };
-		while !cmsg.is_null() {
-			let cmsg_hdr = unsafe { &*cmsg };
-
-			if cmsg_hdr.cmsg_level == libc::SOL_SOCKET && cmsg_hdr.cmsg_type == libc::SO_TIMESTAMPNS_NEW {
-				let timestamp_ptr = unsafe { libc::CMSG_DATA(cmsg) } as *const KernelTimespec;
-				// The pointer to the control message data is not guaranteed to be aligned.
-				let timestamp = unsafe { timestamp_ptr.read_unaligned() };
-
-				return Ok(RecvInfo {
-					length: length as usize,
-					timestamp_s: timestamp.tv_sec,
-					timestamp_ns: timestamp.tv_nsec as u32,
-				});
-			}
+		assert!(sv_payload(0x4000, &[0; 65528]).is_err());
+		assert_eq!(sv_payload(0x4000, &[0; 65527]).unwrap().len(), 65535);
+	}
 
-			cmsg = unsafe { libc::CMSG_NXTHDR(&raw const msg, cmsg) };
-		}
+	#[test]
+	fn wait_readable_timeout() {
+		let (sender, receiver) = std::os::unix::net::UnixDatagram::pair().unwrap();
+		assert!(!wait_readable(receiver.as_fd(), Duration::from_millis(10)).unwrap());
 
-		unreachable!("did not receive timestamp control message");
+		sender.send(b"frame").unwrap();
+		assert!(wait_readable(receiver.as_fd(), Duration::from_millis(10)).unwrap());
 	}
 }
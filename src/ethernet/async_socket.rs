@@ -0,0 +1,108 @@
+use std::os::fd::AsRawFd;
+
+use tokio::io::{Interest, unix::AsyncFd};
+
+use super::{EthernetSocket, RecvInfo};
+
+/// An `EthernetSocket` for use in a tokio runtime, whose receive methods wait for frames without blocking a thread.
+#[derive(Debug)]
+pub struct AsyncEthernetSocket {
+	inner: AsyncFd<EthernetSocket>,
+}
+
+impl AsyncEthernetSocket {
+	/// Puts `socket` into non-blocking mode and registers it with the current runtime. This must be called from within
+	/// a runtime with I/O enabled.
+	pub fn new(socket: EthernetSocket) -> std::io::Result<Self> {
+		set_nonblocking(&socket)?;
+		Ok(Self {
+			inner: AsyncFd::with_interest(socket, Interest::READABLE)?,
+		})
+	}
+
+	/// Returns the underlying socket, which can be used to send frames. Its receive methods do not block, returning a
+	/// `WouldBlock` error if no frame is available.
+	pub fn get_ref(&self) -> &EthernetSocket {
+		self.inner.get_ref()
+	}
+
+	/// Receives a single Ethernet frame, in the same way as `EthernetSocket::recv`.
+	pub async fn recv(&self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+		self.read_with(|socket| socket.recv(buf)).await
+	}
+
+	/// Receives as many Ethernet frames as are available, in the same way as `EthernetSocket::recv_batch`.
+	pub async fn recv_batch<B: AsMut<[u8]>>(&self, bufs: &mut [B], infos: &mut Vec<RecvInfo>) -> std::io::Result<()> {
+		self.read_with(|socket| socket.recv_batch(bufs, infos)).await
+	}
+
+	/// Calls `read` each time the socket becomes readable, until it returns something other than `WouldBlock`.
+	///
+	/// The readiness is only cleared when `read` would block. A BPF device can return several frames from a single
+	/// read, which are returned by later calls without the device becoming readable again.
+	async fn read_with<T>(&self, mut read: impl FnMut(&EthernetSocket) -> std::io::Result<T>) -> std::io::Result<T> {
+		loop {
+			let mut guard = self.inner.readable().await?;
+			match guard.try_io(|inner| read(inner.get_ref())) {
+				Ok(Err(err)) if err.kind() == std::io::ErrorKind::Interrupted => {}
+				Ok(result) => return result,
+				Err(_would_block) => {}
+			}
+		}
+	}
+}
+
+fn set_nonblocking(socket: &EthernetSocket) -> std::io::Result<()> {
+	let flags = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_GETFL) };
+	// `fcntl` returns -1 on error, with the error code in `errno`.
+	if flags == -1 {
+		return Err(std::io::Error::last_os_error());
+	}
+	let result = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) };
+	if result == -1 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use std::{ffi::OsStr, time::Duration};
+
+	use super::*;
+	use crate::ethernet::MacAddress;
+
+	/// Sends an SV message on the loopback interface and checks that it is received without blocking the runtime.
+	#[test]
+	fn recv_loopback() {
+		let destination = MacAddress::from_bytes([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x07]);
+		let socket = match EthernetSocket::new(OsStr::new("lo"), destination) {
+			Ok(socket) => socket,
+			Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => return,
+			Err(err) => panic!("unable to create socket: {err}"),
+		};
+
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_all()
+			.build()
+			.unwrap();
+		runtime.block_on(async {
+			let socket = AsyncEthernetSocket::new(socket).unwrap();
+			socket.get_ref().send(destination, 0x4A5C, b"async payload").unwrap();
+
+			// Frames sent by other tests are skipped.
+			let mut buf = [0; 1522];
+			let receive = async {
+				loop {
+					let info = socket.recv(&mut buf).await.unwrap();
+					if buf[..info.length].starts_with(&[0x4A, 0x5C]) {
+						return info;
+					}
+				}
+			};
+			let info = tokio::time::timeout(Duration::from_secs(1), receive).await.unwrap();
+			assert!(info.length >= 21);
+			assert_eq!(&buf[8..21], b"async payload");
+		});
+	}
+}
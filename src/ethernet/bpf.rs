@@ -0,0 +1,417 @@
+use std::{
+	ffi::{OsStr, c_uint, c_ulong, c_void},
+	fs::{File, OpenOptions},
+	os::{
+		fd::{AsFd, AsRawFd, OwnedFd, RawFd},
+		unix::ffi::OsStrExt,
+	},
+	sync::Mutex,
+	time::Duration,
+};
+
+use super::{ETHERTYPE_SV, ETHERTYPE_VLAN, MacAddress, RecvInfo, SvFrame, split_vlan_tci, sv_payload, wait_readable};
+
+/// The alignment of each frame's header in the data read from a BPF device. Its type differs between platforms.
+#[allow(clippy::unnecessary_cast)]
+const BPF_ALIGNMENT: usize = libc::BPF_ALIGNMENT as usize;
+
+/// A BPF instruction, matching `struct bpf_insn`.
+#[repr(C)]
+struct BpfInsn {
+	code: u16,
+	jt: u8,
+	jf: u8,
+	k: u32,
+}
+
+/// A BPF program, matching `struct bpf_program`.
+#[repr(C)]
+struct BpfProgram {
+	bf_len: c_uint,
+	bf_insns: *const BpfInsn,
+}
+
+impl BpfInsn {
+	const fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+		Self { code, jt, jf, k }
+	}
+}
+
+// Instruction codes, from `net/bpf.h`.
+const BPF_LD_H_ABS: u16 = 0x28; // BPF_LD | BPF_H | BPF_ABS
+const BPF_JMP_JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+const BPF_RET_K: u16 = 0x06; // BPF_RET | BPF_K
+
+/// A filter program which accepts only frames with the SV EtherType, either untagged or with a single 802.1Q tag, so
+/// that other frames are discarded by the kernel. Jump offsets count the instructions to skip.
+const SV_FILTER: [BpfInsn; 7] = [
+	// Load the EtherType, and accept the frame if it is the SV EtherType.
+	BpfInsn::new(BPF_LD_H_ABS, 0, 0, 12),
+	BpfInsn::new(BPF_JMP_JEQ_K, 3, 0, ETHERTYPE_SV as u32),
+	// Otherwise, if the frame has a VLAN tag, load the EtherType following the tag and accept the frame if it is the SV
+	// EtherType.
+	BpfInsn::new(BPF_JMP_JEQ_K, 0, 3, ETHERTYPE_VLAN as u32),
+	BpfInsn::new(BPF_LD_H_ABS, 0, 0, 16),
+	BpfInsn::new(BPF_JMP_JEQ_K, 0, 1, ETHERTYPE_SV as u32),
+	// Accept the whole frame.
+	BpfInsn::new(BPF_RET_K, 0, 0, u32::MAX),
+	// Reject the frame.
+	BpfInsn::new(BPF_RET_K, 0, 0, 0),
+];
+
+/// The frames returned by the most recent `read` from a BPF device, of which those before `offset` have already been
+/// returned by `recv`.
+#[derive(Debug)]
+struct ReadBuffer {
+	data: Box<[u8]>,
+	offset: usize,
+	length: usize,
+}
+
+#[derive(Debug)]
+pub struct EthernetSocket {
+	fd: OwnedFd,
+	buffer: Mutex<ReadBuffer>,
+}
+
+impl EthernetSocket {
+	/// Creates a BPF device which receives Ethernet frames containing sampled value messages on the specified
+	/// interface.
+	///
+	/// The interface is put into promiscuous mode rather than joining the multicast group of `destination`, since
+	/// joining requires a separate socket. As on Linux, every frame with the SV EtherType is received.
+	pub fn new(interface: &OsStr, destination: MacAddress) -> std::io::Result<Self> {
+		let fd = OwnedFd::from(open_bpf_device()?);
+
+		// Attach the device to the interface. The name must leave room for a null terminator.
+		let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+		let name = interface.as_bytes();
+		if name.len() >= ifreq.ifr_name.len() {
+			return Err(std::io::ErrorKind::InvalidInput.into());
+		}
+		for (dest, &byte) in ifreq.ifr_name.iter_mut().zip(name) {
+			*dest = byte as libc::c_char;
+		}
+		ioctl(&fd, libc::BIOCSETIF, &raw mut ifreq)?;
+
+		// By default, a read only returns once the kernel's buffer is full or a timeout expires, which would delay
+		// frames. In immediate mode, a read returns as soon as a frame is available.
+		let mut immediate: c_uint = 1;
+		ioctl(&fd, libc::BIOCIMMEDIATE, &raw mut immediate)?;
+
+		let mut program = BpfProgram {
+			bf_len: SV_FILTER.len() as c_uint,
+			bf_insns: SV_FILTER.as_ptr(),
+		};
+		ioctl(&fd, libc::BIOCSETF, &raw mut program)?;
+
+		ioctl(&fd, libc::BIOCPROMISC as c_ulong, std::ptr::null_mut::<c_void>())?;
+		log::debug!("Receiving in promiscuous mode instead of joining multicast group {destination}.");
+
+		// Reads must use a buffer of exactly the kernel's buffer length.
+		let mut buffer_length: c_uint = 0;
+		ioctl(&fd, libc::BIOCGBLEN, &raw mut buffer_length)?;
+
+		Ok(Self {
+			fd,
+			buffer: Mutex::new(ReadBuffer {
+				data: vec![0; buffer_length as usize].into_boxed_slice(),
+				offset: 0,
+				length: 0,
+			}),
+		})
+	}
+
+	/// Makes the device report the VLAN tag of each frame received. A BPF device receives complete frames, so tags are
+	/// always reported and this does nothing.
+	pub fn receive_vlan_tags(&self) -> std::io::Result<()> {
+		Ok(())
+	}
+
+	/// Makes the device report hardware timestamps. BPF devices do not support hardware timestamping, so a warning is
+	/// logged and software timestamps are still used.
+	pub fn enable_hardware_timestamps(&mut self) -> std::io::Result<()> {
+		log::warn!("Hardware timestamps are not supported on this platform, so software timestamps will be used.");
+		Ok(())
+	}
+
+	/// Receives a single Ethernet frame. The frame's payload will be written to `buf`, while its length and timestamp
+	/// are returned in the `RecvInfo` structure.
+	///
+	/// A single read from a BPF device can return several frames, so frames are only read from the device once those
+	/// from the previous read have all been returned. This function will block until a frame is received.
+	pub fn recv(&self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+		Ok(self
+			.recv_with_timeout(buf, None)?
+			.expect("blocking receive returned without a frame"))
+	}
+
+	/// Receives a single Ethernet frame, as for `recv`, but waits no longer than `timeout` for the device to become
+	/// readable. Returns `None` if no frame was received, which also happens if the wait is interrupted by a signal.
+	pub fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> std::io::Result<Option<RecvInfo>> {
+		self.recv_with_timeout(buf, Some(timeout))
+	}
+
+	/// Receives a single Ethernet frame, waiting indefinitely if `timeout` is `None`.
+	fn recv_with_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> std::io::Result<Option<RecvInfo>> {
+		let mut buffer = self.buffer.lock().expect("BPF buffer mutex was poisoned");
+		loop {
+			if let Some(info) = buffer.next_sv_frame(buf) {
+				return Ok(Some(info));
+			}
+
+			if let Some(timeout) = timeout {
+				if !wait_readable(self.fd.as_fd(), timeout)? {
+					return Ok(None);
+				}
+			}
+
+			let ReadBuffer { data, offset, length } = &mut *buffer;
+			let result = unsafe { libc::read(self.fd.as_raw_fd(), data.as_mut_ptr() as *mut c_void, data.len()) };
+			// `read` returns -1 on error, with the error code in `errno`.
+			if result == -1 {
+				let err = std::io::Error::last_os_error();
+				if err.kind() != std::io::ErrorKind::Interrupted {
+					return Err(err);
+				}
+				// A blocking receive is retried, while one with a timeout returns so that its caller can check for
+				// shutdown.
+				if timeout.is_some() {
+					return Ok(None);
+				}
+				continue;
+			}
+			*offset = 0;
+			*length = result as usize;
+		}
+	}
+
+	/// Receives as many Ethernet frames as are available, up to the number of buffers in `bufs`. The payload of each
+	/// frame is written to the corresponding buffer, while `infos` is replaced with the `RecvInfo` of each frame
+	/// received.
+	///
+	/// Only the frames returned by a single read from the device are received. This function will block until at
+	/// least one frame is received.
+	pub fn recv_batch<B: AsMut<[u8]>>(&self, bufs: &mut [B], infos: &mut Vec<RecvInfo>) -> std::io::Result<()> {
+		self.recv_batch_with_timeout(bufs, infos, None)
+	}
+
+	/// Receives as many Ethernet frames as are available, as for `recv_batch`, but waits no longer than `timeout` for
+	/// the device to become readable. If no frame was received, which also happens if the wait is interrupted by a
+	/// signal, `infos` is left empty.
+	pub fn recv_batch_timeout<B: AsMut<[u8]>>(
+		&self,
+		bufs: &mut [B],
+		infos: &mut Vec<RecvInfo>,
+		timeout: Duration,
+	) -> std::io::Result<()> {
+		self.recv_batch_with_timeout(bufs, infos, Some(timeout))
+	}
+
+	/// Receives a batch of Ethernet frames, waiting indefinitely for the first if `timeout` is `None`.
+	fn recv_batch_with_timeout<B: AsMut<[u8]>>(
+		&self,
+		bufs: &mut [B],
+		infos: &mut Vec<RecvInfo>,
+		timeout: Option<Duration>,
+	) -> std::io::Result<()> {
+		infos.clear();
+		let Some((first, rest)) = bufs.split_first_mut() else {
+			return Ok(());
+		};
+		let Some(info) = self.recv_with_timeout(first.as_mut(), timeout)? else {
+			return Ok(());
+		};
+		infos.push(info);
+
+		let mut buffer = self.buffer.lock().expect("BPF buffer mutex was poisoned");
+		for buf in rest {
+			let Some(info) = buffer.next_sv_frame(buf.as_mut()) else {
+				break;
+			};
+			infos.push(info);
+		}
+		Ok(())
+	}
+
+	/// Sends an SV message with the given APPID to `dst_mac` on the device's interface. `payload` is the message's
+	/// APDU, which is preceded by the APPID and length header. The kernel fills in the source address of the Ethernet
+	/// header with the interface's address.
+	pub fn send(&self, dst_mac: MacAddress, appid: u16, payload: &[u8]) -> std::io::Result<()> {
+		let mut frame = Vec::with_capacity(14 + payload.len());
+		frame.extend(dst_mac.to_bytes());
+		frame.extend([0; 6]);
+		frame.extend(ETHERTYPE_SV.to_be_bytes());
+		frame.extend(sv_payload(appid, payload)?);
+
+		let result = unsafe { libc::write(self.fd.as_raw_fd(), frame.as_ptr() as *const c_void, frame.len()) };
+		// `write` returns -1 on error, with the error code in `errno`.
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
+
+impl AsRawFd for EthernetSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.as_raw_fd()
+	}
+}
+
+impl ReadBuffer {
+	/// Returns the next SV frame remaining from the previous read, writing its payload to `buf`. Returns `None` once
+	/// there are no more frames.
+	fn next_sv_frame(&mut self, buf: &mut [u8]) -> Option<RecvInfo> {
+		loop {
+			let (timestamp, frame) = next_frame(&self.data[..self.length], &mut self.offset)?;
+			let Some(SvFrame {
+				source_mac,
+				vlan_tci,
+				payload,
+			}) = SvFrame::parse(frame)
+			else {
+				continue;
+			};
+			// As with a Linux packet socket, frames which are too long for `buf` are skipped, since a truncated SV
+			// message cannot be parsed.
+			let (length, capacity) = (payload.len(), buf.len());
+			if length > capacity {
+				log::warn!("Skipped a {length} byte frame, which is longer than the {capacity} byte receive buffer.");
+				continue;
+			}
+			buf[..length].copy_from_slice(payload);
+			let (vlan_id, priority) = vlan_tci.map(split_vlan_tci).unzip();
+			return Some(RecvInfo {
+				length,
+				timestamp_s: timestamp.0,
+				timestamp_ns: timestamp.1,
+				hardware_timestamp: false,
+				source_mac: Some(source_mac),
+				vlan_id,
+				priority,
+			});
+		}
+	}
+}
+
+/// Opens the first available BPF device. FreeBSD and recent versions of macOS have a cloning device, while other
+/// versions of macOS have a fixed number of devices, of which one that is not in use must be found. The device is
+/// opened for writing as well as reading so that frames can be sent.
+fn open_bpf_device() -> std::io::Result<File> {
+	match OpenOptions::new().read(true).write(true).open("/dev/bpf") {
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+		result => return result,
+	}
+	for index in 0..256 {
+		match OpenOptions::new().read(true).write(true).open(format!("/dev/bpf{index}")) {
+			Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {}
+			result => return result,
+		}
+	}
+	Err(std::io::Error::from_raw_os_error(libc::EBUSY))
+}
+
+/// Performs an `ioctl` on a BPF device, with `arg` as its argument.
+fn ioctl<T>(fd: &OwnedFd, request: c_ulong, arg: *mut T) -> std::io::Result<()> {
+	let result = unsafe { libc::ioctl(fd.as_raw_fd(), request, arg) };
+	// `ioctl` returns -1 on error, with the error code in `errno`.
+	if result == -1 {
+		Err(std::io::Error::last_os_error())
+	} else {
+		Ok(())
+	}
+}
+
+/// Returns the next frame in the data returned by a read from a BPF device, starting at `offset`, along with its
+/// timestamp in seconds and nanoseconds. `offset` is advanced to the following frame. Returns `None` once there are no
+/// more frames.
+///
+/// Each frame is preceded by a `bpf_hdr` structure, and padded so that the next header is aligned to `BPF_ALIGNMENT`.
+fn next_frame<'a>(data: &'a [u8], offset: &mut usize) -> Option<((i64, u32), &'a [u8])> {
+	let header_bytes = data.get(*offset..*offset + size_of::<libc::bpf_hdr>())?;
+	// The header is aligned to `BPF_ALIGNMENT`, which may be less than the alignment of `bpf_hdr`.
+	let header = unsafe { header_bytes.as_ptr().cast::<libc::bpf_hdr>().read_unaligned() };
+
+	let start = *offset + header.bh_hdrlen as usize;
+	let end = start + header.bh_caplen as usize;
+	*offset = end.next_multiple_of(BPF_ALIGNMENT);
+
+	let timestamp = (header.bh_tstamp.tv_sec as i64, header.bh_tstamp.tv_usec as u32 * 1000);
+	// A frame extending past the end of the data means the data is corrupt, so the rest of it is discarded.
+	let Some(frame) = data.get(start..end) else {
+		*offset = data.len();
+		return None;
+	};
+	Some((timestamp, frame))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn bpf_data(frames: &[&[u8]]) -> Vec<u8> {
+		let header_length = size_of::<libc::bpf_hdr>();
+		let mut data = vec![];
+		for (i, frame) in frames.iter().enumerate() {
+			let mut header: libc::bpf_hdr = unsafe { std::mem::zeroed() };
+			header.bh_tstamp.tv_sec = (1000 + i) as _;
+			header.bh_tstamp.tv_usec = 250_000;
+			header.bh_caplen = frame.len() as u32;
+			header.bh_datalen = frame.len() as u32;
+			header.bh_hdrlen = header_length as u16;
+			let header_bytes = unsafe { std::slice::from_raw_parts((&raw const header).cast::<u8>(), header_length) };
+			data.extend(header_bytes);
+			data.extend(*frame);
+			data.resize(data.len().next_multiple_of(BPF_ALIGNMENT), 0);
+		}
+		data
+	}
+
+	#[test]
+	fn frames_in_read_buffer() {
+		let data = bpf_data(&[&[1; 61], &[2; 64]]);
+		let mut offset = 0;
+
+		let (timestamp, frame) = next_frame(&data, &mut offset).unwrap();
+		assert_eq!(timestamp, (1000, 250_000_000));
+		assert_eq!(frame, [1; 61]);
+
+		let (timestamp, frame) = next_frame(&data, &mut offset).unwrap();
+		assert_eq!(timestamp, (1001, 250_000_000));
+		assert_eq!(frame, [2; 64]);
+
+		assert_eq!(next_frame(&data, &mut offset), None);
+	}
+
+	#[test]
+	fn truncated_read_buffer() {
+		let data = bpf_data(&[&[1; 61]]);
+		let mut offset = 0;
+		assert_eq!(next_frame(&data[..data.len() - 8], &mut offset), None);
+		assert_eq!(offset, data.len() - 8);
+	}
+
+	#[test]
+	fn sv_frames_in_read_buffer() {
+		let mut sv_frame = vec![0x01, 0x0C, 0xCD, 0x04, 0x00, 0x01, 0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF];
+		sv_frame.extend(ETHERTYPE_SV.to_be_bytes());
+		sv_frame.extend(b"payload");
+		let data = bpf_data(&[&sv_frame, &[0; 64], &sv_frame]);
+		let mut buffer = ReadBuffer {
+			length: data.len(),
+			data: data.into_boxed_slice(),
+			offset: 0,
+		};
+
+		// Frames without the SV EtherType are skipped.
+		let mut buf = [0; 16];
+		let info = buffer.next_sv_frame(&mut buf).unwrap();
+		assert_eq!((info.length, info.timestamp_s), (7, 1000));
+		assert_eq!(&buf[..7], b"payload");
+		assert_eq!(info.source_mac, Some(MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF])));
+
+		// Payloads longer than the buffer are skipped rather than truncated.
+		assert!(buffer.next_sv_frame(&mut [0; 4]).is_none());
+	}
+}
@@ -0,0 +1,955 @@
+use std::{
+	ffi::{c_int, c_longlong, c_uint, c_ulong, c_ushort, c_void, CString, OsStr},
+	os::{
+		fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+		unix::ffi::OsStrExt,
+	},
+	time::Duration,
+};
+
+use super::{ETHERTYPE_SV, MacAddress, RecvInfo, split_vlan_tci, sv_payload, wait_readable};
+
+/// Obtains the index of the network interface with the given name.
+fn interface_name_to_index(name: &OsStr) -> std::io::Result<c_uint> {
+	// `if_nametoindex` expects a null terminated string.
+	let c_name = CString::new(name.as_bytes()).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+
+	let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+	// `if_nametoindex` returns 0 on error, with the error code in `errno`.
+	if index == 0 {
+		Err(std::io::Error::last_os_error())
+	} else {
+		Ok(index)
+	}
+}
+
+/// Logs that a frame of `length` bytes was skipped because it did not fit in a buffer of `capacity` bytes.
+fn log_truncated(length: usize, capacity: usize) {
+	log::warn!("Skipped a {length} byte frame, which is longer than the {capacity} byte receive buffer.");
+}
+
+/// Determines whether a receive error only means that no frame was available, either because the socket would have
+/// blocked or because a signal interrupted the call.
+fn is_retryable(err: &std::io::Error) -> bool {
+	matches!(
+		err.kind(),
+		std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+	)
+}
+
+/// Extracts the source MAC address from the address written by `recvmsg`, whose length is `address_length`. Returns
+/// `None` if the address is incomplete or is not a MAC address.
+fn source_mac_from_address(address: &libc::sockaddr_ll, address_length: libc::socklen_t) -> Option<MacAddress> {
+	// For Ethernet frames, the first 6 bytes of `sll_addr` contain the source MAC address.
+	let complete = address_length as usize >= size_of::<libc::sockaddr_ll>();
+	(complete && address.sll_halen == 6).then(|| MacAddress(std::array::from_fn(|i| address.sll_addr[i])))
+}
+
+/// Extracts the tag control information of the frame's VLAN tag from its auxiliary data. Returns `None` if the frame
+/// was not tagged.
+fn vlan_tci_from_auxdata(auxdata: &libc::tpacket_auxdata) -> Option<u16> {
+	// Kernels before 3.0 do not set `TP_STATUS_VLAN_VALID`, but a non-zero tag control information is still valid.
+	let valid = auxdata.tp_status & libc::TP_STATUS_VLAN_VALID != 0 || auxdata.tp_vlan_tci != 0;
+	valid.then_some(auxdata.tp_vlan_tci)
+}
+
+/// Binds a packet socket so that it only receives frames with the EtherType `protocol` on the specified interface.
+fn bind(socket: c_int, interface_index: c_uint, protocol: u16) -> std::io::Result<()> {
+	let address = libc::sockaddr_ll {
+		sll_family: libc::AF_PACKET as c_ushort, // Always `AF_PACKET`.
+		sll_protocol: protocol.to_be(), // The EtherType of frames to receive.
+		sll_ifindex: interface_index as c_int, // The numerical index of the interface to receive from.
+		// Remaining fields are not used for `bind`.
+		sll_hatype: 0,
+		sll_pkttype: 0,
+		sll_halen: 0,
+		sll_addr: [0; 8],
+	};
+
+	let result = unsafe {
+		libc::bind(
+			socket,
+			&address as *const libc::sockaddr_ll as *const libc::sockaddr,
+			size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+		)
+	};
+	// `bind` returns -1 on error, with the error code in `errno`.
+	if result == -1 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+// Instruction codes, from `linux/filter.h`.
+const BPF_LD_H_ABS: u16 = 0x28; // BPF_LD | BPF_H | BPF_ABS
+const BPF_JMP_JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+const BPF_RET_K: u16 = 0x06; // BPF_RET | BPF_K
+
+const fn sock_filter(code: u16, jt: u8, jf: u8, k: u32) -> libc::sock_filter {
+	libc::sock_filter { code, jt, jf, k }
+}
+
+/// A socket filter which accepts only received frames with the SV EtherType. Rather than loading from the frame, it
+/// loads the protocol and packet type determined by the kernel, which has already removed any VLAN tag. Jump offsets
+/// count the instructions to skip.
+const SV_SOCKET_FILTER: [libc::sock_filter; 6] = [
+	// Load the EtherType, and reject the frame unless it is the SV EtherType.
+	sock_filter(BPF_LD_H_ABS, 0, 0, (libc::SKF_AD_OFF + libc::SKF_AD_PROTOCOL) as u32),
+	sock_filter(BPF_JMP_JEQ_K, 0, 3, ETHERTYPE_SV as u32),
+	// Load the packet type, and reject frames sent from this host.
+	sock_filter(BPF_LD_H_ABS, 0, 0, (libc::SKF_AD_OFF + libc::SKF_AD_PKTTYPE) as u32),
+	sock_filter(BPF_JMP_JEQ_K, 1, 0, libc::PACKET_OUTGOING as u32),
+	// Accept the whole frame.
+	sock_filter(BPF_RET_K, 0, 0, u32::MAX),
+	// Reject the frame.
+	sock_filter(BPF_RET_K, 0, 0, 0),
+];
+
+/// The hardware timestamping configuration of a network interface. This matches Linux's `hwtstamp_config` type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HwTimestampConfig {
+	flags: c_int,
+	tx_type: c_int,
+	rx_filter: c_int,
+}
+
+#[derive(Debug)]
+pub struct EthernetSocket {
+	fd: OwnedFd,
+	interface_index: c_uint,
+	/// The interface's hardware timestamping configuration before `enable_hardware_timestamps` changed it, which is
+	/// restored when the socket is dropped.
+	previous_hwtstamp_config: Option<HwTimestampConfig>,
+}
+
+impl EthernetSocket {
+	/// Creates an Ethernet socket which receives Ethernet frames containing sampled value messages.
+	///
+	/// If `interface` is `None`, Ethernet frames will be received from all network interfaces. Otherwise, frames will
+	/// only be received on the specified interface.
+	pub fn new(interface: &OsStr, source_addr: MacAddress) -> std::io::Result<Self> {
+		// Create the socket.
+		// - `AF_PACKET` specifies that the socket is for receiving layer 2 frames (see the `packet(7)` man page).
+		// - For packet sockets, `SOCK_DGRAM` indicates that only the payload should be included. We use this type so
+		//   that we don't need to worry about handling VLAN tagging.
+		// - When a packet socket is created, it will receive frames on all network interfaces until it is bound to a
+		//   particular interface using `bind`. In the brief period of time between creating the socket and calling
+		//   `bind`, the socket can still receive frames from other interfaces. To prevent this, we specify 0 as the
+		//   protocol, meaning no frames will be received. The EtherType to filter for is instead passed to `bind`,
+		//   along with the network interface.
+		let socket = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, 0) };
+		// `socket` returns -1 on error, with the error code in `errno`.
+		if socket == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		// Get the numerical index of the network interface from its name.
+		let interface_index = interface_name_to_index(interface)?;
+
+		// Bind the socket such that we only receive frames on the specified interface.
+		bind(socket, interface_index, ETHERTYPE_SV)?;
+
+		// Enable the `SO_TIMESTAMPNS_NEW` socket option so that we get a timestamp with each frame received.
+		// This timestamp will be more accurate than simply checking the time after receiving a frame, since it does
+		// not include the time taken by the kernel to process the frame.
+		let optval = 1;
+		let result = unsafe {
+			libc::setsockopt(
+				socket,
+				libc::SOL_SOCKET,
+				libc::SO_TIMESTAMPNS_NEW,
+				&raw const optval as *const c_void,
+				size_of::<c_int>() as libc::socklen_t,
+			)
+		};
+		// `setsockopt` returns -1 on error, with the error code in `errno`.
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		// Enable the `PACKET_AUXDATA` socket option so that we get the frame's VLAN tag with each frame received. Since
+		// the socket has type `SOCK_DGRAM`, the tag is otherwise removed along with the rest of the header. Tags are
+		// only reported once `receive_vlan_tags` has been called.
+		let result = unsafe {
+			libc::setsockopt(
+				socket,
+				libc::SOL_PACKET,
+				libc::PACKET_AUXDATA,
+				&raw const optval as *const c_void,
+				size_of::<c_int>() as libc::socklen_t,
+			)
+		};
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		// Configure the network interface to receive frames with the specified multicast destination address.
+		let mreq = libc::packet_mreq {
+			mr_ifindex: interface_index as c_int,
+			mr_type: libc::PACKET_MR_MULTICAST as c_ushort,
+			mr_alen: 6,
+			mr_address: std::array::from_fn(|i| source_addr.to_bytes().get(i).cloned().unwrap_or(0)),
+		};
+
+		let result = unsafe {
+			libc::setsockopt(
+				socket,
+				libc::SOL_PACKET,
+				libc::PACKET_ADD_MEMBERSHIP,
+				&raw const mreq as *const c_void,
+				size_of::<libc::packet_mreq>() as libc::socklen_t,
+			)
+		};
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		Ok(Self {
+			fd: unsafe { OwnedFd::from_raw_fd(socket) },
+			interface_index,
+			previous_hwtstamp_config: None,
+		})
+	}
+
+	/// Makes the socket report the VLAN tag of each frame received, in the `vlan_id` and `priority` fields of
+	/// `RecvInfo`.
+	///
+	/// The kernel removes VLAN tags before passing frames to sockets bound to a particular EtherType, so the socket is
+	/// instead bound to receive every EtherType. A socket filter is attached first so that the kernel still discards
+	/// frames without the SV EtherType, along with frames sent from this host.
+	pub fn receive_vlan_tags(&self) -> std::io::Result<()> {
+		let program = libc::sock_fprog {
+			len: SV_SOCKET_FILTER.len() as c_ushort,
+			filter: SV_SOCKET_FILTER.as_ptr() as *mut libc::sock_filter,
+		};
+		let result = unsafe {
+			libc::setsockopt(
+				self.fd.as_raw_fd(),
+				libc::SOL_SOCKET,
+				libc::SO_ATTACH_FILTER,
+				&raw const program as *const c_void,
+				size_of::<libc::sock_fprog>() as libc::socklen_t,
+			)
+		};
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+
+		bind(self.fd.as_raw_fd(), self.interface_index, libc::ETH_P_ALL as u16)
+	}
+
+	/// Makes the socket report the timestamp taken by the network interface's hardware clock when each frame was
+	/// received, rather than the kernel's software timestamp. Frames without a hardware timestamp, such as those
+	/// received on an interface which does not support hardware timestamping, are still given a software timestamp.
+	///
+	/// The interface is first configured to timestamp every received frame, which requires the `CAP_NET_ADMIN`
+	/// capability. If this fails, a warning is logged and hardware timestamps are only reported if the interface has
+	/// already been configured by another program, such as `ptp4l`. The interface's previous configuration is restored
+	/// when the socket is dropped.
+	pub fn enable_hardware_timestamps(&mut self) -> std::io::Result<()> {
+		if let Err(err) = self.configure_hardware_timestamping() {
+			log::warn!("Unable to enable hardware timestamping on the interface: {err}");
+		}
+
+		let flags = libc::SOF_TIMESTAMPING_RX_HARDWARE | libc::SOF_TIMESTAMPING_RAW_HARDWARE;
+		let result = unsafe {
+			libc::setsockopt(
+				self.fd.as_raw_fd(),
+				libc::SOL_SOCKET,
+				libc::SO_TIMESTAMPING_NEW,
+				&raw const flags as *const c_void,
+				size_of::<c_uint>() as libc::socklen_t,
+			)
+		};
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+
+	/// Configures the network interface to timestamp all received frames using the `SIOCSHWTSTAMP` ioctl, first saving
+	/// its current configuration so that it can be restored. Transmit timestamping is left as it was, so that a PTP
+	/// daemon using the interface is not disturbed. Some older drivers cannot report their configuration, in which
+	/// case transmit timestamping is disabled and the configuration is not restored.
+	fn configure_hardware_timestamping(&mut self) -> std::io::Result<()> {
+		let mut config = HwTimestampConfig {
+			flags: 0,
+			tx_type: libc::HWTSTAMP_TX_OFF as c_int,
+			rx_filter: libc::HWTSTAMP_FILTER_ALL as c_int,
+		};
+		let previous = self
+			.hwtstamp_ioctl(libc::SIOCGHWTSTAMP, config)
+			.inspect_err(|err| log::debug!("Unable to read the interface's hardware timestamping configuration: {err}"))
+			.ok();
+		if let Some(previous) = previous {
+			config.tx_type = previous.tx_type;
+		}
+		let applied = self.hwtstamp_ioctl(libc::SIOCSHWTSTAMP, config)?;
+		if self.previous_hwtstamp_config.is_none() {
+			self.previous_hwtstamp_config = previous.filter(|&previous| previous != applied);
+		}
+		Ok(())
+	}
+
+	/// Gets (`SIOCGHWTSTAMP`) or sets (`SIOCSHWTSTAMP`) the network interface's hardware timestamping configuration.
+	/// Returns the configuration reported by the driver, which may differ from the one requested.
+	fn hwtstamp_ioctl(&self, request_code: c_ulong, config: HwTimestampConfig) -> std::io::Result<HwTimestampConfig> {
+		let mut config = libc::hwtstamp_config {
+			flags: config.flags,
+			tx_type: config.tx_type,
+			rx_filter: config.rx_filter,
+		};
+		let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+		// `if_indextoname` writes the null terminated name into a buffer of `IF_NAMESIZE` bytes.
+		if unsafe { libc::if_indextoname(self.interface_index, request.ifr_name.as_mut_ptr()) }.is_null() {
+			return Err(std::io::Error::last_os_error());
+		}
+		request.ifr_ifru.ifru_data = &raw mut config as *mut libc::c_char;
+
+		let result = unsafe { libc::ioctl(self.fd.as_raw_fd(), request_code, &raw mut request) };
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(HwTimestampConfig {
+			flags: config.flags,
+			tx_type: config.tx_type,
+			rx_filter: config.rx_filter,
+		})
+	}
+
+	/// Receives a single Ethernet frame on the socket. The frame's payload will be written to `buf`, while its length,
+	/// timestamp and VLAN tag are returned in the `RecvInfo` structure.
+	///
+	/// This function will block until a frame is received.
+	pub fn recv(&self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+		self.recv_with_flags(buf, 0)
+	}
+
+	/// Receives a single Ethernet frame on the socket, as for `recv`, but waits no longer than `timeout` for it to
+	/// arrive. Returns `None` if no frame was received, which also happens if the wait is interrupted by a signal.
+	pub fn recv_timeout(&self, buf: &mut [u8], timeout: Duration) -> std::io::Result<Option<RecvInfo>> {
+		if !wait_readable(self.fd.as_fd(), timeout)? {
+			return Ok(None);
+		}
+		match self.recv_with_flags(buf, libc::MSG_DONTWAIT) {
+			Ok(info) => Ok(Some(info)),
+			Err(err) if is_retryable(&err) => Ok(None),
+			Err(err) => Err(err),
+		}
+	}
+
+	/// Receives a single Ethernet frame using `recvmsg` with the given flags. The call is retried if it is interrupted
+	/// by a signal. Frames which are too long for `buf` are skipped, since a truncated SV message cannot be parsed.
+	fn recv_with_flags(&self, buf: &mut [u8], flags: c_int) -> std::io::Result<RecvInfo> {
+		let capacity = buf.len();
+		loop {
+			let mut message = Message::new(buf);
+			let mut msg = message.msghdr();
+
+			// With `MSG_TRUNC`, the full length of a frame is returned even if it did not fit in the buffer.
+			let length = unsafe { libc::recvmsg(self.fd.as_raw_fd(), &raw mut msg, flags | libc::MSG_TRUNC) };
+			// `recvmsg` returns -1 on error, with the error code in `errno`.
+			if length == -1 {
+				let err = std::io::Error::last_os_error();
+				if err.kind() == std::io::ErrorKind::Interrupted {
+					continue;
+				}
+				return Err(err);
+			}
+
+			if msg.msg_flags & libc::MSG_TRUNC != 0 {
+				log_truncated(length as usize, capacity);
+				continue;
+			}
+			return Ok(message.recv_info(&msg, length as usize));
+		}
+	}
+
+	/// Receives as many Ethernet frames as are available on the socket, up to the number of buffers in `bufs`, using
+	/// a single system call. The payload of each frame is written to the corresponding buffer, while `infos` is
+	/// replaced with the `RecvInfo` of each frame received.
+	///
+	/// This function will block until at least one frame is received.
+	pub fn recv_batch<B: AsMut<[u8]>>(&self, bufs: &mut [B], infos: &mut Vec<RecvInfo>) -> std::io::Result<()> {
+		self.recv_batch_with_flags(bufs, infos, 0)
+	}
+
+	/// Receives as many Ethernet frames as are available on the socket, as for `recv_batch`, but waits no longer than
+	/// `timeout` for the first to arrive. If no frame was received, which also happens if the wait is interrupted by a
+	/// signal, `infos` is left empty.
+	pub fn recv_batch_timeout<B: AsMut<[u8]>>(
+		&self,
+		bufs: &mut [B],
+		infos: &mut Vec<RecvInfo>,
+		timeout: Duration,
+	) -> std::io::Result<()> {
+		infos.clear();
+		if !wait_readable(self.fd.as_fd(), timeout)? {
+			return Ok(());
+		}
+		match self.recv_batch_with_flags(bufs, infos, libc::MSG_DONTWAIT) {
+			Err(err) if is_retryable(&err) => Ok(()),
+			result => result,
+		}
+	}
+
+	/// Receives a batch of Ethernet frames using `recvmmsg` with the given flags, in addition to `MSG_WAITFORONE`. As
+	/// for `recv_with_flags`, the call is retried if it is interrupted, and frames which are too long for their buffer
+	/// are skipped. The buffers are reordered so that those of the frames received come first.
+	fn recv_batch_with_flags<B: AsMut<[u8]>>(
+		&self,
+		bufs: &mut [B],
+		infos: &mut Vec<RecvInfo>,
+		flags: c_int,
+	) -> std::io::Result<()> {
+		infos.clear();
+		loop {
+			let received = self.recv_mmsg(bufs, flags)?;
+			for (index, info) in received.into_iter().enumerate() {
+				if let Some(info) = info {
+					bufs.swap(infos.len(), index);
+					infos.push(info);
+				}
+			}
+			// Without blocking, there may be no frames to receive in place of those skipped.
+			if !infos.is_empty() || flags & libc::MSG_DONTWAIT != 0 {
+				return Ok(());
+			}
+		}
+	}
+
+	/// Calls `recvmmsg` once, returning the `RecvInfo` of each frame received, or `None` for each frame which was too
+	/// long for its buffer.
+	fn recv_mmsg<B: AsMut<[u8]>>(&self, bufs: &mut [B], flags: c_int) -> std::io::Result<Vec<Option<RecvInfo>>> {
+		// The messages must not move once their headers have been created, since the headers point into them.
+		let mut messages: Vec<Message> = bufs.iter_mut().map(|buf| Message::new(buf.as_mut())).collect();
+		let mut msgs: Vec<libc::mmsghdr> = messages
+			.iter_mut()
+			.map(|message| libc::mmsghdr {
+				msg_hdr: message.msghdr(),
+				msg_len: 0,
+			})
+			.collect();
+
+		// With `MSG_WAITFORONE`, `recvmmsg` blocks until a frame is received, then returns any others which are
+		// already available without waiting for the rest of the buffers to be filled.
+		let count = loop {
+			let count = unsafe {
+				libc::recvmmsg(
+					self.fd.as_raw_fd(),
+					msgs.as_mut_ptr(),
+					msgs.len() as c_uint,
+					libc::MSG_WAITFORONE | libc::MSG_TRUNC | flags,
+					std::ptr::null_mut(),
+				)
+			};
+			// `recvmmsg` returns -1 on error, with the error code in `errno`.
+			if count != -1 {
+				break count as usize;
+			}
+			let err = std::io::Error::last_os_error();
+			if err.kind() != std::io::ErrorKind::Interrupted {
+				return Err(err);
+			}
+		};
+
+		let received = messages
+			.iter()
+			.zip(&msgs)
+			.take(count)
+			.map(|(message, msg)| {
+				let length = msg.msg_len as usize;
+				if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+					log_truncated(length, message.iov.iov_len);
+					return None;
+				}
+				Some(message.recv_info(&msg.msg_hdr, length))
+			})
+			.collect();
+		Ok(received)
+	}
+
+	/// Sends an SV message with the given APPID to `dst_mac` on the socket's interface. `payload` is the message's
+	/// APDU, which is preceded by the APPID and length header. The kernel adds the Ethernet header, with the
+	/// interface's address as the source address.
+	pub fn send(&self, dst_mac: MacAddress, appid: u16, payload: &[u8]) -> std::io::Result<()> {
+		let payload = sv_payload(appid, payload)?;
+
+		let mut address = libc::sockaddr_ll {
+			sll_family: libc::AF_PACKET as c_ushort,
+			sll_protocol: ETHERTYPE_SV.to_be(),
+			sll_ifindex: self.interface_index as c_int,
+			sll_hatype: 0,
+			sll_pkttype: 0,
+			sll_halen: 6,
+			sll_addr: [0; 8],
+		};
+		address.sll_addr[..6].copy_from_slice(&dst_mac.to_bytes());
+
+		let result = unsafe {
+			libc::sendto(
+				self.fd.as_raw_fd(),
+				payload.as_ptr() as *const c_void,
+				payload.len(),
+				0,
+				&raw const address as *const libc::sockaddr,
+				size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+			)
+		};
+		// `sendto` returns -1 on error, with the error code in `errno`.
+		if result == -1 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
+
+impl Drop for EthernetSocket {
+	fn drop(&mut self) {
+		if let Some(previous) = self.previous_hwtstamp_config {
+			if let Err(err) = self.hwtstamp_ioctl(libc::SIOCSHWTSTAMP, previous) {
+				log::warn!("Unable to restore the interface's hardware timestamping configuration: {err}");
+			}
+		}
+	}
+}
+
+impl AsRawFd for EthernetSocket {
+	fn as_raw_fd(&self) -> RawFd {
+		self.fd.as_raw_fd()
+	}
+}
+
+/// This matches Linux's `__kernel_timespec` type, which uses 64 bit fields even on 32 bit systems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct KernelTimespec {
+	tv_sec: c_longlong,
+	tv_nsec: c_longlong,
+}
+
+/// This matches Linux's `scm_timestamping64` type, which holds the timestamps reported by `SO_TIMESTAMPING_NEW`. The
+/// first is a software timestamp, the second is unused, and the third is the raw hardware timestamp. Timestamps which
+/// are not reported are zero.
+#[repr(C)]
+struct ScmTimestamping {
+	ts: [KernelTimespec; 3],
+}
+
+impl ScmTimestamping {
+	/// Returns the raw hardware timestamp, if there is one.
+	fn hardware(&self) -> Option<KernelTimespec> {
+		let timestamp = self.ts[2];
+		(timestamp.tv_sec != 0 || timestamp.tv_nsec != 0).then_some(timestamp)
+	}
+}
+
+// Timestamps and VLAN tags are received as control messages (also known as ancillary data), which requires a separate
+// buffer. This buffer must have enough space for all three control messages and some additional metadata; the total
+// size is calculated using `CMSG_SPACE`.
+const CMSG_BUFFER_LENGTH: usize = unsafe {
+	libc::CMSG_SPACE(size_of::<KernelTimespec>() as u32)
+		+ libc::CMSG_SPACE(size_of::<ScmTimestamping>() as u32)
+		+ libc::CMSG_SPACE(size_of::<libc::tpacket_auxdata>() as u32)
+} as usize;
+
+/// The control message buffer must have the same alignment as the `cmsghdr` type. A struct is used to control its
+/// alignment.
+#[repr(C)]
+struct CMsgBuffer {
+	// Since the struct uses the C representation, the first member is guaranteed to be at offset 0, meaning it has the
+	// same alignment as the struct.
+	buffer: [u8; CMSG_BUFFER_LENGTH],
+	// A zero-sized array does not affect the size of the containing struct, but does affect its alignment. Since a
+	// struct has the same alignment as its most aligned member, this guarantees that it will have an alignment at least
+	// as large as `cmsghdr`.
+	_align: [libc::cmsghdr; 0],
+}
+
+/// The buffers that `recvmsg` writes a single frame and its metadata into.
+struct Message {
+	// The `recvmsg` function is able to write data into several non-contiguous buffers. Since we don't need this
+	// feature, we can just specifiy a single buffer.
+	iov: libc::iovec,
+	// The address of the sender is written here, allowing us to get the frame's source MAC address.
+	source_address: libc::sockaddr_ll,
+	cmsg_buffer: CMsgBuffer,
+}
+
+impl Message {
+	fn new(buf: &mut [u8]) -> Self {
+		Self {
+			iov: libc::iovec {
+				iov_base: buf.as_mut_ptr() as *mut c_void,
+				iov_len: buf.len(),
+			},
+			source_address: unsafe { std::mem::zeroed() },
+			cmsg_buffer: CMsgBuffer {
+				buffer: [0; CMSG_BUFFER_LENGTH],
+				_align: [],
+			},
+		}
+	}
+
+	/// Creates the header passed to `recvmsg`, which points into this message.
+	fn msghdr(&mut self) -> libc::msghdr {
+		libc::msghdr {
+			msg_name: &raw mut self.source_address as *mut c_void,
+			msg_namelen: size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+			msg_iov: &raw mut self.iov,
+			msg_iovlen: 1,
+			msg_control: self.cmsg_buffer.buffer.as_mut_ptr() as *mut c_void,
+			msg_controllen: self.cmsg_buffer.buffer.len(),
+			msg_flags: 0,
+		}
+	}
+
+	/// Extracts the metadata of a frame of `length` bytes, once `msg` has been filled in by `recvmsg`.
+	fn recv_info(&self, msg: &libc::msghdr, length: usize) -> RecvInfo {
+		let source_mac = source_mac_from_address(&self.source_address, msg.msg_namelen);
+		if let Some(source_mac) = source_mac {
+			log::trace!("Received {length} byte frame from {source_mac}.");
+		}
+
+		// Iterate through all received control messages to get the ones containing the timestamps and VLAN tag.
+		let mut timestamp = None;
+		let mut hardware_timestamp = None;
+		let mut vlan_tci = None;
+		let mut cmsg: *const libc::cmsghdr = unsafe { libc::CMSG_FIRSTHDR(msg) };
+		while !cmsg.is_null() {
+			let cmsg_hdr = unsafe { &*cmsg };
+
+			// The pointer to the control message data is not guaranteed to be aligned.
+			if cmsg_hdr.cmsg_level == libc::SOL_SOCKET && cmsg_hdr.cmsg_type == libc::SO_TIMESTAMPNS_NEW {
+				let timestamp_ptr = unsafe { libc::CMSG_DATA(cmsg) } as *const KernelTimespec;
+				timestamp = Some(unsafe { timestamp_ptr.read_unaligned() });
+			} else if cmsg_hdr.cmsg_level == libc::SOL_SOCKET && cmsg_hdr.cmsg_type == libc::SO_TIMESTAMPING_NEW {
+				let timestamping_ptr = unsafe { libc::CMSG_DATA(cmsg) } as *const ScmTimestamping;
+				let timestamping = unsafe { timestamping_ptr.read_unaligned() };
+				hardware_timestamp = timestamping.hardware();
+			} else if cmsg_hdr.cmsg_level == libc::SOL_PACKET && cmsg_hdr.cmsg_type == libc::PACKET_AUXDATA {
+				let auxdata_ptr = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::tpacket_auxdata;
+				vlan_tci = vlan_tci_from_auxdata(&unsafe { auxdata_ptr.read_unaligned() });
+			}
+
+			cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) };
+		}
+
+		// The software timestamp is used for frames without a hardware timestamp.
+		let hardware = hardware_timestamp.is_some();
+		let timestamp = hardware_timestamp.or(timestamp).expect("did not receive timestamp control message");
+		let (vlan_id, priority) = vlan_tci.map(split_vlan_tci).unzip();
+		RecvInfo {
+			length,
+			timestamp_s: timestamp.tv_sec,
+			timestamp_ns: timestamp.tv_nsec as u32,
+			hardware_timestamp: hardware,
+			source_mac,
+			vlan_id,
+			priority,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn source_mac_from_address_valid() {
+		let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+		address.sll_halen = 6;
+		address.sll_addr = [0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E, 0x00, 0x00];
+
+		let length = size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+		let source_mac = source_mac_from_address(&address, length);
+		assert_eq!(source_mac, Some(MacAddress([0x00, 0x1A, 0x2B, 0x3C, 0x4D, 0x5E])));
+	}
+
+	#[test]
+	fn source_mac_from_address_invalid() {
+		let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+		address.sll_halen = 6;
+		assert_eq!(source_mac_from_address(&address, 4), None);
+
+		address.sll_halen = 0;
+		let length = size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+		assert_eq!(source_mac_from_address(&address, length), None);
+	}
+
+	#[test]
+	fn vlan_tci_from_auxdata_valid() {
+		let mut auxdata: libc::tpacket_auxdata = unsafe { std::mem::zeroed() };
+		assert_eq!(vlan_tci_from_auxdata(&auxdata), None);
+
+		// VLAN 0 with priority 0 is only distinguishable from an untagged frame by the status flag.
+		auxdata.tp_status = libc::TP_STATUS_VLAN_VALID;
+		assert_eq!(vlan_tci_from_auxdata(&auxdata), Some(0));
+
+		auxdata.tp_status = 0;
+		auxdata.tp_vlan_tci = 0x8064;
+		assert_eq!(vlan_tci_from_auxdata(&auxdata).map(split_vlan_tci), Some((100, 4)));
+	}
+
+	#[test]
+	fn scm_timestamping_hardware() {
+		let software = KernelTimespec { tv_sec: 1700000000, tv_nsec: 5 };
+		let zero = KernelTimespec { tv_sec: 0, tv_nsec: 0 };
+		let timestamping = ScmTimestamping { ts: [software, zero, zero] };
+		assert_eq!(timestamping.hardware(), None);
+
+		let hardware = KernelTimespec { tv_sec: 1700000000, tv_nsec: 0 };
+		let timestamping = ScmTimestamping { ts: [zero, zero, hardware] };
+		assert_eq!(timestamping.hardware(), Some(hardware));
+	}
+
+	/// Creates a socket receiving on the loopback interface, which times out rather than blocking forever. This
+	/// requires permission to create packet sockets, so returns `None` if that permission is missing.
+	fn loopback_socket(destination: MacAddress) -> Option<EthernetSocket> {
+		let recv_socket = match EthernetSocket::new(OsStr::new("lo"), destination) {
+			Ok(socket) => socket,
+			Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => return None,
+			Err(err) => panic!("unable to create socket: {err}"),
+		};
+
+		// Avoid blocking forever if the frame is never received.
+		let timeout = libc::timeval { tv_sec: 1, tv_usec: 0 };
+		let result = unsafe {
+			libc::setsockopt(
+				recv_socket.fd.as_raw_fd(),
+				libc::SOL_SOCKET,
+				libc::SO_RCVTIMEO,
+				&raw const timeout as *const c_void,
+				size_of::<libc::timeval>() as libc::socklen_t,
+			)
+		};
+		assert_eq!(result, 0);
+		Some(recv_socket)
+	}
+
+	/// Sends a complete Ethernet frame on the loopback interface.
+	fn send_on_loopback(frame: &[u8]) {
+		let send_socket = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+		assert_ne!(send_socket, -1);
+		let send_socket = unsafe { OwnedFd::from_raw_fd(send_socket) };
+
+		let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+		address.sll_family = libc::AF_PACKET as c_ushort;
+		address.sll_ifindex = interface_name_to_index(OsStr::new("lo")).unwrap() as c_int;
+		let result = unsafe {
+			libc::sendto(
+				send_socket.as_raw_fd(),
+				frame.as_ptr() as *const c_void,
+				frame.len(),
+				0,
+				&raw const address as *const libc::sockaddr,
+				size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+			)
+		};
+		assert_eq!(result, frame.len() as isize);
+	}
+
+	/// Creates an SV frame, optionally with a VLAN tag.
+	fn sv_frame(destination: MacAddress, source: MacAddress, vlan_tci: Option<u16>, payload: &[u8]) -> Vec<u8> {
+		let mut frame = Vec::new();
+		frame.extend(destination.to_bytes());
+		frame.extend(source.to_bytes());
+		if let Some(vlan_tci) = vlan_tci {
+			frame.extend(super::super::ETHERTYPE_VLAN.to_be_bytes());
+			frame.extend(vlan_tci.to_be_bytes());
+		}
+		frame.extend(ETHERTYPE_SV.to_be_bytes());
+		frame.extend(payload);
+		frame
+	}
+
+	/// Receives frames until one starting with `prefix` is received. Since the tests may run at once, frames sent by
+	/// other tests are skipped.
+	fn recv_with_prefix(socket: &EthernetSocket, buf: &mut [u8], prefix: &[u8]) -> RecvInfo {
+		loop {
+			let info = socket.recv(buf).unwrap();
+			if buf[..info.length].starts_with(prefix) {
+				return info;
+			}
+		}
+	}
+
+	/// Sends an SV frame on the loopback interface and checks that it is received along with its source address.
+	#[test]
+	fn recv_loopback_source_mac() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x01]);
+		let source = MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF]);
+		let Some(recv_socket) = loopback_socket(destination) else {
+			return;
+		};
+
+		send_on_loopback(&sv_frame(destination, source, None, b"payload"));
+
+		let mut buf = [0; 1522];
+		let info = recv_with_prefix(&recv_socket, &mut buf, b"payload");
+		assert_eq!(&buf[..info.length], b"payload");
+		assert_eq!(info.source_mac, Some(source));
+		assert_eq!((info.vlan_id, info.priority), (None, None));
+	}
+
+	/// Sends a VLAN tagged SV frame on the loopback interface and checks that its tag is received, although the kernel
+	/// removes it from the payload.
+	#[test]
+	fn recv_loopback_vlan() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x02]);
+		let source = MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF]);
+		let Some(recv_socket) = loopback_socket(destination) else {
+			return;
+		};
+		recv_socket.receive_vlan_tags().unwrap();
+
+		send_on_loopback(&sv_frame(destination, source, Some(0x8064), b"tagged payload"));
+
+		let mut buf = [0; 1522];
+		let info = recv_with_prefix(&recv_socket, &mut buf, b"tagged payload");
+		assert_eq!(&buf[..info.length], b"tagged payload");
+		assert_eq!((info.vlan_id, info.priority), (Some(100), Some(4)));
+	}
+
+	/// Enables hardware timestamps on the loopback interface, which does not support them, and checks that frames are
+	/// still received with a software timestamp.
+	#[test]
+	fn recv_loopback_hardware_timestamp_fallback() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x05]);
+		let source = MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF]);
+		let Some(mut recv_socket) = loopback_socket(destination) else {
+			return;
+		};
+		recv_socket.enable_hardware_timestamps().unwrap();
+
+		send_on_loopback(&sv_frame(destination, source, None, b"timestamped payload"));
+
+		let mut buf = [0; 1522];
+		let info = recv_with_prefix(&recv_socket, &mut buf, b"timestamped payload");
+		assert!(!info.hardware_timestamp);
+		assert!(info.timestamp_s > 0);
+	}
+
+	/// Sends an SV frame on the loopback interface and checks that it is received by `recv_timeout`.
+	#[test]
+	fn recv_timeout_loopback() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x06]);
+		let source = MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF]);
+		let Some(recv_socket) = loopback_socket(destination) else {
+			return;
+		};
+
+		send_on_loopback(&sv_frame(destination, source, None, b"timeout payload"));
+
+		let mut buf = [0; 1522];
+		let deadline = std::time::Instant::now() + Duration::from_secs(1);
+		let info = loop {
+			assert!(std::time::Instant::now() < deadline, "frame was not received");
+			if let Some(info) = recv_socket.recv_timeout(&mut buf, Duration::from_millis(100)).unwrap() {
+				if buf[..info.length].starts_with(b"timeout payload") {
+					break info;
+				}
+			}
+		};
+		assert_eq!(info.source_mac, Some(source));
+	}
+
+	/// Checks that a frame which is too long for the buffer is skipped, in both a single and a batch receive.
+	#[test]
+	fn recv_loopback_skips_long_frames() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x07]);
+		let source = MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF]);
+		let Some(recv_socket) = loopback_socket(destination) else {
+			return;
+		};
+
+		send_on_loopback(&sv_frame(destination, source, None, &[0xAA; 64]));
+		send_on_loopback(&sv_frame(destination, source, None, b"short"));
+		let mut buf = [0; 32];
+		let info = recv_socket.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..info.length], b"short");
+
+		send_on_loopback(&sv_frame(destination, source, None, b"first"));
+		send_on_loopback(&sv_frame(destination, source, None, &[0xAA; 64]));
+		send_on_loopback(&sv_frame(destination, source, None, b"third"));
+		let mut bufs = [[0; 32]; 4];
+		let mut infos = Vec::new();
+		let mut received = Vec::new();
+		while received.len() < 2 {
+			recv_socket.recv_batch(&mut bufs, &mut infos).unwrap();
+			// The buffers of the frames received come first.
+			received.extend(infos.iter().zip(&bufs).map(|(info, buf)| buf[..info.length].to_vec()));
+		}
+		assert_eq!(received, [b"first", b"third"]);
+	}
+
+	/// Sends several SV frames on the loopback interface and checks that they are received in batches.
+	#[test]
+	fn recv_batch_loopback() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x03]);
+		let source = MacAddress([0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF]);
+		let Some(recv_socket) = loopback_socket(destination) else {
+			return;
+		};
+
+		for i in 0..3 {
+			send_on_loopback(&sv_frame(destination, source, None, &[b"batch".as_slice(), &[i]].concat()));
+		}
+
+		let mut bufs = [[0; 1522]; 4];
+		let mut infos = Vec::new();
+		let mut received = Vec::new();
+		while received.len() < 3 {
+			recv_socket.recv_batch(&mut bufs, &mut infos).unwrap();
+			assert!(!infos.is_empty() && infos.len() <= 4);
+			for (info, buf) in infos.iter().zip(&bufs) {
+				if buf[..info.length].starts_with(b"batch") {
+					assert_eq!(info.source_mac, Some(source));
+					assert!(info.timestamp_s > 0);
+					received.push(buf[..info.length].to_vec());
+				}
+			}
+		}
+		assert_eq!(received, [b"batch\x00", b"batch\x01", b"batch\x02"]);
+	}
+
+	/// Sends an SV message on the loopback interface and checks that it is received and can be parsed.
+	#[test]
+	fn send_loopback() {
+		let destination = MacAddress([0x01, 0x0C, 0xCD, 0x04, 0x00, 0x04]);
+		let Some(socket) = loopback_socket(destination) else {
+			return;
+		};
+
+		let message = crate::SvMessage {
+			appid: 0x4123,
+			length: 0,
+			simulated: false,
+			reserved: [0; 2],
+			asdus: vec![crate::Asdu {
+				svid: "SEND".into(),
+				datset: None,
+				smp_cnt: 17,
+				conf_rev: 1,
+				refr_tm: None,
+				smp_synch: 2,
+				smp_rate: None,
+				sample: crate::Sample {
+					current_a: 1.5,
+					..Default::default()
+				},
+				smp_mod: None,
+				gm_identity: None,
+			}],
+		};
+		let encoded = crate::encode_sv_message(&message);
+		socket.send(destination, message.appid, &encoded[8..]).unwrap();
+
+		let mut buf = [0; 1522];
+		let info = recv_with_prefix(&socket, &mut buf, &[0x41, 0x23]);
+		assert_eq!(&buf[..encoded.len()], encoded);
+		let parsed = crate::parse(&buf[..info.length]).unwrap();
+		assert_eq!(parsed.asdus, message.asdus);
+		assert!(parsed.padding(&buf[..info.length]).iter().all(|&byte| byte == 0));
+	}
+}
@@ -1,51 +1,148 @@
-mod ber;
-mod bytes;
+pub mod ber;
+pub mod bytes;
+pub mod capture;
+pub mod clock;
 pub mod config;
+pub mod discovery;
 pub mod ethernet;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod output;
+pub mod pcap;
 pub mod sample_buffer;
+pub mod source;
+pub mod stats;
+mod xml;
+
+use std::borrow::Cow;
 
 use ber::{Encoding, Tag};
 use bytes::BytesReader;
+use config::{SampleFormat, ValueBounds};
+use serde::Serialize;
 
 pub use ber::DecodeError;
 
-fn read_iec61850_int8u(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<u8, DecodeError> {
-	if let &[b_0] = ber::read_octet_string(reader, encoding)? {
-		Ok(b_0)
-	} else {
-		// TODO: Specific error type.
-		Err(DecodeError::InvalidIntegerEncoding)
-	}
+fn read_iec61850_int8u(
+	reader: &mut BytesReader<'_>,
+	encoding: Encoding,
+	options: &ParseOptions,
+) -> Result<u8, DecodeError> {
+	read_iec61850_octets(reader, encoding, options).map(u8::from_be_bytes)
+}
+
+fn read_iec61850_int16u(
+	reader: &mut BytesReader<'_>,
+	encoding: Encoding,
+	options: &ParseOptions,
+) -> Result<u16, DecodeError> {
+	read_iec61850_octets(reader, encoding, options).map(u16::from_be_bytes)
 }
 
-fn read_iec61850_int16u(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<u16, DecodeError> {
-	if let &[b_0, b_1] = ber::read_octet_string(reader, encoding)? {
-		Ok(u16::from_be_bytes([b_0, b_1]))
+fn read_iec61850_int32u(
+	reader: &mut BytesReader<'_>,
+	encoding: Encoding,
+	options: &ParseOptions,
+) -> Result<u32, DecodeError> {
+	read_iec61850_octets(reader, encoding, options).map(u32::from_be_bytes)
+}
+
+/// Reads an OCTET STRING, which may be in the constructed form if `options` allows it.
+fn read_iec61850_octet_string<'b>(
+	reader: &mut BytesReader<'b>,
+	encoding: Encoding,
+	options: &ParseOptions,
+) -> Result<Cow<'b, [u8]>, DecodeError> {
+	if options.constructed_strings {
+		ber::read_octet_string_owned(reader, encoding).map(Cow::Owned)
 	} else {
-		// TODO: Specific error type.
-		Err(DecodeError::InvalidIntegerEncoding)
+		ber::read_octet_string(reader, encoding).map(Cow::Borrowed)
 	}
 }
 
-fn read_iec61850_int32u(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<u32, DecodeError> {
-	if let &[b_0, b_1, b_2, b_3] = ber::read_octet_string(reader, encoding)? {
-		Ok(u32::from_be_bytes([b_0, b_1, b_2, b_3]))
-	} else {
-		// TODO: Specific error type.
-		Err(DecodeError::InvalidIntegerEncoding)
+fn read_iec61850_octets<const N: usize>(
+	reader: &mut BytesReader<'_>,
+	encoding: Encoding,
+	options: &ParseOptions,
+) -> Result<[u8; N], DecodeError> {
+	let bytes = read_iec61850_octet_string(reader, encoding, options)?;
+	bytes[..].try_into().map_err(|_| DecodeError::UnexpectedLength {
+		expected: N,
+		actual: bytes.len(),
+	})
+}
+
+fn read_iec61850_utctime(
+	reader: &mut BytesReader<'_>,
+	encoding: Encoding,
+	options: &ParseOptions,
+) -> Result<UtcTime, DecodeError> {
+	read_iec61850_octets(reader, encoding, options).map(UtcTime::from_octets)
+}
+
+/// An IEC 61850 UtcTime, as used for the refrTm field of an ASDU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UtcTime {
+	/// The number of whole seconds since the Unix epoch, excluding leap seconds.
+	pub seconds: u32,
+	/// The fraction of a second, in units of 2^-24 seconds.
+	pub fraction: u32,
+	pub quality: TimeQuality,
+}
+
+impl UtcTime {
+	/// Decodes a UtcTime from its eight octets: four for the seconds, three for the fraction and one for the quality.
+	pub fn from_octets(octets: [u8; 8]) -> Self {
+		let [b_0, b_1, b_2, b_3, b_4, b_5, b_6, b_7] = octets;
+		Self {
+			seconds: u32::from_be_bytes([b_0, b_1, b_2, b_3]),
+			fraction: u32::from_be_bytes([0, b_4, b_5, b_6]),
+			quality: TimeQuality::from_octet(b_7),
+		}
+	}
+
+	pub fn to_octets(&self) -> [u8; 8] {
+		let [b_0, b_1, b_2, b_3] = self.seconds.to_be_bytes();
+		let [_, b_4, b_5, b_6] = self.fraction.to_be_bytes();
+		[b_0, b_1, b_2, b_3, b_4, b_5, b_6, self.quality.to_octet()]
+	}
+
+	/// Converts the fraction of a second to nanoseconds, rounded to the nearest nanosecond.
+	pub fn nanoseconds(&self) -> u32 {
+		((u64::from(self.fraction) * 1_000_000_000 + (1 << 23)) >> 24) as u32
 	}
 }
 
-fn read_iec61850_utctime(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<u64, DecodeError> {
-	if let &[b_0, b_1, b_2, b_3, b_4, b_5, b_6, b_7] = ber::read_octet_string(reader, encoding)? {
-		Ok(u64::from_be_bytes([b_0, b_1, b_2, b_3, b_4, b_5, b_6, b_7]))
-	} else {
-		// TODO: Specific error type.
-		Err(DecodeError::InvalidIntegerEncoding)
+/// The quality of the clock which produced a UtcTime, as given by its final octet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TimeQuality {
+	pub leap_second_known: bool,
+	pub clock_failure: bool,
+	pub clock_not_synchronised: bool,
+	/// The number of significant bits in the fraction of a second. Values from 25 to 30 are invalid, and 31 means that
+	/// the accuracy is unspecified.
+	pub accuracy: u8,
+}
+
+impl TimeQuality {
+	pub fn from_octet(octet: u8) -> Self {
+		Self {
+			leap_second_known: octet & 0x80 != 0,
+			clock_failure: octet & 0x40 != 0,
+			clock_not_synchronised: octet & 0x20 != 0,
+			accuracy: octet & 0x1F,
+		}
+	}
+
+	pub fn to_octet(&self) -> u8 {
+		u8::from(self.leap_second_known) << 7
+			| u8::from(self.clock_failure) << 6
+			| u8::from(self.clock_not_synchronised) << 5
+			| self.accuracy & 0x1F
 	}
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct Sample {
 	pub current_a: f32,
 	pub current_b: f32,
@@ -55,96 +152,383 @@ pub struct Sample {
 	pub voltage_b: f32,
 	pub voltage_c: f32,
 	pub voltage_n: f32,
+	/// The quality word following each value, in the same order as the values.
+	pub quality: [Quality; 8],
 }
 
 impl Sample {
-	fn read(reader: &mut BytesReader<'_>, encoding: Encoding) -> Result<Self, DecodeError> {
-		let bytes = ber::read_octet_string(reader, encoding)?;
+	/// The value of one count of a current, in amperes, as specified by 9-2LE.
+	const CURRENT_SCALE: f64 = 0.001;
+	/// The value of one count of a voltage, in volts, as specified by 9-2LE.
+	const VOLTAGE_SCALE: f64 = 0.01;
+
+	/// The names of the channels, in the order of `as_array` and of the sample field. The first four channels are
+	/// currents and the rest are voltages, as specified by 9-2LE; input channels in the configuration are indices into
+	/// this order.
+	pub const CHANNEL_NAMES: [&'static str; 8] = ["Ia", "Ib", "Ic", "In", "Va", "Vb", "Vc", "Vn"];
+
+	/// Creates a sample from its values, in the order of `CHANNEL_NAMES`, and their quality words.
+	pub fn from_array(values: [f32; 8], quality: [Quality; 8]) -> Self {
+		let [current_a, current_b, current_c, current_n, voltage_a, voltage_b, voltage_c, voltage_n] = values;
+		Self {
+			current_a,
+			current_b,
+			current_c,
+			current_n,
+			voltage_a,
+			voltage_b,
+			voltage_c,
+			voltage_n,
+			quality,
+		}
+	}
+
+	/// Returns the values of the sample, in the order of `CHANNEL_NAMES`.
+	pub fn as_array(&self) -> [f32; 8] {
+		[
+			self.current_a,
+			self.current_b,
+			self.current_c,
+			self.current_n,
+			self.voltage_a,
+			self.voltage_b,
+			self.voltage_c,
+			self.voltage_n,
+		]
+	}
+
+	/// Returns the value of one count of the channel with the specified index, in amperes or volts.
+	fn count_scale(channel: usize) -> f64 {
+		if channel < 4 {
+			Self::CURRENT_SCALE
+		} else {
+			Self::VOLTAGE_SCALE
+		}
+	}
+
+	fn read(reader: &mut BytesReader<'_>, encoding: Encoding, options: &ParseOptions) -> Result<Self, DecodeError> {
+		let bytes = read_iec61850_octet_string(reader, encoding, options)?;
 		if bytes.len() != 64 {
-			// TODO: Specific error type.
-			return Err(DecodeError::InvalidIntegerEncoding);
-		}
-
-		let mut values_iter = bytes
-			.chunks_exact(8)
-			.map(|chunk| i32::from_be_bytes(chunk[0..4].try_into().unwrap()) as f64);
-
-		let current_scale = 0.001;
-		let voltage_scale = 0.01;
-
-		Ok(Self {
-			current_a: (values_iter.next().unwrap() * current_scale) as f32,
-			current_b: (values_iter.next().unwrap() * current_scale) as f32,
-			current_c: (values_iter.next().unwrap() * current_scale) as f32,
-			current_n: (values_iter.next().unwrap() * current_scale) as f32,
-			voltage_a: (values_iter.next().unwrap() * voltage_scale) as f32,
-			voltage_b: (values_iter.next().unwrap() * voltage_scale) as f32,
-			voltage_c: (values_iter.next().unwrap() * voltage_scale) as f32,
-			voltage_n: (values_iter.next().unwrap() * voltage_scale) as f32,
-		})
+			return Err(DecodeError::UnexpectedLength {
+				expected: 64,
+				actual: bytes.len(),
+			});
+		}
+
+		// Each channel is a 4-byte value followed by a 4-byte quality word, as in the 9-2LE profile.
+		let mut channels = BytesReader::new(&bytes);
+		let mut values = [0.0; 8];
+		let mut quality = [Quality::default(); 8];
+		for (channel, (value, quality)) in values.iter_mut().zip(&mut quality).enumerate() {
+			let count = options.sample_format.decode(channels.read_bytes(4)?.try_into().unwrap());
+			// Only floating point values can be NaN or infinite. Such a value would make its channel's range infinite
+			// in the output, so the sample is rejected.
+			if !count.is_finite() {
+				return Err(DecodeError::NonFiniteValue);
+			}
+			*value = (count * Self::count_scale(channel)) as f32;
+			*quality = Quality(channels.read_u32_be()?);
+		}
+		Ok(Self::from_array(values, quality))
+	}
+
+	/// Returns whether every value in the sample has good validity.
+	pub fn is_good(&self) -> bool {
+		self.quality.iter().all(|quality| quality.validity() == Validity::Good)
+	}
+
+	/// Encodes the sample as the 64-byte contents of the sample field, rounding each value to the nearest count. Values
+	/// are always encoded as 32-bit integers, as specified by 9-2LE.
+	fn to_bytes(&self) -> [u8; 64] {
+		let counts = (self.as_array().into_iter().enumerate())
+			.map(|(channel, value)| (f64::from(value) / Self::count_scale(channel)).round() as i32);
+
+		let mut bytes = [0; 64];
+		for ((chunk, count), quality) in bytes.chunks_exact_mut(8).zip(counts).zip(self.quality) {
+			chunk[0..4].copy_from_slice(&count.to_be_bytes());
+			chunk[4..8].copy_from_slice(&quality.0.to_be_bytes());
+		}
+		bytes
+	}
+
+	/// Clamps each value in the sample to the bounds for its channel type, if any. Returns the number of values which
+	/// were out of bounds.
+	pub fn clamp_to_bounds(&mut self, current: Option<ValueBounds>, voltage: Option<ValueBounds>) -> usize {
+		let mut values = self.as_array();
+		let mut out_of_bounds = 0;
+		for (channel, value) in values.iter_mut().enumerate() {
+			let bounds = if channel < 4 { current } else { voltage };
+			if let Some(bounds) = bounds.filter(|bounds| !bounds.contains(*value)) {
+				*value = value.clamp(bounds.min, bounds.max);
+				out_of_bounds += 1;
+			}
+		}
+		*self = Self::from_array(values, self.quality);
+		out_of_bounds
+	}
+}
+
+/// The quality attribute of a sampled value, encoded as in IEC 61850-9-2LE. Bits are numbered from the least
+/// significant bit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Quality(pub u32);
+
+impl Quality {
+	pub const OVERFLOW: u32 = 1 << 2;
+	pub const OUT_OF_RANGE: u32 = 1 << 3;
+	pub const BAD_REFERENCE: u32 = 1 << 4;
+	pub const OSCILLATORY: u32 = 1 << 5;
+	pub const FAILURE: u32 = 1 << 6;
+	pub const OLD_DATA: u32 = 1 << 7;
+	pub const INCONSISTENT: u32 = 1 << 8;
+	pub const INACCURATE: u32 = 1 << 9;
+	/// Set if the value has been substituted, rather than coming from the process.
+	pub const SUBSTITUTED: u32 = 1 << 10;
+	pub const TEST: u32 = 1 << 11;
+	pub const OPERATOR_BLOCKED: u32 = 1 << 12;
+	/// Set if the value has been calculated from other values, such as a neutral current derived from the phases.
+	pub const DERIVED: u32 = 1 << 13;
+
+	pub fn validity(self) -> Validity {
+		match self.0 & 0b11 {
+			0b00 => Validity::Good,
+			0b01 => Validity::Invalid,
+			0b10 => Validity::Reserved,
+			_ => Validity::Questionable,
+		}
 	}
+
+	/// Returns whether all of the specified flags (a combination of the associated constants) are set.
+	pub fn contains(self, flags: u32) -> bool {
+		self.0 & flags == flags
+	}
+}
+
+/// The validity field of a `Quality`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validity {
+	Good,
+	Invalid,
+	Reserved,
+	Questionable,
 }
 
-#[derive(Debug, Clone)]
-pub struct Asdu {
-	pub svid: String,
-	pub datset: Option<String>,
+/// An ASDU of an SV message. Its svID and datSet are `String`s, except in an `AsduRef`, where they borrow from the
+/// bytes the ASDU was parsed from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Asdu<S = String> {
+	pub svid: S,
+	pub datset: Option<S>,
 	pub smp_cnt: u16,
 	pub conf_rev: u32,
-	pub refr_tm: Option<u64>,
+	pub refr_tm: Option<UtcTime>,
 	pub smp_synch: u8,
 	pub smp_rate: Option<u16>,
 	pub sample: Sample,
 	pub smp_mod: Option<u16>,
+	pub gm_identity: Option<[u8; 8]>,
+}
+
+impl<S> Asdu<S> {
+	/// Returns the sample rate in Hz described by the smpRate and smpMod fields, or `None` if smpRate is absent or
+	/// smpMod has a reserved value. `nominal_frequency` is needed to interpret rates given per nominal period.
+	pub fn effective_sample_rate(&self, nominal_frequency: u32) -> Option<f64> {
+		let smp_rate = f64::from(self.smp_rate?);
+		let mode = match self.smp_mod {
+			Some(smp_mod) => SampleMode::from_smp_mod(smp_mod)?,
+			None => SampleMode::SamplesPerPeriod,
+		};
+		Some(match mode {
+			SampleMode::SamplesPerPeriod => smp_rate * f64::from(nominal_frequency),
+			SampleMode::SamplesPerSecond => smp_rate,
+			SampleMode::SecondsPerSample => 1.0 / smp_rate,
+		})
+	}
+}
+
+/// An ASDU whose svID and datSet borrow from the bytes it was parsed from, as returned by `parse_borrowed`. This avoids
+/// allocating the strings for each ASDU received.
+pub type AsduRef<'a> = Asdu<&'a str>;
+
+impl AsduRef<'_> {
+	/// Copies the strings of this ASDU, so that it no longer borrows from the parsed bytes.
+	pub fn into_owned(self) -> Asdu {
+		Asdu {
+			svid: self.svid.to_owned(),
+			datset: self.datset.map(str::to_owned),
+			smp_cnt: self.smp_cnt,
+			conf_rev: self.conf_rev,
+			refr_tm: self.refr_tm,
+			smp_synch: self.smp_synch,
+			smp_rate: self.smp_rate,
+			sample: self.sample,
+			smp_mod: self.smp_mod,
+			gm_identity: self.gm_identity,
+		}
+	}
 }
 
-fn read_asdu(reader: &mut BytesReader<'_>) -> Result<Asdu, DecodeError> {
+/// The unit of an ASDU's smpRate field, as given by its smpMod field. IEC 61850-9-2 specifies that samples per nominal
+/// period are used if smpMod is absent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleMode {
+	SamplesPerPeriod,
+	SamplesPerSecond,
+	SecondsPerSample,
+}
+
+impl SampleMode {
+	/// Returns the mode for the given smpMod value, or `None` if the value is reserved.
+	pub fn from_smp_mod(smp_mod: u16) -> Option<Self> {
+		match smp_mod {
+			0 => Some(Self::SamplesPerPeriod),
+			1 => Some(Self::SamplesPerSecond),
+			2 => Some(Self::SecondsPerSample),
+			_ => None,
+		}
+	}
+}
+
+/// Options controlling how strictly sampled value messages are decoded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+	/// Whether to reject ASDUs containing fields after the last field defined by IEC 61850-9-2. If this is `false`,
+	/// such fields are skipped.
+	pub strict_asdu_fields: bool,
+	/// Whether to accept OCTET STRING fields in the constructed form, in which the string is split into segments. These
+	/// are reassembled into a copy of the string, so this is slower than the usual primitive form.
+	pub constructed_strings: bool,
+	/// Whether to reject lengths in the long form which could have been encoded in fewer bytes, as DER requires. Some
+	/// IEDs send such lengths, so they are accepted by default.
+	pub minimal_lengths: bool,
+	/// How the values in the sample field are encoded.
+	pub sample_format: SampleFormat,
+	/// Whether to reject messages whose APPID is outside `SV_APPID_RANGE`, which are usually misrouted GOOSE messages
+	/// or garbage.
+	pub validate_appid: bool,
+}
+
+/// The range of APPIDs reserved for sampled values by IEC 61850-8-1.
+pub const SV_APPID_RANGE: std::ops::RangeInclusive<u16> = 0x4000..=0x7FFF;
+
+/// Reads a length, rejecting non-minimal encodings if `options` requires it.
+fn read_length(reader: &mut BytesReader<'_>, options: &ParseOptions) -> Result<usize, DecodeError> {
+	if options.minimal_lengths {
+		ber::read_minimal_length(reader)
+	} else {
+		ber::read_length(reader)
+	}
+}
+
+/// Reads a field's value using `read`, first checking that its length is minimal if `options` requires it.
+fn read_field_value<'b, T>(
+	reader: &mut BytesReader<'b>,
+	encoding: Encoding,
+	options: &ParseOptions,
+	read: impl FnOnce(&mut BytesReader<'b>, Encoding) -> Result<T, DecodeError>,
+) -> Result<T, DecodeError> {
+	if options.minimal_lengths {
+		ber::read_minimal_length(&mut reader.clone())?;
+	}
+	read(reader, encoding)
+}
+
+/// Reads a field with the specified tag, using `read` to read its value. The offset of the field is attached to any
+/// error.
+fn read_required_field<'b, T>(
+	reader: &mut BytesReader<'b>,
+	tag: Tag,
+	options: &ParseOptions,
+	read: impl FnOnce(&mut BytesReader<'b>, Encoding) -> Result<T, DecodeError>,
+) -> Result<T, DecodeError> {
+	let offset = reader.position();
+	ber::read_required_identifier(reader, tag)
+		.and_then(|encoding| read_field_value(reader, encoding, options, read))
+		.map_err(|err| err.at(offset))
+}
+
+/// Reads a field with the specified tag if it is present, using `read` to read its value. The offset of the field is
+/// attached to any error.
+fn read_optional_field<'b, T>(
+	reader: &mut BytesReader<'b>,
+	tag: Tag,
+	options: &ParseOptions,
+	read: impl FnOnce(&mut BytesReader<'b>, Encoding) -> Result<T, DecodeError>,
+) -> Result<Option<T>, DecodeError> {
+	let offset = reader.position();
+	ber::read_optional_identifier(reader, tag)
+		.and_then(|encoding| {
+			encoding
+				.map(|encoding| read_field_value(reader, encoding, options, read))
+				.transpose()
+		})
+		.map_err(|err| err.at(offset))
+}
+
+fn read_asdu<'b>(reader: &mut BytesReader<'b>, options: &ParseOptions) -> Result<AsduRef<'b>, DecodeError> {
 	// svID [0] IMPLICIT VisibleString
-	let svid = ber::read_required_identifier(reader, Tag::ContextSpecific(0))
-		.and_then(|encoding| ber::read_visiblestring(reader, encoding))?;
+	let svid = read_required_field(reader, Tag::ContextSpecific(0), options, ber::read_visiblestring)?;
 
 	// datset [1] IMPLICIT VisibleString OPTIONAL
-	let datset = ber::read_optional_identifier(reader, Tag::ContextSpecific(1))?
-		.map(|encoding| ber::read_visiblestring(reader, encoding))
-		.transpose()?;
+	let datset = read_optional_field(reader, Tag::ContextSpecific(1), options, ber::read_visiblestring)?;
 
 	// smpCnt [2] IMPLICIT OCTET STRING (SIZE(2))
-	let smp_cnt = ber::read_required_identifier(reader, Tag::ContextSpecific(2))
-		.and_then(|encoding| read_iec61850_int16u(reader, encoding))?;
+	let smp_cnt = read_required_field(reader, Tag::ContextSpecific(2), options, |reader, encoding| {
+		read_iec61850_int16u(reader, encoding, options)
+	})?;
 
 	// confRev [3] IMPLICIT OCTET STRING (SIZE(4))
-	let conf_rev = ber::read_required_identifier(reader, Tag::ContextSpecific(3))
-		.and_then(|encoding| read_iec61850_int32u(reader, encoding))?;
+	let conf_rev = read_required_field(reader, Tag::ContextSpecific(3), options, |reader, encoding| {
+		read_iec61850_int32u(reader, encoding, options)
+	})?;
 
 	// refrTm [4] IMPLICIT UtcTime OPTIONAL
 	// (This is not the universal ASN.1 UTCTime type, but the IEC 61850 UtcTime type)
-	let refr_tm = ber::read_optional_identifier(reader, Tag::ContextSpecific(4))?
-		.map(|encoding| read_iec61850_utctime(reader, encoding))
-		.transpose()?;
+	let refr_tm = read_optional_field(reader, Tag::ContextSpecific(4), options, |reader, encoding| {
+		read_iec61850_utctime(reader, encoding, options)
+	})?;
 
 	// smpSynch [5] IMPLICIT OCTET STRING (SIZE(1))
-	let smp_synch = ber::read_required_identifier(reader, Tag::ContextSpecific(5))
-		.and_then(|encoding| read_iec61850_int8u(reader, encoding))?;
+	let smp_synch = read_required_field(reader, Tag::ContextSpecific(5), options, |reader, encoding| {
+		read_iec61850_int8u(reader, encoding, options)
+	})?;
 
 	// smpRate [6] IMPLICIT OCTET STRING (SIZE(2)) OPTIONAL
-	let smp_rate = ber::read_optional_identifier(reader, Tag::ContextSpecific(6))?
-		.map(|encoding| read_iec61850_int16u(reader, encoding))
-		.transpose()?;
+	let smp_rate = read_optional_field(reader, Tag::ContextSpecific(6), options, |reader, encoding| {
+		read_iec61850_int16u(reader, encoding, options)
+	})?;
 
 	// sample [7] IMPLICIT OCTET STRING (SIZE(n))
-	let sample = ber::read_required_identifier(reader, Tag::ContextSpecific(7))
-		.and_then(|encoding| Sample::read(reader, encoding))?;
+	let sample = read_required_field(reader, Tag::ContextSpecific(7), options, |reader, encoding| {
+		Sample::read(reader, encoding, options)
+	})?;
 
 	// smpMod [8] IMPLICIT OCTET STRING (SIZE(2)) OPTIONAL
-	let smp_mod = ber::read_optional_identifier(reader, Tag::ContextSpecific(8))?
-		.map(|encoding| read_iec61850_int16u(reader, encoding))
-		.transpose()?;
+	let smp_mod = read_optional_field(reader, Tag::ContextSpecific(8), options, |reader, encoding| {
+		read_iec61850_int16u(reader, encoding, options)
+	})?;
 
-	// TODO: gmIdentity [9] IMPLICIT OCTET STRING (SIZE(8)) OPTIONAL
+	// gmIdentity [9] IMPLICIT OCTET STRING (SIZE(8)) OPTIONAL
+	let gm_identity = read_optional_field(reader, Tag::ContextSpecific(9), options, |reader, encoding| {
+		read_iec61850_octets(reader, encoding, options)
+	})?;
 
-	Ok(Asdu {
-		svid: svid.into(),
-		datset: datset.map(Into::into),
+	// Any remaining fields are not defined by the standard, but may be added by future editions or by vendors.
+	while !reader.is_empty() {
+		let offset = reader.position();
+		if options.strict_asdu_fields {
+			return Err(DecodeError::UnknownAsduField.at(offset));
+		}
+
+		let identifier = ber::read_identifier(reader).map_err(|err| err.at(offset))?;
+		let length = read_length(reader, options).map_err(|err| err.at(offset))?;
+		reader.skip(length).map_err(|err| DecodeError::from(err).at(offset))?;
+		log::debug!("Skipped unknown ASDU field with tag {:?} ({length} bytes).", identifier.tag);
+	}
+
+	Ok(AsduRef {
+		svid,
+		datset,
 		smp_cnt,
 		conf_rev,
 		refr_tm,
@@ -152,62 +536,901 @@ fn read_asdu(reader: &mut BytesReader<'_>) -> Result<Asdu, DecodeError> {
 		sample,
 		smp_rate,
 		smp_mod,
+		gm_identity,
 	})
 }
 
-fn read_savpdu(reader: &mut BytesReader<'_>) -> Result<Vec<Asdu>, DecodeError> {
+fn write_asdu(buf: &mut Vec<u8>, asdu: &Asdu) {
+	let mut contents = vec![];
+	ber::write_octet_string(&mut contents, Tag::ContextSpecific(0), asdu.svid.as_bytes());
+	if let Some(datset) = &asdu.datset {
+		ber::write_octet_string(&mut contents, Tag::ContextSpecific(1), datset.as_bytes());
+	}
+	ber::write_octet_string(&mut contents, Tag::ContextSpecific(2), &asdu.smp_cnt.to_be_bytes());
+	ber::write_octet_string(&mut contents, Tag::ContextSpecific(3), &asdu.conf_rev.to_be_bytes());
+	if let Some(refr_tm) = &asdu.refr_tm {
+		ber::write_octet_string(&mut contents, Tag::ContextSpecific(4), &refr_tm.to_octets());
+	}
+	ber::write_octet_string(&mut contents, Tag::ContextSpecific(5), &[asdu.smp_synch]);
+	if let Some(smp_rate) = asdu.smp_rate {
+		ber::write_octet_string(&mut contents, Tag::ContextSpecific(6), &smp_rate.to_be_bytes());
+	}
+	ber::write_octet_string(&mut contents, Tag::ContextSpecific(7), &asdu.sample.to_bytes());
+	if let Some(smp_mod) = asdu.smp_mod {
+		ber::write_octet_string(&mut contents, Tag::ContextSpecific(8), &smp_mod.to_be_bytes());
+	}
+	if let Some(gm_identity) = &asdu.gm_identity {
+		ber::write_octet_string(&mut contents, Tag::ContextSpecific(9), gm_identity);
+	}
+	ber::write_constructed(buf, Tag::Universal(16), &contents);
+}
+
+fn read_savpdu<'b>(reader: &mut BytesReader<'b>, options: &ParseOptions) -> Result<Vec<AsduRef<'b>>, DecodeError> {
 	// noASDU [0] IMPLICIT INTEGER (1..65535)
-	let encoding = ber::read_required_identifier(reader, Tag::ContextSpecific(0))?;
-	let no_asdu = ber::read_integer_as_u16(reader, encoding)?;
+	let offset = reader.position();
+	let no_asdu = read_required_field(reader, Tag::ContextSpecific(0), options, ber::read_integer_as_u16)?;
 
 	if no_asdu == 0 {
-		return Err(DecodeError::TagOutOfRange);
+		return Err(DecodeError::TagOutOfRange.at(offset));
 	}
 
 	// security [1] ANY OPTIONAL
-	if ber::read_optional_identifier(reader, Tag::ContextSpecific(1))?.is_some() {
+	read_optional_field(reader, Tag::ContextSpecific(1), options, |reader, _| {
 		let length = ber::read_length(reader)?;
-		reader.skip(length)?;
-	}
+		reader.skip(length).map_err(Into::into)
+	})?;
 
 	// asdu [2] IMPLICIT SEQUENCE OF ASDU
-	let _ = ber::read_required_identifier(reader, Tag::ContextSpecific(2))?;
-	let length = ber::read_length(reader)?;
-	let mut inner_reader = reader.take_sub_reader(length)?;
-
-	(0..no_asdu)
-		.map(|_| {
-			let _ = ber::read_required_identifier(&mut inner_reader, Tag::Universal(16))?;
-			let length = ber::read_length(&mut inner_reader)?;
-			read_asdu(&mut inner_reader.take_sub_reader(length)?)
-		})
-		.collect::<Result<Vec<_>, _>>()
+	let mut inner_reader = read_required_field(reader, Tag::ContextSpecific(2), options, |reader, _| {
+		let length = ber::read_length(reader)?;
+		reader.take_sub_reader(length).map_err(Into::into)
+	})?;
+
+	let mut asdus = Vec::with_capacity(no_asdu.into());
+	for _ in 0..no_asdu {
+		if inner_reader.is_empty() {
+			let error = DecodeError::AsduCountMismatch {
+				declared: no_asdu,
+				found: asdus.len(),
+			};
+			return Err(error.at(inner_reader.position()));
+		}
+		asdus.push(read_required_field(&mut inner_reader, Tag::Universal(16), options, |reader, _| {
+			let length = ber::read_length(reader)?;
+			read_asdu(&mut reader.take_sub_reader(length)?, options)
+		})?);
+	}
+
+	if !inner_reader.is_empty() {
+		let offset = inner_reader.position();
+		let error = DecodeError::AsduCountMismatch {
+			declared: no_asdu,
+			found: asdus.len() + count_elements(&mut inner_reader),
+		};
+		return Err(error.at(offset));
+	}
+	Ok(asdus)
 }
 
-#[derive(Debug, Clone)]
-pub struct SvMessage {
+/// Counts the elements remaining in `reader`, without decoding them. Any trailing bytes which do not form a complete
+/// element are counted as one more.
+fn count_elements(reader: &mut BytesReader<'_>) -> usize {
+	let mut count = 0;
+	while !reader.is_empty() {
+		count += 1;
+		let skipped = ber::read_identifier(reader)
+			.and_then(|_| ber::read_length(reader))
+			.and_then(|length| reader.skip(length).map_err(Into::into));
+		if skipped.is_err() {
+			break;
+		}
+	}
+	count
+}
+
+/// An SV message. Its ASDUs' strings are `String`s, except in an `SvMessageRef`, where they borrow from the bytes the
+/// message was parsed from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SvMessage<S = String> {
 	pub appid: u16,
-	pub asdus: Vec<Asdu>,
+	/// The number of bytes occupied by the SV message (including the header), as given by its length field. Any bytes
+	/// in the frame's payload beyond this point are padding.
+	pub length: usize,
+	/// Whether the simulation bit (the most significant bit of the first reserved field) is set, indicating that the
+	/// message was sent by test equipment rather than a merging unit.
+	pub simulated: bool,
+	/// The reserved fields, with the simulation bit cleared. These are normally zero.
+	pub reserved: [u16; 2],
+	pub asdus: Vec<Asdu<S>>,
+}
+
+/// An SV message whose ASDUs borrow their strings from the bytes it was parsed from, as returned by `parse_borrowed`.
+pub type SvMessageRef<'a> = SvMessage<&'a str>;
+
+impl SvMessageRef<'_> {
+	/// Copies the strings of every ASDU, so that the message no longer borrows from the parsed bytes.
+	pub fn into_owned(self) -> SvMessage {
+		SvMessage {
+			appid: self.appid,
+			length: self.length,
+			simulated: self.simulated,
+			reserved: self.reserved,
+			asdus: self.asdus.into_iter().map(AsduRef::into_owned).collect(),
+		}
+	}
+}
+
+impl<S> SvMessage<S> {
+	/// Returns the bytes following the SV message in `bytes`, which should be the same slice passed to `parse`.
+	///
+	/// Ethernet frames have a minimum size, so short frames are padded after the SV message. This padding is normally
+	/// zero; anything else suggests that the frame is malformed.
+	pub fn padding<'b>(&self, bytes: &'b [u8]) -> &'b [u8] {
+		bytes.get(self.length..).unwrap_or_default()
+	}
+
+	/// Returns the difference in smpCnt between each pair of consecutive ASDUs, allowing for the counter resetting to
+	/// zero after `period` samples. These should all be the same; anything else indicates jitter or missing samples in
+	/// the merging unit.
+	pub fn smp_cnt_spacing(&self, period: u32) -> Vec<u32> {
+		self.asdus
+			.windows(2)
+			.map(|pair| (u32::from(pair[1].smp_cnt) + period - u32::from(pair[0].smp_cnt) % period) % period)
+			.collect()
+	}
+}
+
+/// Encodes an SV message, without any padding, in a form accepted by `parse`. The `length` field of `message` is
+/// ignored, and the actual length of the encoding is written instead. Sample values are rounded to the resolution of
+/// the 9-2LE encoding.
+///
+/// # Panics
+///
+/// Panics if `message` has no ASDUs, or if the encoding is longer than the 65535 bytes allowed by the length field.
+pub fn encode_sv_message(message: &SvMessage) -> Vec<u8> {
+	assert!(!message.asdus.is_empty(), "an SV message must contain at least one ASDU");
+
+	let mut asdus = vec![];
+	for asdu in &message.asdus {
+		write_asdu(&mut asdus, asdu);
+	}
+
+	let mut savpdu = vec![];
+	let no_asdu = u16::try_from(message.asdus.len()).expect("too many ASDUs");
+	ber::write_integer(&mut savpdu, Tag::ContextSpecific(0), no_asdu.into());
+	ber::write_constructed(&mut savpdu, Tag::ContextSpecific(2), &asdus);
+
+	let mut apdu = vec![];
+	ber::write_constructed(&mut apdu, Tag::Application(0), &savpdu);
+
+	let mut header = sv_header(message.appid, apdu.len()).expect("SV message is too long");
+	let reserved_1 = message.reserved[0] | if message.simulated { SIMULATION_BIT } else { 0 };
+	header[4..6].copy_from_slice(&reserved_1.to_be_bytes());
+	header[6..8].copy_from_slice(&message.reserved[1].to_be_bytes());
+	let mut bytes = Vec::with_capacity(header.len() + apdu.len());
+	bytes.extend(header);
+	bytes.extend(apdu);
+	bytes
+}
+
+/// The bit of the first reserved field which indicates a simulated message.
+const SIMULATION_BIT: u16 = 1 << 15;
+
+/// Returns the header which precedes an APDU of `apdu_length` bytes in an SV message, containing the APPID and the
+/// length of the message, as read by `parse`. The reserved fields are zero. Returns `None` if the message would be
+/// longer than the 65535 bytes allowed by the length field.
+pub fn sv_header(appid: u16, apdu_length: usize) -> Option<[u8; 8]> {
+	let length = u16::try_from(apdu_length.checked_add(8)?).ok()?;
+	let mut header = [0; 8];
+	header[..2].copy_from_slice(&appid.to_be_bytes());
+	header[2..4].copy_from_slice(&length.to_be_bytes());
+	Some(header)
 }
 
 pub fn parse(bytes: &[u8]) -> Result<SvMessage, DecodeError> {
+	parse_with_options(bytes, &ParseOptions::default())
+}
+
+/// Reads the APPID at the start of an SV message, without parsing the rest of the message. This allows frames to be
+/// rejected cheaply. Returns `None` if `bytes` is too short to contain an APPID.
+pub fn peek_appid(bytes: &[u8]) -> Option<u16> {
+	Some(u16::from_be_bytes(bytes.get(0..2)?.try_into().unwrap()))
+}
+
+/// The start of the first ASDU in an SV message, as read by `peek_first_asdu`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirstAsdu<'a> {
+	/// The number of ASDUs in the message.
+	pub no_asdu: u16,
+	pub svid: &'a str,
+	pub smp_cnt: u16,
+}
+
+/// Reads the number of ASDUs in an SV message, and the svID and smpCnt of the first, without parsing the rest of the
+/// message. This allows frames to be skipped cheaply by frame decimation. Returns `None` if the message cannot be
+/// parsed as far as the first smpCnt.
+pub fn peek_first_asdu<'a>(bytes: &'a [u8], options: &ParseOptions) -> Option<FirstAsdu<'a>> {
+	read_first_asdu(&mut BytesReader::new(bytes), options).ok()
+}
+
+fn read_first_asdu<'b>(reader: &mut BytesReader<'b>, options: &ParseOptions) -> Result<FirstAsdu<'b>, DecodeError> {
+	// The APPID, length and reserved fields.
+	reader.skip(8)?;
+	let _ = ber::read_required_identifier(reader, Tag::Application(0))?;
+	read_length(reader, options)?;
+
+	let no_asdu = read_required_field(reader, Tag::ContextSpecific(0), options, ber::read_integer_as_u16)?;
+	if no_asdu == 0 {
+		return Err(DecodeError::TagOutOfRange);
+	}
+	read_optional_field(reader, Tag::ContextSpecific(1), options, |reader, _| {
+		let length = ber::read_length(reader)?;
+		reader.skip(length).map_err(Into::into)
+	})?;
+	let mut asdus = read_required_field(reader, Tag::ContextSpecific(2), options, |reader, _| {
+		let length = ber::read_length(reader)?;
+		reader.take_sub_reader(length).map_err(Into::into)
+	})?;
+	let mut asdu = read_required_field(&mut asdus, Tag::Universal(16), options, |reader, _| {
+		let length = ber::read_length(reader)?;
+		reader.take_sub_reader(length).map_err(Into::into)
+	})?;
+
+	let svid = read_required_field(&mut asdu, Tag::ContextSpecific(0), options, ber::read_visiblestring)?;
+	read_optional_field(&mut asdu, Tag::ContextSpecific(1), options, ber::read_visiblestring)?;
+	let smp_cnt = read_required_field(&mut asdu, Tag::ContextSpecific(2), options, |reader, encoding| {
+		read_iec61850_int16u(reader, encoding, options)
+	})?;
+	Ok(FirstAsdu { no_asdu, svid, smp_cnt })
+}
+
+/// Parses an SV message from the payload of an Ethernet frame. Errors include the offset within `bytes` at which they
+/// occurred.
+pub fn parse_with_options(bytes: &[u8], options: &ParseOptions) -> Result<SvMessage, DecodeError> {
+	parse_borrowed_with_options(bytes, options).map(SvMessageRef::into_owned)
+}
+
+/// Parses an SV message in the same way as `parse`, but without copying the svID and datSet of each ASDU, which
+/// borrow from `bytes` instead.
+pub fn parse_borrowed(bytes: &[u8]) -> Result<SvMessageRef<'_>, DecodeError> {
+	parse_borrowed_with_options(bytes, &ParseOptions::default())
+}
+
+/// Parses an SV message in the same way as `parse_with_options`, but without copying the svID and datSet of each ASDU,
+/// which borrow from `bytes` instead.
+pub fn parse_borrowed_with_options<'a>(
+	bytes: &'a [u8],
+	options: &ParseOptions,
+) -> Result<SvMessageRef<'a>, DecodeError> {
 	let mut reader = BytesReader::new(bytes);
+	read_sv_message(&mut reader, options).map_err(|err| err.at(reader.position()))
+}
 
+fn read_sv_message<'b>(reader: &mut BytesReader<'b>, options: &ParseOptions) -> Result<SvMessageRef<'b>, DecodeError> {
 	let appid = reader.read_u16_be()?;
+	if options.validate_appid && !SV_APPID_RANGE.contains(&appid) {
+		return Err(DecodeError::AppidOutOfRange(appid).at(0));
+	}
 	let length = reader.read_u16_be()? as usize;
-	let _reserved_1 = reader.read_u16_be()?;
-	let _reserved_2 = reader.read_u16_be()?;
+	let reserved_1 = reader.read_u16_be()?;
+	let reserved_2 = reader.read_u16_be()?;
 
 	if length < 8 {
-		return Err(DecodeError::LengthOutOfRange.into());
+		return Err(DecodeError::LengthOutOfRange);
 	}
 
 	reader.limit(length - 8)?;
 
-	let _ = ber::read_required_identifier(&mut reader, Tag::Application(0))?;
-	let length = ber::read_length(&mut reader)?;
-	reader.limit(length)?;
-	let asdus = read_savpdu(&mut reader)?;
+	let _ = ber::read_required_identifier(reader, Tag::Application(0))?;
+	let apdu_length = read_length(reader, options)?;
+	reader.limit(apdu_length)?;
+	let asdus = read_savpdu(reader, options)?;
+
+	Ok(SvMessageRef {
+		appid,
+		length,
+		simulated: reserved_1 & SIMULATION_BIT != 0,
+		reserved: [reserved_1 & !SIMULATION_BIT, reserved_2],
+		asdus,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Encodes a message containing `asdu_count` ASDUs with `encode_sv_message`. Each ASDU has only the required fields
+	/// present, with consecutive smpCnt starting from 300, and is then passed to `alter`.
+	fn test_frame(asdu_count: u16, alter: impl Fn(&mut Asdu)) -> Vec<u8> {
+		let asdus = (0..asdu_count)
+			.map(|i| {
+				let mut asdu = Asdu {
+					svid: "TEST".into(),
+					datset: None,
+					smp_cnt: 300 + i,
+					conf_rev: 1,
+					refr_tm: None,
+					smp_synch: 2,
+					smp_rate: None,
+					sample: Sample::default(),
+					smp_mod: None,
+					gm_identity: None,
+				};
+				alter(&mut asdu);
+				asdu
+			})
+			.collect();
+		encode_sv_message(&SvMessage {
+			appid: 0x4000,
+			length: 0,
+			simulated: false,
+			reserved: [0, 0],
+			asdus,
+		})
+	}
+
+	#[test]
+	fn parse_unpadded() {
+		let frame = test_frame(1, |_| {});
+		let message = parse(&frame).unwrap();
+		assert_eq!(message.appid, 0x4000);
+		assert_eq!(message.length, frame.len());
+		assert!(message.padding(&frame).is_empty());
+	}
+
+	#[test]
+	fn parse_simulated() {
+		let mut frame = test_frame(1, |_| {});
+		let message = parse(&frame).unwrap();
+		assert!(!message.simulated);
+		assert_eq!(message.reserved, [0, 0]);
+
+		frame[4..8].copy_from_slice(&[0x80, 0x12, 0x34, 0x56]);
+		let message = parse(&frame).unwrap();
+		assert!(message.simulated);
+		assert_eq!(message.reserved, [0x0012, 0x3456]);
+	}
+
+	#[test]
+	fn parse_padded() {
+		let mut frame = test_frame(1, |_| {});
+		let length = frame.len();
+		frame.resize(length + 16, 0);
+
+		let message = parse(&frame).unwrap();
+		assert_eq!(message.length, length);
+		assert_eq!(message.padding(&frame), [0; 16]);
+
+		frame[length + 3] = 0xAB;
+		let message = parse(&frame).unwrap();
+		assert!(message.padding(&frame).iter().any(|&b| b != 0));
+	}
+
+	#[test]
+	fn parse_gm_identity() {
+		let frame = test_frame(1, |asdu| asdu.gm_identity = Some([1, 2, 3, 4, 5, 6, 7, 8]));
+		let options = ParseOptions {
+			strict_asdu_fields: true,
+			..Default::default()
+		};
+		let message = parse_with_options(&frame, &options).unwrap();
+		assert_eq!(message.asdus[0].gm_identity, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+	}
+
+	#[test]
+	fn parse_borrowed_svid() {
+		let frame = test_frame(1, |_| {});
+		let message = parse_borrowed(&frame).unwrap();
+		let svid = message.asdus[0].svid;
+		assert_eq!(svid, "TEST");
+		// The svID points into the frame rather than a copy.
+		assert!(frame.as_ptr_range().contains(&svid.as_ptr()));
+		assert_eq!(message.into_owned(), parse(&frame).unwrap());
+	}
+
+	#[test]
+	fn parse_appid_out_of_range() {
+		let options = ParseOptions {
+			validate_appid: true,
+			..Default::default()
+		};
+		let mut frame = test_frame(1, |_| {});
+		assert!(parse_with_options(&frame, &options).is_ok());
+
+		// GOOSE messages use APPIDs from 0x0000 to 0x3FFF.
+		frame[..2].copy_from_slice(&0x0001_u16.to_be_bytes());
+		assert!(parse(&frame).is_ok());
+		let error = parse_with_options(&frame, &options).unwrap_err();
+		assert_eq!(error.kind(), &DecodeError::AppidOutOfRange(0x0001));
+		assert_eq!(error.offset(), Some(0));
+		assert_eq!(error.to_string(), "APPID 0x0001 is outside the range reserved for sampled values at byte 0");
+
+		frame[..2].copy_from_slice(&0x8000_u16.to_be_bytes());
+		assert!(parse_with_options(&frame, &options).is_err());
+		frame[..2].copy_from_slice(&0x7FFF_u16.to_be_bytes());
+		assert!(parse_with_options(&frame, &options).is_ok());
+	}
+
+	#[test]
+	fn parse_unknown_trailing_field() {
+		// The gmIdentity field, which ends the frame, is given a tag which is not used by any ASDU field.
+		let mut frame = test_frame(1, |asdu| asdu.gm_identity = Some([0; 8]));
+		let field = frame.len() - 10;
+		frame[field] = 0x8A;
+
+		let message = parse(&frame).unwrap();
+		assert_eq!(message.asdus.len(), 1);
+		assert_eq!(message.asdus[0].svid, "TEST");
+
+		let options = ParseOptions {
+			strict_asdu_fields: true,
+			..Default::default()
+		};
+		let result = parse_with_options(&frame, &options);
+		assert_eq!(result.unwrap_err().kind(), &DecodeError::UnknownAsduField);
+	}
+
+	#[test]
+	fn parse_non_minimal_length() {
+		// The svID "TEST" is encoded with a two-octet length, in the space of a five-character svID.
+		let mut frame = test_frame(1, |asdu| asdu.svid = "TESTX".into());
+		let svid = frame.windows(2).position(|bytes| bytes == [0x80, 0x05]).unwrap();
+		frame[svid + 1..svid + 7].copy_from_slice(b"\x81\x04TEST");
+
+		assert_eq!(parse(&frame).unwrap().asdus[0].svid, "TEST");
+
+		let options = ParseOptions {
+			minimal_lengths: true,
+			..Default::default()
+		};
+		let result = parse_with_options(&frame, &options);
+		assert_eq!(result.unwrap_err().kind(), &DecodeError::NonMinimalLength);
+	}
+
+	#[test]
+	fn parse_truncated_trailing_field() {
+		// The unknown field which ends the frame declares more bytes than remain.
+		let mut frame = test_frame(1, |asdu| asdu.gm_identity = Some([0; 8]));
+		let field = frame.len() - 10;
+		frame[field..field + 2].copy_from_slice(&[0x8A, 0x0A]);
+		parse(&frame).expect_err("should fail when unknown field is truncated");
+	}
+
+	#[test]
+	fn sample_quality() {
+		let words = [0, 0x01, 0x03, 0x02, 0x2000, 0x0800 | 0x41, 0, 0];
+		let values = [1.0, 2.0, 3.0, 4.0, 50.0, 60.0, 70.0, 80.0];
+		let frame = test_frame(1, |asdu| asdu.sample = Sample::from_array(values, words.map(Quality)));
+
+		let sample = parse(&frame).unwrap().asdus.remove(0).sample;
+		assert_eq!(sample.current_a, 1.0);
+		assert_eq!(sample.voltage_n, 80.0);
+		let validity = sample.quality.map(Quality::validity);
+		assert_eq!(validity[..4], [Validity::Good, Validity::Invalid, Validity::Questionable, Validity::Reserved]);
+		assert_eq!(validity[4], Validity::Good);
+		assert!(sample.quality[4].contains(Quality::DERIVED));
+		assert!(sample.quality[5].contains(Quality::TEST | Quality::FAILURE));
+		assert!(!sample.quality[5].contains(Quality::TEST | Quality::DERIVED));
+		assert!(!sample.is_good());
+
+		assert!(parse(&test_frame(1, |_| {})).unwrap().asdus[0].sample.is_good());
+	}
+
+	#[test]
+	fn sample_as_array() {
+		let values = [1.0, 2.0, 3.0, 4.0, 10.0, 20.0, 30.0, 40.0];
+		let sample = Sample::from_array(values, [Quality::default(); 8]);
+		assert_eq!(sample.current_n, 4.0);
+		assert_eq!(sample.voltage_a, 10.0);
+		assert_eq!(sample.as_array(), values);
+		assert_eq!(Sample::CHANNEL_NAMES[4], "Va");
+	}
+
+	#[test]
+	fn sample_clamp_to_bounds() {
+		let mut sample = Sample {
+			current_a: 5.0,
+			current_b: -1.0e6,
+			voltage_a: 230.0,
+			voltage_b: 1.0e6,
+			voltage_c: -1.0e6,
+			..Default::default()
+		};
+
+		let current = ValueBounds { min: -100.0, max: 100.0 };
+		let voltage = ValueBounds { min: -1000.0, max: 1000.0 };
+		assert_eq!(sample.clamp_to_bounds(Some(current), Some(voltage)), 3);
+		assert_eq!(sample.current_a, 5.0);
+		assert_eq!(sample.current_b, -100.0);
+		assert_eq!(sample.voltage_a, 230.0);
+		assert_eq!(sample.voltage_b, 1000.0);
+		assert_eq!(sample.voltage_c, -1000.0);
+
+		assert_eq!(sample.clamp_to_bounds(Some(current), Some(voltage)), 0);
+
+		let mut sample = Sample {
+			voltage_a: 1.0e6,
+			..Default::default()
+		};
+		assert_eq!(sample.clamp_to_bounds(Some(current), None), 0);
+		assert_eq!(sample.voltage_a, 1.0e6);
+	}
 
-	Ok(SvMessage { appid, asdus })
+	#[test]
+	fn effective_sample_rate() {
+		let mut asdu = parse(&test_frame(1, |_| {})).unwrap().asdus.remove(0);
+		assert_eq!(asdu.effective_sample_rate(50), None);
+
+		// Without smpMod, smpRate is the number of samples per nominal period.
+		asdu.smp_rate = Some(80);
+		assert_eq!(asdu.effective_sample_rate(50), Some(4000.0));
+		assert_eq!(asdu.effective_sample_rate(60), Some(4800.0));
+
+		asdu.smp_mod = Some(0);
+		assert_eq!(asdu.effective_sample_rate(50), Some(4000.0));
+
+		asdu.smp_rate = Some(4000);
+		asdu.smp_mod = Some(1);
+		assert_eq!(asdu.effective_sample_rate(50), Some(4000.0));
+
+		asdu.smp_rate = Some(2);
+		asdu.smp_mod = Some(2);
+		assert_eq!(asdu.effective_sample_rate(50), Some(0.5));
+
+		asdu.smp_mod = Some(3);
+		assert_eq!(asdu.effective_sample_rate(50), None);
+	}
+
+	#[test]
+	fn parse_minimal_asdu() {
+		let message = parse(&test_frame(1, |_| {})).unwrap();
+		assert_eq!(message.asdus.len(), 1);
+
+		let asdu = &message.asdus[0];
+		assert_eq!(asdu.svid, "TEST");
+		assert_eq!(asdu.datset, None);
+		assert_eq!(asdu.smp_cnt, 300);
+		assert_eq!(asdu.conf_rev, 1);
+		assert_eq!(asdu.refr_tm, None);
+		assert_eq!(asdu.smp_synch, 2);
+		assert_eq!(asdu.smp_rate, None);
+		assert_eq!(asdu.smp_mod, None);
+		assert_eq!(asdu.gm_identity, None);
+	}
+
+	#[test]
+	fn utctime() {
+		let time = |fraction| UtcTime {
+			seconds: 0,
+			fraction,
+			quality: TimeQuality::from_octet(0x1F),
+		};
+		assert_eq!(time(0).nanoseconds(), 0);
+		assert_eq!(time(1).nanoseconds(), 60);
+		assert_eq!(time(0x40_0000).nanoseconds(), 250_000_000);
+		assert_eq!(time(0xFF_FFFF).nanoseconds(), 999_999_940);
+
+		let quality = TimeQuality::from_octet(0xE0 | 24);
+		assert!(quality.leap_second_known && quality.clock_failure && quality.clock_not_synchronised);
+		assert_eq!(quality.accuracy, 24);
+		assert_eq!(TimeQuality::from_octet(0x1F).accuracy, 31);
+		assert!(!TimeQuality::from_octet(0x40).clock_not_synchronised);
+	}
+
+	#[test]
+	fn parse_unexpected_length() {
+		// The smpCnt field follows the 8-byte header, the APDU, savPDU and ASDU headers, noASDU and the svID.
+		let mut frame = test_frame(1, |_| {});
+		assert_eq!(frame[23..25], [0x82, 0x02]);
+		frame[24] = 0x03;
+		let error = parse(&frame).unwrap_err();
+		assert_eq!(error.kind(), &DecodeError::UnexpectedLength { expected: 2, actual: 3 });
+		assert_eq!(error.offset(), Some(23));
+
+		let mut frame = test_frame(1, |_| {});
+		let seq_data = frame.len() - 66;
+		assert_eq!(frame[seq_data..seq_data + 2], [0x87, 0x40]);
+		frame[seq_data + 1] = 0x30;
+		let error = parse(&frame).unwrap_err();
+		assert_eq!(error.kind(), &DecodeError::UnexpectedLength { expected: 64, actual: 48 });
+		assert_eq!(error.to_string(), "Expected 64 bytes, but found 48 at byte 36");
+	}
+
+	#[test]
+	fn peek_appid_before_parsing() {
+		assert_eq!(peek_appid(&[0x40, 0x01, 0xFF]), Some(0x4001));
+		assert_eq!(peek_appid(&[0x40]), None);
+	}
+
+	#[test]
+	fn peek_first_asdu_before_parsing() {
+		let options = ParseOptions::default();
+		let frame = include_bytes!("../tests/corpus/all_optional_fields.bin");
+		let message = parse(frame).unwrap();
+		let first = FirstAsdu {
+			no_asdu: message.asdus.len() as u16,
+			svid: &message.asdus[0].svid,
+			smp_cnt: message.asdus[0].smp_cnt,
+		};
+		assert_eq!(peek_first_asdu(frame, &options), Some(first));
+
+		let frame = test_frame(4, |_| {});
+		let first = peek_first_asdu(&frame, &options).unwrap();
+		assert_eq!((first.no_asdu, first.smp_cnt), (4, 300));
+		assert_eq!(peek_first_asdu(&frame[..20], &options), None);
+	}
+
+	#[test]
+	fn encode_round_trip() {
+		let mut asdu = Asdu {
+			svid: "MU01".into(),
+			datset: Some("LD0/LLN0$DS".into()),
+			smp_cnt: 3999,
+			conf_rev: 2,
+			refr_tm: Some(UtcTime::from_octets([0x65, 0x92, 0x00, 0x80, 0x80, 0x00, 0x00, 0x0A])),
+			smp_synch: 2,
+			smp_rate: Some(4000),
+			sample: Sample {
+				current_a: 1.5,
+				current_b: -2.0,
+				voltage_a: 230.25,
+				voltage_n: -0.5,
+				..Default::default()
+			},
+			smp_mod: Some(1),
+			gm_identity: Some([0x00, 0x1B, 0x19, 0xFF, 0xFE, 0x00, 0x00, 0x01]),
+		};
+		asdu.sample.quality[1] = Quality(Quality::DERIVED | 0x01);
+		asdu.sample.quality[7] = Quality(Quality::TEST);
+		let minimal = Asdu {
+			svid: "MU01".into(),
+			datset: None,
+			smp_cnt: 0,
+			refr_tm: None,
+			smp_rate: None,
+			smp_mod: None,
+			gm_identity: None,
+			..asdu.clone()
+		};
+		let mut message = SvMessage {
+			appid: 0x4000,
+			length: 0,
+			simulated: true,
+			reserved: [0x0123, 0x4567],
+			asdus: vec![asdu, minimal],
+		};
+
+		let bytes = encode_sv_message(&message);
+		message.length = bytes.len();
+		assert_eq!(parse(&bytes), Ok(message));
+	}
+
+	#[test]
+	fn encode_parsed_frames() {
+		// These frames use the shortest possible lengths, so they are reproduced exactly.
+		let frames = [
+			test_frame(1, |_| {}),
+			include_bytes!("../tests/corpus/all_optional_fields.bin").to_vec(),
+		];
+		for frame in frames {
+			let message = parse(&frame).unwrap();
+			assert_eq!(encode_sv_message(&message), frame);
+		}
+
+		// Long-form lengths are re-encoded in the short form, so only the decoded message is reproduced.
+		let message = parse(&long_form(&test_frame(4, |_| {}), 2)).unwrap();
+		let bytes = encode_sv_message(&message);
+		let reparsed = parse(&bytes).unwrap();
+		assert_eq!(reparsed.asdus, message.asdus);
+		assert_eq!(reparsed.length, bytes.len());
+	}
+
+	#[test]
+	fn parse_float_sample() {
+		// The sample is encoded as integers, then its values are overwritten in the Float32 format.
+		let mut frame = test_frame(1, |asdu| asdu.sample.quality[7] = Quality(0x2000));
+		let seq_data = frame.len() - 64;
+		let values = [1500.0_f32, 0.0, 0.0, 0.0, 23_000.0, 0.0, 0.0, -250.0];
+		for (channel, value) in frame[seq_data..].chunks_mut(8).zip(values) {
+			channel[..4].copy_from_slice(&value.to_be_bytes());
+		}
+
+		let options = ParseOptions {
+			sample_format: SampleFormat::Float32,
+			..Default::default()
+		};
+		let sample = parse_with_options(&frame, &options).unwrap().asdus.remove(0).sample;
+		assert_eq!(sample.current_a, 1.5);
+		assert_eq!(sample.voltage_a, 230.0);
+		assert_eq!(sample.voltage_n, -2.5);
+		assert_eq!(sample.quality[7], Quality(0x2000));
+
+		for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+			frame[seq_data..seq_data + 4].copy_from_slice(&value.to_be_bytes());
+			let error = parse_with_options(&frame, &options).unwrap_err();
+			assert_eq!(error.kind(), &DecodeError::NonFiniteValue);
+		}
+	}
+
+	#[test]
+	fn parse_constructed_sample() {
+		let values = [1.0, 2.0, 3.0, 4.0, 50.0, 60.0, 70.0, 80.0];
+		let mut frame = test_frame(1, |asdu| {
+			asdu.smp_rate = Some(4000);
+			asdu.sample = Sample::from_array(values, [Quality::default(); 8]);
+		});
+		// The sample is split into two segments of 32 bytes. The headers of the segments take the place of the smpRate
+		// field, which precedes the sample.
+		let sample = frame.split_off(frame.len() - 64);
+		frame.truncate(frame.len() - 6);
+		frame.extend([0xA7, 0x44]);
+		for segment in sample.chunks(32) {
+			frame.extend([0x04, 0x20]);
+			frame.extend(segment);
+		}
+
+		assert_eq!(parse(&frame).unwrap_err().kind(), &DecodeError::ConstructedString);
+
+		let options = ParseOptions {
+			constructed_strings: true,
+			..Default::default()
+		};
+		let sample = parse_with_options(&frame, &options).unwrap().asdus.remove(0).sample;
+		assert_eq!(sample.current_a, 1.0);
+		assert_eq!(sample.current_n, 4.0);
+		assert_eq!(sample.voltage_a, 50.0);
+		assert_eq!(sample.voltage_n, 80.0);
+	}
+
+	#[test]
+	fn parse_all_optional_fields() {
+		let frame = test_frame(1, |asdu| {
+			asdu.datset = Some("DS".into());
+			asdu.refr_tm = Some(UtcTime::from_octets([0x65, 0x92, 0x00, 0x80, 0x80, 0x00, 0x00, 0x0A]));
+			asdu.smp_rate = Some(4000);
+			asdu.smp_mod = Some(1);
+			asdu.gm_identity = Some([1, 2, 3, 4, 5, 6, 7, 8]);
+		});
+
+		let options = ParseOptions {
+			strict_asdu_fields: true,
+			..Default::default()
+		};
+		let message = parse_with_options(&frame, &options).unwrap();
+
+		let asdu = &message.asdus[0];
+		assert_eq!(asdu.datset.as_deref(), Some("DS"));
+		let refr_tm = asdu.refr_tm.unwrap();
+		assert_eq!(refr_tm.seconds, 0x6592_0080);
+		assert_eq!(refr_tm.fraction, 0x80_0000);
+		assert_eq!(refr_tm.nanoseconds(), 500_000_000);
+		assert_eq!(
+			refr_tm.quality,
+			TimeQuality {
+				leap_second_known: false,
+				clock_failure: false,
+				clock_not_synchronised: false,
+				accuracy: 10,
+			}
+		);
+		assert_eq!(asdu.smp_rate, Some(4000));
+		assert_eq!(asdu.smp_mod, Some(1));
+		assert_eq!(asdu.gm_identity, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+	}
+
+	#[test]
+	fn parse_some_optional_fields() {
+		// datSet and refrTm are absent, so the field following confRev is smpSynch, and smpRate is present.
+		let message = parse(&test_frame(1, |asdu| asdu.smp_rate = Some(4000))).unwrap();
+
+		let asdu = &message.asdus[0];
+		assert_eq!(asdu.datset, None);
+		assert_eq!(asdu.refr_tm, None);
+		assert_eq!(asdu.smp_synch, 2);
+		assert_eq!(asdu.smp_rate, Some(4000));
+		assert_eq!(asdu.smp_mod, None);
+	}
+
+	#[test]
+	fn parse_missing_required_field() {
+		// smpSynch is given the tag of smpRate, so smpRate is encountered where smpSynch is required.
+		let mut frame = test_frame(1, |_| {});
+		let smp_synch = frame.len() - 69;
+		assert_eq!(frame[smp_synch..smp_synch + 3], [0x85, 0x01, 0x02]);
+		frame[smp_synch] = 0x86;
+
+		let result = parse(&frame);
+		assert_eq!(result.unwrap_err().kind(), &DecodeError::UnexpectedTag);
+	}
+
+	/// Re-encodes every BER length in `tlvs` in the long form, using `octets` length octets.
+	fn long_form_tlvs(tlvs: &[u8], octets: usize) -> Vec<u8> {
+		let mut encoded = vec![];
+		let mut reader = BytesReader::new(tlvs);
+		while reader.position() < tlvs.len() {
+			let tag = tlvs[reader.position()];
+			reader.skip(1).unwrap();
+			let length = ber::read_length(&mut reader).unwrap();
+			let contents = &tlvs[reader.position()..reader.position() + length];
+			reader.skip(length).unwrap();
+			// Only the contents of constructed encodings are themselves TLVs.
+			let contents = if tag & 0x20 != 0 {
+				long_form_tlvs(contents, octets)
+			} else {
+				contents.to_vec()
+			};
+			encoded.push(tag);
+			encoded.push(0x80 | octets as u8);
+			encoded.extend(&contents.len().to_be_bytes()[size_of::<usize>() - octets..]);
+			encoded.extend(contents);
+		}
+		encoded
+	}
+
+	/// Re-encodes a frame from `test_frame` with every length in the long form, using `octets` length octets.
+	fn long_form(frame: &[u8], octets: usize) -> Vec<u8> {
+		let apdu = long_form_tlvs(&frame[8..], octets);
+		let mut encoded = sv_header(0x4000, apdu.len()).unwrap().to_vec();
+		encoded.extend(apdu);
+		encoded
+	}
+
+	#[test]
+	fn parse_long_form_lengths() {
+		// With several ASDUs, the APDU, savPDU and ASDU sequence are longer than 127 bytes, so require the long form
+		// anyway. The lengths of the individual fields are shorter, but are also encoded in the long form, which is
+		// permitted by BER. A single length octet limits the frame to two ASDUs.
+		for (asdu_count, octets) in [(2, 1), (8, 2), (8, 4)] {
+			let frame = test_frame(asdu_count, |asdu| asdu.sample.voltage_n = 140.0);
+			let long_frame = long_form(&frame, octets);
+			assert!(long_frame.len() > frame.len());
+			let message = parse(&long_frame).unwrap();
+			assert_eq!(message.length, long_frame.len());
+			assert_eq!(message.asdus, parse(&frame).unwrap().asdus);
+			assert_eq!(message.asdus.len(), asdu_count as usize);
+			assert_eq!(message.asdus[0].sample.voltage_n, 140.0);
+		}
+	}
+
+	#[test]
+	fn parse_long_form_truncated() {
+		let frame = long_form(&test_frame(8, |_| {}), 2);
+		for length in [frame.len() - 1, frame.len() - 65, 200, 20] {
+			assert!(parse(&frame[..length]).is_err(), "{length}");
+		}
+	}
+
+	#[test]
+	fn parse_asdu_count_mismatch() {
+		// The frame declares two ASDUs, but contains only one, which ends the frame.
+		let mut frame = test_frame(1, |_| {});
+		frame[12] = 2;
+		let error = parse(&frame).unwrap_err();
+		assert_eq!(error.kind(), &DecodeError::AsduCountMismatch { declared: 2, found: 1 });
+		assert_eq!(error.offset(), Some(frame.len()));
+
+		// noASDU follows the 8-byte header, the 4-byte APDU header and the 2-byte noASDU header.
+		let mut frame = test_frame(8, |_| {});
+		assert_eq!(frame[14], 8);
+		frame[14] = 6;
+		let error = parse(&frame).unwrap_err();
+		assert_eq!(error.kind(), &DecodeError::AsduCountMismatch { declared: 6, found: 8 });
+		frame[14] = 9;
+		let error = parse(&frame).unwrap_err();
+		assert_eq!(error.kind(), &DecodeError::AsduCountMismatch { declared: 9, found: 8 });
+	}
+
+	#[test]
+	fn smp_cnt_spacing() {
+		let mut message = parse(&test_frame(4, |_| {})).unwrap();
+		assert_eq!(message.smp_cnt_spacing(4000), [1, 1, 1]);
+
+		for (asdu, smp_cnt) in message.asdus.iter_mut().zip([3998, 3999, 0, 2]) {
+			asdu.smp_cnt = smp_cnt;
+		}
+		assert_eq!(message.smp_cnt_spacing(4000), [1, 1, 2]);
+
+		message.asdus.truncate(1);
+		assert!(message.smp_cnt_spacing(4000).is_empty());
+	}
 }
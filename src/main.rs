@@ -1,85 +1,755 @@
 use std::{
 	ffi::OsStr,
-	net::{Ipv4Addr, UdpSocket},
-	path::PathBuf,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc::{self, Receiver, SyncSender, TrySendError},
+		Mutex,
+	},
+	time::{Duration, Instant},
 };
 
 use clap::Parser;
 use mu_rust::{
-	config::Configuration,
-	ethernet::EthernetSocket,
-	parse,
-	sample_buffer::{sender_thread_fn, SampleBufferQueue},
-	DecodeError,
+	ParseOptions,
+	capture::FrameRecorder,
+	clock::TaiOffsetMonitor,
+	config::{Configuration, OutOfBoundsAction, OutputProtocol, SmpCntReset, TimestampSource},
+	discovery::Discovery,
+	ethernet::{EthernetSocket, MacAddress, RecvInfo},
+	output::{self, Output, TcpOutput, UdpOutput},
+	parse, parse_borrowed_with_options,
+	pcap::PcapSource,
+	peek_appid, peek_first_asdu,
+	sample_buffer::{InsertOutcome, SampleBufferQueue, SampleTime, sender_thread_fn},
+	source::PacketSource,
+	stats::{Counters, SmpCntGapTracker, Throughput},
+};
+use signal_hook::{
+	consts::{SIGINT, SIGTERM},
+	iterator::Signals,
+	low_level::signal_name,
 };
 use thiserror::Error;
 
+/// The maximum number of frames received by each receiver thread at once.
+const RECV_BATCH_SIZE: usize = 32;
+
+/// The longest a receiver thread waits for a frame before checking whether it should stop.
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The longest the statistics thread sleeps before checking whether it should stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Parser)]
 struct CommandLineArgs {
-	#[arg(short, long)]
-	config: PathBuf,
+	/// The configuration file. This is only optional when discovering streams on the interfaces given by `--interface`.
+	#[arg(short, long, required_unless_present = "interfaces")]
+	config: Option<PathBuf>,
+	/// Passively receive for the given number of seconds, then print the streams observed and exit.
+	#[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+	discover: Option<u64>,
+	/// An interface to discover streams on, instead of those in the configuration file. May be given several times.
+	#[arg(long = "interface", value_name = "NAME", requires = "discover")]
+	interfaces: Vec<String>,
+	/// The multicast address to join while discovering streams, instead of the one in the configuration file. By
+	/// default, this is 01-0C-CD-04-00-01.
+	#[arg(long, value_name = "ADDRESS", requires = "discover", value_parser = parse_mac_address)]
+	mac_address: Option<MacAddress>,
+	/// Process the frames in a pcap or pcapng capture file instead of receiving on the configured interfaces, then
+	/// exit.
+	#[arg(long, value_name = "PATH")]
+	pcap: Option<PathBuf>,
+	/// Replay the capture file at the rate at which it was captured, rather than as fast as possible.
+	#[arg(long, requires = "pcap")]
+	realtime: bool,
 }
 
-#[derive(Debug, Error)]
-enum MainError {
-	#[error(transparent)]
-	Io(#[from] std::io::Error),
-	#[error(transparent)]
-	Decode(#[from] DecodeError),
+fn parse_mac_address(address: &str) -> Result<MacAddress, String> {
+	MacAddress::try_from(address.to_owned()).map_err(|err| err.to_string())
 }
 
-fn main() -> Result<(), MainError> {
-	let env = env_logger::Env::default().default_filter_or("info");
-	env_logger::init_from_env(env);
-
-	let args = CommandLineArgs::parse();
-
-	let config_file_str = match std::fs::read_to_string(&args.config) {
+/// Reads the configuration file at `path`, exiting if it cannot be read.
+fn read_configuration(path: &Path) -> Configuration {
+	let config_file_str = match std::fs::read_to_string(path) {
 		Ok(s) => s,
 		Err(err) => {
-			log::error!("Unable to read configuration file '{}': {err}", args.config.display());
+			log::error!("Unable to read configuration file '{}': {err}", path.display());
 			std::process::exit(1);
 		},
 	};
 
-	let configuration = match toml::from_str::<Configuration>(&config_file_str) {
+	match toml::from_str::<Configuration>(&config_file_str) {
 		Ok(c) => c,
 		Err(err) => {
-			log::error!("Unable to read configuration file '{}': {err}", args.config.display());
+			log::error!("Unable to read configuration file '{}': {err}", path.display());
 			std::process::exit(1);
 		},
+	}
+}
+
+#[derive(Debug, Error)]
+enum MainError {
+	#[error(transparent)]
+	Io(#[from] std::io::Error),
+}
+
+/// A frame which has been received but not yet processed.
+struct ReceivedFrame {
+	info: RecvInfo,
+	data: Box<[u8]>,
+}
+
+/// Receives sampled value messages on a single interface and passes them to the processing thread for that interface.
+/// This thread does as little as possible, so that slow processing does not cause the kernel's receive buffer to
+/// overflow. If the processing thread falls too far behind, frames are dropped instead.
+///
+/// Each configured interface has its own receiver thread, so that a failure on one interface does not affect the
+/// others. The thread stops once `shutdown` is set.
+fn receiver_thread_fn(
+	interface: &str,
+	configuration: &Configuration,
+	frames: SyncSender<ReceivedFrame>,
+	counters: &Counters,
+	shutdown: &AtomicBool,
+) -> Result<(), MainError> {
+	let mut recv_socket = EthernetSocket::new(OsStr::new(interface), configuration.mac_address)?;
+	if configuration.vlan_tags {
+		recv_socket.receive_vlan_tags()?;
+	}
+	if configuration.timestamp_source == TimestampSource::Hardware {
+		recv_socket.enable_hardware_timestamps()?;
+	}
+
+	log::info!("Bound socket to interface '{interface}'.");
+
+	// At high sample rates, receiving each frame with a separate system call uses too much CPU time, so all of the
+	// frames which are available are received at once.
+	let mut bufs = vec![[0_u8; 1522]; RECV_BATCH_SIZE]; // The maximum size of an Ethernet frame is 1522 bytes.
+	let mut infos = Vec::with_capacity(RECV_BATCH_SIZE);
+	let mut tai_offset = TaiOffsetMonitor::new();
+
+	while !shutdown.load(Ordering::Relaxed) {
+		recv_socket.recv_batch_timeout(&mut bufs, &mut infos, RECV_TIMEOUT)?;
+		counters.received_frames.fetch_add(infos.len() as u64, Ordering::Relaxed);
+		for (mut info, buf) in infos.drain(..).zip(&bufs) {
+			// Hardware timestamps are taken by the interface's PTP hardware clock, which runs on TAI.
+			if info.hardware_timestamp {
+				info.timestamp_s -= tai_offset.offset();
+			}
+			let data = buf[0..info.length].into();
+			match frames.try_send(ReceivedFrame { info, data }) {
+				Ok(()) => {}
+				Err(TrySendError::Full(_)) => {
+					let total = counters.queue_full_frames.fetch_add(1, Ordering::Relaxed) + 1;
+					log::debug!("Dropped a frame because the receive queue is full ({total} dropped so far).");
+				}
+				// The processing thread has stopped, and will already have reported why.
+				Err(TrySendError::Disconnected(_)) => return Ok(()),
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Reads the frames in a capture file and passes them to the processing thread, in the same way as
+/// `receiver_thread_fn`. Frames are never dropped; if the processing thread falls behind, reading waits for it.
+fn replay_thread_fn(
+	path: &Path,
+	realtime: bool,
+	frames: SyncSender<ReceivedFrame>,
+	counters: &Counters,
+	shutdown: &AtomicBool,
+) -> Result<(), MainError> {
+	let mut source = PcapSource::open(path)?.with_realtime(realtime);
+
+	log::info!("Replaying capture file '{}'.", path.display());
+
+	let mut buf = [0_u8; 1522];
+
+	while !shutdown.load(Ordering::Relaxed) {
+		let info = match source.recv(&mut buf) {
+			Ok(info) => info,
+			Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+				log::info!("Reached the end of capture file '{}'.", path.display());
+				return Ok(());
+			}
+			Err(err) => return Err(err.into()),
+		};
+		counters.received_frames.fetch_add(1, Ordering::Relaxed);
+		let data = buf[0..info.length].into();
+		if frames.send(ReceivedFrame { info, data }).is_err() {
+			// The processing thread has stopped, and will already have reported why.
+			return Ok(());
+		}
+	}
+	Ok(())
+}
+
+/// Applies `f` to the frame recorder, if there is one. Capture is only a diagnostic aid, so if writing fails (e.g.
+/// because the disk is full), the error is logged and the recorder is disabled rather than stopping the bridge.
+fn with_recorder(
+	recorder: &mut Option<FrameRecorder>,
+	interface: &str,
+	f: impl FnOnce(&mut FrameRecorder) -> std::io::Result<()>,
+) {
+	if let Some(Err(err)) = recorder.as_mut().map(f) {
+		log::error!("Frame capture on interface {interface} has been disabled after an error: {err}");
+		*recorder = None;
+	}
+}
+
+/// Processes the frames received on a single interface and inserts their samples into the queue. When the same stream
+/// is received on several interfaces (e.g. with PRP), duplicate samples are written to the same position in the buffer
+/// and so have no effect.
+fn processing_thread_fn(
+	interface: &str,
+	configuration: &Configuration,
+	frames: Receiver<ReceivedFrame>,
+	sample_buffer_queue: &SampleBufferQueue,
+	counters: &Counters,
+	buffer_length: u32,
+) -> Result<(), MainError> {
+	let streams = configuration.streams();
+
+	let mut sample_rate_mismatch_logged = false;
+	let mut previous_smp_cnts = vec![None; streams.len()];
+	let mut smp_cnt_reset_mismatch_logged = false;
+	let mut unexpected_svid_logged = false;
+	// The svID of the first ASDU used by the channels without a stream, if `default_svid` is not set.
+	let mut default_stream_svid = None;
+	let mut default_stream_mixed_logged = false;
+
+	let smp_cnt_period = configuration.smp_cnt_reset.period(configuration.sample_rate);
+	let mut gap_tracker = SmpCntGapTracker::new(streams.len(), smp_cnt_period);
+	let loss_summary_interval = Duration::from_secs(configuration.stats_interval_secs);
+	let mut loss_summary_start = Instant::now();
+	let mut recent_lost_samples: u64 = 0;
+	// Implausible values may occur in every sample, so they are summarised over the same interval.
+	let mut recent_implausible_samples: u64 = 0;
+
+	let mut recorder = configuration
+		.capture
+		.as_ref()
+		.map(|capture| FrameRecorder::new(capture, interface, configuration.mac_address))
+		.transpose()?;
+
+	let parse_options = ParseOptions {
+		strict_asdu_fields: configuration.strict_asdu_fields,
+		constructed_strings: configuration.constructed_strings,
+		minimal_lengths: configuration.minimal_lengths,
+		sample_format: configuration.sample_format,
+		validate_appid: configuration.validate_appid,
 	};
 
-	let recv_socket = EthernetSocket::new(OsStr::new(&configuration.interface), configuration.mac_address)?;
+	for ReceivedFrame { info, data } in frames {
+		if loss_summary_start.elapsed() >= loss_summary_interval {
+			if recent_lost_samples > 0 {
+				log::warn!(
+					"Lost {recent_lost_samples} samples on interface {interface} in the last {} s ({} lost so far).",
+					configuration.stats_interval_secs,
+					counters.lost_samples.load(Ordering::Relaxed)
+				);
+				recent_lost_samples = 0;
+			}
+			if recent_implausible_samples > 0 {
+				log::warn!(
+					"Received {recent_implausible_samples} samples with implausible values on interface {interface} in \
+					 the last {} s ({} implausible so far).",
+					configuration.stats_interval_secs,
+					counters.implausible_samples.load(Ordering::Relaxed)
+				);
+				recent_implausible_samples = 0;
+			}
+			loss_summary_start = Instant::now();
+		}
 
-	log::info!("Bound socket to interface '{}'.", &configuration.interface);
-	log::info!("Multicast address is '{}'.", &configuration.mac_address);
+		let frame = &data[..];
+		let Ok(timestamp_s) = u64::try_from(info.timestamp_s) else {
+			let total = counters.invalid_timestamp_frames.fetch_add(1, Ordering::Relaxed) + 1;
+			log::warn!(
+				"Dropped a frame with timestamp {}.{:09} s, which is before the Unix epoch ({total} dropped so far).",
+				info.timestamp_s,
+				info.timestamp_ns
+			);
+			continue;
+		};
 
-	let mut buf = [0_u8; 1522]; // The maximum size of an Ethernet frame is 1522 bytes.
+		with_recorder(&mut recorder, interface, |recorder| recorder.record(timestamp_s, info.timestamp_ns, frame));
 
-	let buffer_length = configuration.sample_rate / (configuration.nominal_frequency * 2);
+		if !configuration.source_filter.accepts(info.source_mac) {
+			counters.filtered_frames.fetch_add(1, Ordering::Relaxed);
+			continue;
+		}
 
-	let send_socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+		// Frames which are too short to contain an APPID are left for the parser to report.
+		if peek_appid(frame).is_some_and(|appid| !configuration.accepts_appid(appid)) {
+			counters.filtered_frames.fetch_add(1, Ordering::Relaxed);
+			continue;
+		}
 
-	let sample_buffer_queue = SampleBufferQueue::new();
+		// With frame decimation, frames holding none of the samples kept are skipped without being parsed. Their
+		// samples are still tracked, so that only real gaps count as losses.
+		let decimated = (configuration.frame_decimation > 1)
+			.then(|| peek_first_asdu(frame, &parse_options))
+			.flatten()
+			.filter(|first| !configuration.decimation_keeps_frame(first.smp_cnt, first.no_asdu));
+		if let Some(first) = decimated {
+			counters.decimated_frames.fetch_add(1, Ordering::Relaxed);
+			if let Some(stream) = Configuration::stream_index(&streams, first.svid) {
+				let smp_cnts = (0..u32::from(first.no_asdu))
+					.map(|i| ((u32::from(first.smp_cnt) + i) % smp_cnt_period) as u16)
+					.collect::<Vec<_>>();
+				let lost = smp_cnts
+					.iter()
+					.map(|&smp_cnt| u64::from(gap_tracker.record(stream, smp_cnt)))
+					.sum();
+				recent_lost_samples += lost;
+				counters.lost_samples.fetch_add(lost, Ordering::Relaxed);
+				previous_smp_cnts[stream] = smp_cnts.last().copied();
+			}
+			continue;
+		}
 
-	log::info!("Datagrams will be sent to {}.", &configuration.destination);
+		let sv_message = match parse_borrowed_with_options(frame, &parse_options) {
+			Ok(sv_message) => {
+				counters.parsed_frames.fetch_add(1, Ordering::Relaxed);
+				sv_message
+			}
+			Err(err) => {
+				let total = counters.parse_errors.fetch_add(1, Ordering::Relaxed) + 1;
+				match peek_appid(frame) {
+					Some(appid) => log::warn!(
+						"Dropped a {} byte frame with APPID {appid:#06X} which could not be parsed ({total} dropped so \
+						 far): {err}",
+						frame.len()
+					),
+					None => log::warn!(
+						"Dropped a {} byte frame which could not be parsed ({total} dropped so far): {err}",
+						frame.len()
+					),
+				}
+				log::debug!("Frame which could not be parsed: {}", err.context(frame));
+				with_recorder(&mut recorder, interface, FrameRecorder::note_parse_error);
+				continue;
+			}
+		};
+		if sv_message.simulated && configuration.drop_simulated_frames {
+			counters.filtered_frames.fetch_add(1, Ordering::Relaxed);
+			continue;
+		}
+		if configuration.validate_padding {
+			let non_zero = sv_message.padding(frame).iter().filter(|&&b| b != 0).count();
+			if non_zero > 0 {
+				log::warn!("Frame contains {non_zero} non-zero bytes after the SV message.");
+			}
+		}
 
-	std::thread::scope(|scope| {
-		let _sender_thread = scope.spawn(|| sender_thread_fn(&sample_buffer_queue, send_socket, configuration.destination, &configuration.channels));
-		loop {
-			let info = recv_socket.recv(&mut buf)?;
-			let sv_message = parse(&buf[0..info.length])?;
-			for asdu in sv_message.asdus {
-				assert!(info.timestamp_s >= 0); // TODO: handle correctly (probably just ignore sample entirely)
-				sample_buffer_queue.insert_sample(
-					info.timestamp_s as u64,
+		if configuration.asdu_spacing_diagnostics {
+			let spacing = sv_message.smp_cnt_spacing(configuration.smp_cnt_reset.period(configuration.sample_rate));
+			if let Some(&max) = spacing.iter().max() {
+				counters.max_asdu_spacing.fetch_max(u64::from(max), Ordering::Relaxed);
+				if spacing.iter().any(|&s| s != spacing[0]) {
+					counters.irregular_spacing_frames.fetch_add(1, Ordering::Relaxed);
+					log::debug!("ASDUs in frame have irregular smpCnt spacing {spacing:?}.");
+				}
+			}
+		}
+
+		if let Some(validation) = &configuration.expected_svid {
+			if let Some(asdu) = sv_message.asdus.iter().find(|asdu| !validation.pattern.matches(asdu.svid)) {
+				counters.unexpected_svid_frames.fetch_add(1, Ordering::Relaxed);
+				if !unexpected_svid_logged {
+					log::warn!("Received an ASDU with unexpected svID '{}' on interface '{interface}'.", asdu.svid);
+					unexpected_svid_logged = true;
+				}
+				if validation.strict {
+					continue;
+				}
+			}
+		}
+
+		for mut asdu in sv_message.asdus {
+			let Some(stream) = Configuration::stream_index(&streams, asdu.svid) else {
+				counters.unused_svid_asdus.fetch_add(1, Ordering::Relaxed);
+				log::debug!("Ignoring ASDU from unused stream '{}'.", asdu.svid);
+				continue;
+			};
+			if streams[stream].is_none() && !default_stream_mixed_logged {
+				match default_stream_svid {
+					None => default_stream_svid = Some(asdu.svid.to_owned()),
+					Some(ref svid) if svid != asdu.svid => {
+						log::warn!(
+							"The channels without a stream are taking samples from both '{svid}' and '{}'. Set \
+							 default_svid, or the stream of each channel, to choose which is used.",
+							asdu.svid
+						);
+						default_stream_mixed_logged = true;
+					}
+					Some(_) => {}
+				}
+			}
+
+			let smp_cnt_reset = configuration.smp_cnt_reset;
+			let previous_smp_cnt = previous_smp_cnts[stream].replace(asdu.smp_cnt);
+			if let Some(previous) = previous_smp_cnt {
+				if !smp_cnt_reset.is_consistent(configuration.sample_rate, previous, asdu.smp_cnt)
+					&& !smp_cnt_reset_mismatch_logged
+				{
+					log::warn!(
+						"smpCnt of stream '{}' went from {previous} to {}, which is inconsistent with resetting \
+						 every {} samples. Check the smp_cnt_reset option.",
+						asdu.svid,
+						asdu.smp_cnt,
+						smp_cnt_reset.period(configuration.sample_rate)
+					);
+					smp_cnt_reset_mismatch_logged = true;
+				}
+			}
+			// Every sample is tracked, including those skipped by decimation, so that only real gaps count as losses.
+			let lost = u64::from(gap_tracker.record(stream, asdu.smp_cnt));
+			if lost > 0 {
+				recent_lost_samples += lost;
+				counters.lost_samples.fetch_add(lost, Ordering::Relaxed);
+			}
+
+			// A mismatched sample rate would silently produce wrong timestamps, so it is reported, but only once to
+			// avoid flooding the log.
+			let effective_sample_rate = asdu.effective_sample_rate(configuration.nominal_frequency);
+			if let Some(rate) = effective_sample_rate.filter(|&rate| rate != f64::from(configuration.sample_rate)) {
+				if !sample_rate_mismatch_logged {
+					log::warn!(
+						"Stream '{}' has a sample rate of {rate} Hz (smpRate {:?}, smpMod {:?}), but the configured \
+						 sample rate is {} Hz.",
+						asdu.svid,
+						asdu.smp_rate,
+						asdu.smp_mod,
+						configuration.sample_rate
+					);
+					sample_rate_mismatch_logged = true;
+				}
+			}
+
+			// With frame decimation, the retained samples are spaced `frame_decimation` sample periods apart, so their
+			// sample counts are scaled down to match the output sample rate.
+			let Some(smp_cnt) = configuration.decimate(asdu.smp_cnt) else {
+				continue;
+			};
+
+			if !configuration.local_sync.accepts(asdu.smp_synch) {
+				log::debug!("Dropping locally synchronised sample {} from stream '{}'.", asdu.smp_cnt, asdu.svid);
+				continue;
+			}
+
+			let plausibility = &configuration.plausibility;
+			let out_of_bounds = asdu.sample.clamp_to_bounds(plausibility.current, plausibility.voltage);
+			if out_of_bounds > 0 {
+				counters.implausible_samples.fetch_add(1, Ordering::Relaxed);
+				recent_implausible_samples += 1;
+				log::debug!("Sample {} contains {out_of_bounds} implausible values.", asdu.smp_cnt);
+				if plausibility.action == OutOfBoundsAction::Drop {
+					continue;
+				}
+			}
+
+			asdu.smp_cnt = smp_cnt;
+
+			let sample_rate = configuration.output_sample_rate();
+			let recv_time =
+				SampleTime::checked_from_seconds_and_nanoseconds(timestamp_s, info.timestamp_ns, sample_rate);
+			let outcome = match (configuration.time_source.refr_tm(&asdu), recv_time) {
+				(Some(refr_tm), Some(recv_time)) => sample_buffer_queue.insert_sample_at(
+					stream,
+					SampleTime::from_utc_time(refr_tm, sample_rate),
+					recv_time,
+					sample_rate,
+					buffer_length,
+					asdu,
+				),
+				(Some(_), None) => InsertOutcome::InvalidTimestamp,
+				(None, _) => sample_buffer_queue.insert_sample(
+					stream,
+					timestamp_s,
 					info.timestamp_ns,
-					configuration.sample_rate,
+					sample_rate,
 					buffer_length,
 					asdu,
-				);
+				),
+			};
+			match outcome {
+				InsertOutcome::Inserted => {
+					counters.inserted_asdus.fetch_add(1, Ordering::Relaxed);
+				}
+				InsertOutcome::InvalidTimestamp => {
+					let total = counters.invalid_timestamp_samples.fetch_add(1, Ordering::Relaxed) + 1;
+					log::debug!("Dropped a sample whose timestamp cannot be represented ({total} dropped so far).");
+				}
 			}
 		}
-	})
+	}
+
+	// The receiver thread has stopped, and will report why.
+	Ok(())
+}
+
+/// Receives sampled value messages on a single interface and records the streams they belong to. Frames which cannot
+/// be parsed are ignored, since the purpose of discovery is to find out what is on the network. The thread stops once
+/// `stop` is set.
+fn discovery_thread_fn(
+	interface: &str,
+	configuration: &Configuration,
+	discovery: &Mutex<Discovery>,
+	stop: &AtomicBool,
+) -> Result<(), MainError> {
+	let mut recv_socket = EthernetSocket::new(OsStr::new(interface), configuration.mac_address)?;
+	if configuration.vlan_tags {
+		recv_socket.receive_vlan_tags()?;
+	}
+	if configuration.timestamp_source == TimestampSource::Hardware {
+		recv_socket.enable_hardware_timestamps()?;
+	}
+
+	log::info!("Bound socket to interface '{interface}'.");
+
+	let mut buf = [0_u8; 1522];
+
+	while !stop.load(Ordering::Relaxed) {
+		let Some(info) = recv_socket.recv_timeout(&mut buf, RECV_TIMEOUT)? else {
+			continue;
+		};
+		match parse(&buf[0..info.length]) {
+			Ok(message) => discovery.lock().unwrap().record(&message, info.source_mac, info.vlan_id),
+			Err(err) => log::debug!("Ignoring frame received on interface '{interface}': {err}"),
+		}
+	}
+	Ok(())
+}
+
+/// Receives on every configured interface for `duration`, then prints a table of the streams observed and exits.
+fn discover(configuration: &Configuration, duration: Duration) -> ! {
+	let discovery = Mutex::new(Discovery::new());
+	let stop = AtomicBool::new(false);
+
+	log::info!("Discovering streams for {} seconds.", duration.as_secs());
+
+	let elapsed = std::thread::scope(|scope| {
+		for interface in &configuration.interfaces {
+			let (discovery, stop) = (&discovery, &stop);
+			scope.spawn(move || {
+				if let Err(err) = discovery_thread_fn(interface, configuration, discovery, stop) {
+					log::error!("Stopped receiving on interface '{interface}': {err}");
+				}
+			});
+		}
+
+		let start = Instant::now();
+		std::thread::sleep(duration);
+		stop.store(true, Ordering::Relaxed);
+		start.elapsed()
+	});
+
+	print!("{}", discovery.into_inner().unwrap().to_table(elapsed));
+	std::process::exit(0);
+}
+
+/// Logs the throughput of the bridge and the number of queued buffers every `stats_interval_secs`, until `stop` is set.
+fn stats_thread_fn(
+	configuration: &Configuration,
+	sample_buffer_queue: &SampleBufferQueue,
+	counters: &Counters,
+	stop: &AtomicBool,
+) {
+	let interval = Duration::from_secs(configuration.stats_interval_secs);
+	let mut previous = Throughput::from_counters(counters);
+	let mut next_report = Instant::now() + interval;
+	while !stop.load(Ordering::Relaxed) {
+		// Sleep until the next report is due, but wake regularly so that the thread stops promptly.
+		let now = Instant::now();
+		if now < next_report {
+			std::thread::sleep((next_report - now).min(STOP_CHECK_INTERVAL));
+			continue;
+		}
+		next_report = now + interval;
+
+		let current = Throughput::from_counters(counters);
+		let recent = current.since(&previous);
+		previous = current;
+		log::info!(
+			"In the last {} s: {} frames received, {} parsed, {} ASDUs inserted, {} buffers sent, {} frames dropped \
+			 (receive queue full), {} buffers dropped (send queue full). {} buffers queued.",
+			configuration.stats_interval_secs,
+			recent.received_frames,
+			recent.parsed_frames,
+			recent.inserted_asdus,
+			recent.flushed_buffers,
+			recent.queue_full_frames,
+			recent.dropped_buffers,
+			sample_buffer_queue.len()
+		);
+	}
+}
+
+fn main() -> Result<(), MainError> {
+	let env = env_logger::Env::default().default_filter_or("info");
+	env_logger::init_from_env(env);
+
+	let args = CommandLineArgs::parse();
+
+	// Only discovery can run without a configuration file, which it needs just for the interfaces and address.
+	let mut configuration = args.config.as_deref().map(read_configuration).unwrap_or_default();
+
+	// These options can only be given when discovering.
+	if !args.interfaces.is_empty() {
+		configuration.interfaces = args.interfaces;
+	}
+	if let Some(mac_address) = args.mac_address {
+		configuration.mac_address = mac_address;
+	}
+
+	log::info!("Multicast address is '{}'.", &configuration.mac_address);
+
+	if let Some(seconds) = args.discover {
+		discover(&configuration, Duration::from_secs(seconds));
+	}
+
+	if let Err(err) = configuration.validate() {
+		log::error!("Invalid configuration: {err}");
+		std::process::exit(1);
+	}
+
+	// The configuration has been validated, so this cannot fail.
+	let buffer_length = configuration.buffer_length().unwrap();
+
+	let mut outputs = Vec::with_capacity(configuration.destinations.len());
+	for &destination in &configuration.destinations {
+		outputs.push(match configuration.protocol {
+			OutputProtocol::Udp => {
+				let output = UdpOutput::bind(destination)?;
+				let interface = configuration.multicast_interface.as_deref();
+				output.set_multicast_options(configuration.multicast_ttl, interface)?;
+				Output::Udp(output)
+			}
+			OutputProtocol::Tcp => Output::Tcp(TcpOutput::new(destination, configuration.tcp_max_pending)),
+		});
+	}
+
+	let mut sample_buffer_queue = SampleBufferQueue::with_stream_count(configuration.streams().len())
+		.with_send_delay(configuration.send_delay_secs)
+		.with_max_len(configuration.max_queued_buffers);
+	if args.pcap.is_some() {
+		sample_buffer_queue = sample_buffer_queue.with_replay_clock();
+	}
+	if configuration.smp_cnt_reset == SmpCntReset::Wrap {
+		// The sample counts are divided by the frame decimation factor, so they wrap sooner.
+		let period = SmpCntReset::Wrap.period(configuration.sample_rate) / configuration.frame_decimation;
+		sample_buffer_queue = sample_buffer_queue.with_smp_cnt_wrap(period);
+	}
+	let counters = Counters::new(configuration.channels.len());
+
+	// The receive interfaces are often dedicated to sampled values and have no IP address, so the output does not
+	// depend on them. Failing to find a route is not fatal, since it may appear later (e.g. once a link comes up).
+	for destination in &configuration.destinations {
+		match output::route_to(*destination) {
+			Ok(local) => log::info!("Datagrams will be sent from {local} to {destination}."),
+			Err(err) => log::warn!("There is currently no route to {destination}: {err}"),
+		}
+	}
+
+	let replay_name = args
+		.pcap
+		.as_ref()
+		.map(|path| path.file_stem().unwrap_or(path.as_os_str()).to_string_lossy().into_owned());
+
+	#[cfg(feature = "metrics")]
+	let metrics_listener = configuration.metrics_addr.map(std::net::TcpListener::bind).transpose()?;
+	#[cfg(feature = "metrics")]
+	if let Some(listener) = &metrics_listener {
+		log::info!("Serving metrics at http://{}/metrics.", listener.local_addr()?);
+	}
+	#[cfg(not(feature = "metrics"))]
+	if configuration.metrics_addr.is_some() {
+		log::warn!("Metrics will not be served, since this build does not include the metrics feature.");
+	}
+
+	let mut signals = Signals::new([SIGINT, SIGTERM])?;
+	let signals_handle = signals.handle();
+	let shutdown = AtomicBool::new(false);
+	// Stops the threads which only report on the bridge, once everything else has finished.
+	let stop_reporting = AtomicBool::new(false);
+
+	std::thread::scope(|scope| {
+		let sender_thread = scope.spawn(|| sender_thread_fn(&sample_buffer_queue, outputs, &configuration, &counters));
+
+		scope.spawn(|| stats_thread_fn(&configuration, &sample_buffer_queue, &counters, &stop_reporting));
+		#[cfg(feature = "metrics")]
+		if let Some(listener) = metrics_listener {
+			let (configuration, counters, stop_reporting) = (&configuration, &counters, &stop_reporting);
+			scope.spawn(move || mu_rust::metrics::serve(listener, configuration, counters, stop_reporting));
+		}
+
+		// On SIGINT or SIGTERM, stop receiving. The frames already received are then processed and the queued buffers
+		// sent before exiting. The signal iterator ends without a signal if every interface stops first.
+		let shutdown_ref = &shutdown;
+		scope.spawn(move || {
+			if let Some(signal) = signals.forever().next() {
+				log::info!("Received {}, shutting down.", signal_name(signal).unwrap_or("signal"));
+				shutdown_ref.store(true, Ordering::Relaxed);
+			}
+		});
+
+		// A capture file is processed in place of the interfaces, and is named after the file for logging and capture.
+		let inputs = match &replay_name {
+			Some(name) => std::slice::from_ref(name),
+			None => &configuration.interfaces[..],
+		};
+		let threads = inputs
+			.iter()
+			.map(|interface| {
+				let (configuration, sample_buffer_queue, counters) = (&configuration, &sample_buffer_queue, &counters);
+				let shutdown = &shutdown;
+				let (pcap, realtime) = (args.pcap.as_deref(), args.realtime);
+				let (sender, receiver) = mpsc::sync_channel(configuration.receive_queue_capacity);
+				let receiver_thread = scope.spawn(move || match pcap {
+					Some(path) => replay_thread_fn(path, realtime, sender, counters, shutdown),
+					None => receiver_thread_fn(interface, configuration, sender, counters, shutdown),
+				});
+				let processing_thread = scope.spawn(move || {
+					processing_thread_fn(
+						interface,
+						configuration,
+						receiver,
+						sample_buffer_queue,
+						counters,
+						buffer_length,
+					)
+				});
+				(interface, receiver_thread, processing_thread)
+			})
+			.collect::<Vec<_>>();
+
+		for (interface, receiver_thread, processing_thread) in threads {
+			if let Err(err) = receiver_thread.join().expect("receiver thread panicked") {
+				log::error!("Stopped receiving on interface '{interface}': {err}");
+			}
+			if let Err(err) = processing_thread.join().expect("processing thread panicked") {
+				log::error!("Stopped processing frames from interface '{interface}': {err}");
+			}
+		}
+
+		let shutting_down = shutdown.load(Ordering::Relaxed);
+		if args.pcap.is_none() && !shutting_down {
+			log::error!("No interfaces are receiving.");
+		}
+		signals_handle.close();
+		sample_buffer_queue.set_done();
+		sender_thread.join().expect("sender thread panicked");
+		stop_reporting.store(true, Ordering::Relaxed);
+	});
+
+	counters.log_summary();
+	if args.pcap.is_none() && !shutdown.load(Ordering::Relaxed) {
+		std::process::exit(1);
+	}
+	Ok(())
 }
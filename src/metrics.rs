@@ -0,0 +1,222 @@
+use std::{
+	fmt::Write as _,
+	io::{ErrorKind, Read, Write},
+	net::{TcpListener, TcpStream},
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+	time::Duration,
+};
+
+use crate::{config::Configuration, stats::Counters};
+
+/// How often the server checks whether it should stop while no connections are waiting.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The maximum time to wait for a client to send its request, so that a stalled client cannot block the server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The largest request which is read. Only the request line is used, so anything beyond this is ignored.
+const MAX_REQUEST_LENGTH: usize = 4096;
+
+/// Serves the counters at `/metrics` in the Prometheus text format, until `stop` is set. Connections are handled one
+/// at a time, which is enough for a scraper polling every few seconds.
+pub fn serve(listener: TcpListener, configuration: &Configuration, counters: &Counters, stop: &AtomicBool) {
+	if let Err(err) = listener.set_nonblocking(true) {
+		log::error!("Unable to serve metrics: {err}");
+		return;
+	}
+	while !stop.load(Ordering::Relaxed) {
+		match listener.accept() {
+			Ok((stream, _)) => {
+				if let Err(err) = respond(stream, configuration, counters) {
+					log::debug!("Unable to respond to metrics request: {err}");
+				}
+			}
+			Err(err) if err.kind() == ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_INTERVAL),
+			Err(err) => log::warn!("Unable to accept metrics connection: {err}"),
+		}
+	}
+}
+
+fn respond(mut stream: TcpStream, configuration: &Configuration, counters: &Counters) -> std::io::Result<()> {
+	stream.set_nonblocking(false)?;
+	stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+	let mut request = Vec::new();
+	let mut buf = [0; 1024];
+	while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_LENGTH {
+		let length = stream.read(&mut buf)?;
+		if length == 0 {
+			break;
+		}
+		request.extend_from_slice(&buf[..length]);
+	}
+
+	let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+	let mut parts = request_line.split(|&b| b == b' ');
+	let (status, body) = match (parts.next(), parts.next()) {
+		(Some(b"GET"), Some(b"/metrics")) => ("200 OK", render(configuration, counters)),
+		(Some(b"GET"), Some(_)) => ("404 Not Found", "Not found\n".to_owned()),
+		_ => ("405 Method Not Allowed", "Method not allowed\n".to_owned()),
+	};
+	write!(
+		stream,
+		"HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+		 Connection: close\r\n\r\n{body}",
+		body.len()
+	)
+}
+
+/// Formats the counters in the Prometheus text format. Per-channel counters are labelled with the channel name.
+pub fn render(configuration: &Configuration, counters: &Counters) -> String {
+	let c = counters;
+	#[rustfmt::skip]
+	let totals = [
+		("frames_received", "Frames received on all interfaces.", &c.received_frames),
+		("frames_dropped", "Frames dropped because the receive queue was full.", &c.queue_full_frames),
+		("frames_filtered", "Frames rejected by source, APPID or simulation.", &c.filtered_frames),
+		("frames_decimated", "Frames skipped unparsed due to frame decimation.", &c.decimated_frames),
+		("frames_parsed", "Frames which were parsed successfully.", &c.parsed_frames),
+		("parse_errors", "Frames which could not be parsed.", &c.parse_errors),
+		("frames_invalid_timestamp", "Frames with a timestamp before 1970.", &c.invalid_timestamp_frames),
+		("frames_unexpected_svid", "Frames with an unexpected svID.", &c.unexpected_svid_frames),
+		("frames_irregular_spacing", "Frames with unevenly spaced ASDUs.", &c.irregular_spacing_frames),
+		("asdus_inserted", "ASDUs whose samples were inserted into a buffer.", &c.inserted_asdus),
+		("asdus_unused_svid", "ASDUs ignored because their svID is not used by any channel.", &c.unused_svid_asdus),
+		("samples_missing", "Samples not received in time for their buffer.", &c.missing_samples),
+		("samples_lost", "Samples detected as lost from gaps in smpCnt.", &c.lost_samples),
+		("samples_implausible", "Samples outside the plausibility bounds.", &c.implausible_samples),
+		("samples_invalid_timestamp", "Samples with an unrepresentable time.", &c.invalid_timestamp_samples),
+		("buffers_sent", "Buffers sent as OpenPMU datagrams.", &c.flushed_buffers),
+		("buffers_send_failures", "Buffers which could not be sent.", &c.send_failures),
+		("buffers_dropped", "Buffers dropped because the send queue was full.", &c.dropped_buffers),
+		("buffers_unsynchronised", "Buffers held while the clock was unsynchronised.", &c.unsynchronised_buffers),
+	];
+	#[rustfmt::skip]
+	let per_channel = [
+		("samples_clipped", "Samples clipped during quantization.", &c.clipped_samples),
+		("samples_saturated", "Samples at or beyond the saturation threshold.", &c.saturated_samples),
+	];
+
+	let mut metrics = Metrics(String::new());
+	for (name, help, value) in totals {
+		metrics.counter(name, help, value);
+	}
+	metrics.gauge("max_asdu_spacing", "Largest smpCnt spacing between ASDUs in a frame.", &c.max_asdu_spacing);
+	let channels = configuration.channels.iter().map(|channel| channel.name.as_str()).collect::<Vec<_>>();
+	for (name, help, values) in per_channel {
+		metrics.channel_counter(name, help, &channels, values);
+	}
+	metrics.0
+}
+
+/// The prefix of every metric name.
+const PREFIX: &str = "sv_bridge";
+
+struct Metrics(String);
+
+impl Metrics {
+	fn counter(&mut self, name: &str, help: &str, value: &AtomicU64) {
+		let name = format!("{PREFIX}_{name}_total");
+		self.header(&name, help, "counter");
+		// Writing to a `String` cannot fail.
+		writeln!(self.0, "{name} {}", value.load(Ordering::Relaxed)).unwrap();
+	}
+
+	fn gauge(&mut self, name: &str, help: &str, value: &AtomicU64) {
+		let name = format!("{PREFIX}_{name}");
+		self.header(&name, help, "gauge");
+		writeln!(self.0, "{name} {}", value.load(Ordering::Relaxed)).unwrap();
+	}
+
+	fn channel_counter(&mut self, name: &str, help: &str, channels: &[&str], values: &[AtomicU64]) {
+		let name = format!("{PREFIX}_{name}_total");
+		self.header(&name, help, "counter");
+		for (channel, value) in channels.iter().zip(values) {
+			let channel = escape_label(channel);
+			writeln!(
+				self.0,
+				"{name}{{channel=\"{channel}\"}} {}",
+				value.load(Ordering::Relaxed)
+			)
+			.unwrap();
+		}
+	}
+
+	fn header(&mut self, name: &str, help: &str, type_: &str) {
+		writeln!(self.0, "# HELP {name} {help}").unwrap();
+		writeln!(self.0, "# TYPE {name} {type_}").unwrap();
+	}
+}
+
+/// Escapes a label value, in which backslashes, double quotes and line feeds must be escaped.
+fn escape_label(value: &str) -> String {
+	value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{Shutdown, SocketAddr};
+
+	use super::*;
+	use crate::config::{OutputChannel, OutputChannelType};
+
+	fn test_configuration() -> Configuration {
+		Configuration::builder()
+			.interface("eth0")
+			.channel(OutputChannel::new("Va \"north\"", OutputChannelType::Voltage, "a", 4))
+			.destination("127.0.0.1:48001".parse().unwrap())
+			.build()
+			.unwrap()
+	}
+
+	#[test]
+	fn render_counters() {
+		let configuration = test_configuration();
+		let counters = Counters::new(1);
+		counters.received_frames.store(12, Ordering::Relaxed);
+		counters.saturated_samples[0].store(3, Ordering::Relaxed);
+
+		let metrics = render(&configuration, &counters);
+		let expected = "# TYPE sv_bridge_frames_received_total counter\nsv_bridge_frames_received_total 12\n";
+		assert!(metrics.contains(expected));
+		assert!(metrics.contains("# TYPE sv_bridge_max_asdu_spacing gauge\nsv_bridge_max_asdu_spacing 0\n"));
+		assert!(metrics.contains("sv_bridge_samples_saturated_total{channel=\"Va \\\"north\\\"\"} 3\n"));
+	}
+
+	fn request(address: SocketAddr, request: &str) -> String {
+		let mut stream = TcpStream::connect(address).unwrap();
+		stream.write_all(request.as_bytes()).unwrap();
+		stream.shutdown(Shutdown::Write).unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).unwrap();
+		response
+	}
+
+	#[test]
+	fn serve_metrics() {
+		let configuration = test_configuration();
+		let counters = Counters::new(1);
+		let stop = AtomicBool::new(false);
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+
+		std::thread::scope(|scope| {
+			scope.spawn(|| serve(listener, &configuration, &counters, &stop));
+
+			let response = request(address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+			assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+			let (_, body) = response.split_once("\r\n\r\n").unwrap();
+			assert_eq!(body, render(&configuration, &counters));
+
+			let response = request(address, "GET / HTTP/1.1\r\n\r\n");
+			assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+			let response = request(address, "POST /metrics HTTP/1.1\r\n\r\n");
+			assert!(
+				response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+				"{response}"
+			);
+
+			stop.store(true, Ordering::Relaxed);
+		});
+	}
+}
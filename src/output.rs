@@ -0,0 +1,476 @@
+use std::{
+	collections::VecDeque,
+	ffi::{c_int, c_uint, CString},
+	io::Write,
+	net::{AddrParseError, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, UdpSocket},
+	os::fd::{AsRawFd, RawFd},
+	time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+/// The delay before the first attempt to reconnect after a TCP connection fails. This doubles after each failed
+/// attempt, up to `MAX_RECONNECT_DELAY`.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// The maximum time to wait when connecting or writing to the TCP destination, so that an unresponsive destination
+/// does not stall the sender thread indefinitely.
+const TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Something which OpenPMU datagrams can be sent to.
+pub trait OutputSink {
+	fn send(&mut self, datagram: &[u8]) -> std::io::Result<()>;
+}
+
+/// The destination for OpenPMU datagrams.
+#[derive(Debug)]
+pub enum Output {
+	/// Each datagram is sent as a single UDP datagram.
+	Udp(UdpOutput),
+	/// Datagrams are written to a TCP stream.
+	Tcp(TcpOutput),
+}
+
+impl Output {
+	pub fn destination(&self) -> SocketAddr {
+		match self {
+			Self::Udp(output) => output.destination,
+			Self::Tcp(output) => output.destination,
+		}
+	}
+}
+
+impl OutputSink for Output {
+	fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+		match self {
+			Self::Udp(output) => output.send(datagram),
+			Self::Tcp(output) => OutputSink::send(output, datagram),
+		}
+	}
+}
+
+/// Sends each datagram to every output. A failure to send to one output is logged and does not prevent sending to the
+/// others; an error is returned only if every output failed.
+impl OutputSink for Vec<Output> {
+	fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+		let output_count = self.len();
+		send_to_each(self, datagram, |output, err| {
+			// With a single output, the caller reports the error.
+			if output_count > 1 {
+				log::warn!("Unable to send datagram to {}: {err}", output.destination());
+			}
+		})
+	}
+}
+
+/// Sends a datagram to each sink, calling `on_error` for each one which fails. Returns the last error only if every
+/// sink failed.
+fn send_to_each<S: OutputSink>(
+	sinks: &mut [S],
+	datagram: &[u8],
+	mut on_error: impl FnMut(&S, &std::io::Error),
+) -> std::io::Result<()> {
+	let mut sent = false;
+	let mut result = Ok(());
+	for sink in sinks {
+		match sink.send(datagram) {
+			Ok(()) => sent = true,
+			Err(err) => {
+				on_error(sink, &err);
+				result = Err(err);
+			}
+		}
+	}
+	if sent {
+		Ok(())
+	} else {
+		result
+	}
+}
+
+/// Sends each OpenPMU datagram as a single UDP datagram.
+#[derive(Debug)]
+pub struct UdpOutput {
+	socket: UdpSocket,
+	destination: SocketAddr,
+}
+
+impl UdpOutput {
+	pub fn new(socket: UdpSocket, destination: SocketAddr) -> Self {
+		Self { socket, destination }
+	}
+
+	/// Creates an output with a new socket, bound to an ephemeral port on the unspecified address of the same family
+	/// as `destination`, since a socket of one family cannot send to an address of the other.
+	pub fn bind(destination: SocketAddr) -> std::io::Result<Self> {
+		let socket = UdpSocket::bind(unspecified_address(destination))?;
+		Ok(Self::new(socket, destination))
+	}
+
+	/// If the destination is a multicast address, sets the TTL (or IPv6 hop limit) of datagrams and the name of the
+	/// interface they are sent from. Options which are `None` keep the system default. Nothing is changed for a unicast
+	/// destination.
+	pub fn set_multicast_options(&self, ttl: Option<u32>, interface: Option<&str>) -> std::io::Result<()> {
+		if !self.destination.ip().is_multicast() {
+			return Ok(());
+		}
+		let interface_index = interface.map(interface_index).transpose()?;
+		let fd = self.socket.as_raw_fd();
+		match self.destination {
+			SocketAddr::V4(_) => {
+				if let Some(ttl) = ttl {
+					self.socket.set_multicast_ttl_v4(ttl)?;
+				}
+				if let Some(index) = interface_index {
+					let request = libc::ip_mreqn {
+						imr_multiaddr: libc::in_addr { s_addr: 0 },
+						imr_address: libc::in_addr { s_addr: 0 },
+						imr_ifindex: index as c_int,
+					};
+					setsockopt(fd, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &request)?;
+				}
+			}
+			SocketAddr::V6(_) => {
+				if let Some(hops) = ttl {
+					setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS, &(hops as c_int))?;
+				}
+				if let Some(index) = interface_index {
+					setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, &(index as c_uint))?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Sets a socket option whose value is a `T`.
+fn setsockopt<T>(fd: RawFd, level: c_int, name: c_int, value: &T) -> std::io::Result<()> {
+	let length = std::mem::size_of::<T>() as libc::socklen_t;
+	let result = unsafe { libc::setsockopt(fd, level, name, (value as *const T).cast(), length) };
+	// `setsockopt` returns -1 on error, with the error code in `errno`.
+	if result == 0 {
+		Ok(())
+	} else {
+		Err(std::io::Error::last_os_error())
+	}
+}
+
+impl OutputSink for UdpOutput {
+	fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+		self.socket.send_to(datagram, self.destination).map(|_| ())
+	}
+}
+
+/// Determines the local address which datagrams to `destination` would be sent from, which checks that there is a route
+/// to it. The output does not use the interfaces which sampled values are received on, so those do not need an IP
+/// address; datagrams leave through whichever interface the routing table selects for the destination.
+pub fn route_to(destination: SocketAddr) -> std::io::Result<SocketAddr> {
+	// Connecting a UDP socket sends nothing, but fails if there is no route to the destination.
+	let socket = UdpSocket::bind(unspecified_address(destination))?;
+	socket.connect(destination)?;
+	socket.local_addr()
+}
+
+/// Returns the unspecified address, with port 0, of the same family as `destination`.
+fn unspecified_address(destination: SocketAddr) -> SocketAddr {
+	match destination {
+		SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+		SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum DestinationError {
+	#[error("invalid destination address: {0}")]
+	Invalid(#[from] AddrParseError),
+	#[error("unknown interface '{name}' in destination address: {source}")]
+	UnknownInterface { name: String, source: std::io::Error },
+}
+
+/// Parses a destination address. As well as the formats accepted by `SocketAddr`, which include a numeric zone for
+/// IPv6 addresses (`[fe80::1%2]:48001`), the zone may be given as the name of an interface (`[fe80::1%eth0]:48001`).
+/// A zone is needed for link-local destinations, to select the interface that datagrams are sent from.
+pub fn parse_destination(destination: &str) -> Result<SocketAddr, DestinationError> {
+	let err = match destination.parse() {
+		Ok(address) => return Ok(address),
+		Err(err) => err,
+	};
+	let zoned = destination
+		.strip_prefix('[')
+		.and_then(|rest| rest.split_once("]:"))
+		.and_then(|(address, port)| {
+			let (address, name) = address.split_once('%')?;
+			Some((address.parse::<Ipv6Addr>().ok()?, name, port.parse::<u16>().ok()?))
+		});
+	let Some((address, name, port)) = zoned else {
+		return Err(err.into());
+	};
+	let scope_id = interface_index(name).map_err(|source| DestinationError::UnknownInterface {
+		name: name.to_owned(),
+		source,
+	})?;
+	Ok(SocketAddrV6::new(address, port, 0, scope_id).into())
+}
+
+/// Obtains the index of the network interface with the given name.
+fn interface_index(name: &str) -> std::io::Result<u32> {
+	// `if_nametoindex` expects a null terminated string.
+	let c_name = CString::new(name).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+	let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+	// `if_nametoindex` returns 0 on error, with the error code in `errno`.
+	if index == 0 {
+		Err(std::io::Error::last_os_error())
+	} else {
+		Ok(index)
+	}
+}
+
+/// Writes OpenPMU datagrams to a TCP stream, each preceded by its length as a 4 byte big-endian integer.
+///
+/// If the connection fails, it is re-established with an exponentially increasing delay between attempts. While
+/// disconnected, up to `max_pending` datagrams are held and sent once the connection is re-established; older
+/// datagrams are dropped once this limit is reached.
+#[derive(Debug)]
+pub struct TcpOutput {
+	destination: SocketAddr,
+	stream: Option<TcpStream>,
+	/// The earliest time at which the next connection attempt may be made.
+	next_attempt: Instant,
+	reconnect_delay: Duration,
+	pending: VecDeque<Vec<u8>>,
+	max_pending: usize,
+}
+
+impl TcpOutput {
+	/// Creates a new TCP output. The connection is established when the first datagram is sent.
+	pub fn new(destination: SocketAddr, max_pending: usize) -> Self {
+		Self {
+			destination,
+			stream: None,
+			next_attempt: Instant::now(),
+			reconnect_delay: MIN_RECONNECT_DELAY,
+			pending: VecDeque::new(),
+			max_pending,
+		}
+	}
+
+	/// Sends a datagram, or holds it until the connection is re-established.
+	pub fn send(&mut self, datagram: &[u8]) {
+		self.pending.push_back(datagram.to_vec());
+
+		if self.stream.is_none() && Instant::now() >= self.next_attempt {
+			self.connect();
+		}
+
+		if let Some(stream) = self.stream.as_mut() {
+			while let Some(datagram) = self.pending.front() {
+				let result = stream
+					.write_all(&(datagram.len() as u32).to_be_bytes())
+					.and_then(|()| stream.write_all(datagram));
+				if let Err(err) = result {
+					log::warn!("Lost connection to {}: {err}", self.destination);
+					self.stream = None;
+					self.schedule_reconnect();
+					break;
+				}
+				self.pending.pop_front();
+			}
+		}
+
+		while self.pending.len() > self.max_pending {
+			self.pending.pop_front();
+		}
+	}
+
+	fn connect(&mut self) {
+		let result = TcpStream::connect_timeout(&self.destination, TCP_TIMEOUT)
+			.and_then(|stream| stream.set_write_timeout(Some(TCP_TIMEOUT)).map(|()| stream));
+		match result {
+			Ok(stream) => {
+				log::info!("Connected to {}.", self.destination);
+				self.stream = Some(stream);
+				self.reconnect_delay = MIN_RECONNECT_DELAY;
+			}
+			Err(err) => {
+				log::warn!("Unable to connect to {}: {err}", self.destination);
+				self.schedule_reconnect();
+			}
+		}
+	}
+
+	fn schedule_reconnect(&mut self) {
+		self.next_attempt = Instant::now() + self.reconnect_delay;
+		self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+	}
+}
+
+/// Datagrams which cannot be sent immediately are held rather than failing, so this never returns an error.
+impl OutputSink for TcpOutput {
+	fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+		TcpOutput::send(self, datagram);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{io::Read, net::TcpListener};
+
+	use super::*;
+
+	#[test]
+	fn route_to_loopback() {
+		let local = route_to("127.0.0.1:48001".parse().unwrap()).unwrap();
+		assert!(local.ip().is_loopback());
+	}
+
+	#[test]
+	fn udp_output() {
+		let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let mut output = Output::Udp(UdpOutput::new(socket, receiver.local_addr().unwrap()));
+		output.send(b"datagram").unwrap();
+
+		let mut buf = [0; 16];
+		let length = receiver.recv(&mut buf).unwrap();
+		assert_eq!(buf[..length], *b"datagram");
+	}
+
+	#[test]
+	fn udp_output_ipv6() {
+		let receiver = UdpSocket::bind("[::1]:0").unwrap();
+		let mut output = UdpOutput::bind(receiver.local_addr().unwrap()).unwrap();
+		assert!(output.socket.local_addr().unwrap().is_ipv6());
+		output.send(b"datagram").unwrap();
+
+		let mut buf = [0; 16];
+		let length = receiver.recv(&mut buf).unwrap();
+		assert_eq!(buf[..length], *b"datagram");
+	}
+
+	#[test]
+	fn multicast_options() {
+		let loopback = if cfg!(target_os = "linux") { "lo" } else { "lo0" };
+		let output = UdpOutput::bind("239.255.0.1:48001".parse().unwrap()).unwrap();
+		output.set_multicast_options(Some(16), Some(loopback)).unwrap();
+		assert_eq!(output.socket.multicast_ttl_v4().unwrap(), 16);
+
+		let output = UdpOutput::bind("[ff05::1]:48001".parse().unwrap()).unwrap();
+		output.set_multicast_options(Some(16), Some(loopback)).unwrap();
+		output
+			.set_multicast_options(None, Some("nonexistent0"))
+			.expect_err("should fail for an unknown interface");
+
+		// Unicast destinations are left unchanged.
+		let output = UdpOutput::bind("127.0.0.1:48001".parse().unwrap()).unwrap();
+		output.set_multicast_options(Some(16), Some("nonexistent0")).unwrap();
+		assert_eq!(output.socket.multicast_ttl_v4().unwrap(), 1);
+	}
+
+	#[test]
+	fn destination_zone() {
+		let destination = parse_destination("127.0.0.1:48001").unwrap();
+		assert_eq!(destination, "127.0.0.1:48001".parse().unwrap());
+		let SocketAddr::V6(destination) = parse_destination("[fe80::1%2]:48001").unwrap() else {
+			panic!("should be an IPv6 address");
+		};
+		assert_eq!((destination.scope_id(), destination.port()), (2, 48001));
+
+		let loopback = if cfg!(target_os = "linux") { "lo" } else { "lo0" };
+		let SocketAddr::V6(destination) = parse_destination(&format!("[fe80::1%{loopback}]:48001")).unwrap() else {
+			panic!("should be an IPv6 address");
+		};
+		assert_eq!(destination.scope_id(), interface_index(loopback).unwrap());
+
+		assert!(matches!(
+			parse_destination("[fe80::1%nonexistent0]:48001"),
+			Err(DestinationError::UnknownInterface { .. })
+		));
+		assert!(matches!(parse_destination("[fe80::1%lo]"), Err(DestinationError::Invalid(_))));
+	}
+
+	#[test]
+	fn fan_out() {
+		let receivers = [UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap()];
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let mut outputs = receivers
+			.iter()
+			.map(|receiver| Output::Udp(UdpOutput::new(socket.try_clone().unwrap(), receiver.local_addr().unwrap())))
+			.collect::<Vec<_>>();
+
+		outputs.send(b"datagram").unwrap();
+		for receiver in &receivers {
+			let mut buf = [0; 16];
+			let length = receiver.recv(&mut buf).unwrap();
+			assert_eq!(buf[..length], *b"datagram");
+		}
+	}
+
+	/// A sink which records the datagrams sent to it, or fails to send every datagram.
+	#[derive(Debug, Default)]
+	struct MockSink {
+		fail: bool,
+		sent: Vec<Vec<u8>>,
+	}
+
+	impl OutputSink for MockSink {
+		fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+			if self.fail {
+				return Err(std::io::ErrorKind::ConnectionRefused.into());
+			}
+			self.sent.push(datagram.to_vec());
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn fan_out_with_failures() {
+		let failing = MockSink {
+			fail: true,
+			..MockSink::default()
+		};
+		let mut sinks = [failing, MockSink::default(), MockSink::default()];
+		let mut errors = 0;
+
+		// One sink failing does not prevent sending to the others.
+		send_to_each(&mut sinks, b"datagram", |_, _| errors += 1).unwrap();
+		assert_eq!(errors, 1);
+		assert!(sinks[1..].iter().all(|sink| sink.sent == [b"datagram"]));
+
+		let result = send_to_each(&mut sinks[..1], b"datagram", |_, _| {});
+		assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::ConnectionRefused);
+	}
+
+	#[test]
+	fn tcp_length_prefix() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut output = TcpOutput::new(listener.local_addr().unwrap(), 0);
+
+		output.send(b"first");
+		output.send(b"second datagram");
+
+		let (mut stream, _) = listener.accept().unwrap();
+		let mut received = vec![0; 4 + 5 + 4 + 15];
+		stream.read_exact(&mut received).unwrap();
+		assert_eq!(received, b"\x00\x00\x00\x05first\x00\x00\x00\x0Fsecond datagram");
+	}
+
+	#[test]
+	fn tcp_disconnected() {
+		// Find an address with nothing listening on it.
+		let destination = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+		let mut output = TcpOutput::new(destination, 2);
+		for datagram in [b"a", b"b", b"c"] {
+			output.send(datagram);
+		}
+		assert!(output.stream.is_none());
+		assert_eq!(output.pending, [b"b", b"c"]);
+
+		let mut output = TcpOutput::new(destination, 0);
+		output.send(b"a");
+		assert!(output.pending.is_empty());
+	}
+}
@@ -0,0 +1,548 @@
+use std::{
+	fs::File,
+	io::{BufReader, Read},
+	path::Path,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	ethernet::{split_vlan_tci, MacAddress, RecvInfo, SvFrame},
+	source::PacketSource,
+};
+
+/// The magic numbers identifying a pcap file, with microsecond and nanosecond resolution timestamps respectively.
+const PCAP_MAGIC_US: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NS: u32 = 0xA1B2_3C4D;
+
+/// The pcapng block types which are used. Every pcapng file starts with a section header block.
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+/// The magic number in a pcapng section header block, from which the section's byte order is determined.
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// The interface description option giving the resolution of the interface's timestamps.
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+/// The pcap link type for Ethernet frames.
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// The largest frame or block which will be read, to avoid allocating huge buffers for corrupt files. This is the
+/// maximum snapshot length used by libpcap.
+const MAX_LENGTH: usize = 262_144;
+
+/// A pcapng interface, along with the number of units per second of its timestamps.
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+	link_type: u32,
+	units_per_second: u64,
+}
+
+#[derive(Debug)]
+enum Format {
+	Pcap {
+		big_endian: bool,
+		nanosecond: bool,
+	},
+	PcapNg {
+		big_endian: bool,
+		interfaces: Vec<Interface>,
+	},
+}
+
+/// Reads sampled value frames from a pcap or pcapng capture file, so that a capture can be processed in the same way as
+/// frames received from a network interface.
+///
+/// As with `EthernetSocket`, only the payload of each frame is returned, without the Ethernet header or any VLAN tags.
+/// Frames with a different EtherType, or captured on interfaces other than Ethernet, are skipped. Each frame's
+/// timestamp is the time at which it was captured.
+///
+/// By default, frames are read as fast as possible. In real time mode, each frame is returned once the same time has
+/// passed since the first frame as passed between them when they were captured.
+///
+/// Once the end of the file is reached, `recv` fails with `ErrorKind::UnexpectedEof`.
+#[derive(Debug)]
+pub struct PcapSource<R = BufReader<File>> {
+	reader: R,
+	format: Format,
+	realtime: bool,
+	/// The time at which the first frame was returned in real time mode, along with its capture timestamp.
+	replay_start: Option<(Instant, Duration)>,
+}
+
+impl PcapSource {
+	/// Opens the capture file at `path`.
+	pub fn open(path: &Path) -> std::io::Result<Self> {
+		Self::new(BufReader::new(File::open(path)?))
+	}
+}
+
+impl<R: Read> PcapSource<R> {
+	/// Creates a source which reads a capture file from `reader`, detecting its format from its header.
+	pub fn new(mut reader: R) -> std::io::Result<Self> {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic)?;
+
+		let format = if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+			let mut header = [0; 8];
+			header[0..4].copy_from_slice(&magic);
+			reader.read_exact(&mut header[4..8])?;
+			let mut big_endian = false;
+			read_block_body(&mut reader, header, &mut big_endian)?;
+			Format::PcapNg {
+				big_endian,
+				interfaces: vec![],
+			}
+		} else {
+			let big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+				(PCAP_MAGIC_US | PCAP_MAGIC_NS, _) => false,
+				(_, PCAP_MAGIC_US | PCAP_MAGIC_NS) => true,
+				_ => return Err(invalid_data("not a pcap or pcapng file".to_owned())),
+			};
+
+			// The rest of the header contains the version, time zone, timestamp accuracy, snapshot length and link
+			// type, of which only the link type is needed.
+			let mut header = [0; 20];
+			reader.read_exact(&mut header)?;
+			let link_type = read_u32(&header[16..20], big_endian);
+			if link_type != LINKTYPE_ETHERNET {
+				return Err(invalid_data(format!("unsupported link type {link_type}")));
+			}
+
+			Format::Pcap {
+				big_endian,
+				nanosecond: read_u32(&magic, big_endian) == PCAP_MAGIC_NS,
+			}
+		};
+
+		Ok(Self {
+			reader,
+			format,
+			realtime: false,
+			replay_start: None,
+		})
+	}
+
+	/// Sets whether frames are returned at the rate at which they were captured, rather than as fast as possible.
+	pub fn with_realtime(mut self, realtime: bool) -> Self {
+		self.realtime = realtime;
+		self
+	}
+
+	/// Reads the next Ethernet frame, returning its capture timestamp (since the Unix epoch) and its contents.
+	fn read_frame(&mut self) -> std::io::Result<(Duration, Vec<u8>)> {
+		let Format::Pcap { big_endian, nanosecond } = self.format else {
+			return self.read_pcapng_frame();
+		};
+
+		let mut header = [0; 16];
+		self.reader.read_exact(&mut header)?;
+		let seconds = read_u32(&header[0..4], big_endian);
+		let fraction = read_u32(&header[4..8], big_endian);
+		let nanoseconds = if nanosecond {
+			fraction
+		} else {
+			fraction.saturating_mul(1000)
+		};
+		let frame = read_vec(&mut self.reader, read_u32(&header[8..12], big_endian) as usize)?;
+		Ok((Duration::new(seconds.into(), nanoseconds), frame))
+	}
+
+	fn read_pcapng_frame(&mut self) -> std::io::Result<(Duration, Vec<u8>)> {
+		let Format::PcapNg { big_endian, interfaces } = &mut self.format else {
+			unreachable!();
+		};
+
+		loop {
+			let mut header = [0; 8];
+			self.reader.read_exact(&mut header)?;
+			let (block_type, body) = read_block_body(&mut self.reader, header, big_endian)?;
+			let big_endian = *big_endian;
+			match block_type {
+				// Interfaces are numbered within each section.
+				PCAPNG_SECTION_HEADER => interfaces.clear(),
+				PCAPNG_INTERFACE_DESCRIPTION => interfaces.push(read_interface(&body, big_endian)?),
+				PCAPNG_ENHANCED_PACKET => {
+					if body.len() < 20 {
+						return Err(invalid_data("truncated enhanced packet block".to_owned()));
+					}
+					let interface_id = read_u32(&body[0..4], big_endian) as usize;
+					let Some(interface) = interfaces.get(interface_id) else {
+						return Err(invalid_data(format!(
+							"packet refers to unknown interface {interface_id}"
+						)));
+					};
+					if interface.link_type != LINKTYPE_ETHERNET {
+						continue;
+					}
+
+					let high = u64::from(read_u32(&body[4..8], big_endian));
+					let low = u64::from(read_u32(&body[8..12], big_endian));
+					let timestamp = (high << 32) | low;
+					let units = interface.units_per_second;
+					let nanoseconds = u128::from(timestamp % units) * 1_000_000_000 / u128::from(units);
+					let timestamp = Duration::new(timestamp / units, nanoseconds as u32);
+
+					let length = read_u32(&body[12..16], big_endian) as usize;
+					let Some(frame) = body.get(20..20 + length) else {
+						return Err(invalid_data("truncated enhanced packet block".to_owned()));
+					};
+					return Ok((timestamp, frame.to_vec()));
+				}
+				// Other blocks (e.g. statistics and name resolution) are not needed.
+				_ => {}
+			}
+		}
+	}
+
+	/// In real time mode, waits until the same time has passed since the first frame as passed between the first frame
+	/// and the frame captured at `timestamp`.
+	fn wait_for(&mut self, timestamp: Duration) {
+		if !self.realtime {
+			return;
+		}
+		let (start, first) = *self.replay_start.get_or_insert((Instant::now(), timestamp));
+		if let Some(remaining) = timestamp.saturating_sub(first).checked_sub(start.elapsed()) {
+			std::thread::sleep(remaining);
+		}
+	}
+}
+
+impl<R: Read> PacketSource for PcapSource<R> {
+	fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+		loop {
+			let (timestamp, frame) = self.read_frame()?;
+			let Some(SvFrame {
+				source_mac,
+				vlan_tci,
+				payload,
+			}) = SvFrame::parse(&frame)
+			else {
+				continue;
+			};
+			// `FrameRecorder` does not know the source address, and writes zero instead.
+			let source_mac = Some(source_mac).filter(|&source_mac| source_mac != MacAddress::from_bytes([0; 6]));
+			let (vlan_id, priority) = vlan_tci.map(split_vlan_tci).unzip();
+			let Some(destination) = buf.get_mut(..payload.len()) else {
+				return Err(invalid_data(format!("{} byte frame is too large", payload.len())));
+			};
+			destination.copy_from_slice(payload);
+
+			self.wait_for(timestamp);
+			return Ok(RecvInfo {
+				length: payload.len(),
+				timestamp_s: timestamp.as_secs() as i64,
+				timestamp_ns: timestamp.subsec_nanos(),
+				hardware_timestamp: false,
+				source_mac,
+				vlan_id,
+				priority,
+			});
+		}
+	}
+}
+
+/// Reads the rest of a pcapng block whose first 8 bytes (the block type and length) are `header`, returning its type
+/// and body. A section header block sets the byte order of the blocks which follow it.
+fn read_block_body(reader: &mut impl Read, header: [u8; 8], big_endian: &mut bool) -> std::io::Result<(u32, Vec<u8>)> {
+	// The section header block type reads the same in either byte order.
+	let block_type = read_u32(&header[0..4], *big_endian);
+	let mut body = vec![];
+	if block_type == PCAPNG_SECTION_HEADER {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic)?;
+		*big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+			(PCAPNG_BYTE_ORDER_MAGIC, _) => false,
+			(_, PCAPNG_BYTE_ORDER_MAGIC) => true,
+			_ => return Err(invalid_data("invalid pcapng byte order magic".to_owned())),
+		};
+		body.extend(magic);
+	}
+
+	// The length includes the type and length at the start of the block, and the copy of the length at the end.
+	let length = read_u32(&header[4..8], *big_endian) as usize;
+	if length < 12 + body.len() || !length.is_multiple_of(4) {
+		return Err(invalid_data(format!("invalid pcapng block length {length}")));
+	}
+	body.extend(read_vec(reader, length - 12 - body.len())?);
+	reader.read_exact(&mut [0; 4])?;
+	Ok((block_type, body))
+}
+
+/// Reads the link type and timestamp resolution from the body of an interface description block.
+fn read_interface(body: &[u8], big_endian: bool) -> std::io::Result<Interface> {
+	if body.len() < 8 {
+		return Err(invalid_data("truncated interface description block".to_owned()));
+	}
+	let mut interface = Interface {
+		link_type: u32::from(read_u16(&body[0..2], big_endian)),
+		units_per_second: 1_000_000,
+	};
+
+	// Options follow the link type, reserved field and snapshot length. Each has a code and length, and is padded to a
+	// multiple of 4 bytes.
+	let mut options = &body[8..];
+	while options.len() >= 4 {
+		let code = read_u16(&options[0..2], big_endian);
+		let length = read_u16(&options[2..4], big_endian) as usize;
+		let Some(&value) = options.get(4..4 + length).and_then(<[u8]>::first) else {
+			break;
+		};
+		if code == PCAPNG_OPTION_TSRESOL {
+			// The resolution is a negative power of 10, or of 2 if the most significant bit is set.
+			let units_per_second = if value & 0x80 == 0 {
+				10_u64.checked_pow(u32::from(value))
+			} else {
+				1_u64.checked_shl(u32::from(value & 0x7F))
+			};
+			interface.units_per_second = units_per_second
+				.filter(|&units| units != 0)
+				.ok_or_else(|| invalid_data(format!("unsupported timestamp resolution {value:#04X}")))?;
+		}
+		options = options.get(4 + length.next_multiple_of(4)..).unwrap_or_default();
+	}
+
+	Ok(interface)
+}
+
+fn read_vec(reader: &mut impl Read, length: usize) -> std::io::Result<Vec<u8>> {
+	if length > MAX_LENGTH {
+		return Err(invalid_data(format!("length {length} is too large")));
+	}
+	let mut buf = vec![0; length];
+	reader.read_exact(&mut buf)?;
+	Ok(buf)
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+	let bytes = bytes.try_into().unwrap();
+	if big_endian {
+		u16::from_be_bytes(bytes)
+	} else {
+		u16::from_le_bytes(bytes)
+	}
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+	let bytes = bytes.try_into().unwrap();
+	if big_endian {
+		u32::from_be_bytes(bytes)
+	} else {
+		u32::from_le_bytes(bytes)
+	}
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Cursor, ErrorKind};
+
+	use super::*;
+	use crate::{
+		capture::FrameRecorder,
+		config::CaptureConfig,
+		ethernet::{ETHERTYPE_QINQ, ETHERTYPE_SV, ETHERTYPE_VLAN},
+	};
+
+	const DESTINATION: [u8; 6] = [0x01, 0x0C, 0xCD, 0x04, 0x00, 0x01];
+	const SOURCE: [u8; 6] = [0x02, 0x00, 0x00, 0xAB, 0xCD, 0xEF];
+
+	/// Builds an Ethernet frame with the specified VLAN tags, each given by its EtherType and tag control information.
+	fn ethernet_frame(tags: &[(u16, u16)], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+		let mut frame = [DESTINATION, SOURCE].concat();
+		for &(tag_ethertype, tci) in tags {
+			frame.extend(tag_ethertype.to_be_bytes());
+			frame.extend(tci.to_be_bytes());
+		}
+		frame.extend(ethertype.to_be_bytes());
+		frame.extend(payload);
+		frame
+	}
+
+	fn recv_all<R: Read>(mut source: PcapSource<R>) -> Vec<(RecvInfo, Vec<u8>)> {
+		let mut buf = [0; 1522];
+		let mut received = vec![];
+		loop {
+			match source.recv(&mut buf) {
+				Ok(info) => received.push((info.clone(), buf[..info.length].to_vec())),
+				Err(err) if err.kind() == ErrorKind::UnexpectedEof => return received,
+				Err(err) => panic!("{err}"),
+			}
+		}
+	}
+
+	#[test]
+	fn recorded_pcap() {
+		let directory = std::env::temp_dir().join(format!("mu_rust_pcap_{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&directory);
+		let config = CaptureConfig {
+			directory: directory.clone(),
+			file_duration_secs: 10,
+			file_count: 1,
+			freeze_after_parse_errors: None,
+		};
+		let mut recorder = FrameRecorder::new(&config, "eth0", MacAddress::from_bytes(DESTINATION)).unwrap();
+		recorder.record(1000, 250_000_000, b"first").unwrap();
+		recorder.record(1001, 999_999_999, b"second").unwrap();
+		drop(recorder);
+
+		let source = PcapSource::open(&directory.join("eth0-1000.pcap")).unwrap();
+		let received = recv_all(source);
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0].1, b"first");
+		assert_eq!(
+			(received[0].0.timestamp_s, received[0].0.timestamp_ns),
+			(1000, 250_000_000)
+		);
+		// The recorder does not know the source address.
+		assert_eq!(received[0].0.source_mac, None);
+		assert_eq!(received[0].0.vlan_id, None);
+		assert_eq!(received[1].1, b"second");
+		assert_eq!(
+			(received[1].0.timestamp_s, received[1].0.timestamp_ns),
+			(1001, 999_999_999)
+		);
+
+		std::fs::remove_dir_all(&directory).unwrap();
+	}
+
+	#[test]
+	fn big_endian_microsecond_pcap() {
+		let mut file = vec![];
+		file.extend(PCAP_MAGIC_US.to_be_bytes());
+		file.extend([0, 2, 0, 4]); // Version
+		file.extend([0; 8]); // Time zone and accuracy
+		file.extend(65535_u32.to_be_bytes());
+		file.extend(LINKTYPE_ETHERNET.to_be_bytes());
+
+		let frames = [
+			ethernet_frame(&[], 0x0800, b"ipv4"),
+			ethernet_frame(&[(ETHERTYPE_VLAN, 0x8064)], ETHERTYPE_SV, b"tagged"),
+			ethernet_frame(&[(ETHERTYPE_QINQ, 0x2005), (ETHERTYPE_VLAN, 0x8064)], ETHERTYPE_SV, b"double tagged"),
+		];
+		for (i, frame) in frames.iter().enumerate() {
+			file.extend((2000 + i as u32).to_be_bytes());
+			file.extend(123_456_u32.to_be_bytes());
+			file.extend((frame.len() as u32).to_be_bytes());
+			file.extend((frame.len() as u32).to_be_bytes());
+			file.extend(frame);
+		}
+
+		let received = recv_all(PcapSource::new(Cursor::new(file)).unwrap());
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0].1, b"tagged");
+		assert_eq!(
+			(received[0].0.timestamp_s, received[0].0.timestamp_ns),
+			(2001, 123_456_000)
+		);
+		assert_eq!(received[0].0.source_mac, Some(MacAddress::from_bytes(SOURCE)));
+		assert_eq!((received[0].0.vlan_id, received[0].0.priority), (Some(100), Some(4)));
+		// The outermost tag is reported.
+		assert_eq!(received[1].1, b"double tagged");
+		assert_eq!((received[1].0.vlan_id, received[1].0.priority), (Some(5), Some(1)));
+	}
+
+	fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+		let padded_length = body.len().next_multiple_of(4);
+		let length = (12 + padded_length) as u32;
+		let mut block = vec![];
+		block.extend(block_type.to_le_bytes());
+		block.extend(length.to_le_bytes());
+		block.extend(body);
+		block.resize(8 + padded_length, 0);
+		block.extend(length.to_le_bytes());
+		block
+	}
+
+	fn pcapng_interface(link_type: u16, tsresol: Option<u8>) -> Vec<u8> {
+		let mut body = vec![];
+		body.extend(link_type.to_le_bytes());
+		body.extend([0; 2]);
+		body.extend(65535_u32.to_le_bytes());
+		if let Some(tsresol) = tsresol {
+			body.extend(PCAPNG_OPTION_TSRESOL.to_le_bytes());
+			body.extend(1_u16.to_le_bytes());
+			body.extend([tsresol, 0, 0, 0]);
+			body.extend([0; 4]); // End of options
+		}
+		pcapng_block(PCAPNG_INTERFACE_DESCRIPTION, &body)
+	}
+
+	fn pcapng_packet(interface_id: u32, timestamp: u64, frame: &[u8]) -> Vec<u8> {
+		let mut body = vec![];
+		body.extend(interface_id.to_le_bytes());
+		body.extend(((timestamp >> 32) as u32).to_le_bytes());
+		body.extend((timestamp as u32).to_le_bytes());
+		body.extend((frame.len() as u32).to_le_bytes());
+		body.extend((frame.len() as u32).to_le_bytes());
+		body.extend(frame);
+		pcapng_block(PCAPNG_ENHANCED_PACKET, &body)
+	}
+
+	#[test]
+	fn pcapng() {
+		let mut section_header = vec![];
+		section_header.extend(PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+		section_header.extend([1, 0, 0, 0]); // Version
+		section_header.extend(u64::MAX.to_le_bytes()); // Unknown section length
+
+		let mut file = pcapng_block(PCAPNG_SECTION_HEADER, &section_header);
+		file.extend(pcapng_interface(1, None));
+		// Interface 1 uses nanosecond timestamps, and interface 2 is not Ethernet.
+		file.extend(pcapng_interface(1, Some(9)));
+		file.extend(pcapng_interface(113, None));
+		file.extend(pcapng_packet(
+			0,
+			3_000_000_500_000,
+			&ethernet_frame(&[], ETHERTYPE_SV, b"microseconds"),
+		));
+		file.extend(pcapng_packet(2, 0, &ethernet_frame(&[], ETHERTYPE_SV, b"not ethernet")));
+		file.extend(pcapng_block(0x0000_0005, &[0; 8])); // Interface statistics
+		file.extend(pcapng_packet(
+			1,
+			3_000_000_000_123,
+			&ethernet_frame(&[], ETHERTYPE_SV, b"nanoseconds"),
+		));
+
+		let received = recv_all(PcapSource::new(Cursor::new(file)).unwrap());
+		assert_eq!(received.len(), 2);
+		assert_eq!(received[0].1, b"microseconds");
+		assert_eq!(
+			(received[0].0.timestamp_s, received[0].0.timestamp_ns),
+			(3_000_000, 500_000_000)
+		);
+		assert_eq!(received[1].1, b"nanoseconds");
+		assert_eq!((received[1].0.timestamp_s, received[1].0.timestamp_ns), (3000, 123));
+	}
+
+	#[test]
+	fn invalid_file() {
+		let err = PcapSource::new(Cursor::new(b"not a capture".to_vec())).unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn realtime() {
+		let mut file = vec![];
+		file.extend(PCAP_MAGIC_NS.to_le_bytes());
+		file.extend([2, 0, 4, 0]);
+		file.extend([0; 8]);
+		file.extend(65535_u32.to_le_bytes());
+		file.extend(LINKTYPE_ETHERNET.to_le_bytes());
+		for nanoseconds in [0_u32, 30_000_000] {
+			let frame = ethernet_frame(&[], ETHERTYPE_SV, b"frame");
+			file.extend(1000_u32.to_le_bytes());
+			file.extend(nanoseconds.to_le_bytes());
+			file.extend((frame.len() as u32).to_le_bytes());
+			file.extend((frame.len() as u32).to_le_bytes());
+			file.extend(frame);
+		}
+
+		let start = Instant::now();
+		let received = recv_all(PcapSource::new(Cursor::new(file)).unwrap().with_realtime(true));
+		assert_eq!(received.len(), 2);
+		assert!(start.elapsed() >= Duration::from_millis(30));
+	}
+}
@@ -1,21 +1,27 @@
 use std::{
 	collections::VecDeque,
-	fmt::Write,
-	net::{SocketAddr, UdpSocket},
 	sync::{
 		Condvar, Mutex,
-		atomic::{AtomicBool, Ordering},
+		atomic::{AtomicBool, AtomicU64, Ordering},
 	},
-	time::{Duration, SystemTime, UNIX_EPOCH},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use base64::Engine;
 use thiserror::Error;
 
 use crate::{
-	config::{OutputChannel, OutputChannelType},
+	clock::ClockSyncMonitor,
+	config::{
+		ClockSyncPolicy, Configuration, InitialBufferPolicy, LocalSyncPolicy, OutputChannel, OutputChannelType,
+		TimestampFormat, Transform,
+	},
+	output::OutputSink,
+	stats::{Counters, SaturationSummary},
+	xml::{escape_xml, XmlWriter},
 	Asdu,
-	Sample
+	Sample,
+	UtcTime
 };
 
 const NS_PER_SEC: u64 = 1_000_000_000;
@@ -43,15 +49,41 @@ pub struct SampleTime(u64);
 impl SampleTime {
 	/// Creates a new `SampleTime` from the specified number of seconds since the Unix epoch, plus the specified number of
 	/// sample periods. The number of seconds is converted to sample periods using the specified sample rate.
+	///
+	/// This overflows for times which cannot be represented, so `checked_from_seconds_and_samples` should be used for
+	/// untrusted times.
 	pub fn from_seconds_and_samples(seconds: u64, samples: u32, sample_rate: u32) -> Self {
 		Self(seconds * sample_rate as u64 + samples as u64)
 	}
 
+	/// Creates a new `SampleTime` in the same way as `from_seconds_and_samples`, returning `None` if the time cannot be
+	/// represented.
+	pub fn checked_from_seconds_and_samples(seconds: u64, samples: u32, sample_rate: u32) -> Option<Self> {
+		let time = seconds.checked_mul(sample_rate as u64)?.checked_add(samples as u64)?;
+		Some(Self(time))
+	}
+
 	/// Creates a new `SampleTime` from the specified number of seconds and nanoseconds since the Unix epoch.
+	///
+	/// This overflows for times which cannot be represented, so `checked_from_seconds_and_nanoseconds` should be used
+	/// for untrusted times.
 	pub fn from_seconds_and_nanoseconds(seconds: u64, nanoseconds: u32, sample_rate: u32) -> Self {
 		Self(seconds * sample_rate as u64 + nanoseconds as u64 * sample_rate as u64 / NS_PER_SEC)
 	}
 
+	/// Creates a new `SampleTime` in the same way as `from_seconds_and_nanoseconds`, returning `None` if the time
+	/// cannot be represented.
+	pub fn checked_from_seconds_and_nanoseconds(seconds: u64, nanoseconds: u32, sample_rate: u32) -> Option<Self> {
+		let samples = (nanoseconds as u64 * sample_rate as u64 / NS_PER_SEC) as u32;
+		Self::checked_from_seconds_and_samples(seconds, samples, sample_rate)
+	}
+
+	/// Creates a new `SampleTime` from an IEC 61850 UtcTime, rounded to the nearest sample period.
+	pub fn from_utc_time(time: UtcTime, sample_rate: u32) -> Self {
+		let samples = (time.nanoseconds() as u64 * sample_rate as u64 + NS_PER_SEC / 2) / NS_PER_SEC;
+		Self(time.seconds as u64 * sample_rate as u64 + samples)
+	}
+
 	/// Gets the number of whole seconds since the Unix epoch, assuming the specified number of samples per second.
 	pub fn as_secs(self, sample_rate: u32) -> u64 {
 		self.0 / sample_rate as u64
@@ -64,10 +96,18 @@ impl SampleTime {
 	}
 
 	/// Calculates a new `SampleTime` by adding the specified number of samples to this `SampleTime`.
+	///
+	/// This overflows if the result cannot be represented, so `checked_add_samples` should be used for untrusted times.
 	pub fn add_samples(self, samples: u32) -> Self {
 		Self(self.0 + samples as u64)
 	}
 
+	/// Calculates a new `SampleTime` in the same way as `add_samples`, returning `None` if the result cannot be
+	/// represented.
+	pub fn checked_add_samples(self, samples: u32) -> Option<Self> {
+		self.0.checked_add(samples as u64).map(Self)
+	}
+
 	/// Returns the number of seconds since the Unix epoch, including the fractional portion, as an `f64`.
 	pub fn as_secs_f64(self, sample_rate: u32) -> f64 {
 		self.0 as f64 / sample_rate as f64
@@ -81,40 +121,14 @@ impl SampleTime {
 	/// Converts this timestamp into a Gregorian calendar date and time. Returns a tuple containing the year, month,
 	/// day, hours, minutes, seconds and microseconds, in that order. The values for the day and month start at 1.
 	pub fn to_date_time(self, sample_rate: u32) -> (u32, u32, u32, u32, u32, u32, u32) {
-		// This implementation is based on the formulas presented in the book 'Calendrical Calculations' by Edward M.
-		// Reingold and Nachum Dershowitz.
-		// TODO: A more efficient algorithm could be used.
-
-		let date = self.0 / (86400 * sample_rate as u64) + fixed_from_gregorian(1970, 1, 1);
-
-		let d_0 = date - 1;
-		let n_400 = d_0 / 146097;
-		let d_1 = d_0 % 146097;
-		let n_100 = d_1 / 36524;
-		let d_2 = d_1 % 36524;
-		let n_4 = d_2 / 1461;
-		let d_3 = d_2 % 1461;
-		let n_1 = d_3 / 365;
-		let year = 400 * n_400 + 100 * n_100 + 4 * n_4 + n_1 + if n_100 == 4 || n_4 == 4 { 0 } else { 1 };
-
-		let prior_days = date - fixed_from_gregorian(year, 1, 1);
-		let correction = if date < fixed_from_gregorian(year, 3, 1) {
-			0
-		} else if is_gregorian_leap_year(year) {
-			1
-		} else {
-			2
-		};
-
-		let month = (12 * (prior_days + correction) + 373) / 367;
-		let day = date - fixed_from_gregorian(year, month, 1) + 1;
+		let (year, month, day) = civil_from_days(self.0 / (86400 * sample_rate as u64));
 
 		let time = (self.0 % (86400 * sample_rate as u64) / sample_rate as u64) as u32;
 		let hours = time / 3600;
 		let minutes = time % 3600 / 60;
 		let seconds = time % 60;
 
-		let microseconds = ((self.0 % sample_rate as u64) as f32 / sample_rate as f32 * 1_000_000.0) as u32;
+		let microseconds = (self.0 % sample_rate as u64 * 1_000_000 / sample_rate as u64) as u32;
 
 		(
 			year as u32,
@@ -126,23 +140,31 @@ impl SampleTime {
 			microseconds,
 		)
 	}
-}
 
-fn is_gregorian_leap_year(year: u64) -> bool {
-	year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+	/// Formats this timestamp as an ISO 8601 UTC date and time with microsecond precision, e.g.
+	/// `2024-01-01T00:00:00.000000Z`.
+	pub fn to_iso8601(self, sample_rate: u32) -> String {
+		let (year, month, day, hours, minutes, seconds, microseconds) = self.to_date_time(sample_rate);
+		format!("{year:04}-{month:02}-{day:02}T{hours:02}:{minutes:02}:{seconds:02}.{microseconds:06}Z")
+	}
 }
 
-/// Converts a date in the Gregorian calendar to the number of days since 0001-01-01 in the proleptic Gregorian
-/// calendar.
-#[rustfmt::skip]
-fn fixed_from_gregorian(year: u64, month: u64, day: u64) -> u64 {
-	365 * (year - 1)
-		+ (year - 1) / 4
-		- (year - 1) / 100
-		+ (year - 1) / 400
-		+ (367 * month - 362) / 12
-		+ day
-		- if month <= 2 { 0 } else if is_gregorian_leap_year(year) { 1 } else { 2 }
+/// Converts a number of days since 1970-01-01 into a year, month and day in the proleptic Gregorian calendar, using
+/// Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+	// Counting from 0000-03-01 puts the leap day at the end of each year, and makes each 400 year era start on the
+	// same day.
+	let days = days + 719_468;
+	let era = days / 146_097;
+	let day_of_era = days % 146_097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	// Months are counted from March, and have a repeating pattern of lengths over each five months.
+	let month_from_march = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+	let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+	let year = 400 * era + year_of_era + u64::from(month <= 2);
+	(year, month, day)
 }
 
 /// A struct containing sample data for a single channel in a sample buffer. The `SampleBuffer` struct contains one
@@ -165,16 +187,40 @@ impl SampleBufferChannel {
 		Self { buffer, max: 0.0 }
 	}
 
-	/// Inserts a sample at the specified index in the buffer, updating the `max` field if necessary.
-	/// TODO: What should happen if samples are inserted at the same position multiple times? Simply overwriting may
-	///       cause `max` to be incorrect.
+	/// Returns the number of samples whose magnitude is at or beyond `threshold`.
+	pub fn count_saturated(&self, threshold: f32) -> u32 {
+		self.buffer.iter().filter(|value| value.abs() >= threshold).count() as u32
+	}
+
+	/// Creates a copy of this channel with a transform applied to every sample.
+	pub fn transformed(&self, transform: &Transform) -> Self {
+		let buffer = self.buffer.iter().map(|&value| transform.apply(value)).collect::<Box<[f32]>>();
+		let max = buffer.iter().fold(0.0_f32, |max, value| max.max(value.abs()));
+		Self { buffer, max }
+	}
+
+	/// Inserts a sample at the specified index in the buffer, updating the `max` field if necessary. A duplicate sample
+	/// overwrites the existing one; if that was the largest, `max` is recalculated from the whole buffer.
 	pub fn insert_sample(&mut self, index: u32, value: f32) {
-		self.buffer[index as usize] = value;
-		self.max = self.max.max(value.abs());
+		let previous = std::mem::replace(&mut self.buffer[index as usize], value);
+		if value.abs() >= self.max {
+			self.max = value.abs();
+		} else if previous.abs() == self.max {
+			self.max = self.buffer.iter().fold(0.0_f32, |max, value| max.max(value.abs()));
+		}
 	}
 }
 
-const SEND_DELAY: f64 = 0.05;
+/// The result of inserting a sample into a `SampleBufferQueue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+	Inserted,
+	/// The sample was rejected because its timestamp, or the end of the buffer covering it, cannot be represented.
+	InvalidTimestamp,
+}
+
+/// How long after the end of each buffer's timespan it is sent, in seconds, unless configured otherwise.
+const DEFAULT_SEND_DELAY: f64 = 0.05;
 
 #[derive(Debug, Error)]
 pub enum BufferFlushError {
@@ -182,13 +228,17 @@ pub enum BufferFlushError {
 	Format(#[from] std::fmt::Error),
 	#[error(transparent)]
 	Io(#[from] std::io::Error),
+	#[error("channel payload decodes to {actual} bytes, but {expected} bytes were expected")]
+	PayloadLength { expected: usize, actual: usize },
 }
 
 /// A struct containing sample data corresponding to a particular period of time.
 #[derive(Debug)]
 pub struct SampleBuffer {
-	/// The sample data, split into individual channels.
-	channels: [SampleBufferChannel; 8],
+	/// The sample data for each input stream, split into individual channels.
+	streams: Vec<[SampleBufferChannel; 8]>,
+	/// Which positions in the buffer have received a sample, for each input stream.
+	received: Vec<Box<[bool]>>,
 	/// The sample rate of the samples in the buffer.
 	sample_rate: u32,
 	/// The timestamp corresponding to the first sample in the buffer.
@@ -199,69 +249,239 @@ pub struct SampleBuffer {
 	/// The number of samples in the buffer. The buffer's end time can be calculated by multiplying this number by
 	/// `sample_rate`.
 	length: u32,
+	/// Whether this is the first buffer after startup and it is known to be missing samples at its start, because
+	/// the first sample received was partway through the buffer's timespan.
+	partial: bool,
+	/// The svID of each input stream, taken from the first ASDU of that stream inserted into the buffer. This is `None`
+	/// for streams which have not contributed any samples.
+	svids: Vec<Option<String>>,
+	/// Whether any sample in the buffer came from a merging unit synchronised to a local clock.
+	local_sync: bool,
+	/// Whether the system clock was found to be unsynchronised when the buffer was sent.
+	clock_unsynchronised: bool,
+	/// The header fields of the ASDU whose sample started the buffer, if it was started by a sample.
+	first_asdu: Option<AsduHeader>,
+}
+
+/// The fields of an ASDU which describe the merging unit's state, rather than the sample itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AsduHeader {
+	smp_cnt: u16,
+	conf_rev: u32,
+	smp_synch: u8,
 }
 
 impl SampleBuffer {
-	/// Creates a new sample buffer with the specified start time, creation time, length and sample rate. All samples
-	/// are initialised to zero.
-	pub fn new(sample_rate: u32, start_time: SampleTime, creation_time: SampleTime, length: u32) -> Self {
-		let channels = std::array::from_fn(|_| SampleBufferChannel::new(length as usize));
+	/// Creates a new sample buffer with the specified start time, creation time, length and sample rate, holding
+	/// samples from `stream_count` input streams. All samples are initialised to zero.
+	pub fn new(
+		sample_rate: u32,
+		start_time: SampleTime,
+		creation_time: SampleTime,
+		length: u32,
+		stream_count: usize,
+	) -> Self {
+		let streams = (0..stream_count)
+			.map(|_| std::array::from_fn(|_| SampleBufferChannel::new(length as usize)))
+			.collect();
 		Self {
-			channels,
+			streams,
+			received: vec![vec![false; length as usize].into_boxed_slice(); stream_count],
 			sample_rate,
 			start_time,
 			creation_time,
 			length,
+			partial: false,
+			svids: vec![None; stream_count],
+			local_sync: false,
+			clock_unsynchronised: false,
+			first_asdu: None,
 		}
 	}
 
-	/// Insert a sample into the buffer at the specified position.
-	pub fn insert_sample(&mut self, smp_cnt: u32, sample: Sample) {
-		let index = smp_cnt - self.start_time.subsec_samples(self.sample_rate);
+	/// Returns the svID of each input stream which has contributed samples to this buffer, by stream index.
+	pub fn svids(&self) -> &[Option<String>] {
+		&self.svids
+	}
+
+	/// Records the svID of the specified input stream, if it is not already known.
+	fn set_svid(&mut self, stream: usize, svid: &str) {
+		if let Some(slot @ None) = self.svids.get_mut(stream) {
+			*slot = Some(svid.to_owned());
+		}
+	}
+
+	/// Returns whether this is an incomplete initial buffer (see the `partial` field).
+	pub fn is_partial(&self) -> bool {
+		self.partial
+	}
+
+	/// Insert a sample from the specified input stream into the buffer at the specified position. Samples whose
+	/// position is outside the buffer's timespan are ignored.
+	pub fn insert_sample(&mut self, stream: usize, smp_cnt: u32, sample: Sample) {
+		let Some(index) = smp_cnt.checked_sub(self.start_time.subsec_samples(self.sample_rate)) else {
+			return;
+		};
+		let Some(channels) = self.streams.get_mut(stream) else {
+			return;
+		};
 		if index < self.length {
-			self.channels[0].insert_sample(index, sample.current_a);
-			self.channels[1].insert_sample(index, sample.current_b);
-			self.channels[2].insert_sample(index, sample.current_c);
-			self.channels[3].insert_sample(index, sample.current_n);
-			self.channels[4].insert_sample(index, sample.voltage_a);
-			self.channels[5].insert_sample(index, sample.voltage_b);
-			self.channels[6].insert_sample(index, sample.voltage_c);
-			self.channels[7].insert_sample(index, sample.voltage_n);
+			self.received[stream][index as usize] = true;
+			for (channel, value) in channels.iter_mut().zip(sample.as_array()) {
+				channel.insert_sample(index, value);
+			}
 		}
 	}
 
-	/// Generates an OpenPMU XML sample datagram and sends it to the specified destination.
-	pub fn flush(&self, out_skt: &UdpSocket, dest: SocketAddr, channels: &[OutputChannel]) -> Result<(), BufferFlushError> {
+	/// Returns the number of positions in the buffer which have not received a sample, summed over all input streams.
+	pub fn missing_samples(&self) -> u64 {
+		self.received.iter().flatten().filter(|&&received| !received).count() as u64
+	}
+
+	/// Estimates the frequency of the signal in this buffer, in hertz, from every voltage output channel in
+	/// `configuration`. The channels are fitted together, so the estimate does not depend on any one phase having a
+	/// large signal. Returns `None` if the buffer is all zeros, or if the fit does not correspond to a sinusoid.
+	///
+	/// Unlike the `<Frequency>` element written for `frequency_from_voltages`, the estimate is not checked against the
+	/// nominal frequency.
+	pub fn estimate_frequency(&self, configuration: &Configuration) -> Option<f32> {
+		let streams = configuration.streams();
+		let fit = configuration
+			.channels
+			.iter()
+			.filter(|channel| matches!(channel.type_, OutputChannelType::Voltage))
+			.map(|channel| {
+				let stream = streams
+					.iter()
+					.position(|&s| s == configuration.channel_stream(channel))
+					.unwrap();
+				FrequencyFit::new(&self.streams[stream][channel.input_channel].buffer)
+			})
+			.fold(FrequencyFit::default(), FrequencyFit::combine);
+		fit.frequency(self.sample_rate).map(|frequency| frequency as f32)
+	}
+
+	/// Generates an OpenPMU XML sample datagram and sends it to the specified output.
+	pub fn flush(
+		&self,
+		output: &mut impl OutputSink,
+		configuration: &Configuration,
+		counters: &Counters,
+	) -> Result<(), BufferFlushError> {
+		let mut buf = self.to_xml(configuration, counters)?;
+
+		if let Some(size) = configuration.datagram_size {
+			if !pad_datagram(&mut buf, size) {
+				log::warn!("Datagram of {} bytes is larger than datagram_size ({size} bytes).", buf.len());
+			}
+		}
+
+		output.send(buf.as_bytes())?;
+		Ok(())
+	}
+
+	/// Generates an OpenPMU XML sample datagram, adding the number of samples clipped and saturated in each channel to
+	/// `counters`.
+	fn to_xml(&self, configuration: &Configuration, counters: &Counters) -> Result<String, BufferFlushError> {
+		let mut counts = vec![ChannelCounts::default(); configuration.channels.len()];
+		let xml = self.write_xml(configuration, &mut counts)?;
+		for (i, counts) in counts.into_iter().enumerate() {
+			counters.clipped_samples[i].fetch_add(counts.clipped.into(), Ordering::Relaxed);
+			counters.saturated_samples[i].fetch_add(counts.saturated.into(), Ordering::Relaxed);
+		}
+		Ok(xml)
+	}
+
+	/// Generates the OpenPMU XML sample datagram for this buffer, as sent by `flush`. Unlike `flush`, this does not
+	/// send the datagram or update any counters, so it can be used to test or reuse the output format.
+	pub fn to_openpmu_xml(&self, configuration: &Configuration) -> Result<String, BufferFlushError> {
+		self.write_xml(
+			configuration,
+			&mut vec![ChannelCounts::default(); configuration.channels.len()],
+		)
+	}
+
+	/// Generates an OpenPMU XML sample datagram, writing the number of samples clipped and saturated in each channel to
+	/// `counts`.
+	fn write_xml(
+		&self,
+		configuration: &Configuration,
+		counts: &mut [ChannelCounts],
+	) -> Result<String, BufferFlushError> {
 		let frame = self.start_time.subsec_samples(self.sample_rate) / self.length;
+		let channels = &configuration.channels;
+		let streams = configuration.streams();
 
-		let (year, month, day, hours, minutes, seconds, microseconds) = self.start_time.to_date_time(self.sample_rate);
-
-		let mut buf = String::new();
-		writeln!(&mut buf, "<OpenPMU>")?;
-		writeln!(&mut buf, "\t<Format>Samples</Format>")?;
-		writeln!(&mut buf, "\t<Date>{year:04}-{month:02}-{day:02}</Date>")?;
-		writeln!(
-			&mut buf,
-			"\t<Time>{hours:02}:{minutes:02}:{seconds:02}.{microseconds:06}</Time>"
-		)?;
-		writeln!(&mut buf, "\t<Frame>{frame}</Frame>")?;
-		writeln!(&mut buf, "\t<Fs>{}</Fs>", self.sample_rate)?;
-		writeln!(&mut buf, "\t<n>{}</n>", self.length)?;
-		writeln!(&mut buf, "\t<bits>16</bits>")?;
-		writeln!(&mut buf, "\t<Channels>{}</Channels>", channels.len())?;
+		let mut xml = XmlWriter::new(configuration.compact_xml);
+		xml.start("OpenPMU")?;
+		xml.element("Format", "Samples")?;
+		write_xml_timestamp(&mut xml, self.start_time, self.sample_rate, configuration.timestamp_format)?;
+		xml.element("Frame", frame)?;
+		xml.element("Fs", self.sample_rate)?;
+		xml.element("n", self.length)?;
+		xml.element("bits", configuration.bits)?;
+		xml.element("Channels", channels.len())?;
+		if self.partial && configuration.initial_buffer == InitialBufferPolicy::Flag {
+			xml.element("Partial", true)?;
+		}
+		if configuration.local_sync.flags(self.local_sync) {
+			xml.element("LocalSync", true)?;
+		}
+		if self.clock_unsynchronised {
+			xml.element("ClockUnsynchronised", true)?;
+		}
+		if let Some(first_asdu) = self.first_asdu.filter(|_| configuration.emit_asdu_header) {
+			xml.element("SmpCnt", first_asdu.smp_cnt)?;
+			xml.element("ConfRev", first_asdu.conf_rev)?;
+			xml.element("SmpSynch", first_asdu.smp_synch)?;
+		}
+		let nominal_frequency = configuration.nominal_frequency as f64;
+		let frequency = if let Some(reference) = &configuration.frequency_reference {
+			// The configuration has been validated, so the reference channel exists.
+			let channel = channels.iter().find(|channel| &channel.name == reference).unwrap();
+			let stream = streams.iter().position(|&s| s == configuration.channel_stream(channel)).unwrap();
+			let data = &self.streams[stream][channel.input_channel];
+			Some(estimate_frequency(&data.buffer, self.sample_rate, nominal_frequency))
+		} else if configuration.frequency_from_voltages {
+			let frequency = self.estimate_frequency(configuration).map(f64::from);
+			Some(frequency.filter(|&frequency| is_near_nominal(frequency, nominal_frequency)))
+		} else {
+			None
+		};
+		match frequency {
+			Some(Some(frequency)) => xml.element("Frequency", format_args!("{frequency:.4}"))?,
+			Some(None) => {
+				xml.element("Frequency", format_args!("{nominal_frequency:.4}"))?;
+				xml.element("FrequencyReliable", false)?;
+			}
+			None => {}
+		}
 
 		for (i, channel) in channels.iter().enumerate() {
-			let type_ = match channel.type_ {
-				OutputChannelType::Voltage => "V",
-				OutputChannelType::Current => "I",
+			// Every channel's stream is present in `streams`, since it is derived from the channels.
+			let stream = streams.iter().position(|&s| s == configuration.channel_stream(channel)).unwrap();
+			let data = &self.streams[stream][channel.input_channel];
+			// Saturation is a property of the measurement, so it is detected before calibration or transforms.
+			let saturated = configuration
+				.saturation
+				.for_type(&channel.type_)
+				.map(|threshold| data.count_saturated(threshold));
+			let transformed = channel.effective_transform().map(|transform| data.transformed(&transform));
+			let data = transformed.as_ref().unwrap_or(data);
+			let diagnostics = ChannelDiagnostics {
+				emit_clipped: configuration.emit_clip_counts,
+				saturated,
+			};
+			let clipped =
+				write_xml_channel_data(&mut xml, i, channel, data, self.length, configuration.bits, diagnostics)?;
+			counts[i] = ChannelCounts {
+				clipped,
+				saturated: saturated.unwrap_or(0),
 			};
-			write_xml_channel_data(&mut buf, i, &channel.name, type_, &channel.phase, &self.channels[channel.input_channel])?;
 		}
 
-		writeln!(&mut buf, "</OpenPMU>")?;
-
-		out_skt.send_to(buf.as_bytes(), dest)?;
-		Ok(())
+		xml.end("OpenPMU")?;
+		Ok(xml.into_string())
 	}
 
 	/// Given a sample timestamp, determines if it falls within this buffer's timespan.
@@ -274,52 +494,231 @@ impl SampleBuffer {
 		timestamp >= self.start_time.add_samples(self.length)
 	}
 
-	/// Calculates the time at which this buffer should be sent.
-	pub fn get_send_time(&self) -> f64 {
+	/// Calculates the time at which this buffer should be sent, `send_delay` seconds after the end of its timespan.
+	pub fn get_send_time(&self, send_delay: f64) -> f64 {
 		self.creation_time
 			.add_samples(self.length)
 			.as_secs_f64(self.sample_rate)
-			+ SEND_DELAY
+			+ send_delay
 	}
 }
 
+/// Writes the timestamp of a sample datagram in the specified format.
+fn write_xml_timestamp(
+	xml: &mut XmlWriter,
+	time: SampleTime,
+	sample_rate: u32,
+	format: TimestampFormat,
+) -> Result<(), BufferFlushError> {
+	match format {
+		TimestampFormat::Split => {
+			let (year, month, day, hours, minutes, seconds, microseconds) = time.to_date_time(sample_rate);
+			xml.element("Date", format_args!("{year:04}-{month:02}-{day:02}"))?;
+			xml.element("Time", format_args!("{hours:02}:{minutes:02}:{seconds:02}.{microseconds:06}"))?;
+		}
+		TimestampFormat::Iso8601 => {
+			xml.element("Timestamp", time.to_iso8601(sample_rate))?;
+		}
+	}
+	Ok(())
+}
+
+/// Estimates the frequency of the sinusoidal signal in `samples`, which were taken at `sample_rate`.
+///
+/// For a sinusoid with an angular frequency of ω radians per sample, `x[n - 1] + x[n + 1] = 2 cos(ω) x[n]` holds for
+/// every sample, so cos(ω) is found by a least-squares fit over the buffer. This works over windows shorter than a
+/// cycle, such as the half-cycle buffers used by default. Returns `None` if there is no signal, or if the estimate is
+/// more than 20% away from `nominal_frequency`, which suggests that the signal is too distorted to be useful.
+fn estimate_frequency(samples: &[f32], sample_rate: u32, nominal_frequency: f64) -> Option<f64> {
+	let frequency = FrequencyFit::new(samples).frequency(sample_rate)?;
+	is_near_nominal(frequency, nominal_frequency).then_some(frequency)
+}
+
+/// Whether `frequency` is close enough to `nominal_frequency` for an estimate to be trusted.
+fn is_near_nominal(frequency: f64, nominal_frequency: f64) -> bool {
+	(frequency - nominal_frequency).abs() <= 0.2 * nominal_frequency
+}
+
+/// The sums of the least-squares fit of cos(ω) used by `estimate_frequency`. Fits of several signals with the same
+/// frequency, such as the phases of a three-phase system, can be combined by adding their sums.
+#[derive(Debug, Clone, Copy, Default)]
+struct FrequencyFit {
+	numerator: f64,
+	denominator: f64,
+}
+
+impl FrequencyFit {
+	fn new(samples: &[f32]) -> Self {
+		let mut fit = Self::default();
+		for window in samples.windows(3) {
+			let [previous, current, next] = [window[0], window[1], window[2]].map(f64::from);
+			fit.numerator += current * (previous + next);
+			fit.denominator += 2.0 * current * current;
+		}
+		fit
+	}
+
+	fn combine(self, other: Self) -> Self {
+		Self {
+			numerator: self.numerator + other.numerator,
+			denominator: self.denominator + other.denominator,
+		}
+	}
+
+	/// Returns the fitted frequency, or `None` if there was no signal or the fit does not correspond to a sinusoid.
+	fn frequency(self, sample_rate: u32) -> Option<f64> {
+		if self.denominator == 0.0 {
+			return None;
+		}
+		let cos_omega = self.numerator / self.denominator;
+		if !(-1.0..=1.0).contains(&cos_omega) {
+			return None;
+		}
+		Some(cos_omega.acos() * sample_rate as f64 / std::f64::consts::TAU)
+	}
+}
+
+/// Pads a datagram with spaces so that it is `size` bytes long. Whitespace after the root element is permitted by XML,
+/// so the padding is ignored by consumers. Returns `false`, leaving the datagram unchanged, if it is already longer
+/// than `size`.
+fn pad_datagram(buf: &mut String, size: usize) -> bool {
+	if buf.len() > size {
+		return false;
+	}
+	buf.extend(std::iter::repeat_n(' ', size - buf.len()));
+	true
+}
+
+/// The number of samples clipped and saturated in one channel of a buffer.
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelCounts {
+	clipped: u32,
+	saturated: u32,
+}
+
+/// The diagnostic elements written for each channel of a datagram.
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelDiagnostics {
+	/// Whether to write the number of samples clipped during quantization.
+	emit_clipped: bool,
+	/// The number of samples at or beyond the saturation threshold, if one is configured for the channel.
+	saturated: Option<u32>,
+}
+
 fn write_xml_channel_data(
-	buf: &mut String,
+	xml: &mut XmlWriter,
 	index: usize,
-	name: &str,
-	type_: &str,
-	phase: &str,
+	output_channel: &OutputChannel,
 	channel: &SampleBufferChannel,
-) -> Result<(), BufferFlushError> {
-	writeln!(buf, "\t<Channel_{index}>")?;
-	writeln!(buf, "\t\t<Name>{name}</Name>")?;
-	writeln!(buf, "\t\t<Type>{type_}</Type>")?;
-	writeln!(buf, "\t\t<Phase>{phase}</Phase>")?;
-	writeln!(buf, "\t\t<Range>{}</Range>", channel.max)?;
-
-	let mut channel_bytes_buf = Vec::with_capacity(channel.buffer.len() * 2);
-	if channel.max == 0.0 {
-		channel_bytes_buf.resize(channel.buffer.len() * 2, 0);
+	n: u32,
+	bits: u32,
+	diagnostics: ChannelDiagnostics,
+) -> Result<u32, BufferFlushError> {
+	let type_ = match output_channel.type_ {
+		OutputChannelType::Voltage => "V",
+		OutputChannelType::Current => "I",
+	};
+	let range = output_channel.nominal.unwrap_or(channel.max);
+	let element = format!("Channel_{index}");
+
+	xml.start(&element)?;
+	xml.element("Name", escape_xml(&output_channel.name))?;
+	xml.element("Type", type_)?;
+	xml.element("Phase", escape_xml(&output_channel.phase))?;
+	xml.element("Range", range)?;
+
+	let (channel_bytes_buf, clipped) = build_channel(channel, range, bits);
+	if diagnostics.emit_clipped {
+		xml.element("Clipped", clipped)?;
+	}
+	if let Some(saturated) = diagnostics.saturated {
+		xml.element("Saturated", saturated)?;
+	}
+
+	check_payload_length(&channel_bytes_buf, n, bits as usize / 8)?;
+	xml.element_with("Payload", |buf| {
+		base64::engine::general_purpose::STANDARD.encode_string(&channel_bytes_buf, buf);
+	})?;
+
+	xml.end(&element)?;
+	Ok(clipped)
+}
+
+/// Checks that the bytes of a channel payload hold `n` samples of `bytes_per_sample` bytes each, as declared in the
+/// datagram, before they are encoded. This guards against producing a malformed datagram if the payload and metadata
+/// get out of step.
+fn check_payload_length(payload: &[u8], n: u32, bytes_per_sample: usize) -> Result<(), BufferFlushError> {
+	let expected = n as usize * bytes_per_sample;
+	let actual = payload.len();
+	if actual != expected {
+		return Err(BufferFlushError::PayloadLength { expected, actual });
+	}
+	Ok(())
+}
+
+/// Quantizes a channel's samples to the nearest integers of `bits` bits (16 or 32), such that a value of `range`
+/// corresponds to the largest positive integer. Returns the big-endian bytes of the quantized samples, along with the
+/// number of samples which were clipped because their magnitude exceeded `range`.
+fn build_channel(channel: &SampleBufferChannel, range: f32, bits: u32) -> (Vec<u8>, u32) {
+	let bytes_per_sample = bits as usize / 8;
+	let mut channel_bytes_buf = Vec::with_capacity(channel.buffer.len() * bytes_per_sample);
+	let mut clipped = 0;
+
+	if range == 0.0 {
+		channel_bytes_buf.resize(channel.buffer.len() * bytes_per_sample, 0);
 	} else {
+		// Scaling is done in double precision, since single precision cannot represent every 32-bit integer.
+		let full_scale = ((1_u64 << (bits - 1)) - 1) as f64;
 		for &value in &channel.buffer {
-			let converted = (value / channel.max * 32767.0) as i16;
-			channel_bytes_buf.extend(converted.to_be_bytes());
+			let scaled = f64::from(value) / f64::from(range) * full_scale;
+			if scaled.abs() > full_scale {
+				clipped += 1;
+			}
+			// Rounding to the nearest integer, rather than truncating towards zero, avoids biasing the output towards
+			// zero. Since `full_scale` is an integer, the rounded value cannot exceed it.
+			let converted = scaled.clamp(-full_scale, full_scale).round();
+			match bits {
+				16 => channel_bytes_buf.extend((converted as i16).to_be_bytes()),
+				_ => channel_bytes_buf.extend((converted as i32).to_be_bytes()),
+			}
 		}
 	}
 
-	write!(buf, "\t\t<Payload>")?;
-	base64::engine::general_purpose::STANDARD.encode_string(&channel_bytes_buf, buf);
-	writeln!(buf, "</Payload>")?;
-
-	writeln!(buf, "\t</Channel_{index}>")?;
-	Ok(())
+	(channel_bytes_buf, clipped)
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct SampleBufferQueue {
 	queue: Mutex<VecDeque<SampleBuffer>>,
 	cond_var: Condvar,
+	/// Wakes `sender_task` whenever `cond_var` wakes `sender_thread_fn`.
+	#[cfg(feature = "tokio")]
+	notify: tokio::sync::Notify,
 	done: AtomicBool,
+	/// Set once the first buffer has been created.
+	started: AtomicBool,
+	/// The number of input streams whose samples are combined in each buffer.
+	stream_count: usize,
+	/// If set, smpCnt wraps after this number of samples instead of resetting at the top of each second.
+	smp_cnt_wrap: Option<u32>,
+	/// For wrapping sample counters, the offset between each stream's smpCnt and the sample time, which is determined
+	/// when the first sample of the stream is received.
+	wrap_offsets: Mutex<Vec<Option<u64>>>,
+	/// How long after the end of each buffer's timespan it is sent, in seconds.
+	send_delay: f64,
+	/// The maximum number of buffers in the queue, if limited.
+	max_len: Option<usize>,
+	/// The number of buffers dropped because the queue was full, since they were last counted by the sender thread.
+	dropped_buffers: AtomicU64,
+	/// When replaying a capture, the receive time of the latest sample, in seconds since the Unix epoch, stored as the
+	/// bits of an `f64`. This is used instead of the system time to decide when each buffer is sent.
+	replay_time: Option<AtomicU64>,
+}
+
+impl Default for SampleBufferQueue {
+	fn default() -> Self {
+		Self::with_stream_count(1)
+	}
 }
 
 impl SampleBufferQueue {
@@ -327,24 +726,150 @@ impl SampleBufferQueue {
 		Self::default()
 	}
 
+	/// Creates a queue whose buffers combine samples from `stream_count` input streams. Samples from different streams
+	/// with the same timestamp are placed in the same buffer, so the streams are time-aligned in the output.
+	pub fn with_stream_count(stream_count: usize) -> Self {
+		Self {
+			queue: Mutex::default(),
+			cond_var: Condvar::default(),
+			#[cfg(feature = "tokio")]
+			notify: tokio::sync::Notify::new(),
+			done: AtomicBool::default(),
+			started: AtomicBool::default(),
+			stream_count,
+			smp_cnt_wrap: None,
+			wrap_offsets: Mutex::new(vec![None; stream_count]),
+			send_delay: DEFAULT_SEND_DELAY,
+			max_len: None,
+			dropped_buffers: AtomicU64::default(),
+			replay_time: None,
+		}
+	}
+
+	/// Configures the queue for input streams whose smpCnt wraps after `period` samples, rather than resetting at the
+	/// top of each second.
+	pub fn with_smp_cnt_wrap(mut self, period: u32) -> Self {
+		self.smp_cnt_wrap = Some(period);
+		self
+	}
+
+	/// Configures the queue to send each buffer `send_delay` seconds after the end of its timespan.
+	pub fn with_send_delay(mut self, send_delay: f64) -> Self {
+		self.send_delay = send_delay;
+		self
+	}
+
+	/// Limits the number of buffers in the queue to `max_len`, so that memory use is bounded if the sender thread
+	/// stalls. When a new buffer is needed while the queue is full, the oldest buffer is dropped, since it is the most
+	/// likely to be out of date by the time it is sent.
+	pub fn with_max_len(mut self, max_len: usize) -> Self {
+		self.max_len = Some(max_len);
+		self
+	}
+
+	/// Configures the queue for samples replayed from a capture, whose receive times are unrelated to the system time.
+	/// Each buffer is sent as soon as a sample is received after its send time, without waiting.
+	pub fn with_replay_clock(mut self) -> Self {
+		self.replay_time = Some(AtomicU64::default());
+		self
+	}
+
+	/// Determines the timestamp of a sample from its smpCnt and the time at which it was received. Returns `None` if
+	/// the timestamp cannot be represented.
+	fn sample_time(
+		&self,
+		stream: usize,
+		recv_time_sec: u64,
+		recv_time_nsec: u32,
+		sample_rate: u32,
+		smp_cnt: u16,
+	) -> Option<SampleTime> {
+		let Some(period) = self.smp_cnt_wrap else {
+			// smpCnt gives the position within the second, so the sample was taken either in the second in which it
+			// was received, or the previous one if the counter is ahead of the receive time.
+			let sample_time_sec = if smp_cnt as u64 * NS_PER_SEC > recv_time_nsec as u64 * sample_rate as u64 {
+				recv_time_sec.checked_sub(1)?
+			} else {
+				recv_time_sec
+			};
+			return SampleTime::checked_from_seconds_and_samples(sample_time_sec, smp_cnt as u32, sample_rate);
+		};
+
+		// The first sample from each stream is taken to have been sampled when it was received. Later samples are
+		// placed at the time nearest to their receive time which is consistent with the first, so that the spacing
+		// between samples is exact even though the network latency varies.
+		let period = u64::from(period);
+		let smp_cnt = u64::from(smp_cnt) % period;
+		let recv_time = SampleTime::checked_from_seconds_and_nanoseconds(recv_time_sec, recv_time_nsec, sample_rate)?.0;
+		// The intermediate values below are at most two periods after the receive time.
+		recv_time.checked_add(2 * period)?;
+		let mut offsets = self.wrap_offsets.lock().expect("offsets mutex was poisoned");
+		let offset = *offsets[stream].get_or_insert((recv_time + period - smp_cnt) % period);
+		let latest = recv_time + period / 2;
+		latest
+			.checked_sub((latest + period - (smp_cnt + offset) % period) % period)
+			.map(SampleTime)
+	}
+
+	/// Inserts the sample from an ASDU into the buffer covering its timestamp, creating a new buffer if needed. The
+	/// timestamp is determined from the ASDU's smpCnt and the time at which it was received.
 	pub fn insert_sample(
 		&self,
+		stream: usize,
 		recv_time_sec: u64,
 		recv_time_nsec: u32,
 		sample_rate: u32,
 		buffer_length: u32,
-		asdu: Asdu,
-	) {
-		let sample_time_sec = if asdu.smp_cnt as u64 * NS_PER_SEC > recv_time_nsec as u64 * sample_rate as u64 {
-			recv_time_sec - 1
-		} else {
-			recv_time_sec
+		asdu: Asdu<impl AsRef<str>>,
+	) -> InsertOutcome {
+		let times = self
+			.sample_time(stream, recv_time_sec, recv_time_nsec, sample_rate, asdu.smp_cnt)
+			.zip(SampleTime::checked_from_seconds_and_nanoseconds(recv_time_sec, recv_time_nsec, sample_rate));
+		let Some((timestamp, recv_time)) = times else {
+			return InsertOutcome::InvalidTimestamp;
 		};
+		self.insert_sample_at(stream, timestamp, recv_time, sample_rate, buffer_length, asdu)
+	}
+
+	/// Inserts the sample from an ASDU in the same way as `insert_sample`, but with a timestamp which is already known
+	/// (e.g. from the ASDU's refrTm), rather than determined from its smpCnt.
+	pub fn insert_sample_at(
+		&self,
+		stream: usize,
+		timestamp: SampleTime,
+		recv_time: SampleTime,
+		sample_rate: u32,
+		buffer_length: u32,
+		asdu: Asdu<impl AsRef<str>>,
+	) -> InsertOutcome {
+		// The end of the buffer covering the timestamp must also be representable.
+		if timestamp.buffer_start_time(buffer_length).checked_add_samples(buffer_length).is_none() {
+			return InsertOutcome::InvalidTimestamp;
+		}
+		self.insert_at(stream, timestamp, recv_time, sample_rate, buffer_length, asdu);
+		InsertOutcome::Inserted
+	}
 
-		let timestamp = SampleTime::from_seconds_and_samples(sample_time_sec, asdu.smp_cnt as u32, sample_rate);
+	fn insert_at(
+		&self,
+		stream: usize,
+		timestamp: SampleTime,
+		recv_time: SampleTime,
+		sample_rate: u32,
+		buffer_length: u32,
+		asdu: Asdu<impl AsRef<str>>,
+	) {
+		// This is the same as smpCnt when it resets every second, but not when it wraps.
+		let position = timestamp.subsec_samples(sample_rate);
 
 		let mut queue = self.queue.lock().expect("queue mutex was poisoned");
 
+		if let Some(replay_time) = &self.replay_time {
+			// Non-negative floating point numbers have the same order as their bits.
+			replay_time.fetch_max(recv_time.as_secs_f64(sample_rate).to_bits(), Ordering::SeqCst);
+			self.notify_sender();
+		}
+
 		if queue
 			.back()
 			.is_none_or(|buffer| buffer.is_sample_after_timespan(timestamp))
@@ -352,12 +877,27 @@ impl SampleBufferQueue {
 			let mut new_buffer = SampleBuffer::new(
 				sample_rate,
 				timestamp.buffer_start_time(buffer_length),
-				SampleTime::from_seconds_and_nanoseconds(recv_time_sec, recv_time_nsec, sample_rate),
+				recv_time,
 				buffer_length,
+				self.stream_count,
 			);
-			new_buffer.insert_sample(asdu.smp_cnt as u32, asdu.sample);
+			if !self.started.swap(true, Ordering::SeqCst) {
+				new_buffer.partial = timestamp != new_buffer.start_time;
+			}
+			new_buffer.set_svid(stream, asdu.svid.as_ref());
+			new_buffer.local_sync = asdu.smp_synch == LocalSyncPolicy::LOCAL;
+			new_buffer.first_asdu = Some(AsduHeader {
+				smp_cnt: asdu.smp_cnt,
+				conf_rev: asdu.conf_rev,
+				smp_synch: asdu.smp_synch,
+			});
+			new_buffer.insert_sample(stream, position, asdu.sample);
+			if self.max_len.is_some_and(|max_len| queue.len() >= max_len) {
+				queue.pop_front();
+				self.dropped_buffers.fetch_add(1, Ordering::Relaxed);
+			}
 			queue.push_back(new_buffer);
-			self.cond_var.notify_one();
+			self.notify_sender();
 		} else {
 			let buffer = queue
 				.iter_mut()
@@ -365,26 +905,100 @@ impl SampleBufferQueue {
 				.find(|buffer| buffer.is_sample_within_timespan(timestamp));
 
 			if let Some(buffer) = buffer {
-				buffer.insert_sample(asdu.smp_cnt as u32, asdu.sample);
+				buffer.set_svid(stream, asdu.svid.as_ref());
+				buffer.local_sync |= asdu.smp_synch == LocalSyncPolicy::LOCAL;
+				buffer.insert_sample(stream, position, asdu.sample);
 			}
 		}
 	}
 
+	/// Returns the number of buffers in the queue, including the buffer currently being filled.
+	pub fn len(&self) -> usize {
+		self.queue.lock().expect("queue mutex was poisoned").len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Wakes the sender, whether it is a thread or a task, to check the queue again.
+	fn notify_sender(&self) {
+		self.cond_var.notify_one();
+		#[cfg(feature = "tokio")]
+		self.notify.notify_one();
+	}
+
+	/// When replaying a capture, returns the receive time of the latest sample.
+	fn replay_time(&self) -> Option<f64> {
+		self.replay_time
+			.as_ref()
+			.map(|time| f64::from_bits(time.load(Ordering::SeqCst)))
+	}
+
+	/// Determines whether the sender should stop waiting, because the first buffer in `queue` is ready to be sent or
+	/// the queue is done.
+	fn sender_ready(&self, queue: &VecDeque<SampleBuffer>) -> bool {
+		let ready = queue.front().is_some_and(|buffer| {
+			self.replay_time()
+				.is_none_or(|time| buffer.get_send_time(self.send_delay) <= time)
+		});
+		ready || self.done.load(Ordering::SeqCst)
+	}
+
+	/// Returns the number of seconds until the first buffer in `queue` should be sent, or `None` if the queue is empty.
+	fn time_until_send(&self, queue: &VecDeque<SampleBuffer>) -> Option<f64> {
+		// Once done, the buffers already queued are still sent before stopping, without waiting for their send times
+		// since no more samples will arrive. Buffers replayed from a capture are also sent without waiting.
+		queue.front().map(|buffer| {
+			if self.replay_time().is_some() || self.done.load(Ordering::SeqCst) {
+				return 0.0;
+			}
+			let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+			buffer.get_send_time(self.send_delay) - now
+		})
+	}
+
 	fn wait_for_sample_buffer(&self) -> Option<f64> {
 		let queue = self
 			.cond_var
-			.wait_while(self.queue.lock().unwrap(), |queue| {
-				queue.is_empty() && !self.done.load(Ordering::SeqCst)
-			})
+			.wait_while(self.queue.lock().unwrap(), |queue| !self.sender_ready(queue))
 			.unwrap();
+		self.time_until_send(&queue)
+	}
 
-		if self.done.load(Ordering::SeqCst) {
-			None
-		} else {
-			Some(
-				queue.front().unwrap().get_send_time()
-					- SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-			)
+	/// Waits for a buffer in the same way as `wait_for_sample_buffer`, without blocking the runtime.
+	#[cfg(feature = "tokio")]
+	async fn wait_for_sample_buffer_async(&self) -> Option<f64> {
+		loop {
+			{
+				let queue = self.queue.lock().unwrap();
+				if self.sender_ready(&queue) {
+					return self.time_until_send(&queue);
+				}
+			}
+			// `notify_one` stores a permit if there is no waiting task, so a notification sent after the queue was
+			// checked is not missed.
+			self.notify.notified().await;
+		}
+	}
+
+	/// Waits for `duration` seconds before sending a buffer, returning early if the queue is marked as done.
+	fn sleep(&self, duration: f64) {
+		let queue = self.queue.lock().unwrap();
+		let _ = self
+			.cond_var
+			.wait_timeout_while(queue, Duration::from_secs_f64(duration), |_| !self.done.load(Ordering::SeqCst))
+			.unwrap();
+	}
+
+	/// Sleeps in the same way as `sleep`, without blocking the runtime.
+	#[cfg(feature = "tokio")]
+	async fn sleep_async(&self, duration: f64) {
+		let deadline = tokio::time::Instant::now() + Duration::from_secs_f64(duration);
+		while !self.done.load(Ordering::SeqCst) {
+			if tokio::time::timeout_at(deadline, self.notify.notified()).await.is_err() {
+				return;
+			}
 		}
 	}
 
@@ -393,19 +1007,997 @@ impl SampleBufferQueue {
 		queue.pop_front().unwrap()
 	}
 
+	/// Returns the number of buffers dropped because the queue was full since this was last called.
+	fn take_dropped_buffers(&self) -> u64 {
+		self.dropped_buffers.swap(0, Ordering::Relaxed)
+	}
+
 	pub fn set_done(&self) {
 		self.done.store(true, Ordering::SeqCst);
-		self.cond_var.notify_one();
+		self.notify_sender();
 	}
 }
 
-pub fn sender_thread_fn(queue: &SampleBufferQueue, out_socket: UdpSocket, dest: SocketAddr, channels: &[OutputChannel]) {
+pub fn sender_thread_fn(
+	queue: &SampleBufferQueue,
+	mut output: impl OutputSink,
+	configuration: &Configuration,
+	counters: &Counters,
+) {
+	let mut state = SenderState::new(configuration);
 	while let Some(sleep_time) = queue.wait_for_sample_buffer() {
 		if sleep_time > 0.0 {
-			std::thread::sleep(Duration::from_secs_f64(sleep_time));
+			queue.sleep(sleep_time);
+		}
+		send_next_buffer(queue, &mut output, configuration, counters, &mut state);
+	}
+}
+
+/// Sends buffers from the queue in the same way as `sender_thread_fn`, but as a task in a tokio runtime, which sleeps
+/// until each buffer's send time without blocking a thread. The task returns once the queue is done and empty.
+///
+/// Sending to `output` is still blocking, which takes little time for UDP. A TCP output can block while it connects,
+/// so should be used with a multi-threaded runtime.
+#[cfg(feature = "tokio")]
+pub async fn sender_task(
+	queue: &SampleBufferQueue,
+	mut output: impl OutputSink,
+	configuration: &Configuration,
+	counters: &Counters,
+) {
+	let mut state = SenderState::new(configuration);
+	while let Some(sleep_time) = queue.wait_for_sample_buffer_async().await {
+		if sleep_time > 0.0 {
+			queue.sleep_async(sleep_time).await;
+		}
+		send_next_buffer(queue, &mut output, configuration, counters, &mut state);
+	}
+}
+
+/// State kept by the sender between buffers.
+struct SenderState {
+	clock_sync_monitor: ClockSyncMonitor,
+	saturation_summary: SaturationSummary,
+}
+
+impl SenderState {
+	fn new(configuration: &Configuration) -> Self {
+		let mut clock_sync_monitor = ClockSyncMonitor::new();
+		if configuration.clock_sync != ClockSyncPolicy::Ignore {
+			// Check at startup, so that the initial status is logged before any samples are received.
+			clock_sync_monitor.is_synchronised();
 		}
+		let saturation_summary = SaturationSummary::new(
+			configuration.channels.len(),
+			Duration::from_secs(configuration.stats_interval_secs),
+			Instant::now(),
+		);
+		Self {
+			clock_sync_monitor,
+			saturation_summary,
+		}
+	}
+}
 
+/// Removes the first buffer from the queue and sends it, unless the configuration says that it should be discarded or
+/// held.
+fn send_next_buffer(
+	queue: &SampleBufferQueue,
+	output: &mut impl OutputSink,
+	configuration: &Configuration,
+	counters: &Counters,
+	state: &mut SenderState,
+) {
+	let mut buffer = queue.pop_sample_buffer();
+	let dropped = queue.take_dropped_buffers();
+	if dropped > 0 {
+		let total = counters.dropped_buffers.fetch_add(dropped, Ordering::Relaxed) + dropped;
+		log::warn!("Dropped {dropped} buffers because the queue was full ({total} dropped so far).");
+	}
+	if buffer.is_partial() {
+		match configuration.initial_buffer {
+			InitialBufferPolicy::Send => log::info!("Sending incomplete initial buffer."),
+			InitialBufferPolicy::Flag => log::info!("Sending incomplete initial buffer with partial flag."),
+			InitialBufferPolicy::Discard => {
+				log::info!("Discarding incomplete initial buffer.");
+				return;
+			}
+		}
+	}
+	match configuration.clock_sync {
+		ClockSyncPolicy::Ignore => {}
+		ClockSyncPolicy::Flag => buffer.clock_unsynchronised = !state.clock_sync_monitor.is_synchronised(),
+		ClockSyncPolicy::Hold => {
+			if !state.clock_sync_monitor.is_synchronised() {
+				counters.unsynchronised_buffers.fetch_add(1, Ordering::Relaxed);
+				return;
+			}
+		}
+	}
+	log::trace!(
+		"Sending buffer at {} from streams {:?}.",
+		buffer.start_time.to_iso8601(buffer.sample_rate),
+		buffer.svids()
+	);
+	counters
+		.missing_samples
+		.fetch_add(buffer.missing_samples(), Ordering::Relaxed);
+	match buffer.flush(output, configuration, counters) {
+		Ok(()) => counters.flushed_buffers.fetch_add(1, Ordering::Relaxed),
+		Err(err) => {
+			log::warn!("Unable to send buffer: {err}");
+			counters.send_failures.fetch_add(1, Ordering::Relaxed)
+		}
+	};
+	if let Some(saturated) = state.saturation_summary.poll(counters, Instant::now()) {
+		for (channel, saturated) in configuration.channels.iter().zip(saturated) {
+			if saturated > 0 {
+				log::warn!(
+					"{saturated} samples in channel {} were saturated in the last {} s.",
+					channel.name,
+					configuration.stats_interval_secs
+				);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_asdu(smp_cnt: u16) -> Asdu {
+		Asdu {
+			svid: String::new(),
+			datset: None,
+			smp_cnt,
+			conf_rev: 1,
+			refr_tm: None,
+			smp_synch: 2,
+			smp_rate: None,
+			sample: Sample::default(),
+			smp_mod: None,
+			gm_identity: None,
+		}
+	}
+
+	#[test]
+	fn to_iso8601() {
+		// 2024-01-01 00:00:00 UTC
+		let seconds = 1_704_067_200;
+
+		let time = SampleTime::from_seconds_and_samples(seconds, 0, 4000);
+		assert_eq!(time.to_iso8601(4000), "2024-01-01T00:00:00.000000Z");
+
+		let time = SampleTime::from_seconds_and_samples(seconds, 1, 4800);
+		assert_eq!(time.to_iso8601(4800), "2024-01-01T00:00:00.000208Z");
+
+		let time = SampleTime::from_seconds_and_samples(seconds + 3661, 2800, 4000);
+		assert_eq!(time.to_iso8601(4000), "2024-01-01T01:01:01.700000Z");
+
+		let time = SampleTime::from_seconds_and_samples(seconds - 1, 4799, 4800);
+		assert_eq!(time.to_iso8601(4800), "2023-12-31T23:59:59.999791Z");
+	}
+
+	fn split_timestamp(seconds: u64, samples: u32, sample_rate: u32) -> String {
+		let time = SampleTime::from_seconds_and_samples(seconds, samples, sample_rate);
+		let mut xml = XmlWriter::new(false);
+		xml.start("OpenPMU").unwrap();
+		write_xml_timestamp(&mut xml, time, sample_rate, TimestampFormat::Split).unwrap();
+		xml.into_string().split_off("<OpenPMU>\n".len())
+	}
+
+	#[test]
+	fn timestamp_midnight() {
+		// 2024-06-15 00:00:00 UTC
+		assert_eq!(
+			split_timestamp(1_718_409_600, 0, 4000),
+			"\t<Date>2024-06-15</Date>\n\t<Time>00:00:00.000000</Time>\n"
+		);
+		assert_eq!(
+			split_timestamp(1_718_409_599, 3999, 4000),
+			"\t<Date>2024-06-14</Date>\n\t<Time>23:59:59.999750</Time>\n"
+		);
+	}
+
+	#[test]
+	fn timestamp_year_boundaries() {
+		let cases = [
+			(0, "1970-01-01"),
+			(946_684_799, "1999-12-31"),
+			(946_684_800, "2000-01-01"),
+			(951_782_400, "2000-02-29"),
+			(951_868_800, "2000-03-01"),
+			(1_709_164_800, "2024-02-29"),
+			(1_735_689_599, "2024-12-31"),
+			(1_735_689_600, "2025-01-01"),
+			(4_107_542_400, "2100-03-01"),
+		];
+		for (seconds, date) in cases {
+			let timestamp = split_timestamp(seconds, 0, 4800);
+			assert!(timestamp.starts_with(&format!("\t<Date>{date}</Date>\n")), "{seconds}: {timestamp}");
+		}
+	}
+
+	#[test]
+	fn timestamp_leap_second() {
+		// A leap second was inserted at the end of 2016-12-31. Unix time does not count leap seconds, so 23:59:60 is
+		// never produced, and the second after 23:59:59 is 00:00:00 of the next day.
+		assert_eq!(
+			split_timestamp(1_483_228_799, 2400, 4800),
+			"\t<Date>2016-12-31</Date>\n\t<Time>23:59:59.500000</Time>\n"
+		);
+		assert_eq!(
+			split_timestamp(1_483_228_800, 0, 4800),
+			"\t<Date>2017-01-01</Date>\n\t<Time>00:00:00.000000</Time>\n"
+		);
+	}
+
+	fn is_gregorian_leap_year(year: u64) -> bool {
+		year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+	}
+
+	/// Converts a date in the Gregorian calendar to the number of days since 0001-01-01 in the proleptic Gregorian
+	/// calendar.
+	#[rustfmt::skip]
+	fn fixed_from_gregorian(year: u64, month: u64, day: u64) -> u64 {
+		365 * (year - 1)
+			+ (year - 1) / 4
+			- (year - 1) / 100
+			+ (year - 1) / 400
+			+ (367 * month - 362) / 12
+			+ day
+			- if month <= 2 { 0 } else if is_gregorian_leap_year(year) { 1 } else { 2 }
+	}
+
+	/// The original conversion from days since 1970-01-01 to a date, based on the formulas in 'Calendrical
+	/// Calculations' by Edward M. Reingold and Nachum Dershowitz, against which `civil_from_days` is checked.
+	fn reference_civil_from_days(days: u64) -> (u64, u64, u64) {
+		let date = days + fixed_from_gregorian(1970, 1, 1);
+
+		let d_0 = date - 1;
+		let n_400 = d_0 / 146097;
+		let d_1 = d_0 % 146097;
+		let n_100 = d_1 / 36524;
+		let d_2 = d_1 % 36524;
+		let n_4 = d_2 / 1461;
+		let d_3 = d_2 % 1461;
+		let n_1 = d_3 / 365;
+		let year = 400 * n_400 + 100 * n_100 + 4 * n_4 + n_1 + if n_100 == 4 || n_1 == 4 { 0 } else { 1 };
+
+		let prior_days = date - fixed_from_gregorian(year, 1, 1);
+		let correction = if date < fixed_from_gregorian(year, 3, 1) {
+			0
+		} else if is_gregorian_leap_year(year) {
+			1
+		} else {
+			2
+		};
+
+		let month = (12 * (prior_days + correction) + 373) / 367;
+		let day = date - fixed_from_gregorian(year, month, 1) + 1;
+		(year, month, day)
+	}
+
+	#[test]
+	fn to_date_time_known_timestamps() {
+		let cases = [
+			(0, (1970, 1, 1, 0, 0, 0, 0)),
+			// A leap day, and the day after it.
+			(1_709_164_800, (2024, 2, 29, 0, 0, 0, 0)),
+			(1_709_251_199, (2024, 2, 29, 23, 59, 59, 0)),
+			(1_709_251_200, (2024, 3, 1, 0, 0, 0, 0)),
+			// Either side of a year boundary.
+			(1_483_228_799, (2016, 12, 31, 23, 59, 59, 0)),
+			(1_483_228_800, (2017, 1, 1, 0, 0, 0, 0)),
+			// 2100 is not a leap year.
+			(4_107_542_399, (2100, 2, 28, 23, 59, 59, 0)),
+			(4_107_542_400, (2100, 3, 1, 0, 0, 0, 0)),
+		];
+		for (seconds, expected) in cases {
+			assert_eq!(SampleTime::from_seconds_and_samples(seconds, 0, 1).to_date_time(1), expected, "{seconds}");
+		}
+		let time = SampleTime::from_seconds_and_samples(1_483_228_800, 3999, 4000);
+		assert_eq!(time.to_date_time(4000), (2017, 1, 1, 0, 0, 0, 999_750));
+	}
+
+	#[test]
+	fn civil_from_days_matches_reference() {
+		// Every day from 1970 until well beyond the range of 32-bit timestamps.
+		for days in 0..150_000 {
+			assert_eq!(civil_from_days(days), reference_civil_from_days(days), "{days}");
+		}
+		for days in [1_000_000, 2_932_896, 10_000_000] {
+			assert_eq!(civil_from_days(days), reference_civil_from_days(days), "{days}");
+		}
+	}
+
+	#[test]
+	fn to_date_time_consecutive_days() {
+		// Check that each day from 1970 to 2200 follows on from the previous one.
+		let (mut year, mut month, mut day) = (1970, 1, 1);
+		for days in 0..84_000 {
+			let (y, m, d, ..) = SampleTime::from_seconds_and_samples(days * 86400, 0, 1).to_date_time(1);
+			assert_eq!((y, m, d), (year, month, day));
+
+			let month_length = match month {
+				2 if is_gregorian_leap_year(year as u64) => 29,
+				2 => 28,
+				4 | 6 | 9 | 11 => 30,
+				_ => 31,
+			};
+			day += 1;
+			if day > month_length {
+				day = 1;
+				month += 1;
+			}
+			if month > 12 {
+				month = 1;
+				year += 1;
+			}
+		}
+	}
+
+	#[test]
+	fn channel_max_overwrite() {
+		let mut channel = SampleBufferChannel::new(4);
+		channel.insert_sample(0, 3.0);
+		channel.insert_sample(1, -100.0);
+		assert_eq!(channel.max, 100.0);
+
+		// Replacing the largest value with a smaller one reduces the max to the next largest value.
+		channel.insert_sample(1, 2.0);
+		assert_eq!(channel.max, 3.0);
+
+		// Replacing a value which is not the largest leaves the max unchanged.
+		channel.insert_sample(1, 1.0);
+		assert_eq!(channel.max, 3.0);
+
+		channel.insert_sample(0, -5.0);
+		assert_eq!(channel.max, 5.0);
+		channel.insert_sample(0, 0.5);
+		assert_eq!(channel.max, 1.0);
+	}
+
+	#[test]
+	fn insert_before_buffer_start() {
+		// The buffer starts at sample 40 of the second.
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4040), SampleTime(4045), 40, 1);
+		let sample = Sample {
+			current_a: 1.0,
+			..Default::default()
+		};
+		buffer.insert_sample(0, 39, sample.clone());
+		buffer.insert_sample(0, 0, sample.clone());
+		assert_eq!(buffer.missing_samples(), 40);
+		assert_eq!(buffer.streams[0][0].max, 0.0);
+
+		buffer.insert_sample(0, 80, sample.clone());
+		assert_eq!(buffer.missing_samples(), 40);
+
+		buffer.insert_sample(0, 40, sample);
+		assert_eq!(buffer.missing_samples(), 39);
+		assert_eq!(buffer.streams[0][0].max, 1.0);
+	}
+
+	#[test]
+	fn build_channel_clipping() {
+		let mut channel = SampleBufferChannel::new(5);
+		for (index, value) in [0.5, 1.5, -2.0, 1.0, -1.0].into_iter().enumerate() {
+			channel.insert_sample(index as u32, value);
+		}
+
+		let (bytes, clipped) = build_channel(&channel, 1.0, 16);
+		assert_eq!(clipped, 2);
+		let values = bytes
+			.chunks_exact(2)
+			.map(|chunk| i16::from_be_bytes([chunk[0], chunk[1]]))
+			.collect::<Vec<_>>();
+		assert_eq!(values, [16384, 32767, -32767, 32767, -32767]);
+
+		let (_, clipped) = build_channel(&channel, channel.max, 16);
+		assert_eq!(clipped, 0);
+	}
+
+	#[test]
+	fn build_channel_rounding() {
+		let mut channel = SampleBufferChannel::new(2001);
+		for index in 0..2001 {
+			channel.insert_sample(index, (index as f32 - 1000.0) / 1000.0 * 230.0);
+		}
+
+		let (bytes, clipped) = build_channel(&channel, channel.max, 16);
+		assert_eq!(clipped, 0);
+		let values = bytes
+			.chunks_exact(2)
+			.map(|chunk| i16::from_be_bytes([chunk[0], chunk[1]]))
+			.collect::<Vec<_>>();
+		assert_eq!((values[0], values[1000], values[2000]), (-32767, 0, 32767));
+		for (index, &value) in values.iter().enumerate() {
+			// The output is symmetric about zero, and each value is within half a count of the exact value.
+			assert_eq!(value, -values[2000 - index]);
+			let exact = (index as f64 - 1000.0) / 1000.0 * 32767.0;
+			assert!((f64::from(value) - exact).abs() <= 0.5 + 1e-3, "{index}: {value} {exact}");
+		}
+	}
+
+	#[test]
+	fn build_channel_32_bit() {
+		let mut channel = SampleBufferChannel::new(5);
+		for (index, value) in [0.5, 1.5, -2.0, 1.0, 1.0e-6].into_iter().enumerate() {
+			channel.insert_sample(index as u32, value);
+		}
+
+		let (bytes, clipped) = build_channel(&channel, 1.0, 32);
+		assert_eq!(clipped, 2);
+		let values = bytes
+			.chunks_exact(4)
+			.map(|chunk| i32::from_be_bytes(chunk.try_into().unwrap()))
+			.collect::<Vec<_>>();
+		// A millionth of the range would be lost at 16 bits.
+		assert_eq!(values, [1073741824, 2147483647, -2147483647, 2147483647, 2147]);
+	}
+
+	#[test]
+	fn bits_32_datagram() {
+		let buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+		let configuration = test_configuration(|config| config.bits = 32);
+		let xml = buffer.to_xml(&configuration, &Counters::new(6)).unwrap();
+		assert!(xml.contains("\t<bits>32</bits>\n"));
+		// 40 samples of 4 bytes each take 216 base64 characters, including padding.
+		let payload = xml.split("<Payload>").nth(1).unwrap().split('<').next().unwrap();
+		assert_eq!(payload.len(), 216);
+	}
+
+	#[test]
+	fn channel_data_nominal() {
+		let mut channel = SampleBufferChannel::new(4);
+		for (index, value) in [115.0, -230.0, 345.0, 0.0].into_iter().enumerate() {
+			channel.insert_sample(index as u32, value);
+		}
+
+		let mut output_channel = OutputChannel::new("VA", OutputChannelType::Voltage, "A", 4);
+		output_channel.nominal = Some(230.0);
+
+		let diagnostics = ChannelDiagnostics {
+			emit_clipped: true,
+			saturated: None,
+		};
+		let mut xml = XmlWriter::new(false);
+		let clipped = write_xml_channel_data(&mut xml, 0, &output_channel, &channel, 4, 16, diagnostics).unwrap();
+		let buf = xml.into_string();
+		assert_eq!(clipped, 1);
+		assert!(buf.contains("<Range>230</Range>"));
+		assert!(buf.contains("<Clipped>1</Clipped>"));
+		assert!(!buf.contains("<Saturated>"));
+
+		// Without a nominal magnitude, the range adapts to the largest sample and nothing is clipped.
+		output_channel.nominal = None;
+		let mut xml = XmlWriter::new(false);
+		let clipped = write_xml_channel_data(&mut xml, 0, &output_channel, &channel, 4, 16, diagnostics).unwrap();
+		let buf = xml.into_string();
+		assert_eq!(clipped, 0);
+		assert!(buf.contains("<Range>345</Range>"));
+	}
+
+	#[test]
+	fn channel_data_phase_label() {
+		let channel = SampleBufferChannel::new(4);
+		let output_channel = OutputChannel::new("V1", OutputChannelType::Voltage, "+seq <L1&L2>", 4);
+
+		let mut xml = XmlWriter::new(false);
+		write_xml_channel_data(
+			&mut xml,
+			0,
+			&output_channel,
+			&channel,
+			4,
+			16,
+			ChannelDiagnostics::default(),
+		)
+		.unwrap();
+		let buf = xml.into_string();
+		assert!(buf.contains("<Phase>+seq &lt;L1&amp;L2&gt;</Phase>"));
+	}
+
+	#[test]
+	fn sample_time_per_second() {
+		let queue = SampleBufferQueue::new();
+
+		// Received 25 ms into the second.
+		let time = queue.sample_time(0, 1_000_000_000, 25_000_000, 4000, 90).unwrap();
+		assert_eq!(time, SampleTime::from_seconds_and_samples(1_000_000_000, 90, 4000));
+
+		// A sample from the end of the previous second, received just after the counter reset.
+		let time = queue.sample_time(0, 1_000_000_000, 1_000_000, 4000, 3998).unwrap();
+		assert_eq!(time, SampleTime::from_seconds_and_samples(999_999_999, 3998, 4000));
+	}
+
+	#[test]
+	fn sample_time_wrapping() {
+		let queue = SampleBufferQueue::new().with_smp_cnt_wrap(65536);
+
+		// The first sample is anchored to its receive time.
+		let first = queue.sample_time(0, 1_000_000_000, 0, 4000, 65000).unwrap();
+		assert_eq!(first, SampleTime::from_seconds_and_samples(1_000_000_000, 0, 4000));
+
+		// Later samples keep their spacing from the first, even across the wrap and with varying latency.
+		let time = queue.sample_time(0, 1_000_000_000, 200_000_000, 4000, 65535).unwrap();
+		assert_eq!(time, first.add_samples(535));
+		let time = queue.sample_time(0, 1_000_000_000, 100_000_000, 4000, 464).unwrap();
+		assert_eq!(time, first.add_samples(1000));
+		// (65000 + 17 * 4000) % 65536 = 1928
+		let time = queue.sample_time(0, 1_000_000_017, 0, 4000, 1928).unwrap();
+		assert_eq!(time, first.add_samples(17 * 4000));
+	}
+
+	#[test]
+	fn missing_samples() {
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4040), SampleTime(4045), 40, 2);
+		assert_eq!(buffer.missing_samples(), 80);
+
+		buffer.insert_sample(0, 40, Sample::default());
+		buffer.insert_sample(0, 40, Sample::default());
+		buffer.insert_sample(1, 79, Sample::default());
+		assert_eq!(buffer.missing_samples(), 78);
+	}
+
+	#[test]
+	fn buffer_svids() {
+		let queue = SampleBufferQueue::with_stream_count(2);
+		let mut asdu = test_asdu(40);
+		asdu.svid = "MU01".to_owned();
+		queue.insert_sample(1, 1_000_000_000, 25_000_000, 4000, 40, asdu);
+
+		let buffers = queue.queue.lock().unwrap();
+		assert_eq!(buffers[0].svids(), [None, Some("MU01".to_owned())]);
+	}
+
+	#[test]
+	fn sample_time_from_refr_tm() {
+		// Slightly before the 41st sample of the second, which it is rounded to.
+		let refr_tm = UtcTime {
+			seconds: 1_000_000_000,
+			fraction: (0.01024 * (1 << 24) as f64) as u32,
+			quality: crate::TimeQuality::from_octet(0),
+		};
+		let timestamp = SampleTime::from_utc_time(refr_tm, 4000);
+		assert_eq!(timestamp, SampleTime::from_seconds_and_samples(1_000_000_000, 41, 4000));
+
+		// The sample is placed by its refrTm, even though it was received in the next second.
+		let queue = SampleBufferQueue::new();
+		let recv_time = SampleTime::from_seconds_and_nanoseconds(1_000_000_001, 5_000_000, 4000);
+		let outcome = queue.insert_sample_at(0, timestamp, recv_time, 4000, 40, test_asdu(3999));
+		assert_eq!(outcome, InsertOutcome::Inserted);
+		let buffers = queue.queue.lock().unwrap();
+		assert_eq!(buffers[0].start_time, SampleTime::from_seconds_and_samples(1_000_000_000, 40, 4000));
+		assert!(buffers[0].received[0][1]);
+	}
+
+	#[test]
+	fn checked_sample_time() {
+		assert_eq!(SampleTime::checked_from_seconds_and_samples(10, 5, 4000), Some(SampleTime(40_005)));
+		assert_eq!(SampleTime::checked_from_seconds_and_samples(u64::MAX / 4000, 4000, 4000), None);
+		assert_eq!(SampleTime::checked_from_seconds_and_nanoseconds(u64::MAX / 2, 0, 4000), None);
+		assert_eq!(SampleTime(u64::MAX - 1).checked_add_samples(1), Some(SampleTime(u64::MAX)));
+		assert_eq!(SampleTime(u64::MAX).checked_add_samples(1), None);
+	}
+
+	#[test]
+	fn insert_invalid_timestamp() {
+		let queue = SampleBufferQueue::new();
+		let outcome = queue.insert_sample(0, u64::MAX / 1000, 25_000_000, 4000, 40, test_asdu(40));
+		assert_eq!(outcome, InsertOutcome::InvalidTimestamp);
+
+		// A sample taken in the second before the epoch.
+		let outcome = queue.insert_sample(0, 0, 25_000_000, 4000, 40, test_asdu(3999));
+		assert_eq!(outcome, InsertOutcome::InvalidTimestamp);
+
+		// The buffer covering the sample would end after the latest representable time.
+		let outcome = queue.insert_sample_at(0, SampleTime(u64::MAX - 1), SampleTime(0), 4000, 40, test_asdu(0));
+		assert_eq!(outcome, InsertOutcome::InvalidTimestamp);
+
+		let queue = SampleBufferQueue::with_stream_count(1).with_smp_cnt_wrap(1 << 16);
+		let outcome = queue.insert_sample(0, u64::MAX / 4000, 0, 4000, 40, test_asdu(0));
+		assert_eq!(outcome, InsertOutcome::InvalidTimestamp);
+
+		assert!(queue.queue.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn datagram_padding() {
+		let mut buf = "<OpenPMU>\n</OpenPMU>\n".to_owned();
+		assert!(pad_datagram(&mut buf, 64));
+		assert_eq!(buf.len(), 64);
+		assert!(buf.starts_with("<OpenPMU>\n</OpenPMU>\n "));
+		assert!(buf[21..].bytes().all(|b| b == b' '));
+
+		assert!(pad_datagram(&mut buf, 64));
+		assert_eq!(buf.len(), 64);
+		assert!(!pad_datagram(&mut buf, 32));
+		assert_eq!(buf.len(), 64);
+	}
+
+	fn sine(frequency: f64, sample_rate: u32, phase: f64, length: usize) -> Vec<f32> {
+		(0..length)
+			.map(|n| {
+				let t = n as f64 / sample_rate as f64;
+				(325.0 * (std::f64::consts::TAU * frequency * t + phase).sin()) as f32
+			})
+			.collect()
+	}
+
+	#[test]
+	fn estimate_frequency_off_nominal() {
+		for frequency in [49.5, 50.0, 50.25, 51.0] {
+			for phase in [0.0, 1.0, 2.5] {
+				let samples = sine(frequency, 4000, phase, 40);
+				let estimate = estimate_frequency(&samples, 4000, 50.0).unwrap();
+				assert!((estimate - frequency).abs() < 0.01, "{frequency} {phase}: {estimate}");
+			}
+		}
+
+		let samples = sine(59.9, 4800, 0.3, 40);
+		let estimate = estimate_frequency(&samples, 4800, 60.0).unwrap();
+		assert!((estimate - 59.9).abs() < 0.01, "{estimate}");
+	}
+
+	#[test]
+	fn estimate_frequency_no_signal() {
+		assert_eq!(estimate_frequency(&[0.0; 40], 4000, 50.0), None);
+		// A signal far from nominal is not trusted.
+		assert_eq!(estimate_frequency(&sine(150.0, 4000, 0.0, 40), 4000, 50.0), None);
+	}
+
+	#[test]
+	fn buffer_estimate_frequency() {
+		let configuration = test_configuration(|config| config.frequency_from_voltages = true);
+		let counters = Counters::new(6);
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+		assert_eq!(buffer.estimate_frequency(&configuration), None);
+		let xml = buffer.to_xml(&configuration, &counters).unwrap();
+		assert!(xml.contains("<Frequency>50.0000</Frequency>"));
+		assert!(xml.contains("<FrequencyReliable>false</FrequencyReliable>"));
+
+		// Only one phase has a signal, and currents are ignored.
+		for (index, value) in sine(49.8, 4000, 0.5, 40).into_iter().enumerate() {
+			buffer.streams[0][5].insert_sample(index as u32, value);
+		}
+		for (index, value) in sine(75.0, 4000, 0.0, 40).into_iter().enumerate() {
+			buffer.streams[0][0].insert_sample(index as u32, value);
+		}
+		let estimate = buffer.estimate_frequency(&configuration).unwrap();
+		assert!((estimate - 49.8).abs() < 0.01, "{estimate}");
+
+		// The three phases are fitted together.
+		for (phase, input_channel) in [0.0, 2.0, 4.0].into_iter().zip(4..7) {
+			for (index, value) in sine(49.8, 4000, phase, 40).into_iter().enumerate() {
+				buffer.streams[0][input_channel].insert_sample(index as u32, value);
+			}
+		}
+		let estimate = buffer.estimate_frequency(&configuration).unwrap();
+		assert!((estimate - 49.8).abs() < 0.01, "{estimate}");
+		let xml = buffer.to_xml(&configuration, &counters).unwrap();
+		assert!(xml.contains(&format!("<Frequency>{:.4}</Frequency>", f64::from(estimate))));
+		assert!(!xml.contains("FrequencyReliable"));
+	}
+
+	#[test]
+	fn buffer_local_sync() {
+		let queue = SampleBufferQueue::new();
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(40));
+		let mut asdu = test_asdu(41);
+		asdu.smp_synch = LocalSyncPolicy::LOCAL;
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, asdu);
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(80));
+
+		let buffers = queue.queue.lock().unwrap();
+		assert!(buffers[0].local_sync);
+		assert!(!buffers[1].local_sync);
+	}
+
+	#[test]
+	fn bounded_queue_drops_oldest() {
+		let queue = SampleBufferQueue::new().with_max_len(10);
+		for i in 0..100 {
+			let smp_cnt = (i * 40) % 4000;
+			queue.insert_sample(0, 1_000_000_001 + i / 100, 999_000_000, 4000, 40, test_asdu(smp_cnt as u16));
+		}
+		assert_eq!(queue.take_dropped_buffers(), 90);
+		assert_eq!(queue.take_dropped_buffers(), 0);
+		assert_eq!(queue.len(), 10);
+
+		let buffers = queue.queue.lock().unwrap();
+		assert_eq!(buffers[0].start_time, SampleTime::from_seconds_and_samples(1_000_000_001, 3600, 4000));
+	}
+
+	#[test]
+	fn done_drains_queue() {
+		let queue = SampleBufferQueue::new();
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(40));
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(80));
+		queue.set_done();
+
+		// The queued buffers are still returned once done, and only then does waiting stop.
+		for _ in 0..2 {
+			assert!(queue.wait_for_sample_buffer().is_some());
+			queue.pop_sample_buffer();
+		}
+		assert_eq!(queue.wait_for_sample_buffer(), None);
+	}
+
+	#[test]
+	fn done_sends_without_waiting() {
+		let queue = SampleBufferQueue::new();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		queue.insert_sample(0, now.as_secs() + 10, now.subsec_nanos(), 4000, 40, test_asdu(0));
+		assert!(queue.wait_for_sample_buffer().unwrap() > 9.0);
+
+		// A sleep until the send time is cut short when the queue is marked as done.
+		let queue = &queue;
+		std::thread::scope(|scope| {
+			let sleeper = scope.spawn(|| queue.sleep(60.0));
+			std::thread::sleep(Duration::from_millis(20));
+			queue.set_done();
+			sleeper.join().unwrap();
+		});
+		assert_eq!(queue.wait_for_sample_buffer(), Some(0.0));
+	}
+
+	/// An output which discards everything sent to it.
+	#[cfg(feature = "tokio")]
+	struct NullSink;
+
+	#[cfg(feature = "tokio")]
+	impl OutputSink for NullSink {
+		fn send(&mut self, _datagram: &[u8]) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[cfg(feature = "tokio")]
+	#[test]
+	fn sender_task_sends_once_done() {
+		let queue = SampleBufferQueue::new();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		queue.insert_sample(0, now.as_secs() + 10, now.subsec_nanos(), 4000, 40, test_asdu(0));
+		let configuration = test_configuration(|_| {});
+		let counters = Counters::new(configuration.channels.len());
+
+		// The buffer's send time is ten seconds away, so the task sleeps until the queue is marked as done by another
+		// thread, then sends the buffer and returns.
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_time()
+			.build()
+			.unwrap();
+		let queue = &queue;
+		std::thread::scope(|scope| {
+			scope.spawn(|| {
+				std::thread::sleep(Duration::from_millis(20));
+				queue.set_done();
+			});
+			runtime.block_on(sender_task(queue, NullSink, &configuration, &counters));
+		});
+		assert_eq!(counters.flushed_buffers.load(Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn replay_clock() {
+		let queue = SampleBufferQueue::new().with_replay_clock();
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(40));
+
+		// The buffer is sent once a sample is received after its send time, rather than by the system time.
+		let queue = &queue;
+		std::thread::scope(|scope| {
+			let waiter = scope.spawn(|| queue.wait_for_sample_buffer());
+			std::thread::sleep(Duration::from_millis(20));
+			assert!(!waiter.is_finished());
+			queue.insert_sample(0, 1_000_000_000, 90_000_000, 4000, 40, test_asdu(340));
+			assert_eq!(waiter.join().unwrap(), Some(0.0));
+		});
+		queue.pop_sample_buffer();
+		queue.set_done();
+		assert_eq!(queue.wait_for_sample_buffer(), Some(0.0));
+	}
+
+	fn test_configuration(options: impl FnOnce(&mut Configuration)) -> Configuration {
+		let mut builder = Configuration::builder()
+			.interface("eth0")
+			.destination("127.0.0.1:48001".parse().unwrap());
+		for (name, input_channel) in [("Ia", 0), ("Ib", 1), ("Ic", 2), ("Va", 4), ("Vb", 5), ("Vc", 6)] {
+			let type_ = if input_channel < 4 {
+				OutputChannelType::Current
+			} else {
+				OutputChannelType::Voltage
+			};
+			builder = builder.channel(OutputChannel::new(name, type_, &name[1..], input_channel));
+		}
+		builder.with(options).build().unwrap()
+	}
+
+	#[test]
+	fn compact_xml() {
+		let buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+
+		let pretty_configuration = test_configuration(|_| {});
+		let counters = Counters::new(6);
+		let pretty = buffer.to_xml(&pretty_configuration, &counters).unwrap();
+		let compact_configuration = test_configuration(|config| config.compact_xml = true);
+		let compact = buffer.to_xml(&compact_configuration, &counters).unwrap();
+
+		assert!(pretty.starts_with("<OpenPMU>\n\t<Format>Samples</Format>\n"));
+		assert!(compact.starts_with("<OpenPMU><Format>Samples</Format><Date>"));
+		assert!(!compact.contains(['\t', '\n']));
+		let stripped = pretty.replace(['\t', '\n'], "");
+		assert_eq!(compact, stripped);
+
+		// With six channels of 40 samples, compact output is 1435 bytes rather than 1567 (8% smaller).
+		assert_eq!(pretty.len() - compact.len(), 132);
+	}
+
+	#[test]
+	fn openpmu_xml_without_sending() {
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 4, 1);
+		buffer.streams[0][0].insert_sample(1, 2.0);
+		buffer.streams[0][0].insert_sample(2, -4.0);
+		let mut configuration = test_configuration(|config| config.compact_xml = true);
+		configuration.channels.truncate(1);
+
+		let xml = buffer.to_openpmu_xml(&configuration).unwrap();
+		let expected = concat!(
+			"<OpenPMU><Format>Samples</Format><Date>1970-01-12</Date><Time>13:46:40.000000</Time><Frame>0</Frame>",
+			"<Fs>4000</Fs><n>4</n><bits>16</bits><Channels>1</Channels><Channel_0><Name>Ia</Name><Type>I</Type>",
+			"<Phase>a</Phase><Range>4</Range><Payload>AABAAIABAAA=</Payload></Channel_0></OpenPMU>",
+		);
+		assert_eq!(xml, expected);
+	}
+
+	#[test]
+	fn frame_number_follows_buffer_length() {
+		// At 50 frames per second, the last buffer of each second starts 3920 samples in.
+		let start = SampleTime(4_000_003_920);
+		let buffer = SampleBuffer::new(4000, start, SampleTime(4_000_004_010), 80, 1);
+		let configuration = test_configuration(|config| {
+			config.compact_xml = true;
+			config.frames_per_second = Some(50);
+		});
+		assert_eq!(configuration.buffer_length(), Ok(80));
+
+		let xml = buffer.to_openpmu_xml(&configuration).unwrap();
+		assert!(xml.contains("<Frame>49</Frame><Fs>4000</Fs><n>80</n>"), "{xml}");
+	}
+
+	#[test]
+	fn asdu_header_elements() {
+		let queue = SampleBufferQueue::new();
+		let mut asdu = test_asdu(42);
+		asdu.conf_rev = 7;
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, asdu);
+		// Later samples in the same buffer do not change the values.
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(41));
 		let buffer = queue.pop_sample_buffer();
-		buffer.flush(&out_socket, dest, channels).unwrap();
+
+		let configuration = test_configuration(|config| config.compact_xml = true);
+		let xml = buffer.to_openpmu_xml(&configuration).unwrap();
+		assert!(!xml.contains("<SmpCnt>"));
+
+		let configuration = test_configuration(|config| {
+			config.compact_xml = true;
+			config.emit_asdu_header = true;
+		});
+		let xml = buffer.to_openpmu_xml(&configuration).unwrap();
+		assert!(xml.contains("<SmpCnt>42</SmpCnt><ConfRev>7</ConfRev><SmpSynch>2</SmpSynch><Channel_0>"));
+	}
+
+	#[test]
+	fn clock_unsynchronised_flag() {
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+		let configuration = test_configuration(|config| config.clock_sync = ClockSyncPolicy::Flag);
+		let counters = Counters::new(6);
+		assert!(!buffer.to_xml(&configuration, &counters).unwrap().contains("ClockUnsynchronised"));
+
+		buffer.clock_unsynchronised = true;
+		let xml = buffer.to_xml(&configuration, &counters).unwrap();
+		assert!(xml.contains("\t<ClockUnsynchronised>true</ClockUnsynchronised>\n"));
+	}
+
+	#[test]
+	fn channels_from_configuration() {
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+		for (input_channel, channel) in buffer.streams[0].iter_mut().enumerate() {
+			channel.insert_sample(0, (input_channel + 1) as f32 * 10.0);
+		}
+		let mut configuration = test_configuration(|config| config.compact_xml = true);
+		configuration.channels[3].name = "Substation2_Va".into();
+		let xml = buffer.to_xml(&configuration, &Counters::new(6)).unwrap();
+
+		assert!(xml.contains("<Channels>6</Channels>"));
+		assert!(!xml.contains("<Channel_6>"));
+		// Each output channel takes its samples from its configured input channel.
+		let expected_channels = [("Ia", 10), ("Ib", 20), ("Ic", 30), ("Substation2_Va", 50), ("Vb", 60), ("Vc", 70)];
+		for (index, (name, range)) in expected_channels.into_iter().enumerate() {
+			let expected = format!("<Channel_{index}><Name>{name}</Name>");
+			assert!(xml.contains(&expected), "{expected}");
+			let phase = &name[name.len() - 1..];
+			let expected = format!("<Phase>{phase}</Phase><Range>{range}</Range>");
+			assert!(xml.contains(&expected), "{expected}");
+		}
+	}
+
+	#[test]
+	fn saturated_samples() {
+		let mut buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+		for (index, value) in [100.0, -250.0, 300.0].into_iter().enumerate() {
+			buffer.streams[0][0].insert_sample(index as u32, value);
+			buffer.streams[0][4].insert_sample(index as u32, value);
+		}
+		let mut configuration = test_configuration(|config| {
+			config.compact_xml = true;
+			config.saturation.current = Some(250.0);
+		});
+		// The threshold applies to the received values, before calibration.
+		configuration.channels[0].gain = 0.5;
+		let counters = Counters::new(6);
+		let xml = buffer.to_xml(&configuration, &counters).unwrap();
+
+		assert!(xml.contains("<Channel_0><Name>Ia</Name><Type>I</Type><Phase>a</Phase><Range>150</Range><Saturated>2"));
+		// No threshold is configured for voltage channels.
+		assert_eq!(xml.matches("<Saturated>").count(), 3);
+		let saturated = counters
+			.saturated_samples
+			.iter()
+			.map(|count| count.load(Ordering::Relaxed));
+		assert_eq!(saturated.collect::<Vec<_>>(), [2, 0, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn escaped_channel_name() {
+		let buffer = SampleBuffer::new(4000, SampleTime(4_000_000_000), SampleTime(4_000_000_010), 40, 1);
+		let mut configuration = test_configuration(|config| config.compact_xml = true);
+		configuration.channels[0].name = "A&B<C>".into();
+		configuration.channels[0].phase = "'a'".into();
+		let xml = buffer.to_xml(&configuration, &Counters::new(6)).unwrap();
+
+		assert!(xml.contains("<Channel_0><Name>A&amp;B&lt;C&gt;</Name><Type>I</Type><Phase>&apos;a&apos;</Phase>"));
+		assert!(!xml.contains("A&B"));
+	}
+
+	#[test]
+	fn payload_length_check() {
+		let channel = SampleBufferChannel::new(40);
+		let (payload, _) = build_channel(&channel, 1.0, 16);
+		assert!(check_payload_length(&payload, 40, 2).is_ok());
+		assert!(matches!(
+			check_payload_length(&payload, 41, 2),
+			Err(BufferFlushError::PayloadLength { expected: 82, actual: 80 })
+		));
+		assert!(matches!(
+			check_payload_length(&payload, 40, 4),
+			Err(BufferFlushError::PayloadLength { expected: 160, actual: 80 })
+		));
+	}
+
+	#[test]
+	fn initial_buffer_mid_period() {
+		let queue = SampleBufferQueue::new();
+
+		// The first buffer starts at sample 40, but the first sample received is number 45.
+		for smp_cnt in 45..85 {
+			queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(smp_cnt));
+		}
+
+		let buffers = queue.queue.lock().unwrap();
+		assert_eq!(buffers.len(), 2);
+		assert!(buffers[0].is_partial());
+		assert!(!buffers[1].is_partial());
+	}
+
+	#[test]
+	fn initial_buffer_aligned() {
+		let queue = SampleBufferQueue::new();
+		queue.insert_sample(0, 1_000_000_000, 25_000_000, 4000, 40, test_asdu(40));
+
+		let buffers = queue.queue.lock().unwrap();
+		assert!(!buffers[0].is_partial());
 	}
 }
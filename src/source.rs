@@ -0,0 +1,153 @@
+use crate::ethernet::{EthernetSocket, RecvInfo};
+
+/// A source of received Ethernet frames containing sampled value messages.
+pub trait PacketSource {
+	/// Receives a single frame into `buf`, blocking until one is available.
+	fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<RecvInfo>;
+}
+
+impl PacketSource for EthernetSocket {
+	fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+		EthernetSocket::recv(self, buf)
+	}
+}
+
+/// Wraps a `PacketSource`, randomly dropping and reordering its frames, to simulate an unreliable network when testing
+/// the handling of lost and late samples.
+///
+/// - Each frame is dropped with probability `drop_probability`.
+/// - Otherwise, with probability `reorder_probability`, it is held back and delivered after the next frame instead.
+///   Only one frame is held back at a time.
+///
+/// The random number generator is seeded explicitly, so the same seed and input always give the same output.
+#[cfg(any(test, feature = "simulation"))]
+#[derive(Debug)]
+pub struct LossyReorderingSource<S> {
+	inner: S,
+	drop_probability: f64,
+	reorder_probability: f64,
+	rng_state: u64,
+	/// A frame which has been held back, along with its receive information.
+	held: Option<(Box<[u8]>, RecvInfo)>,
+	/// Whether the held frame should be delivered by the next call to `recv`.
+	release_held: bool,
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl<S: PacketSource> LossyReorderingSource<S> {
+	pub fn new(inner: S, drop_probability: f64, reorder_probability: f64, seed: u64) -> Self {
+		Self {
+			inner,
+			drop_probability,
+			reorder_probability,
+			rng_state: seed,
+			held: None,
+			release_held: false,
+		}
+	}
+
+	/// Returns `true` with the specified probability, using the SplitMix64 generator.
+	fn chance(&mut self, probability: f64) -> bool {
+		self.rng_state = self.rng_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		let mut z = self.rng_state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		z ^= z >> 31;
+		((z >> 11) as f64 / (1_u64 << 53) as f64) < probability
+	}
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl<S: PacketSource> PacketSource for LossyReorderingSource<S> {
+	fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+		if self.release_held {
+			self.release_held = false;
+			if let Some((data, info)) = self.held.take() {
+				buf[..data.len()].copy_from_slice(&data);
+				return Ok(info);
+			}
+		}
+
+		loop {
+			let info = self.inner.recv(buf)?;
+			if self.chance(self.drop_probability) {
+				continue;
+			}
+			if self.held.is_none() && self.chance(self.reorder_probability) {
+				self.held = Some((buf[..info.length].into(), info));
+				continue;
+			}
+			self.release_held = self.held.is_some();
+			return Ok(info);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::VecDeque;
+
+	use super::*;
+
+	/// A source which delivers each of a list of single-byte frames, then fails.
+	struct ListSource(VecDeque<u8>);
+
+	impl PacketSource for ListSource {
+		fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<RecvInfo> {
+			let value = self.0.pop_front().ok_or(std::io::ErrorKind::UnexpectedEof)?;
+			buf[0] = value;
+			Ok(RecvInfo {
+				length: 1,
+				timestamp_s: 0,
+				timestamp_ns: 0,
+				hardware_timestamp: false,
+				source_mac: None,
+				vlan_id: None,
+				priority: None,
+			})
+		}
+	}
+
+	fn receive_all(drop_probability: f64, reorder_probability: f64, seed: u64) -> Vec<u8> {
+		let inner = ListSource((0..100).collect());
+		let mut source = LossyReorderingSource::new(inner, drop_probability, reorder_probability, seed);
+		let mut buf = [0; 1];
+		let mut received = vec![];
+		while let Ok(info) = source.recv(&mut buf) {
+			assert_eq!(info.length, 1);
+			received.push(buf[0]);
+		}
+		received
+	}
+
+	#[test]
+	fn passthrough() {
+		assert_eq!(receive_all(0.0, 0.0, 1), (0..100).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn drop() {
+		let received = receive_all(0.2, 0.0, 1);
+		assert!(received.len() > 60 && received.len() < 95, "{}", received.len());
+		assert!(received.is_sorted());
+		assert_eq!(received, receive_all(0.2, 0.0, 1));
+		assert_ne!(received, receive_all(0.2, 0.0, 2));
+	}
+
+	#[test]
+	fn reorder() {
+		let received = receive_all(0.0, 0.2, 1);
+		assert!(!received.is_sorted());
+		assert_eq!(received, receive_all(0.0, 0.2, 1));
+
+		// Frames are only delayed by one position, and none are lost (unless held back when the inner source ends).
+		let mut sorted = received.clone();
+		sorted.sort();
+		sorted.dedup();
+		assert_eq!(sorted.len(), received.len());
+		assert!(received.len() >= 99);
+		for (position, &value) in received.iter().enumerate() {
+			assert!((position as i32 - value as i32).abs() <= 1);
+		}
+	}
+}
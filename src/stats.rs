@@ -0,0 +1,302 @@
+use std::{
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, Instant},
+};
+
+/// Counters describing the operation of the bridge. These are shared between the receiver and sender threads, so all
+/// counters are atomic.
+#[derive(Debug, Default)]
+pub struct Counters {
+	/// The number of frames received on all interfaces.
+	pub received_frames: AtomicU64,
+	/// The number of samples containing values outside the configured plausibility bounds.
+	pub implausible_samples: AtomicU64,
+	/// The number of frames which were rejected because of their source MAC address or APPID, or because they were
+	/// simulated.
+	pub filtered_frames: AtomicU64,
+	/// The number of frames which were skipped without being parsed, because frame decimation keeps none of their
+	/// samples.
+	pub decimated_frames: AtomicU64,
+	/// The number of frames which were dropped because the receive queue was full.
+	pub queue_full_frames: AtomicU64,
+	/// The number of frames containing an ASDU whose svID did not match the expected pattern.
+	pub unexpected_svid_frames: AtomicU64,
+	/// The number of frames whose ASDUs were not evenly spaced, if `asdu_spacing_diagnostics` is enabled.
+	pub irregular_spacing_frames: AtomicU64,
+	/// The largest smpCnt spacing seen between consecutive ASDUs in a frame, if `asdu_spacing_diagnostics` is enabled.
+	pub max_asdu_spacing: AtomicU64,
+	/// The number of frames which were parsed successfully.
+	pub parsed_frames: AtomicU64,
+	/// The number of frames which could not be parsed.
+	pub parse_errors: AtomicU64,
+	/// The number of frames which were dropped because their receive timestamp was before the Unix epoch.
+	pub invalid_timestamp_frames: AtomicU64,
+	/// The number of ASDUs whose samples were inserted into a buffer.
+	pub inserted_asdus: AtomicU64,
+	/// The number of ASDUs which were ignored because their svID is not used by any channel.
+	pub unused_svid_asdus: AtomicU64,
+	/// The number of samples which were not received in time to be included in their buffer, summed over all input
+	/// streams.
+	pub missing_samples: AtomicU64,
+	/// The number of samples which were never received, as detected from gaps in the smpCnt sequence of each input
+	/// stream on each interface.
+	pub lost_samples: AtomicU64,
+	/// The number of samples which were dropped because their timestamp could not be represented.
+	pub invalid_timestamp_samples: AtomicU64,
+	/// The number of buffers which were sent as OpenPMU datagrams.
+	pub flushed_buffers: AtomicU64,
+	/// The number of buffers which could not be sent.
+	pub send_failures: AtomicU64,
+	/// The number of buffers which were dropped because the queue of buffers waiting to be sent was full.
+	pub dropped_buffers: AtomicU64,
+	/// The number of buffers which were not sent because the system clock was not synchronised, if `clock_sync` is
+	/// `hold`.
+	pub unsynchronised_buffers: AtomicU64,
+	/// The number of samples which were clipped during quantization, for each output channel.
+	pub clipped_samples: Box<[AtomicU64]>,
+	/// The number of samples at or beyond the configured saturation threshold, for each output channel.
+	pub saturated_samples: Box<[AtomicU64]>,
+}
+
+impl Counters {
+	/// Creates a new set of counters, all initialised to zero, for a bridge with the specified number of output
+	/// channels.
+	pub fn new(channel_count: usize) -> Self {
+		Self {
+			clipped_samples: (0..channel_count).map(|_| AtomicU64::new(0)).collect(),
+			saturated_samples: (0..channel_count).map(|_| AtomicU64::new(0)).collect(),
+			..Self::default()
+		}
+	}
+
+	/// Logs the values of all counters, for a summary of the bridge's operation when it exits.
+	pub fn log_summary(&self) {
+		let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+		log::info!(
+			"Frames: {} received, {} parsed, {} dropped (receive queue full), {} filtered, {} decimated, {} failed to \
+			 parse, {} with an invalid timestamp, {} with an unexpected svID.",
+			get(&self.received_frames),
+			get(&self.parsed_frames),
+			get(&self.queue_full_frames),
+			get(&self.filtered_frames),
+			get(&self.decimated_frames),
+			get(&self.parse_errors),
+			get(&self.invalid_timestamp_frames),
+			get(&self.unexpected_svid_frames)
+		);
+		log::info!(
+			"ASDUs: {} inserted into buffers, {} ignored (svID not used by any channel).",
+			get(&self.inserted_asdus),
+			get(&self.unused_svid_asdus)
+		);
+		log::info!(
+			"Samples: {} missing, {} lost, {} with an invalid timestamp, {} implausible, {} clipped, {} saturated.",
+			get(&self.missing_samples),
+			get(&self.lost_samples),
+			get(&self.invalid_timestamp_samples),
+			get(&self.implausible_samples),
+			self.clipped_samples.iter().map(get).sum::<u64>(),
+			self.saturated_samples.iter().map(get).sum::<u64>()
+		);
+		log::info!(
+			"ASDU spacing: {} irregular frames, largest spacing {}.",
+			get(&self.irregular_spacing_frames),
+			get(&self.max_asdu_spacing)
+		);
+		log::info!(
+			"Buffers: {} sent, {} failed to send, {} dropped (queue full), {} held (clock not synchronised).",
+			get(&self.flushed_buffers),
+			get(&self.send_failures),
+			get(&self.dropped_buffers),
+			get(&self.unsynchronised_buffers)
+		);
+	}
+}
+
+/// The counters reported periodically to show the throughput of the bridge.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Throughput {
+	pub received_frames: u64,
+	pub parsed_frames: u64,
+	pub inserted_asdus: u64,
+	pub flushed_buffers: u64,
+	pub queue_full_frames: u64,
+	pub dropped_buffers: u64,
+}
+
+impl Throughput {
+	/// Reads the current totals from `counters`.
+	pub fn from_counters(counters: &Counters) -> Self {
+		let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+		Self {
+			received_frames: get(&counters.received_frames),
+			parsed_frames: get(&counters.parsed_frames),
+			inserted_asdus: get(&counters.inserted_asdus),
+			flushed_buffers: get(&counters.flushed_buffers),
+			queue_full_frames: get(&counters.queue_full_frames),
+			dropped_buffers: get(&counters.dropped_buffers),
+		}
+	}
+
+	/// Returns the increase in each counter since `previous`.
+	pub fn since(&self, previous: &Self) -> Self {
+		Self {
+			received_frames: self.received_frames - previous.received_frames,
+			parsed_frames: self.parsed_frames - previous.parsed_frames,
+			inserted_asdus: self.inserted_asdus - previous.inserted_asdus,
+			flushed_buffers: self.flushed_buffers - previous.flushed_buffers,
+			queue_full_frames: self.queue_full_frames - previous.queue_full_frames,
+			dropped_buffers: self.dropped_buffers - previous.dropped_buffers,
+		}
+	}
+}
+
+/// Determines when to log a summary of the samples saturated in each output channel, from the totals in `Counters`.
+#[derive(Debug)]
+pub struct SaturationSummary {
+	interval: Duration,
+	start: Instant,
+	/// The totals for each channel when the current interval started.
+	previous: Vec<u64>,
+}
+
+impl SaturationSummary {
+	pub fn new(channel_count: usize, interval: Duration, now: Instant) -> Self {
+		Self {
+			interval,
+			start: now,
+			previous: vec![0; channel_count],
+		}
+	}
+
+	/// Once the interval has elapsed, starts a new interval and returns the number of samples saturated in each
+	/// channel during the last one. Returns `None` if the interval has not elapsed or no samples were saturated.
+	pub fn poll(&mut self, counters: &Counters, now: Instant) -> Option<Vec<u64>> {
+		if now.duration_since(self.start) < self.interval {
+			return None;
+		}
+		self.start = now;
+		let recent = counters
+			.saturated_samples
+			.iter()
+			.zip(&mut self.previous)
+			.map(|(counter, previous)| {
+				let total = counter.load(Ordering::Relaxed);
+				total - std::mem::replace(previous, total)
+			})
+			.collect::<Vec<_>>();
+		recent.iter().any(|&count| count > 0).then_some(recent)
+	}
+}
+
+/// Detects lost samples from gaps in the smpCnt sequence of each input stream.
+#[derive(Debug)]
+pub struct SmpCntGapTracker {
+	/// The number of samples after which smpCnt returns to zero.
+	period: u32,
+	/// The latest smpCnt received from each stream.
+	latest: Vec<Option<u32>>,
+}
+
+impl SmpCntGapTracker {
+	/// Creates a tracker for `stream_count` input streams, whose smpCnt returns to zero after `period` samples.
+	pub fn new(stream_count: usize, period: u32) -> Self {
+		Self {
+			period,
+			latest: vec![None; stream_count],
+		}
+	}
+
+	/// Records a sample from the specified stream, returning the number of samples lost since the latest one.
+	///
+	/// smpCnt returning to zero at the end of each period is not a gap. A jump of half a period or more is taken to be
+	/// a duplicate or a sample received out of order rather than a loss, so it is not counted and does not replace the
+	/// latest sample.
+	pub fn record(&mut self, stream: usize, smp_cnt: u16) -> u32 {
+		let smp_cnt = u32::from(smp_cnt) % self.period;
+		let Some(latest) = self.latest[stream] else {
+			self.latest[stream] = Some(smp_cnt);
+			return 0;
+		};
+		let expected = (latest + 1) % self.period;
+		let gap = (smp_cnt + self.period - expected) % self.period;
+		if gap >= self.period / 2 {
+			return 0;
+		}
+		self.latest[stream] = Some(smp_cnt);
+		gap
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn consecutive_samples() {
+		let mut tracker = SmpCntGapTracker::new(1, 4000);
+		for smp_cnt in (3990..4000).chain(0..10) {
+			assert_eq!(tracker.record(0, smp_cnt), 0);
+		}
+	}
+
+	#[test]
+	fn gaps() {
+		let mut tracker = SmpCntGapTracker::new(2, 4000);
+		tracker.record(0, 10);
+		assert_eq!(tracker.record(0, 13), 2);
+		// Streams are tracked separately, and a gap can span the reset at the end of the second.
+		assert_eq!(tracker.record(1, 3998), 0);
+		assert_eq!(tracker.record(1, 1), 2);
+		assert_eq!(tracker.record(0, 14), 0);
+	}
+
+	#[test]
+	fn duplicates_and_reordering() {
+		let mut tracker = SmpCntGapTracker::new(1, 65536);
+		tracker.record(0, 65535);
+		assert_eq!(tracker.record(0, 65535), 0);
+		assert_eq!(tracker.record(0, 1), 1);
+		// The sample which arrived late is not counted again.
+		assert_eq!(tracker.record(0, 0), 0);
+		assert_eq!(tracker.record(0, 2), 0);
+	}
+
+	#[test]
+	fn throughput_since() {
+		let counters = Counters::new(0);
+		counters.received_frames.store(10, Ordering::Relaxed);
+		counters.inserted_asdus.store(20, Ordering::Relaxed);
+		let previous = Throughput::from_counters(&counters);
+
+		counters.received_frames.fetch_add(5, Ordering::Relaxed);
+		counters.dropped_buffers.fetch_add(1, Ordering::Relaxed);
+		let recent = Throughput::from_counters(&counters).since(&previous);
+		let expected = Throughput {
+			received_frames: 5,
+			dropped_buffers: 1,
+			..Throughput::default()
+		};
+		assert_eq!(recent, expected);
+	}
+
+	#[test]
+	fn saturation_summary() {
+		let counters = Counters::new(2);
+		let start = Instant::now();
+		let mut summary = SaturationSummary::new(2, Duration::from_secs(10), start);
+		counters.saturated_samples[1].fetch_add(3, Ordering::Relaxed);
+		assert_eq!(summary.poll(&counters, start + Duration::from_secs(5)), None);
+		assert_eq!(
+			summary.poll(&counters, start + Duration::from_secs(10)),
+			Some(vec![0, 3])
+		);
+		// Only samples saturated since the last summary are reported.
+		counters.saturated_samples[0].fetch_add(1, Ordering::Relaxed);
+		assert_eq!(
+			summary.poll(&counters, start + Duration::from_secs(20)),
+			Some(vec![1, 0])
+		);
+		assert_eq!(summary.poll(&counters, start + Duration::from_secs(30)), None);
+	}
+}
@@ -0,0 +1,133 @@
+use std::{
+	borrow::Cow,
+	fmt::{Display, Write},
+};
+
+/// Writes the simple XML documents used by OpenPMU, which consist only of nested elements without attributes.
+///
+/// In pretty mode, each element is placed on its own line and indented with tabs according to its depth. In compact
+/// mode, no whitespace is written between elements.
+#[derive(Debug)]
+pub(crate) struct XmlWriter {
+	buf: String,
+	compact: bool,
+	depth: usize,
+}
+
+impl XmlWriter {
+	pub fn new(compact: bool) -> Self {
+		Self {
+			buf: String::new(),
+			compact,
+			depth: 0,
+		}
+	}
+
+	/// Writes the start tag of an element which contains other elements.
+	pub fn start(&mut self, name: impl Display) -> std::fmt::Result {
+		self.indent();
+		write!(self.buf, "<{name}>")?;
+		self.newline();
+		self.depth += 1;
+		Ok(())
+	}
+
+	/// Writes the end tag of an element started with `start`.
+	pub fn end(&mut self, name: impl Display) -> std::fmt::Result {
+		self.depth -= 1;
+		self.indent();
+		write!(self.buf, "</{name}>")?;
+		self.newline();
+		Ok(())
+	}
+
+	/// Writes an element containing the specified text.
+	pub fn element(&mut self, name: impl Display, text: impl Display) -> std::fmt::Result {
+		self.indent();
+		write!(self.buf, "<{name}>{text}</{name}>")?;
+		self.newline();
+		Ok(())
+	}
+
+	/// Writes an element whose text is written directly into the document by `write_text`, which avoids formatting
+	/// large values (such as payloads) separately.
+	pub fn element_with(&mut self, name: impl Display, write_text: impl FnOnce(&mut String)) -> std::fmt::Result {
+		self.indent();
+		write!(self.buf, "<{name}>")?;
+		write_text(&mut self.buf);
+		write!(self.buf, "</{name}>")?;
+		self.newline();
+		Ok(())
+	}
+
+	pub fn into_string(self) -> String {
+		self.buf
+	}
+
+	fn indent(&mut self) {
+		if !self.compact {
+			self.buf.extend(std::iter::repeat_n('\t', self.depth));
+		}
+	}
+
+	fn newline(&mut self) {
+		if !self.compact {
+			self.buf.push('\n');
+		}
+	}
+}
+
+/// Replaces the characters which have a special meaning in XML with the corresponding entity references.
+pub(crate) fn escape_xml(s: &str) -> Cow<'_, str> {
+	if !s.contains(['&', '<', '>', '"', '\'']) {
+		return Cow::Borrowed(s);
+	}
+
+	let mut escaped = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&apos;"),
+			_ => escaped.push(c),
+		}
+	}
+	Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn write_document(compact: bool) -> String {
+		let mut xml = XmlWriter::new(compact);
+		xml.start("OpenPMU").unwrap();
+		xml.element("Fs", 4000).unwrap();
+		xml.start("Channel_0").unwrap();
+		xml.element("Phase", escape_xml("<a&b>")).unwrap();
+		xml.element_with("Payload", |buf| buf.push_str("AAAA")).unwrap();
+		xml.end("Channel_0").unwrap();
+		xml.end("OpenPMU").unwrap();
+		xml.into_string()
+	}
+
+	#[test]
+	fn pretty() {
+		assert_eq!(
+			write_document(false),
+			"<OpenPMU>\n\t<Fs>4000</Fs>\n\t<Channel_0>\n\t\t<Phase>&lt;a&amp;b&gt;</Phase>\n\
+			 \t\t<Payload>AAAA</Payload>\n\t</Channel_0>\n</OpenPMU>\n"
+		);
+	}
+
+	#[test]
+	fn compact() {
+		assert_eq!(
+			write_document(true),
+			"<OpenPMU><Fs>4000</Fs><Channel_0><Phase>&lt;a&amp;b&gt;</Phase>\
+			 <Payload>AAAA</Payload></Channel_0></OpenPMU>"
+		);
+	}
+}
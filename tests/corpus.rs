@@ -0,0 +1,59 @@
+//! Regression tests which parse SV frames and compare the results with the expected output. The fixtures are
+//! synthetic frames, not captures from real devices.
+//!
+//! Each fixture in `tests/corpus` consists of a `.bin` file containing the payload of an Ethernet frame (starting at
+//! the APPID), and a `.json` file with the same name containing the expected result of `parse`. For frames which
+//! should fail to parse, the JSON file instead contains an object of the form `{"error": "<message>"}`.
+//!
+//! To add a fixture, place its `.bin` file in the directory and run the tests with `UPDATE_CORPUS=1` set, which writes
+//! the JSON file for any fixture whose output differs. Check the generated output by hand before committing it.
+
+use std::path::Path;
+
+use mu_rust::parse;
+use serde_json::{json, Value};
+
+fn decode(bytes: &[u8]) -> Value {
+	match parse(bytes) {
+		Ok(message) => serde_json::to_value(message).unwrap(),
+		Err(err) => json!({ "error": err.to_string() }),
+	}
+}
+
+#[test]
+fn corpus() {
+	let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+	let update = std::env::var_os("UPDATE_CORPUS").is_some();
+
+	let mut fixtures = std::fs::read_dir(&directory)
+		.unwrap()
+		.map(|entry| entry.unwrap().path())
+		.filter(|path| path.extension().is_some_and(|extension| extension == "bin"))
+		.collect::<Vec<_>>();
+	fixtures.sort();
+	assert!(!fixtures.is_empty(), "no fixtures found in '{}'", directory.display());
+
+	let mut failures = Vec::new();
+	for fixture in fixtures {
+		let actual = decode(&std::fs::read(&fixture).unwrap());
+
+		let expected_path = fixture.with_extension("json");
+		let expected = std::fs::read_to_string(&expected_path)
+			.ok()
+			.map(|s| serde_json::from_str::<Value>(&s).unwrap());
+
+		if expected.as_ref() == Some(&actual) {
+			continue;
+		}
+
+		if update {
+			let mut output = serde_json::to_string_pretty(&actual).unwrap();
+			output.push('\n');
+			std::fs::write(&expected_path, output).unwrap();
+		} else {
+			failures.push(format!("{}: got {actual}", fixture.display()));
+		}
+	}
+
+	assert!(failures.is_empty(), "fixtures did not match:\n{}", failures.join("\n"));
+}